@@ -0,0 +1,384 @@
+use crate::error::MyError;
+use crate::logger::{self, LogUser};
+use crate::messages::Messages;
+use crate::metrics;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use chrono::{DateTime, Duration, Utc};
+use remnawave::api::types::users::UserData;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, UserId};
+use uuid::Uuid;
+
+/// Entries shown in the chat when no export format is asked for.
+const CHAT_LIMIT: usize = 20;
+
+/// One state-changing action, as kept in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Unix seconds.
+    pub at: i64,
+    /// Telegram ID of whoever did it.
+    pub actor_id: u64,
+    /// Panel user the action was applied to, if known.
+    pub target_uuid: Option<Uuid>,
+    pub action: String,
+    /// JSON snapshot of the target before the action.
+    pub before: Option<String>,
+    /// JSON snapshot of the target after the action.
+    pub after: Option<String>,
+    /// `ok` or `error: <message>`.
+    pub outcome: String,
+}
+
+/// The fields of a panel user that actions change.
+pub fn snapshot(user: &UserData) -> String {
+    json!({
+        "username": user.username,
+        "status": user.status.to_string(),
+        "expireAt": user.expire_at,
+        "usedTrafficBytes": user.used_traffic_bytes,
+        "trafficLimitBytes": user.traffic_limit_bytes,
+        "shortUuid": user.short_uuid,
+        "telegramId": user.telegram_id,
+    })
+    .to_string()
+}
+
+/// Records `action` of `actor` on a panel user in the audit log.
+///
+/// `before` is the user before the action, `outcome` the user after it
+/// (if the action leaves one) or the error. Failing to write the log is
+/// logged, not returned, so that auditing never breaks the action itself.
+pub fn record(
+    storage: &Storage,
+    actor: UserId,
+    action: &str,
+    before: Option<&UserData>,
+    outcome: Result<Option<&UserData>, &MyError>,
+) {
+    let after = outcome.as_ref().ok().copied().flatten();
+    let entry = AuditEntry {
+        at: Utc::now().timestamp(),
+        actor_id: actor.0,
+        target_uuid: before.or(after).map(|user| user.uuid),
+        action: action.to_string(),
+        before: before.map(snapshot),
+        after: after.map(snapshot),
+        outcome: match outcome {
+            Ok(_) => "ok".to_string(),
+            Err(e) => format!("error: {}", e),
+        },
+    };
+    log::info!(
+        "Audit: {} by {} on {}: {}",
+        entry.action,
+        LogUser(actor.0),
+        entry
+            .target_uuid
+            .map_or_else(|| "-".to_string(), |uuid| uuid.to_string()),
+        entry.outcome
+    );
+    if let Err(e) = storage.record_audit(&entry) {
+        log::error!("Failed to write audit entry {}: {}", entry.action, e);
+    }
+}
+
+/// Which audit entries to return; `None` fields match anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditFilter {
+    pub actor_id: Option<u64>,
+    pub target_uuid: Option<Uuid>,
+    pub action: Option<String>,
+    /// Unix seconds of the oldest entry.
+    pub since: Option<i64>,
+}
+
+/// How `/audit` returns the entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Chat,
+    Csv,
+    Json,
+}
+
+/// Parses the arguments of `/audit`: space-separated `actor=<id>`,
+/// `target=<uuid>`, `action=<name>`, `days=<n>` and `format=csv|json`.
+fn parse_args(args: &str, now: DateTime<Utc>) -> Result<(AuditFilter, Format), String> {
+    let mut filter = AuditFilter::default();
+    let mut format = Format::Chat;
+    for arg in args.split_whitespace() {
+        let Some((key, value)) = arg.split_once('=') else {
+            return Err(arg.to_string());
+        };
+        match key {
+            "actor" => filter.actor_id = Some(value.parse().map_err(|_| arg.to_string())?),
+            "target" => filter.target_uuid = Some(value.parse().map_err(|_| arg.to_string())?),
+            "action" => filter.action = Some(value.to_string()),
+            "days" => {
+                let since = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|days| *days > 0)
+                    .and_then(|days| Duration::try_days(days.into()))
+                    .and_then(|days| now.checked_sub_signed(days))
+                    .ok_or_else(|| arg.to_string())?;
+                filter.since = Some(since.timestamp());
+            }
+            "format" => {
+                format = match value {
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    _ => return Err(arg.to_string()),
+                }
+            }
+            _ => return Err(arg.to_string()),
+        }
+    }
+    Ok((filter, format))
+}
+
+fn timestamp(at: i64) -> String {
+    DateTime::<Utc>::from_timestamp(at, 0)
+        .map(|at| at.to_rfc3339())
+        .unwrap_or_else(|| at.to_string())
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a newline.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("at,actor_id,target_uuid,action,before,after,outcome\n");
+    for entry in entries {
+        let fields = [
+            timestamp(entry.at),
+            entry.actor_id.to_string(),
+            entry
+                .target_uuid
+                .map(|uuid| uuid.to_string())
+                .unwrap_or_default(),
+            entry.action.clone(),
+            entry.before.clone().unwrap_or_default(),
+            entry.after.clone().unwrap_or_default(),
+            entry.outcome.clone(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn to_json(entries: &[AuditEntry]) -> String {
+    let snapshot = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|value| serde_json::from_str(value).unwrap_or_else(|_| Value::from(value)))
+    };
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "at": timestamp(entry.at),
+                "actorId": entry.actor_id,
+                "targetUuid": entry.target_uuid,
+                "action": entry.action,
+                "before": snapshot(&entry.before),
+                "after": snapshot(&entry.after),
+                "outcome": entry.outcome,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&entries).unwrap_or_default()
+}
+
+fn to_chat(entries: &[AuditEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{} · {} · {} · {} · {}",
+                timestamp(entry.at),
+                entry.actor_id,
+                entry.action,
+                entry
+                    .target_uuid
+                    .map_or_else(|| "—".to_string(), |uuid| uuid.to_string()),
+                entry.outcome
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Handles the admin `/audit [filters]` command.
+///
+/// Shows the latest matching entries in the chat, or sends all of them
+/// as a CSV or JSON file.
+pub async fn command(bot: Bot, msg: Message, args: String, storage: Arc<Storage>) -> HandlerResult {
+    if let Some(user) = &msg.from {
+        log::info!("Admin {} called /audit {}", logger::user(user.id), args);
+    }
+    metrics::record_command("audit");
+    let messages = Messages::ru();
+
+    let (filter, format) = match parse_args(&args, Utc::now()) {
+        Ok(parsed) => parsed,
+        Err(arg) => {
+            bot.send_message(msg.chat.id, messages.audit_usage(&arg))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let limit = (format == Format::Chat).then_some(CHAT_LIMIT);
+    let entries = storage.audit_entries(&filter, limit)?;
+    if entries.is_empty() {
+        bot.send_message(msg.chat.id, messages.audit_empty())
+            .await?;
+        return Ok(());
+    }
+
+    let (contents, name) = match format {
+        Format::Chat => {
+            bot.send_message(
+                msg.chat.id,
+                messages.audit_entries(entries.len(), &to_chat(&entries)),
+            )
+            .await?;
+            return Ok(());
+        }
+        Format::Csv => (to_csv(&entries), "audit.csv"),
+        Format::Json => (to_json(&entries), "audit.json"),
+    };
+    bot.send_document(msg.chat.id, InputFile::memory(contents).file_name(name))
+        .caption(messages.audit_export(entries.len()))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(outcome: &str) -> AuditEntry {
+        AuditEntry {
+            at: 0,
+            actor_id: 42,
+            target_uuid: None,
+            action: "delete_me".to_string(),
+            before: Some(r#"{"username":"bob"}"#.to_string()),
+            after: None,
+            outcome: outcome.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_filters() {
+        let now = Utc::now();
+        let uuid = Uuid::new_v4();
+        let (filter, format) = parse_args(
+            &format!(
+                "actor=42 target={} action=delete_me days=7 format=csv",
+                uuid
+            ),
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(
+            filter,
+            AuditFilter {
+                actor_id: Some(42),
+                target_uuid: Some(uuid),
+                action: Some("delete_me".to_string()),
+                since: Some((now - Duration::days(7)).timestamp()),
+            }
+        );
+        assert_eq!(format, Format::Csv);
+    }
+
+    #[test]
+    fn rejects_unknown_arguments() {
+        assert_eq!(
+            parse_args("actor=bob", Utc::now()).unwrap_err(),
+            "actor=bob"
+        );
+        assert_eq!(
+            parse_args("everything", Utc::now()).unwrap_err(),
+            "everything"
+        );
+        assert_eq!(
+            parse_args("format=xml", Utc::now()).unwrap_err(),
+            "format=xml"
+        );
+        for days in [
+            "days=-1",
+            "days=0",
+            "days=99999999999999",
+            "days=4000000000",
+        ] {
+            assert_eq!(parse_args(days, Utc::now()).unwrap_err(), days);
+        }
+    }
+
+    #[test]
+    fn escapes_csv_fields() {
+        let csv = to_csv(&[entry("error: bad, \"very\" bad")]);
+        let row = csv.lines().nth(1).unwrap();
+
+        assert_eq!(
+            row,
+            r#"1970-01-01T00:00:00+00:00,42,,delete_me,"{""username"":""bob""}",,"error: bad, ""very"" bad""#
+        );
+    }
+
+    #[test]
+    fn exports_snapshots_as_json() {
+        let json: Value = serde_json::from_str(&to_json(&[entry("ok")])).unwrap();
+
+        assert_eq!(json[0]["before"]["username"], "bob");
+        assert_eq!(json[0]["after"], Value::Null);
+    }
+
+    #[test]
+    fn stores_and_filters_entries() {
+        let storage = Storage::open_in_memory().unwrap();
+        let mut old = entry("ok");
+        old.at = 100;
+        storage.record_audit(&old).unwrap();
+        let mut other = entry("ok");
+        other.at = 200;
+        other.actor_id = 7;
+        other.action = "admin_extend".to_string();
+        storage.record_audit(&other).unwrap();
+
+        let all = storage
+            .audit_entries(&AuditFilter::default(), None)
+            .unwrap();
+        assert_eq!(all, vec![other.clone(), old.clone()]);
+
+        let by_actor = AuditFilter {
+            actor_id: Some(42),
+            ..Default::default()
+        };
+        assert_eq!(storage.audit_entries(&by_actor, None).unwrap(), vec![old]);
+
+        let recent = AuditFilter {
+            since: Some(150),
+            ..Default::default()
+        };
+        assert_eq!(
+            storage.audit_entries(&recent, Some(1)).unwrap(),
+            vec![other]
+        );
+    }
+}
@@ -0,0 +1,545 @@
+use crate::audit;
+use crate::client::get_client;
+use crate::config::{BalanceConfig, Config, Plan};
+use crate::error::MyError;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::outbox::{Outbox, OutgoingMessage};
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use crate::users;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::prelude::*;
+use teloxide::types::{LabeledPrice, MessageId, PreCheckoutQuery, SuccessfulPayment};
+
+/// Prefix of the invoice payloads of top-ups, followed by the amount.
+pub const PAYLOAD_PREFIX: &str = "topup:";
+
+/// Ledger entries shown on the balance screen.
+const LEDGER_SHOWN: u32 = 5;
+
+/// Why a balance changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerKind {
+    /// A payment of the user.
+    TopUp,
+    /// A bonus for a user who came through the user's referral link.
+    Referral,
+    /// A promo code.
+    Promo,
+    /// An automatic renewal of the subscription.
+    Renewal,
+}
+
+impl LedgerKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LedgerKind::TopUp => "top_up",
+            LedgerKind::Referral => "referral",
+            LedgerKind::Promo => "promo",
+            LedgerKind::Renewal => "renewal",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "top_up" => Some(LedgerKind::TopUp),
+            "referral" => Some(LedgerKind::Referral),
+            "promo" => Some(LedgerKind::Promo),
+            "renewal" => Some(LedgerKind::Renewal),
+            _ => None,
+        }
+    }
+}
+
+/// A change of a balance, as kept in the ledger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// Positive for credits, negative for charges.
+    pub amount: i64,
+    /// The balance after the change.
+    pub balance: i64,
+    pub kind: LedgerKind,
+    pub at: DateTime<Utc>,
+}
+
+/// Outcome of [`Storage::post`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Posting {
+    /// The balance changed and is now the given amount.
+    Done(i64),
+    /// The balance is too low for the charge; nothing changed.
+    Insufficient,
+    /// The change was already made; nothing changed.
+    Duplicate,
+}
+
+/// What a renewal check does for a subscription with some time left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Too early to do anything.
+    Wait,
+    /// Warn the user if their balance is short.
+    Warn,
+    /// Renew from the balance.
+    Renew,
+}
+
+fn stage(left: chrono::Duration, config: &BalanceConfig) -> Stage {
+    let reached = |before: std::time::Duration| {
+        chrono::Duration::from_std(before).is_ok_and(|before| left <= before)
+    };
+    if reached(config.renew_before) {
+        Stage::Renew
+    } else if reached(config.warn_before) {
+        Stage::Warn
+    } else {
+        Stage::Wait
+    }
+}
+
+/// The amount an invoice `payload` tops up, if it may still be bought.
+fn top_up_amount(config: &Config, payload: &str) -> Option<u32> {
+    let amount = payload.strip_prefix(PAYLOAD_PREFIX)?.parse().ok()?;
+    config
+        .balance
+        .top_up_amounts
+        .contains(&amount)
+        .then_some(amount)
+}
+
+/// Sends the balance screen of `user`, or puts it in place of
+/// `message_id` if given.
+async fn send_screen(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    user: UserId,
+    config: &Config,
+    storage: &Storage,
+) -> HandlerResult {
+    let balance = storage.balance(user)?;
+    let renewal = storage.auto_renew_plan(user)?;
+    let plan = renewal.as_deref().and_then(|id| config.payments.plan(id));
+    let text = Messages::ru().balance(
+        balance,
+        &config.payments.currency,
+        plan,
+        &storage.ledger(user, LEDGER_SHOWN)?,
+    );
+    let keyboard = keyboards::balance(config, plan.map(|plan| plan.id.as_str()));
+    match message_id {
+        Some(id) => {
+            bot.edit_message_text(chat_id, id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        None => {
+            bot.send_message(chat_id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The balance screen (`balance`), top-up invoices
+/// (`balance:topup:<amount>`) and the renewal setting
+/// (`balance:renew:<plan id>`, `balance:renew:off`).
+pub async fn handle_callback(
+    bot: &Bot,
+    q: &CallbackQuery,
+    data: &str,
+    config: &Config,
+    storage: &Storage,
+) -> HandlerResult {
+    let user = q.from.id;
+    log::info!("User {} called {}", logger::user(user), data);
+    metrics::record_callback("balance");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+
+    if let Some(amount) = data
+        .strip_prefix("balance:topup:")
+        .and_then(|amount| top_up_amount(config, &format!("{}{}", PAYLOAD_PREFIX, amount)))
+    {
+        bot.answer_callback_query(q.id.clone()).await?;
+        let messages = Messages::ru();
+        let mut invoice = bot.send_invoice(
+            chat_id,
+            messages.top_up_title(),
+            messages.top_up_description(amount, &config.payments.currency),
+            format!("{}{}", PAYLOAD_PREFIX, amount),
+            config.payments.currency.clone(),
+            [LabeledPrice::new(messages.top_up_title(), amount)],
+        );
+        if let Some(token) = &config.payments.provider_token {
+            invoice = invoice.provider_token(token.clone());
+        }
+        invoice.await?;
+        return Ok(());
+    }
+    match data.strip_prefix("balance:renew:") {
+        Some("off") => {
+            storage.set_auto_renew(user, None)?;
+            log::info!("User {} turned off auto-renewal", logger::user(user));
+        }
+        Some(id) => {
+            if storage.family_of(user)?.is_some() {
+                // Members follow the plan of their owner, which would undo
+                // every renewal and get them charged again.
+                bot.answer_callback_query(q.id.clone())
+                    .text(Messages::ru().renewal_family_member())
+                    .show_alert(true)
+                    .await?;
+                return Ok(());
+            }
+            if let Some(plan) = config.payments.plan(id) {
+                storage.set_auto_renew(user, Some(&plan.id))?;
+                log::info!(
+                    "User {} turned on auto-renewal with plan {}",
+                    logger::user(user),
+                    plan.id
+                );
+            }
+        }
+        None => {}
+    }
+    let message_id = q.message.as_ref().map(|msg| msg.id());
+    send_screen(bot, chat_id, message_id, user, config, storage).await
+}
+
+/// Confirms a top-up checkout if its amount may still be bought.
+pub async fn pre_checkout(bot: Bot, q: PreCheckoutQuery, config: Arc<Config>) -> HandlerResult {
+    let valid = top_up_amount(&config, &q.invoice_payload)
+        .is_some_and(|amount| amount == q.total_amount && config.payments.currency == q.currency);
+    log::info!(
+        "User {} checks out {} ({})",
+        logger::user(q.from.id),
+        q.invoice_payload,
+        if valid { "accepted" } else { "declined" }
+    );
+    let mut answer = bot.answer_pre_checkout_query(q.id, valid);
+    if !valid {
+        answer = answer.error_message(Messages::ru().top_up_unavailable());
+    }
+    answer.await?;
+    Ok(())
+}
+
+/// Credits a successful top-up payment to the balance of the payer.
+pub async fn paid(
+    bot: Bot,
+    msg: Message,
+    payment: SuccessfulPayment,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+) -> HandlerResult {
+    let Some(payer) = &msg.from else {
+        return Ok(());
+    };
+    metrics::record_command("top_up_paid");
+    let charge_id = payment.telegram_payment_charge_id.0;
+    let amount = payment.total_amount;
+    let balance = match storage.post(payer.id, amount.into(), LedgerKind::TopUp, &charge_id)? {
+        Posting::Done(balance) => balance,
+        Posting::Duplicate | Posting::Insufficient => {
+            log::warn!("Payment {} is already credited", charge_id);
+            return Ok(());
+        }
+    };
+    log::info!(
+        "User {} topped up {} {}, charge {}",
+        logger::user(payer.id),
+        amount,
+        payment.currency,
+        charge_id
+    );
+    bot.send_message(
+        msg.chat.id,
+        Messages::ru().topped_up(amount, &payment.currency, balance),
+    )
+    .reply_markup(keyboards::back_to_main_menu())
+    .await?;
+    credit_referrer(payer.id, &config, &storage, &outbox)?;
+    Ok(())
+}
+
+/// Credits the referral bonus to whoever invited `payer`, once per
+/// invited user, on their first payment.
+pub(crate) fn credit_referrer(
+    payer: UserId,
+    config: &Config,
+    storage: &Storage,
+    outbox: &Outbox,
+) -> Result<(), MyError> {
+    let bonus = config.balance.referral_bonus;
+    if bonus == 0 {
+        return Ok(());
+    }
+    let Some(referrer) = storage.referrer_of(payer)? else {
+        return Ok(());
+    };
+    let reference = payer.0.to_string();
+    if let Posting::Done(balance) =
+        storage.post(referrer, bonus.into(), LedgerKind::Referral, &reference)?
+    {
+        log::info!(
+            "User {} got a referral bonus for {}",
+            logger::user(referrer),
+            logger::user(payer)
+        );
+        outbox.enqueue(OutgoingMessage::text(
+            ChatId::from(referrer),
+            Messages::ru().referral_bonus(bonus, &config.payments.currency, balance),
+        ));
+    }
+    Ok(())
+}
+
+/// Ledger reference of the renewal of a subscription expiring at
+/// `expiry`: each expiry is paid for once.
+fn renewal_reference(plan: &str, expiry: i64) -> String {
+    format!("{}:{}", plan, expiry)
+}
+
+/// Renews the subscription of `user` with `plan` from their balance when
+/// it is about to expire, or warns them ahead of time that the balance is
+/// short.
+///
+/// A renewal that was paid for but never reached the panel is applied
+/// again on the next check without another charge.
+async fn check_renewal(
+    user: UserId,
+    plan: &Plan,
+    config: &Config,
+    storage: &Storage,
+    outbox: &Outbox,
+) -> Result<(), MyError> {
+    let messages = Messages::ru();
+    let currency = &config.payments.currency;
+    let before = match get_client()
+        .find_user_by_telegram_id(user.0)
+        .await
+        .into_user()
+    {
+        Ok(before) => before,
+        Err(MyError::UserNotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let now = Utc::now();
+    let expiry = before.expire_at.timestamp();
+    let notify = |text: String| outbox.enqueue(OutgoingMessage::text(ChatId::from(user), text));
+
+    match stage(before.expire_at - now, &config.balance) {
+        Stage::Wait => return Ok(()),
+        Stage::Warn => {
+            let balance = storage.balance(user)?;
+            if balance < i64::from(plan.price) && storage.mark_renewal_warned(user, expiry)? {
+                notify(messages.renewal_warning(plan, currency, balance, before.expire_at));
+            }
+            return Ok(());
+        }
+        Stage::Renew => {}
+    }
+
+    // Only this task charges renewals, so an attempt can't race another.
+    let reference = renewal_reference(&plan.id, expiry);
+    let price = i64::from(plan.price);
+    let balance = match storage.post(user, -price, LedgerKind::Renewal, &reference)? {
+        Posting::Done(balance) => balance,
+        // The expiry didn't move since it was paid for: the panel failed
+        // or the bot stopped before extending the subscription.
+        Posting::Duplicate => storage.balance(user)?,
+        Posting::Insufficient => {
+            if storage.mark_renewal_warned(user, expiry)? {
+                let balance = storage.balance(user)?;
+                notify(messages.renewal_warning(plan, currency, balance, before.expire_at));
+            }
+            return Ok(());
+        }
+    };
+    let result = {
+        let _guard = users::locks().lock(user.0).await;
+        let from = before.expire_at.max(now);
+        get_client()
+            .set_user_expiry(
+                before.uuid,
+                from + chrono::Duration::days(i64::from(plan.days)),
+            )
+            .await
+    };
+    audit::record(
+        storage,
+        user,
+        "auto_renew",
+        Some(&before),
+        result.as_ref().map(Some),
+    );
+    let after = result?;
+    log::info!(
+        "Renewed the subscription of {} with plan {} until {}",
+        logger::user(user),
+        plan.id,
+        after.expire_at
+    );
+    notify(messages.renewed(plan, currency, balance, after.expire_at));
+    Ok(())
+}
+
+/// Checks the subscriptions with automatic renewal every
+/// `check_interval`, renewing and warning as needed.
+pub async fn run_renewals(config: Arc<Config>, storage: Arc<Storage>, outbox: Arc<Outbox>) {
+    let mut interval = tokio::time::interval(config.balance.check_interval);
+    loop {
+        interval.tick().await;
+        let renewals = match storage.auto_renewals() {
+            Ok(renewals) => renewals,
+            Err(e) => {
+                log::error!("Failed to list auto-renewals: {}", e);
+                continue;
+            }
+        };
+        for (user, plan) in renewals {
+            let Some(plan) = config.payments.plan(&plan) else {
+                log::warn!(
+                    "User {} renews with plan {} that is no longer on sale",
+                    logger::user(user),
+                    plan
+                );
+                continue;
+            };
+            if let Err(e) = check_renewal(user, plan, &config, &storage, &outbox).await {
+                metrics::record_error(&e);
+                log::error!(
+                    "Failed to renew the subscription of {}: {}",
+                    logger::user(user),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::Ban;
+    use std::time::Duration;
+
+    #[test]
+    fn renewals_start_before_expiry() {
+        let config = BalanceConfig {
+            top_up_amounts: vec![100],
+            referral_bonus: 0,
+            renew_before: Duration::from_secs(24 * 3600),
+            warn_before: Duration::from_secs(72 * 3600),
+            check_interval: Duration::from_secs(3600),
+        };
+        let hours = chrono::Duration::hours;
+        assert_eq!(stage(hours(100), &config), Stage::Wait);
+        assert_eq!(stage(hours(72), &config), Stage::Warn);
+        assert_eq!(stage(hours(25), &config), Stage::Warn);
+        assert_eq!(stage(hours(24), &config), Stage::Renew);
+        assert_eq!(stage(hours(-5), &config), Stage::Renew);
+    }
+
+    #[test]
+    fn renewals_are_charged_once_per_expiry_and_skip_family_members_and_banned_users() {
+        let storage = Storage::open_in_memory().unwrap();
+        let (user, member, owner, banned) = (UserId(1), UserId(2), UserId(3), UserId(4));
+        storage
+            .post(user, 300, LedgerKind::TopUp, "charge")
+            .unwrap();
+        let reference = renewal_reference("month", 1_700_000_000);
+        assert_eq!(
+            storage
+                .post(user, -100, LedgerKind::Renewal, &reference)
+                .unwrap(),
+            Posting::Done(200)
+        );
+        assert_eq!(
+            storage
+                .post(user, -100, LedgerKind::Renewal, &reference)
+                .unwrap(),
+            Posting::Duplicate
+        );
+        assert_eq!(storage.balance(user).unwrap(), 200);
+
+        storage.set_auto_renew(user, Some("month")).unwrap();
+        storage.set_auto_renew(member, Some("month")).unwrap();
+        assert!(storage.create_family(owner, "FAMILY").unwrap());
+        assert!(storage.add_family_member(owner, member, 5).unwrap());
+        storage
+            .post(banned, 300, LedgerKind::TopUp, "banned-charge")
+            .unwrap();
+        storage.set_auto_renew(banned, Some("month")).unwrap();
+        storage
+            .ban(
+                banned,
+                &Ban {
+                    reason: None,
+                    banned_by: UserId(100),
+                    banned_at: 0,
+                    disabled_uuid: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            storage.auto_renewals().unwrap(),
+            vec![(user, "month".to_string())]
+        );
+    }
+
+    #[test]
+    fn postings_are_ledgered_once_and_never_overdraw() {
+        let storage = Storage::open_in_memory().unwrap();
+        let user = UserId(1);
+        assert_eq!(storage.balance(user).unwrap(), 0);
+
+        assert_eq!(
+            storage
+                .post(user, 100, LedgerKind::TopUp, "charge")
+                .unwrap(),
+            Posting::Done(100)
+        );
+        assert_eq!(
+            storage
+                .post(user, 100, LedgerKind::TopUp, "charge")
+                .unwrap(),
+            Posting::Duplicate
+        );
+        assert_eq!(
+            storage
+                .post(user, -150, LedgerKind::Renewal, "month")
+                .unwrap(),
+            Posting::Insufficient
+        );
+        assert_eq!(
+            storage
+                .post(user, -60, LedgerKind::Renewal, "month")
+                .unwrap(),
+            Posting::Done(40)
+        );
+        assert_eq!(storage.balance(user).unwrap(), 40);
+
+        let ledger = storage.ledger(user, 10).unwrap();
+        let amounts: Vec<_> = ledger
+            .iter()
+            .map(|entry| (entry.kind, entry.amount, entry.balance))
+            .collect();
+        assert_eq!(
+            amounts,
+            [
+                (LedgerKind::Renewal, -60, 40),
+                (LedgerKind::TopUp, 100, 100)
+            ]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use crate::audit;
+use crate::client::get_client;
+use crate::config::Config;
+use crate::error::MyError;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use remnawave::api::types::common::UserStatus;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::UserId;
+use uuid::Uuid;
+
+/// A ban of a Telegram user, as kept in the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub reason: Option<String>,
+    pub banned_by: UserId,
+    /// Unix seconds.
+    pub banned_at: i64,
+    /// Panel user disabled by the ban, enabled again when it is lifted.
+    pub disabled_uuid: Option<Uuid>,
+}
+
+/// Splits `/ban` arguments into the Telegram ID and the optional reason.
+fn parse_args(args: &str) -> Option<(UserId, Option<String>)> {
+    let args = args.trim();
+    let (id, reason) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let reason = reason.trim();
+    Some((
+        UserId(id.parse().ok()?),
+        (!reason.is_empty()).then(|| reason.to_string()),
+    ))
+}
+
+/// Handles the admin `/ban <tg id> [reason]` command.
+///
+/// Stores the ban, so that the user is ignored from then on, and disables
+/// their panel account if they have an active one.
+pub async fn ban(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    let Some(admin) = &msg.from else {
+        return Ok(());
+    };
+    log::info!("Admin {} called /ban {}", logger::user(admin.id), args);
+    metrics::record_command("ban");
+    let messages = Messages::ru();
+
+    let Some((user, reason)) = parse_args(&args) else {
+        bot.send_message(msg.chat.id, messages.ban_usage()).await?;
+        return Ok(());
+    };
+    if config.is_admin(user) {
+        bot.send_message(msg.chat.id, messages.ban_admin_refused())
+            .await?;
+        return Ok(());
+    }
+
+    let client = get_client();
+    let disabled_uuid = match client.find_user_by_telegram_id(user.0).await.into_user() {
+        Ok(before) if before.status != UserStatus::Disabled => {
+            let result = client.disable_user(before.uuid).await;
+            audit::record(
+                &storage,
+                admin.id,
+                "ban",
+                Some(&before),
+                result.as_ref().map(Some),
+            );
+            result.map(|user| Some(user.uuid))
+        }
+        Ok(_) | Err(MyError::UserNotFound) => Ok(None),
+        Err(e) => Err(e),
+    };
+    let disabled_uuid = match disabled_uuid {
+        Ok(uuid) => uuid,
+        Err(e) => {
+            // The ban must hold even if the panel is down; the account can
+            // be disabled by hand later.
+            log::error!(
+                "Failed to disable the panel account of {}: {}",
+                logger::user(user),
+                e
+            );
+            None
+        }
+    };
+
+    storage.ban(
+        user,
+        &Ban {
+            reason: reason.clone(),
+            banned_by: admin.id,
+            banned_at: chrono::Utc::now().timestamp(),
+            disabled_uuid,
+        },
+    )?;
+    log::info!(
+        "Admin {} banned {}: {}",
+        logger::user(admin.id),
+        logger::user(user),
+        reason.as_deref().unwrap_or("-")
+    );
+    bot.send_message(
+        msg.chat.id,
+        messages.ban_done(user, reason.as_deref(), disabled_uuid.is_some()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Handles the admin `/unban <tg id>` command.
+///
+/// Lifts the ban and enables the panel account the ban disabled.
+pub async fn unban(bot: Bot, msg: Message, args: String, storage: Arc<Storage>) -> HandlerResult {
+    let Some(admin) = &msg.from else {
+        return Ok(());
+    };
+    log::info!("Admin {} called /unban {}", logger::user(admin.id), args);
+    metrics::record_command("unban");
+    let messages = Messages::ru();
+
+    let Ok(user) = args.trim().parse().map(UserId) else {
+        bot.send_message(msg.chat.id, messages.unban_usage())
+            .await?;
+        return Ok(());
+    };
+    let Some(ban) = storage.unban(user)? else {
+        bot.send_message(msg.chat.id, messages.unban_not_banned(user))
+            .await?;
+        return Ok(());
+    };
+    log::info!(
+        "Admin {} unbanned {}",
+        logger::user(admin.id),
+        logger::user(user)
+    );
+
+    let mut enabled = false;
+    if let Some(uuid) = ban.disabled_uuid {
+        let client = get_client();
+        match client.get_user(uuid).await {
+            Ok(Some(before)) => {
+                let result = client.enable_user(uuid).await;
+                audit::record(
+                    &storage,
+                    admin.id,
+                    "unban",
+                    Some(&before),
+                    result.as_ref().map(Some),
+                );
+                enabled = result.is_ok();
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to fetch panel user {}: {}", uuid, e),
+        }
+    }
+    bot.send_message(msg.chat.id, messages.unban_done(user, enabled))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_id_and_reason() {
+        assert_eq!(parse_args("42"), Some((UserId(42), None)));
+        assert_eq!(
+            parse_args(" 42  spam and abuse "),
+            Some((UserId(42), Some("spam and abuse".to_string())))
+        );
+        assert_eq!(parse_args(""), None);
+        assert_eq!(parse_args("bob spam"), None);
+    }
+
+    #[test]
+    fn stores_and_lifts_bans() {
+        let storage = Storage::open_in_memory().unwrap();
+        let ban = Ban {
+            reason: Some("spam".to_string()),
+            banned_by: UserId(1),
+            banned_at: 100,
+            disabled_uuid: Some(Uuid::new_v4()),
+        };
+
+        storage.ban(UserId(42), &ban).unwrap();
+        assert!(storage.is_banned(UserId(42)).unwrap());
+        assert!(!storage.is_banned(UserId(43)).unwrap());
+
+        assert_eq!(storage.unban(UserId(42)).unwrap(), Some(ban));
+        assert!(!storage.is_banned(UserId(42)).unwrap());
+        assert_eq!(storage.unban(UserId(42)).unwrap(), None);
+    }
+
+    #[test]
+    fn banning_twice_keeps_the_disabled_account() {
+        let storage = Storage::open_in_memory().unwrap();
+        let disabled_uuid = Some(Uuid::new_v4());
+        let first = Ban {
+            reason: Some("spam".to_string()),
+            banned_by: UserId(1),
+            banned_at: 100,
+            disabled_uuid,
+        };
+        // The account is disabled already, so the second ban disables nothing.
+        let second = Ban {
+            reason: Some("more spam".to_string()),
+            banned_by: UserId(2),
+            banned_at: 200,
+            disabled_uuid: None,
+        };
+
+        storage.ban(UserId(42), &first).unwrap();
+        storage.ban(UserId(42), &second).unwrap();
+
+        assert_eq!(
+            storage.unban(UserId(42)).unwrap(),
+            Some(Ban {
+                disabled_uuid,
+                ..second
+            })
+        );
+    }
+}
@@ -1,17 +1,25 @@
-use glebus_vpn_bot::{error::MyError, logger, run};
+use glebus_vpn_bot::{Config, error::MyError, health, logger, run};
 
 #[tokio::main]
 async fn main() -> Result<(), MyError> {
     dotenv::dotenv().ok();
 
-    // Проверки переменных окружения
-    dotenv::var("TELOXIDE_TOKEN").expect("TELOXIDE_TOKEN must be set");
-    dotenv::var("PANEL_BASE_URL").expect("PANEL_BASE_URL must be set");
-    dotenv::var("REMNAWAVE_API_TOKEN").expect("REMNAWAVE_API_TOKEN must be set");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    logger::init_logger()?;
+    // `glebus_vpn_bot healthcheck` проверяет уже запущенного бота (Docker HEALTHCHECK)
+    if std::env::args().nth(1).as_deref() == Some("healthcheck") {
+        return health::check(config.http_listen_addr).await;
+    }
 
-    run().await?;
+    logger::init_logger(&config.logging)?;
+
+    run(config).await?;
 
     Ok(())
 }
@@ -0,0 +1,471 @@
+use crate::client::get_client;
+use crate::error::MyError;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::outbox::{Delivery, MessageContent, Outbox, OutgoingMessage};
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use remnawave::api::types::common::UserStatus;
+use remnawave::api::types::users::UserData;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::{Dialogue, GetChatId, InMemStorage};
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardMarkup, MessageEntity};
+use uuid::Uuid;
+
+pub type BroadcastDialogue = Dialogue<BroadcastState, InMemStorage<BroadcastState>>;
+
+/// Steps of the `/broadcast` dialogue of an admin.
+#[derive(Clone, Debug, Default)]
+pub enum BroadcastState {
+    #[default]
+    Idle,
+    ReceiveContent,
+    ChooseTarget {
+        content: BroadcastContent,
+    },
+    Confirm {
+        content: BroadcastContent,
+        target: Target,
+        recipients: Vec<ChatId>,
+    },
+}
+
+/// The message to broadcast, with its formatting.
+#[derive(Clone, Debug)]
+pub struct BroadcastContent {
+    content: MessageContent,
+    entities: Option<Vec<MessageEntity>>,
+}
+
+impl BroadcastContent {
+    /// Takes the text or the photo with its caption from `msg`.
+    fn from_message(msg: &Message) -> Option<Self> {
+        if let Some(photos) = msg.photo() {
+            let photo = photos
+                .iter()
+                .max_by_key(|photo| photo.width * photo.height)?;
+            return Some(Self {
+                content: MessageContent::Photo {
+                    photo: photo.file.id.clone(),
+                    caption: msg.caption().map(str::to_string),
+                },
+                entities: msg.caption_entities().map(<[_]>::to_vec),
+            });
+        }
+        Some(Self {
+            content: MessageContent::Text(msg.text()?.to_string()),
+            entities: msg.entities().map(<[_]>::to_vec),
+        })
+    }
+
+    fn to(&self, chat_id: ChatId) -> OutgoingMessage {
+        let message = OutgoingMessage::new(chat_id, self.content.clone());
+        match &self.entities {
+            Some(entities) => message.entities(entities.clone()),
+            None => message,
+        }
+    }
+}
+
+/// Who receives a broadcast.
+///
+/// Plans are panel user tags.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// Every panel user with a Telegram ID and everyone who wrote to the
+    /// bot in a private chat.
+    All,
+    Active,
+    Expired,
+    Tag(String),
+    Squad {
+        uuid: Uuid,
+        name: String,
+    },
+}
+
+impl Target {
+    fn matches(&self, user: &UserData) -> bool {
+        match self {
+            Target::All => true,
+            Target::Active => user.status == UserStatus::Active,
+            Target::Expired => user.status == UserStatus::Expired,
+            Target::Tag(tag) => user.tag.as_deref() == Some(tag.as_str()),
+            Target::Squad { uuid, .. } => user
+                .active_internal_squads
+                .iter()
+                .any(|squad| squad.uuid == *uuid),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Target::All => "все пользователи".to_string(),
+            Target::Active => "активные".to_string(),
+            Target::Expired => "с истёкшей подпиской".to_string(),
+            Target::Tag(tag) => format!("тариф {}", tag),
+            Target::Squad { name, .. } => format!("сквад {}", name),
+        }
+    }
+}
+
+/// Chats that receive a broadcast to `target`, without users who blocked the bot.
+async fn recipients(target: &Target, storage: &Storage) -> Result<Vec<ChatId>, MyError> {
+    let users = get_client().get_all_users().await?;
+    let chatted = match target {
+        Target::All => storage.private_chat_user_ids()?,
+        _ => Vec::new(),
+    };
+    Ok(select(
+        target,
+        &users,
+        chatted,
+        &storage.blocked_user_ids()?,
+    ))
+}
+
+/// Telegram IDs of the panel `users` matching `target` and the `chatted`
+/// users, without the `blocked` ones, in ascending order.
+fn select(
+    target: &Target,
+    users: &[UserData],
+    chatted: Vec<i64>,
+    blocked: &HashSet<i64>,
+) -> Vec<ChatId> {
+    let ids: BTreeSet<i64> = users
+        .iter()
+        .filter(|user| target.matches(user))
+        .filter_map(|user| user.telegram_id)
+        .chain(chatted)
+        .filter(|id| !blocked.contains(id))
+        .collect();
+    ids.into_iter().map(ChatId).collect()
+}
+
+/// Handles the admin `/broadcast` command by asking for the message.
+pub async fn start(bot: Bot, msg: Message, dialogue: BroadcastDialogue) -> HandlerResult {
+    if let Some(user) = &msg.from {
+        log::info!("Admin {} called /broadcast", logger::user(user.id));
+    }
+    metrics::record_command("broadcast");
+
+    dialogue.update(BroadcastState::ReceiveContent).await?;
+    bot.send_message(msg.chat.id, Messages::ru().broadcast_prompt())
+        .reply_markup(keyboards::broadcast_cancel())
+        .await?;
+    Ok(())
+}
+
+/// Receives the message to broadcast and asks for the recipients.
+pub async fn receive_content(bot: Bot, msg: Message, dialogue: BroadcastDialogue) -> HandlerResult {
+    let Some(content) = BroadcastContent::from_message(&msg) else {
+        bot.send_message(msg.chat.id, Messages::ru().broadcast_invalid_content())
+            .reply_markup(keyboards::broadcast_cancel())
+            .await?;
+        return Ok(());
+    };
+
+    dialogue
+        .update(BroadcastState::ChooseTarget { content })
+        .await?;
+    bot.send_message(msg.chat.id, Messages::ru().broadcast_choose_target())
+        .reply_markup(keyboards::broadcast_targets())
+        .await?;
+    Ok(())
+}
+
+/// Replaces the text and the keyboard of the message with the pressed button.
+async fn edit(
+    bot: &Bot,
+    q: &CallbackQuery,
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> HandlerResult {
+    let (Some(chat_id), Some(msg)) = (q.chat_id(), &q.message) else {
+        return Ok(());
+    };
+    let request = bot.edit_message_text(chat_id, msg.id(), text);
+    match keyboard {
+        Some(keyboard) => request.reply_markup(keyboard).await?,
+        None => request.await?,
+    };
+    Ok(())
+}
+
+/// Handles the `broadcast:*` buttons of the dialogue.
+pub async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: BroadcastDialogue,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+    let action = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("broadcast:"))
+        .unwrap_or_default();
+    metrics::record_callback("broadcast");
+    let messages = Messages::ru();
+
+    if action == "cancel" {
+        dialogue.exit().await?;
+        return edit(&bot, &q, messages.broadcast_cancelled(), None).await;
+    }
+
+    let state = dialogue.get().await?.unwrap_or_default();
+    let content = match state {
+        BroadcastState::ChooseTarget { content } | BroadcastState::Confirm { content, .. }
+            if action != "confirm" =>
+        {
+            content
+        }
+        BroadcastState::Confirm {
+            content,
+            target,
+            recipients,
+        } => {
+            dialogue.exit().await?;
+            let Some(chat_id) = q.chat_id() else {
+                return Ok(());
+            };
+            log::info!(
+                "Admin {} started a broadcast to {} ({} recipients)",
+                logger::user(q.from.id),
+                target.describe(),
+                recipients.len()
+            );
+            edit(&bot, &q, messages.broadcast_started(recipients.len()), None).await?;
+            tokio::spawn(run(bot.clone(), outbox, chat_id, content, recipients));
+            return Ok(());
+        }
+        _ => return edit(&bot, &q, messages.broadcast_outdated(), None).await,
+    };
+
+    let target = match action.split_once(':') {
+        None if action == "targets" => {
+            dialogue
+                .update(BroadcastState::ChooseTarget { content })
+                .await?;
+            return edit(
+                &bot,
+                &q,
+                messages.broadcast_choose_target(),
+                Some(keyboards::broadcast_targets()),
+            )
+            .await;
+        }
+        None if action == "tags" => {
+            let tags = get_client().get_all_tags().await?;
+            return if tags.is_empty() {
+                edit(
+                    &bot,
+                    &q,
+                    messages.broadcast_no_options(),
+                    Some(keyboards::broadcast_targets()),
+                )
+                .await
+            } else {
+                edit(
+                    &bot,
+                    &q,
+                    messages.broadcast_choose_tag(),
+                    Some(keyboards::broadcast_tags(&tags)),
+                )
+                .await
+            };
+        }
+        None if action == "squads" => {
+            let squads: Vec<_> = get_client()
+                .get_internal_squads()
+                .await?
+                .into_iter()
+                .map(|squad| (squad.uuid, squad.name))
+                .collect();
+            return if squads.is_empty() {
+                edit(
+                    &bot,
+                    &q,
+                    messages.broadcast_no_options(),
+                    Some(keyboards::broadcast_targets()),
+                )
+                .await
+            } else {
+                edit(
+                    &bot,
+                    &q,
+                    messages.broadcast_choose_squad(),
+                    Some(keyboards::broadcast_squads(&squads)),
+                )
+                .await
+            };
+        }
+        Some(("target", "all")) => Target::All,
+        Some(("target", "active")) => Target::Active,
+        Some(("target", "expired")) => Target::Expired,
+        Some(("tag", tag)) => Target::Tag(tag.to_string()),
+        Some(("squad", uuid)) => {
+            let Ok(uuid) = uuid.parse::<Uuid>() else {
+                return edit(&bot, &q, messages.broadcast_outdated(), None).await;
+            };
+            let name = get_client()
+                .get_internal_squads()
+                .await?
+                .into_iter()
+                .find(|squad| squad.uuid == uuid)
+                .map(|squad| squad.name)
+                .unwrap_or_else(|| uuid.to_string());
+            Target::Squad { uuid, name }
+        }
+        _ => return edit(&bot, &q, messages.broadcast_outdated(), None).await,
+    };
+
+    let recipients = recipients(&target, &storage).await?;
+    if recipients.is_empty() {
+        dialogue
+            .update(BroadcastState::ChooseTarget { content })
+            .await?;
+        return edit(
+            &bot,
+            &q,
+            messages.broadcast_no_recipients(&target.describe()),
+            Some(keyboards::broadcast_targets()),
+        )
+        .await;
+    }
+
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    if let Delivery::Failed(e) = outbox.send(content.to(chat_id)).await {
+        return Err(MyError::Custom(format!("Failed to send preview: {}", e)));
+    }
+    bot.send_message(
+        chat_id,
+        messages.broadcast_preview(&target.describe(), recipients.len()),
+    )
+    .reply_markup(keyboards::broadcast_confirm())
+    .await?;
+    dialogue
+        .update(BroadcastState::Confirm {
+            content,
+            target,
+            recipients,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Sends the broadcast through the outbox and reports the result to the admin.
+async fn run(
+    bot: Bot,
+    outbox: Arc<Outbox>,
+    admin_chat: ChatId,
+    content: BroadcastContent,
+    recipients: Vec<ChatId>,
+) {
+    let messages = recipients.iter().map(|&chat| content.to(chat)).collect();
+    let deliveries = outbox.send_all(messages).await;
+
+    let count = |f: fn(&Delivery) -> bool| deliveries.iter().filter(|d| f(d)).count();
+    let delivered = count(|d| *d == Delivery::Delivered);
+    let blocked = count(|d| *d == Delivery::Blocked);
+    let failed = count(|d| matches!(d, Delivery::Failed(_)));
+    log::info!(
+        "Broadcast finished: {} delivered, {} blocked, {} failed",
+        delivered,
+        blocked,
+        failed
+    );
+
+    if let Err(e) = bot
+        .send_message(
+            admin_chat,
+            Messages::ru().broadcast_finished(delivered, blocked, failed),
+        )
+        .await
+    {
+        log::error!("Failed to report the broadcast result: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{telegram_user, test_user};
+    use remnawave::api::types::users::InternalSquad;
+
+    fn user(telegram_id: i64, status: UserStatus, tag: Option<&str>) -> UserData {
+        let mut user = test_user(&telegram_id.to_string(), Some(telegram_id));
+        user.status = status;
+        user.tag = tag.map(str::to_string);
+        user
+    }
+
+    #[test]
+    fn target_matches_status_tag_and_squad() {
+        let squad = Uuid::new_v4();
+        let mut active = user(1, UserStatus::Active, Some("PRO"));
+        active.active_internal_squads.push(InternalSquad {
+            uuid: squad,
+            name: "EU".to_string(),
+        });
+        let expired = user(2, UserStatus::Expired, None);
+        let in_squad = Target::Squad {
+            uuid: squad,
+            name: "EU".to_string(),
+        };
+
+        assert!(Target::All.matches(&active) && Target::All.matches(&expired));
+        assert!(Target::Active.matches(&active) && !Target::Active.matches(&expired));
+        assert!(Target::Expired.matches(&expired) && !Target::Expired.matches(&active));
+        assert!(Target::Tag("PRO".to_string()).matches(&active));
+        assert!(!Target::Tag("PRO".to_string()).matches(&expired));
+        assert!(!Target::Tag("BASIC".to_string()).matches(&active));
+        assert!(in_squad.matches(&active) && !in_squad.matches(&expired));
+    }
+
+    #[test]
+    fn select_merges_chatted_users_and_drops_blocked_ones() {
+        let mut unlinked = test_user("unlinked", None);
+        unlinked.status = UserStatus::Active;
+        let users = vec![
+            user(3, UserStatus::Active, None),
+            user(1, UserStatus::Expired, None),
+            user(2, UserStatus::Active, None),
+            unlinked,
+        ];
+        let blocked = HashSet::from([2]);
+
+        assert_eq!(
+            select(&Target::All, &users, vec![1, 5, 4], &blocked),
+            vec![ChatId(1), ChatId(3), ChatId(4), ChatId(5)]
+        );
+        assert_eq!(
+            select(&Target::Active, &users, Vec::new(), &blocked),
+            vec![ChatId(3)]
+        );
+    }
+
+    #[test]
+    fn only_private_chats_count_as_chatted() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage.touch_user(&telegram_user(1), true).unwrap();
+        storage.touch_user(&telegram_user(2), false).unwrap();
+        storage.touch_user(&telegram_user(3), true).unwrap();
+        storage.touch_user(&telegram_user(3), false).unwrap();
+        storage.touch_user(&telegram_user(4), true).unwrap();
+        storage.mark_blocked(ChatId(4)).unwrap();
+        storage.touch_user(&telegram_user(4), false).unwrap();
+
+        let mut ids = storage.private_chat_user_ids().unwrap();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+        assert!(storage.is_blocked(ChatId(4)).unwrap());
+    }
+}
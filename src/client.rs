@@ -1,18 +1,679 @@
-use once_cell::sync::Lazy;
-use remnawave::RemnawaveApiClient;
-use std::sync::Arc;
-
-static CLIENT: Lazy<Arc<RemnawaveApiClient>> = Lazy::new(|| {
-    Arc::new(
-        RemnawaveApiClient::new(
-            dotenv::var("PANEL_BASE_URL").expect("PANEL_BASE_URL must be set"),
-            Some(dotenv::var("REMNAWAVE_API_TOKEN").expect("REMNAWAVE_API_TOKEN must be set")),
+use crate::config::{Config, PanelConfig};
+use crate::error::MyError;
+use crate::metrics;
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use remnawave::{
+    ApiError, CreateUserRequestDto, CreateUserResponseDto, DeleteUserResponseDto,
+    GetUserByTelegramIdResponseDto, RemnawaveApiClient, RevokeUserSubscriptionBodyDto,
+    UpdateUserRequestDto,
+    api::types::{
+        internal_squads::InternalSquadDto,
+        users::{UsageData, UserData},
+    },
+};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+static CLIENT: OnceCell<Arc<PanelClient>> = OnceCell::new();
+
+/// Upper bound of the delay between two retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// State of the circuit breaker guarding the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    /// Calls go through; counts consecutive transient failures.
+    Closed { failures: u32 },
+    /// Calls are rejected until the given moment.
+    Open { until: Instant },
+    /// A single trial call is in flight since the given moment.
+    HalfOpen { since: Instant },
+}
+
+/// Opens after `threshold` consecutive transient failures and lets a trial
+/// call through once `open_for` has passed.
+struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    threshold: u32,
+    open_for: Duration,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, open_for: Duration) -> Self {
+        Self {
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            threshold,
+            open_for,
+        }
+    }
+
+    /// Returns whether a call may be made right now.
+    ///
+    /// A trial call that never reported back (e.g. was cancelled) is
+    /// replaced by a new one after `open_for`.
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if now >= until => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::HalfOpen { since } if now >= since + self.open_for => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => {
+                log::warn!(
+                    "Panel circuit breaker opened for {} s",
+                    self.open_for.as_secs()
+                );
+                BreakerState::Open {
+                    until: Instant::now() + self.open_for,
+                }
+            }
+        };
+    }
+
+    fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        matches!(*state, BreakerState::Open { until } if Instant::now() < until)
+    }
+}
+
+/// Number of users requested per page when listing all users.
+const USERS_PAGE_SIZE: u32 = 500;
+
+/// Result of looking up the panel user linked to a Telegram account.
+#[derive(Debug)]
+pub enum UserLookup {
+    /// Exactly one panel user is linked.
+    Found(Box<UserData>),
+    /// No panel user is linked.
+    NotFound,
+    /// Several panel users are linked, which needs an admin to sort out.
+    MultipleFound(Vec<UserData>),
+    /// The panel could not be asked.
+    PanelError(MyError),
+}
+
+impl UserLookup {
+    fn from_users(mut users: Vec<UserData>) -> Self {
+        match users.len() {
+            0 => UserLookup::NotFound,
+            1 => UserLookup::Found(Box::new(users.remove(0))),
+            _ => UserLookup::MultipleFound(users),
+        }
+    }
+
+    /// Returns the found user, or `MyError::UserNotFound`,
+    /// `MyError::MultipleUsersFound` or the panel error.
+    pub fn into_user(self) -> Result<UserData, MyError> {
+        match self {
+            UserLookup::Found(user) => Ok(*user),
+            UserLookup::NotFound => Err(MyError::UserNotFound),
+            UserLookup::MultipleFound(users) => Err(MyError::MultipleUsersFound(users.len())),
+            UserLookup::PanelError(e) => Err(e),
+        }
+    }
+}
+
+/// Turns a `404` from the panel into `Ok(None)`.
+fn not_found_as_none<T>(result: Result<T, MyError>) -> Result<Option<T>, MyError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(MyError::Panel(e)) if e.status_code == 404 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the panel rejected a user because one with the same username exists.
+fn is_already_exists(error: &ApiError) -> bool {
+    matches!(error.status_code, 400 | 409)
+        && [&error.message, &error.error]
+            .into_iter()
+            .flatten()
+            .chain(std::iter::once(&error.response_body))
+            .any(|text| text.to_lowercase().contains("already exists"))
+}
+
+/// Whether a failed call is worth retrying: network errors, timeouts,
+/// rate limiting and server-side errors. Client errors like 404 are not.
+fn is_transient(error: &MyError) -> bool {
+    match error {
+        MyError::PanelTimeout(_) => true,
+        MyError::Panel(e) => e.status_code == 0 || e.status_code == 429 || e.status_code >= 500,
+        _ => false,
+    }
+}
+
+/// Remnawave panel client with timeouts, retries and a circuit breaker.
+///
+/// Reads are retried with jittered exponential backoff, other calls are
+/// made once. When the panel keeps failing, the breaker opens and calls fail
+/// fast with `MyError::PanelUnavailable`.
+pub struct PanelClient {
+    api: Arc<RemnawaveApiClient>,
+    breaker: CircuitBreaker,
+    timeout: Duration,
+    max_retries: u32,
+    retry_base_delay: Duration,
+}
+
+impl PanelClient {
+    /// Creates a client for the panel at `base_url`.
+    pub fn new(base_url: &str, token: &str, config: &PanelConfig) -> Result<Self, MyError> {
+        let api = RemnawaveApiClient::new(base_url.to_string(), Some(token.to_string()))
+            .map_err(|e| MyError::Custom(format!("Failed to create RemnawaveApiClient: {}", e)))?;
+
+        Ok(Self {
+            api: Arc::new(api),
+            breaker: CircuitBreaker::new(config.breaker_threshold, config.breaker_open_for),
+            timeout: config.timeout,
+            max_retries: config.max_retries,
+            retry_base_delay: config.retry_base_delay,
+        })
+    }
+
+    /// Returns whether the circuit breaker currently rejects calls.
+    pub fn is_unavailable(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    /// Makes a single guarded attempt of a panel call.
+    async fn attempt<T, F, Fut>(&self, operation: &str, call: &F) -> Result<T, MyError>
+    where
+        F: Fn(Arc<RemnawaveApiClient>) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        if !self.breaker.acquire() {
+            return Err(MyError::PanelUnavailable);
+        }
+
+        let result = match tokio::time::timeout(
+            self.timeout,
+            metrics::track_panel(operation, call(self.api.clone())),
+        )
+        .await
+        {
+            Ok(result) => result.map_err(MyError::from),
+            Err(_) => Err(MyError::PanelTimeout(operation.to_string())),
+        };
+
+        match &result {
+            Err(e) if is_transient(e) => self.breaker.record_failure(),
+            _ => self.breaker.record_success(),
+        }
+        result
+    }
+
+    /// Makes an idempotent panel call, retrying transient failures.
+    ///
+    /// `call` receives the raw API client and may be invoked several times,
+    /// e.g. `|api| async move { api.users.get_by_uuid(uuid).await }`.
+    pub async fn read<T, F, Fut>(&self, operation: &str, call: F) -> Result<T, MyError>
+    where
+        F: Fn(Arc<RemnawaveApiClient>) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(operation, &call).await {
+                Err(e) if is_transient(&e) && attempt < self.max_retries => {
+                    let ceiling = self
+                        .retry_base_delay
+                        .saturating_mul(2u32.saturating_pow(attempt))
+                        .min(MAX_RETRY_DELAY);
+                    let delay = ceiling.mul_f64(rand::random_range(0.5..=1.0));
+                    log::warn!(
+                        "Panel call {} failed ({}), retrying in {} ms",
+                        operation,
+                        e,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Makes a panel call once, for non-idempotent operations and probes.
+    pub async fn call_once<T, F, Fut>(&self, operation: &str, call: F) -> Result<T, MyError>
+    where
+        F: Fn(Arc<RemnawaveApiClient>) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        self.attempt(operation, &call).await
+    }
+
+    pub async fn get_by_telegram_id(
+        &self,
+        telegram_id: u64,
+    ) -> Result<GetUserByTelegramIdResponseDto, MyError> {
+        self.read("get_by_telegram_id", |api| async move {
+            api.users.get_by_telegram_id(telegram_id.to_string()).await
+        })
+        .await
+    }
+
+    /// Fetches all panel users, page by page.
+    pub async fn get_all_users(&self) -> Result<Vec<UserData>, MyError> {
+        let mut users = Vec::new();
+        loop {
+            let start = users.len() as u32;
+            let page = self
+                .read("get_all_users", |api| async move {
+                    api.users.get_all(Some(USERS_PAGE_SIZE), Some(start)).await
+                })
+                .await?
+                .response;
+            let fetched = page.users.len();
+            users.extend(page.users);
+            if fetched == 0 || users.len() >= page.total {
+                return Ok(users);
+            }
+        }
+    }
+
+    pub async fn get_all_tags(&self) -> Result<Vec<String>, MyError> {
+        self.read("get_all_tags", |api| async move {
+            api.users.get_all_tags().await
+        })
+        .await
+        .map(|tags| tags.response.tags)
+    }
+
+    pub async fn get_internal_squads(&self) -> Result<Vec<InternalSquadDto>, MyError> {
+        self.read("get_internal_squads", |api| async move {
+            api.internal_squads.get_all().await
+        })
+        .await
+        .map(|squads| squads.response.internal_squads)
+    }
+
+    /// Fetches a panel user by UUID; `None` if there is no such user.
+    pub async fn get_user(&self, uuid: Uuid) -> Result<Option<UserData>, MyError> {
+        not_found_as_none(
+            self.read("get_by_uuid", |api| async move {
+                api.users.get_by_uuid(uuid).await
+            })
+            .await
+            .map(|user| user.response),
+        )
+    }
+
+    /// Fetches a panel user by username; `None` if there is no such user.
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<UserData>, MyError> {
+        not_found_as_none(
+            self.read("get_by_username", |api| {
+                let username = username.to_string();
+                async move { api.users.get_by_username(username).await }
+            })
+            .await
+            .map(|user| user.response),
+        )
+    }
+
+    pub async fn get_users_by_email(&self, email: &str) -> Result<Vec<UserData>, MyError> {
+        not_found_as_none(
+            self.read("get_by_email", |api| {
+                let email = email.to_string();
+                async move { api.users.get_by_email(email).await }
+            })
+            .await
+            .map(|users| users.response),
+        )
+        .map(Option::unwrap_or_default)
+    }
+
+    /// Number of devices (HWIDs) registered by a panel user.
+    pub async fn count_user_devices(&self, uuid: Uuid) -> Result<usize, MyError> {
+        self.read(
+            "get_user_devices",
+            |api| async move { api.hwid.get(uuid).await },
         )
-        .expect("Failed to create RemnawaveApiClient"),
-    )
-});
+        .await
+        .map(|devices| devices.response.total)
+    }
+
+    /// Traffic of a panel user per node and day between `start` and `end`.
+    pub async fn get_user_usage(
+        &self,
+        uuid: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<UsageData>, MyError> {
+        let format = |at: DateTime<Utc>| Some(at.to_rfc3339_opts(SecondsFormat::Millis, true));
+        self.read("get_usage_by_range", |api| async move {
+            api.users
+                .get_usage_by_range(uuid, format(start), format(end))
+                .await
+        })
+        .await
+        .map(|usage| usage.response)
+    }
+
+    /// Proxy links (`vless://`, `trojan://`, ...) of a panel user's subscription.
+    pub async fn get_subscription_links(&self, uuid: Uuid) -> Result<Vec<String>, MyError> {
+        self.read("get_subscription_by_uuid", |api| async move {
+            api.subscriptions.get_by_uuid(uuid.to_string()).await
+        })
+        .await
+        .map(|subscription| subscription.response.links)
+    }
+
+    pub async fn enable_user(&self, uuid: Uuid) -> Result<UserData, MyError> {
+        self.call_once("enable", |api| async move { api.users.enable(uuid).await })
+            .await
+            .map(|user| user.response)
+    }
+
+    pub async fn disable_user(&self, uuid: Uuid) -> Result<UserData, MyError> {
+        self.call_once(
+            "disable",
+            |api| async move { api.users.disable(uuid).await },
+        )
+        .await
+        .map(|user| user.response)
+    }
+
+    pub async fn reset_user_traffic(&self, uuid: Uuid) -> Result<UserData, MyError> {
+        self.call_once("reset_traffic", |api| async move {
+            api.users.reset_traffic(uuid).await
+        })
+        .await
+        .map(|user| user.response)
+    }
+
+    /// Issues a new subscription link, invalidating the old one.
+    pub async fn revoke_user_subscription(&self, uuid: Uuid) -> Result<UserData, MyError> {
+        self.call_once("revoke_subscription", |api| async move {
+            api.users
+                .revoke_subscription(uuid, RevokeUserSubscriptionBodyDto { short_uuid: None })
+                .await
+        })
+        .await
+        .map(|user| user.response)
+    }
+
+    pub async fn set_user_expiry(
+        &self,
+        uuid: Uuid,
+        expire_at: DateTime<Utc>,
+    ) -> Result<UserData, MyError> {
+        self.update_user(UpdateUserRequestDto {
+            expire_at: Some(expire_at),
+            ..update_request(uuid)
+        })
+        .await
+    }
+
+    /// Sets the expiry and the device limit of a user, `None` lifting the
+    /// limit.
+    pub async fn set_user_plan(
+        &self,
+        uuid: Uuid,
+        expire_at: DateTime<Utc>,
+        hwid_device_limit: Option<usize>,
+    ) -> Result<UserData, MyError> {
+        self.update_user(UpdateUserRequestDto {
+            expire_at: Some(expire_at),
+            hwid_device_limit: Some(hwid_device_limit),
+            ..update_request(uuid)
+        })
+        .await
+    }
+
+    async fn update_user(&self, request: UpdateUserRequestDto) -> Result<UserData, MyError> {
+        self.call_once("update", |api| {
+            let request = request.clone();
+            async move { api.users.update(request).await }
+        })
+        .await
+        .map(|user| user.response)
+    }
+
+    /// Looks up the panel user linked to `telegram_id`.
+    ///
+    /// An empty list and a `404` from the panel both mean `NotFound`.
+    pub async fn find_user_by_telegram_id(&self, telegram_id: u64) -> UserLookup {
+        match self.get_by_telegram_id(telegram_id).await {
+            Ok(users) => UserLookup::from_users(users.response),
+            Err(MyError::Panel(e)) if e.status_code == 404 => UserLookup::NotFound,
+            Err(e) => UserLookup::PanelError(e),
+        }
+    }
+
+    /// Creates a panel user.
+    ///
+    /// A rejection because the username already exists is reported as
+    /// `MyError::UsernameTaken`.
+    pub async fn create_user(
+        &self,
+        request: CreateUserRequestDto,
+    ) -> Result<CreateUserResponseDto, MyError> {
+        let username = request.username.clone();
+        self.call_once("create", |api| {
+            let request = request.clone();
+            async move { api.users.create(request).await }
+        })
+        .await
+        .map_err(|e| match e {
+            MyError::Panel(ref api) if is_already_exists(api) => MyError::UsernameTaken(username),
+            e => e,
+        })
+    }
+
+    pub async fn delete_user(&self, uuid: Uuid) -> Result<DeleteUserResponseDto, MyError> {
+        self.call_once("delete", |api| async move { api.users.delete(uuid).await })
+            .await
+    }
+}
+
+/// An update of user `uuid` that changes nothing.
+fn update_request(uuid: Uuid) -> UpdateUserRequestDto {
+    UpdateUserRequestDto {
+        username: None,
+        uuid: Some(uuid),
+        status: None,
+        traffic_limit_bytes: None,
+        traffic_limit_strategy: None,
+        expire_at: None,
+        description: None,
+        tag: None,
+        telegram_id: None,
+        email: None,
+        hwid_device_limit: None,
+        active_internal_squads: None,
+        external_squad_uuid: None,
+    }
+}
+
+/// Creates the shared PanelClient from the configuration.
+///
+/// Must be called once at startup, before any handler runs.
+pub fn init_client(config: &Config) -> Result<(), MyError> {
+    let client = PanelClient::new(
+        &config.panel_base_url,
+        &config.panel_api_token,
+        &config.panel,
+    )?;
+
+    CLIENT
+        .set(Arc::new(client))
+        .map_err(|_| MyError::Custom("PanelClient is already initialized".to_string()))
+}
+
+/// Returns a shared reference to the PanelClient.
+///
+/// # Panics
+///
+/// Panics if [`init_client`] has not been called.
+pub fn get_client() -> Arc<PanelClient> {
+    CLIENT
+        .get()
+        .expect("PanelClient is not initialized")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, extract::State, http::StatusCode, routing::get};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Behaviour of the fake panel: the first `failures` requests answer
+    /// with `status`, later ones succeed after `delay`.
+    struct FakePanel {
+        hits: AtomicUsize,
+        failures: usize,
+        status: StatusCode,
+        delay: Duration,
+    }
+
+    async fn tags(State(panel): State<Arc<FakePanel>>) -> (StatusCode, &'static str) {
+        let hit = panel.hits.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(panel.delay).await;
+        if hit < panel.failures {
+            (panel.status, r#"{"message":"injected failure"}"#)
+        } else {
+            (StatusCode::OK, r#"{"response":{"tags":["TEST"]}}"#)
+        }
+    }
+
+    /// Starts a fake panel and returns it with a client pointed at it.
+    async fn setup(
+        failures: usize,
+        status: StatusCode,
+        delay: Duration,
+    ) -> (Arc<FakePanel>, PanelClient) {
+        let panel = Arc::new(FakePanel {
+            hits: AtomicUsize::new(0),
+            failures,
+            status,
+            delay,
+        });
+        let app = Router::new()
+            .route("/api/users/tags", get(tags))
+            .with_state(panel.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = PanelConfig {
+            timeout: Duration::from_millis(200),
+            max_retries: 2,
+            retry_base_delay: Duration::from_millis(1),
+            breaker_threshold: 3,
+            breaker_open_for: Duration::from_millis(300),
+        };
+        let client = PanelClient::new(&format!("http://{}", addr), "token", &config).unwrap();
+        (panel, client)
+    }
+
+    async fn read_tags(client: &PanelClient) -> Result<Vec<String>, MyError> {
+        client
+            .read("get_all_tags", |api| async move {
+                api.users.get_all_tags().await
+            })
+            .await
+            .map(|tags| tags.response.tags)
+    }
+
+    #[tokio::test]
+    async fn read_retries_server_errors() {
+        let (panel, client) = setup(2, StatusCode::BAD_GATEWAY, Duration::ZERO).await;
+
+        let tags = read_tags(&client).await.unwrap();
+
+        assert_eq!(tags, vec!["TEST".to_string()]);
+        assert_eq!(panel.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn read_gives_up_after_max_retries() {
+        let (panel, client) = setup(10, StatusCode::SERVICE_UNAVAILABLE, Duration::ZERO).await;
+
+        let error = read_tags(&client).await.unwrap_err();
+
+        assert!(matches!(error, MyError::Panel(e) if e.status_code == 503));
+        assert_eq!(panel.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (panel, client) = setup(1, StatusCode::NOT_FOUND, Duration::ZERO).await;
+
+        let error = read_tags(&client).await.unwrap_err();
+
+        assert!(matches!(error, MyError::Panel(e) if e.status_code == 404));
+        assert_eq!(panel.hits.load(Ordering::SeqCst), 1);
+        assert!(!client.is_unavailable());
+    }
+
+    #[tokio::test]
+    async fn call_once_is_not_retried() {
+        let (panel, client) = setup(1, StatusCode::INTERNAL_SERVER_ERROR, Duration::ZERO).await;
+
+        let result = client
+            .call_once("get_all_tags", |api| async move {
+                api.users.get_all_tags().await
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(panel.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_calls_time_out_and_are_retried() {
+        let (panel, client) = setup(0, StatusCode::OK, Duration::from_millis(500)).await;
+
+        let error = read_tags(&client).await.unwrap_err();
+
+        assert!(matches!(error, MyError::PanelTimeout(_)));
+        assert_eq!(panel.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_and_recovers() {
+        let (panel, client) = setup(3, StatusCode::INTERNAL_SERVER_ERROR, Duration::ZERO).await;
+
+        // Three failed attempts reach the threshold and open the breaker.
+        assert!(read_tags(&client).await.is_err());
+        assert!(client.is_unavailable());
+
+        // While open, calls fail fast without reaching the panel.
+        let error = read_tags(&client).await.unwrap_err();
+        assert!(matches!(error, MyError::PanelUnavailable));
+        assert_eq!(panel.hits.load(Ordering::SeqCst), 3);
 
-/// Returns a shared reference to the RemnawaveApiClient.
-pub fn get_client() -> Arc<RemnawaveApiClient> {
-    CLIENT.clone()
+        // After the open period a trial call goes through and closes it.
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert_eq!(read_tags(&client).await.unwrap(), vec!["TEST".to_string()]);
+        assert!(!client.is_unavailable());
+    }
 }
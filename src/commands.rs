@@ -0,0 +1,103 @@
+use crate::config::Config;
+use crate::messages::Messages;
+use crate::types::{AdminCommand, Command};
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, BotCommandScope, Recipient};
+use teloxide::utils::command::BotCommands;
+
+/// Command menus of every scope, built from the command enums.
+///
+/// Users see their commands in private chats. `admins` additionally see the
+/// admin commands in their private chat with the bot and in `admin_chats`.
+fn menus(admins: &[UserId], admin_chats: &[ChatId]) -> Vec<(BotCommandScope, Vec<BotCommand>)> {
+    let user_commands = Command::bot_commands();
+    let mut admin_commands = user_commands.clone();
+    admin_commands.extend(AdminCommand::bot_commands());
+
+    let mut menus = vec![(BotCommandScope::AllPrivateChats, user_commands)];
+    for admin in admins {
+        menus.push((
+            BotCommandScope::Chat {
+                chat_id: Recipient::Id((*admin).into()),
+            },
+            admin_commands.clone(),
+        ));
+    }
+    for chat_id in admin_chats {
+        for admin in admins {
+            menus.push((
+                BotCommandScope::ChatMember {
+                    chat_id: Recipient::Id(*chat_id),
+                    user_id: *admin,
+                },
+                AdminCommand::bot_commands(),
+            ));
+        }
+    }
+    menus
+}
+
+/// Registers the command menus shown by Telegram clients.
+///
+/// Every menu is set without a language, which Telegram shows to users of
+/// any language, and for each of `Messages::LANGUAGES`. Menus are replaced
+/// on every start, so they follow changes of the command enums. Failures
+/// are logged: an admin who never opened a chat with the bot can't get a
+/// menu there.
+pub async fn register(bot: Bot, config: Arc<Config>) {
+    let mut admin_chats: Vec<ChatId> = config
+        .admin_chat_id
+        .into_iter()
+        .chain(config.support_chat())
+        .collect();
+    admin_chats.dedup();
+    let menus = menus(&config.admin_ids, &admin_chats);
+
+    let languages = std::iter::once(None).chain(Messages::LANGUAGES.iter().copied().map(Some));
+    for language in languages {
+        for (scope, commands) in &menus {
+            let mut request = bot.set_my_commands(commands.clone()).scope(scope.clone());
+            if let Some(language) = language {
+                request = request.language_code(language);
+            }
+            if let Err(e) = request.await {
+                log::warn!(
+                    "Failed to set the commands of {:?} ({}): {}",
+                    scope,
+                    language.unwrap_or("default"),
+                    e
+                );
+            }
+        }
+    }
+    log::info!("Command menus registered");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_menus_include_admin_commands() {
+        let menus = menus(&[UserId(1), UserId(2)], &[ChatId(-100)]);
+
+        let names = |commands: &[BotCommand]| {
+            commands
+                .iter()
+                .map(|command| command.command.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(menus.len(), 1 + 2 + 2);
+        assert_eq!(names(&menus[0].1), ["/help", "/start"]);
+        assert!(names(&menus[1].1).contains(&"/ban".to_string()));
+        assert!(matches!(
+            menus[4].0,
+            BotCommandScope::ChatMember {
+                user_id: UserId(2),
+                ..
+            }
+        ));
+        assert_eq!(names(&menus[4].1)[0], "/broadcast");
+    }
+}
@@ -0,0 +1,1198 @@
+use crate::error::MyError;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use teloxide::types::{ChatId, UserId};
+
+/// Default path of the optional configuration file.
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Default address the HTTP server listens on.
+pub const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+
+/// Default path of the SQLite database.
+pub const DEFAULT_DATABASE_PATH: &str = "data/glebus_vpn_bot.db";
+
+/// Default font of the traffic charts, from the `fonts-dejavu-core` package.
+pub const DEFAULT_CHART_FONT: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+
+/// Default path of the log file.
+pub const DEFAULT_LOG_FILE: &str = "log/glebus_vpn_bot.log";
+
+/// Validated configuration of the bot.
+///
+/// Loaded once at startup by [`Config::load`] and shared with handlers as an
+/// `Arc<Config>` dptree dependency.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Telegram Bot API token.
+    pub telegram_token: String,
+    /// Base URL of the Remnawave panel.
+    pub panel_base_url: String,
+    /// Remnawave API token.
+    pub panel_api_token: String,
+    /// Address of the HTTP server (health checks, webhooks, metrics).
+    pub http_listen_addr: SocketAddr,
+    /// Secret used to verify panel webhooks; webhooks are disabled when unset.
+    pub webhook_secret: Option<String>,
+    /// Chat that receives admin notifications.
+    pub admin_chat_id: Option<ChatId>,
+    /// Group that receives support tickets; the admin chat if unset.
+    pub support_chat_id: Option<ChatId>,
+    /// Telegram users allowed to run admin commands.
+    pub admin_ids: Vec<UserId>,
+    /// SQLite database with the persistent state of the bot.
+    pub database_path: PathBuf,
+    /// TrueType font of the traffic charts.
+    pub chart_font_path: PathBuf,
+    /// Logging settings.
+    pub logging: LoggingConfig,
+    /// Resilience settings of panel API calls.
+    pub panel: PanelConfig,
+    /// Per-user limits of commands and button presses.
+    pub rate_limit: RateLimitConfig,
+    /// Flood limits of outgoing messages.
+    pub outbox: OutboxConfig,
+    /// Who may create a subscription.
+    pub registration: RegistrationConfig,
+    /// Paid plans and how they are paid for.
+    pub payments: PaymentsConfig,
+    /// Subscriptions shared by a family.
+    pub family: FamilyConfig,
+    /// Balances of users and automatic renewal from them.
+    pub balance: BalanceConfig,
+}
+
+/// Timeouts, retries and circuit breaker of panel API calls.
+#[derive(Debug, Clone)]
+pub struct PanelConfig {
+    /// Timeout of a single panel request.
+    pub timeout: Duration,
+    /// How many times a failed read is retried.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubles with every attempt.
+    pub retry_base_delay: Duration,
+    /// Consecutive failures that open the circuit breaker.
+    pub breaker_threshold: u32,
+    /// How long the open breaker rejects calls.
+    pub breaker_open_for: Duration,
+}
+
+/// Token buckets limiting how often a user may run each action.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Actions a user may run in a row before being slowed down.
+    pub burst: u32,
+    /// Sustained number of actions per minute.
+    pub per_minute: u32,
+    /// Minimal interval between expensive actions like link regeneration.
+    pub expensive_cooldown: Duration,
+}
+
+/// Limits of the outgoing message queue, see [`crate::outbox::Outbox`].
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Messages sent per second across all chats.
+    pub global_per_second: u32,
+    /// Minimal interval between messages to the same chat.
+    pub per_chat_interval: Duration,
+    /// Attempts to send a message when the network fails.
+    pub max_attempts: u32,
+}
+
+/// Who may create a subscription, see [`crate::registration`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// Anyone who finds the bot.
+    Open,
+    /// Users who sent an invite code generated by an admin.
+    Invite,
+    /// Users whose request was approved by an admin.
+    Approval,
+}
+
+/// Registration settings.
+#[derive(Debug, Clone)]
+pub struct RegistrationConfig {
+    pub mode: RegistrationMode,
+    /// Refuse registration to Telegram accounts without a username.
+    pub require_username: bool,
+    /// Refuse registration to bot accounts.
+    pub deny_bots: bool,
+}
+
+/// A subscription plan that can be bought, see [`crate::gift`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Short name used in buttons and invoices, like `month`.
+    pub id: String,
+    /// Name shown to users.
+    pub title: String,
+    /// Days of subscription the plan gives.
+    pub days: u32,
+    /// Price in the smallest units of the currency, e.g. cents or stars.
+    pub price: u32,
+}
+
+/// Payment settings.
+#[derive(Debug, Clone)]
+pub struct PaymentsConfig {
+    /// ISO 4217 code of the currency, or `XTR` for Telegram Stars.
+    pub currency: String,
+    /// Token of the payment provider; not needed for Telegram Stars.
+    pub provider_token: Option<String>,
+    /// Plans on sale; nothing can be bought when there are none.
+    pub plans: Vec<Plan>,
+}
+
+impl PaymentsConfig {
+    /// The plan with the given id, if it is on sale.
+    pub fn plan(&self, id: &str) -> Option<&Plan> {
+        self.plans.iter().find(|plan| plan.id == id)
+    }
+}
+
+/// Family subscriptions, see [`crate::family`].
+#[derive(Debug, Clone)]
+pub struct FamilyConfig {
+    /// Members a family may have besides its owner; `0` disables families.
+    pub max_members: u32,
+    /// How often members get the expiry and device limit of their owner.
+    pub sync_interval: Duration,
+}
+
+/// Balance settings, see [`crate::balance`]. Amounts are in the
+/// smallest units of the payments currency.
+#[derive(Debug, Clone)]
+pub struct BalanceConfig {
+    /// Amounts a user may top up their balance with.
+    pub top_up_amounts: Vec<u32>,
+    /// Credited to a referrer when a user they invited pays for the first time.
+    pub referral_bonus: u32,
+    /// How long before expiry a subscription is renewed from the balance.
+    pub renew_before: Duration,
+    /// How long before expiry users are warned that their balance is short.
+    pub warn_before: Duration,
+    /// How often subscriptions are checked for renewal.
+    pub check_interval: Duration,
+}
+
+/// When the log file is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    /// When the file grows over the given number of bytes.
+    Size(u64),
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+/// Encoding of log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable `{d} - {l} - {m}` lines.
+    Pattern,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Logging settings, see [`crate::logger::init_logger`].
+#[derive(Debug, Clone)]
+pub struct LoggingConfig {
+    /// log4rs YAML file; when set, all other logging settings are ignored.
+    pub config_file: Option<PathBuf>,
+    pub console_level: LevelFilter,
+    pub file_level: LevelFilter,
+    pub file_path: PathBuf,
+    pub rotation: LogRotation,
+    /// Number of rotated files to keep.
+    pub retention: u32,
+    pub format: LogFormat,
+    /// Levels of individual modules, e.g. `teloxide` => `warn`.
+    pub module_levels: Vec<(String, LevelFilter)>,
+    /// Whether Telegram user ids are replaced with keyed hashes in logs.
+    pub hash_user_ids: bool,
+    /// Key of the user id hashes.
+    pub hash_salt: String,
+}
+
+/// Problems found in the configuration.
+///
+/// Settings are named after where their value came from: the environment
+/// variable if it is set, the key of the configuration file otherwise.
+#[derive(Debug, Default)]
+struct Errors {
+    from_env: HashSet<&'static str>,
+    messages: Vec<String>,
+}
+
+impl Errors {
+    /// Name of the setting read from the environment variable `env` or
+    /// the file key `key`.
+    fn name(&self, env: &'static str, key: &'static str) -> &'static str {
+        if self.from_env.contains(env) {
+            env
+        } else {
+            key
+        }
+    }
+
+    fn push(&mut self, message: String) {
+        self.messages.push(message);
+    }
+}
+
+/// Configuration as read from the file and the environment, before validation.
+///
+/// Every field is optional so that layers can be merged.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    telegram_token: Option<String>,
+    panel_base_url: Option<String>,
+    panel_api_token: Option<String>,
+    http_listen_addr: Option<String>,
+    webhook_secret: Option<String>,
+    admin_chat_id: Option<i64>,
+    support_chat_id: Option<i64>,
+    admin_ids: Option<Vec<u64>>,
+    database_path: Option<String>,
+    chart_font_path: Option<String>,
+    #[serde(default)]
+    logging: RawLoggingConfig,
+    #[serde(default)]
+    panel: RawPanelConfig,
+    #[serde(default)]
+    rate_limit: RawRateLimitConfig,
+    #[serde(default)]
+    outbox: RawOutboxConfig,
+    #[serde(default)]
+    registration: RawRegistrationConfig,
+    #[serde(default)]
+    payments: RawPaymentsConfig,
+    #[serde(default)]
+    family: RawFamilyConfig,
+    #[serde(default)]
+    balance: RawBalanceConfig,
+}
+
+/// The `[panel]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPanelConfig {
+    timeout_secs: Option<u64>,
+    max_retries: Option<u32>,
+    retry_base_delay_ms: Option<u64>,
+    breaker_threshold: Option<u32>,
+    breaker_open_secs: Option<u64>,
+}
+
+impl RawPanelConfig {
+    /// Validates the panel section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> PanelConfig {
+        let timeout_secs = self.timeout_secs.unwrap_or(10);
+        if timeout_secs == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("PANEL_TIMEOUT_SECS", "panel.timeout_secs")
+            ));
+        }
+        let breaker_threshold = self.breaker_threshold.unwrap_or(5);
+        if breaker_threshold == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("PANEL_BREAKER_THRESHOLD", "panel.breaker_threshold")
+            ));
+        }
+
+        PanelConfig {
+            timeout: Duration::from_secs(timeout_secs),
+            max_retries: self.max_retries.unwrap_or(3),
+            retry_base_delay: Duration::from_millis(self.retry_base_delay_ms.unwrap_or(200)),
+            breaker_threshold,
+            breaker_open_for: Duration::from_secs(self.breaker_open_secs.unwrap_or(30)),
+        }
+    }
+}
+
+/// The `[rate_limit]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimitConfig {
+    burst: Option<u32>,
+    per_minute: Option<u32>,
+    expensive_cooldown_secs: Option<u64>,
+}
+
+impl RawRateLimitConfig {
+    /// Validates the rate limit section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> RateLimitConfig {
+        let burst = self.burst.unwrap_or(5);
+        if burst == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("RATE_LIMIT_BURST", "rate_limit.burst")
+            ));
+        }
+        let per_minute = self.per_minute.unwrap_or(20);
+        if per_minute == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("RATE_LIMIT_PER_MINUTE", "rate_limit.per_minute")
+            ));
+        }
+
+        RateLimitConfig {
+            burst,
+            per_minute,
+            expensive_cooldown: Duration::from_secs(self.expensive_cooldown_secs.unwrap_or(30)),
+        }
+    }
+}
+
+/// The `[outbox]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOutboxConfig {
+    global_per_second: Option<u32>,
+    per_chat_interval_ms: Option<u64>,
+    max_attempts: Option<u32>,
+}
+
+impl RawOutboxConfig {
+    /// Validates the outbox section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> OutboxConfig {
+        let global_per_second = self.global_per_second.unwrap_or(30);
+        if global_per_second == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("OUTBOX_GLOBAL_PER_SECOND", "outbox.global_per_second")
+            ));
+        }
+        let max_attempts = self.max_attempts.unwrap_or(3);
+        if max_attempts == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("OUTBOX_MAX_ATTEMPTS", "outbox.max_attempts")
+            ));
+        }
+
+        OutboxConfig {
+            global_per_second,
+            per_chat_interval: Duration::from_millis(self.per_chat_interval_ms.unwrap_or(1000)),
+            max_attempts,
+        }
+    }
+}
+
+/// The `[registration]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRegistrationConfig {
+    mode: Option<String>,
+    require_username: Option<bool>,
+    deny_bots: Option<bool>,
+}
+
+impl RawRegistrationConfig {
+    /// Validates the registration section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> RegistrationConfig {
+        let mode = match self.mode.as_deref().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("open") => RegistrationMode::Open,
+            Some("invite") => RegistrationMode::Invite,
+            Some("approval") => RegistrationMode::Approval,
+            Some(other) => {
+                errors.push(format!(
+                    "{} must be one of open, invite, approval, got {}",
+                    errors.name("REGISTRATION_MODE", "registration.mode"),
+                    other
+                ));
+                RegistrationMode::Open
+            }
+        };
+        RegistrationConfig {
+            mode,
+            require_username: self.require_username.unwrap_or(false),
+            deny_bots: self.deny_bots.unwrap_or(true),
+        }
+    }
+}
+
+/// The `[payments]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPaymentsConfig {
+    currency: Option<String>,
+    provider_token: Option<String>,
+    #[serde(default)]
+    plans: Vec<RawPlan>,
+}
+
+/// A `[[payments.plans]]` entry of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPlan {
+    id: String,
+    title: String,
+    days: u32,
+    price: u32,
+}
+
+impl RawPaymentsConfig {
+    /// Validates the payments section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> PaymentsConfig {
+        let currency = self
+            .currency
+            .map(|currency| currency.trim().to_ascii_uppercase())
+            .unwrap_or_else(|| "XTR".to_string());
+        if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+            errors.push(format!(
+                "{} must be a currency code like XTR or USD, got {}",
+                errors.name("PAYMENTS_CURRENCY", "payments.currency"),
+                currency
+            ));
+        }
+        let provider_token = self.provider_token.filter(|token| !token.is_empty());
+        if currency != "XTR" && provider_token.is_none() && !self.plans.is_empty() {
+            errors.push(format!(
+                "{} must be set to take payments in {}",
+                errors.name("PAYMENTS_PROVIDER_TOKEN", "payments.provider_token"),
+                currency
+            ));
+        }
+
+        let mut plans: Vec<Plan> = Vec::new();
+        for plan in self.plans {
+            // Ids go into callback data and invoice payloads.
+            let valid_id = !plan.id.is_empty()
+                && plan.id.len() <= 32
+                && plan
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !valid_id {
+                errors.push(format!(
+                    "Ids of payments.plans must be up to 32 letters, digits, _ or -, got {:?}",
+                    plan.id
+                ));
+            } else if plan.id == "off" {
+                errors.push("Plan id off is reserved".to_string());
+            } else if plans.iter().any(|other| other.id == plan.id) {
+                errors.push(format!("Plan {} is defined twice", plan.id));
+            }
+            if plan.days == 0 || plan.price == 0 {
+                errors.push(format!(
+                    "Plan {} must have positive days and price",
+                    plan.id
+                ));
+            }
+            plans.push(Plan {
+                id: plan.id,
+                title: plan.title,
+                days: plan.days,
+                price: plan.price,
+            });
+        }
+        PaymentsConfig {
+            currency,
+            provider_token,
+            plans,
+        }
+    }
+}
+
+/// The `[family]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFamilyConfig {
+    max_members: Option<u32>,
+    sync_interval_secs: Option<u64>,
+}
+
+impl RawFamilyConfig {
+    /// Validates the family section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> FamilyConfig {
+        let sync_interval_secs = self.sync_interval_secs.unwrap_or(600);
+        if sync_interval_secs == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("FAMILY_SYNC_INTERVAL_SECS", "family.sync_interval_secs")
+            ));
+        }
+
+        FamilyConfig {
+            max_members: self.max_members.unwrap_or(5),
+            sync_interval: Duration::from_secs(sync_interval_secs),
+        }
+    }
+}
+
+/// The `[balance]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBalanceConfig {
+    top_up_amounts: Option<Vec<u32>>,
+    referral_bonus: Option<u32>,
+    renew_before_hours: Option<u64>,
+    warn_before_hours: Option<u64>,
+    check_interval_secs: Option<u64>,
+}
+
+impl RawBalanceConfig {
+    /// Validates the balance section, adding every problem found to `errors`.
+    ///
+    /// Top-ups default to the prices of the `plans`.
+    fn validate(self, plans: &[Plan], errors: &mut Errors) -> BalanceConfig {
+        let mut top_up_amounts = self
+            .top_up_amounts
+            .unwrap_or_else(|| plans.iter().map(|plan| plan.price).collect());
+        if top_up_amounts.contains(&0) {
+            errors.push("balance.top_up_amounts must be greater than 0".to_string());
+        }
+        top_up_amounts.sort_unstable();
+        top_up_amounts.dedup();
+        let renew_before_hours = self.renew_before_hours.unwrap_or(24);
+        let warn_before_hours = self.warn_before_hours.unwrap_or(72);
+        if warn_before_hours < renew_before_hours {
+            errors.push(format!(
+                "{} must be at least {} ({}), got {}",
+                errors.name("BALANCE_WARN_BEFORE_HOURS", "balance.warn_before_hours"),
+                errors.name("BALANCE_RENEW_BEFORE_HOURS", "balance.renew_before_hours"),
+                renew_before_hours,
+                warn_before_hours
+            ));
+        }
+        let check_interval_secs = self.check_interval_secs.unwrap_or(3600);
+        if check_interval_secs == 0 {
+            errors.push(format!(
+                "{} must be greater than 0",
+                errors.name("BALANCE_CHECK_INTERVAL_SECS", "balance.check_interval_secs")
+            ));
+        }
+
+        BalanceConfig {
+            top_up_amounts,
+            referral_bonus: self.referral_bonus.unwrap_or(0),
+            renew_before: Duration::from_secs(renew_before_hours * 3600),
+            warn_before: Duration::from_secs(warn_before_hours * 3600),
+            check_interval: Duration::from_secs(check_interval_secs),
+        }
+    }
+}
+
+/// Reads an environment variable, `None` if it isn't set.
+type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Overrides `field` with the environment variable `name` if it is set,
+/// adding an error if the value cannot be parsed.
+fn merge_parsed_env<T: FromStr>(
+    env: Env,
+    name: &'static str,
+    expected: &str,
+    field: &mut Option<T>,
+    errors: &mut Errors,
+) {
+    if let Some(value) = env(name) {
+        errors.from_env.insert(name);
+        match value.parse() {
+            Ok(parsed) => *field = Some(parsed),
+            Err(_) => errors.push(format!("{} must be {}, got {}", name, expected, value)),
+        }
+    }
+}
+
+/// The `[logging]` section of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLoggingConfig {
+    config_file: Option<String>,
+    console_level: Option<String>,
+    file_level: Option<String>,
+    file_path: Option<String>,
+    rotation: Option<String>,
+    max_size: Option<String>,
+    retention: Option<u32>,
+    format: Option<String>,
+    modules: Option<BTreeMap<String, String>>,
+    hash_user_ids: Option<bool>,
+    hash_salt: Option<String>,
+}
+
+/// Parses a size like `10MB`, `512KB` or `1048576`.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_uppercase();
+    let (number, multiplier) = if let Some(number) = value.strip_suffix("GB") {
+        (number, 1024 * 1024 * 1024)
+    } else if let Some(number) = value.strip_suffix("MB") {
+        (number, 1024 * 1024)
+    } else if let Some(number) = value.strip_suffix("KB") {
+        (number, 1024)
+    } else {
+        (value.strip_suffix('B').unwrap_or(&value), 1)
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+impl RawLoggingConfig {
+    /// Validates the logging section, adding every problem found to `errors`.
+    fn validate(self, errors: &mut Errors) -> LoggingConfig {
+        let console_name = errors.name("LOG_CONSOLE_LEVEL", "logging.console_level");
+        let file_name = errors.name("LOG_FILE_LEVEL", "logging.file_level");
+        let modules_name = errors.name("LOG_MODULES", "logging.modules");
+        let mut level = |value: Option<String>, default: LevelFilter, name: &str| match value {
+            None => default,
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                errors.push(format!(
+                    "{} must be one of off, error, warn, info, debug, trace, got {}",
+                    name, value
+                ));
+                default
+            }),
+        };
+        let console_level = level(self.console_level, LevelFilter::Info, console_name);
+        let file_level = level(self.file_level, LevelFilter::Trace, file_name);
+        let module_levels = self
+            .modules
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(module, value)| {
+                let value = level(
+                    Some(value),
+                    LevelFilter::Trace,
+                    &format!("Level of {} in {}", module, modules_name),
+                );
+                (module, value)
+            })
+            .collect();
+
+        let max_size = match self.max_size {
+            None => 10 * 1024 * 1024,
+            Some(value) => parse_size(&value).unwrap_or_else(|| {
+                errors.push(format!(
+                    "{} must be a size like 10MB, got {}",
+                    errors.name("LOG_MAX_SIZE", "logging.max_size"),
+                    value
+                ));
+                0
+            }),
+        };
+        let rotation = match self
+            .rotation
+            .as_deref()
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            None | Some("size") => LogRotation::Size(max_size),
+            Some("hourly") => LogRotation::Hourly,
+            Some("daily") => LogRotation::Daily,
+            Some("weekly") => LogRotation::Weekly,
+            Some(other) => {
+                errors.push(format!(
+                    "{} must be one of size, hourly, daily, weekly, got {}",
+                    errors.name("LOG_ROTATION", "logging.rotation"),
+                    other
+                ));
+                LogRotation::Size(max_size)
+            }
+        };
+
+        let format = match self
+            .format
+            .as_deref()
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            None | Some("pattern") => LogFormat::Pattern,
+            Some("json") => LogFormat::Json,
+            Some(other) => {
+                errors.push(format!(
+                    "{} must be pattern or json, got {}",
+                    errors.name("LOG_FORMAT", "logging.format"),
+                    other
+                ));
+                LogFormat::Pattern
+            }
+        };
+
+        let hash_user_ids = self.hash_user_ids.unwrap_or(false);
+        let hash_salt = self.hash_salt.unwrap_or_default();
+        if hash_user_ids && hash_salt.is_empty() {
+            errors.push(format!(
+                "{} must be set when {} is enabled",
+                errors.name("LOG_HASH_SALT", "logging.hash_salt"),
+                errors.name("LOG_HASH_USER_IDS", "logging.hash_user_ids")
+            ));
+        }
+
+        LoggingConfig {
+            config_file: self.config_file.map(PathBuf::from),
+            console_level,
+            file_level,
+            file_path: PathBuf::from(
+                self.file_path
+                    .unwrap_or_else(|| DEFAULT_LOG_FILE.to_string()),
+            ),
+            rotation,
+            retention: self.retention.unwrap_or(7),
+            format,
+            module_levels,
+            hash_user_ids,
+            hash_salt,
+        }
+    }
+}
+
+impl RawConfig {
+    fn from_file(path: &Path) -> Result<Self, MyError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| MyError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        toml::from_str(&content)
+            .map_err(|e| MyError::Config(format!("cannot parse {}: {}", path.display(), e)))
+    }
+
+    /// Overrides fields with the environment variables that `env` has.
+    ///
+    /// Returns the variables that are set, with those that cannot be
+    /// parsed as errors.
+    fn merge_env(&mut self, env: Env) -> Errors {
+        let mut errors = Errors::default();
+
+        let vars = [
+            ("TELOXIDE_TOKEN", &mut self.telegram_token),
+            ("PANEL_BASE_URL", &mut self.panel_base_url),
+            ("REMNAWAVE_API_TOKEN", &mut self.panel_api_token),
+            ("HTTP_LISTEN_ADDR", &mut self.http_listen_addr),
+            ("REMNAWAVE_WEBHOOK_SECRET", &mut self.webhook_secret),
+            ("DATABASE_PATH", &mut self.database_path),
+            ("CHART_FONT_PATH", &mut self.chart_font_path),
+            ("LOG_CONFIG_FILE", &mut self.logging.config_file),
+            ("LOG_CONSOLE_LEVEL", &mut self.logging.console_level),
+            ("LOG_FILE_LEVEL", &mut self.logging.file_level),
+            ("LOG_FILE_PATH", &mut self.logging.file_path),
+            ("LOG_ROTATION", &mut self.logging.rotation),
+            ("LOG_MAX_SIZE", &mut self.logging.max_size),
+            ("LOG_FORMAT", &mut self.logging.format),
+            ("LOG_HASH_SALT", &mut self.logging.hash_salt),
+            ("REGISTRATION_MODE", &mut self.registration.mode),
+            ("PAYMENTS_CURRENCY", &mut self.payments.currency),
+            ("PAYMENTS_PROVIDER_TOKEN", &mut self.payments.provider_token),
+        ];
+        for (name, field) in vars {
+            if let Some(value) = env(name) {
+                errors.from_env.insert(name);
+                *field = Some(value);
+            }
+        }
+
+        let numeric = [
+            ("PANEL_TIMEOUT_SECS", &mut self.panel.timeout_secs),
+            (
+                "PANEL_RETRY_BASE_DELAY_MS",
+                &mut self.panel.retry_base_delay_ms,
+            ),
+            ("PANEL_BREAKER_OPEN_SECS", &mut self.panel.breaker_open_secs),
+            (
+                "RATE_LIMIT_EXPENSIVE_COOLDOWN_SECS",
+                &mut self.rate_limit.expensive_cooldown_secs,
+            ),
+            (
+                "OUTBOX_PER_CHAT_INTERVAL_MS",
+                &mut self.outbox.per_chat_interval_ms,
+            ),
+            (
+                "FAMILY_SYNC_INTERVAL_SECS",
+                &mut self.family.sync_interval_secs,
+            ),
+            (
+                "BALANCE_RENEW_BEFORE_HOURS",
+                &mut self.balance.renew_before_hours,
+            ),
+            (
+                "BALANCE_WARN_BEFORE_HOURS",
+                &mut self.balance.warn_before_hours,
+            ),
+            (
+                "BALANCE_CHECK_INTERVAL_SECS",
+                &mut self.balance.check_interval_secs,
+            ),
+        ];
+        for (name, field) in numeric {
+            merge_parsed_env(env, name, "a number", field, &mut errors);
+        }
+        let counts = [
+            ("LOG_RETENTION", &mut self.logging.retention),
+            ("PANEL_MAX_RETRIES", &mut self.panel.max_retries),
+            ("PANEL_BREAKER_THRESHOLD", &mut self.panel.breaker_threshold),
+            ("RATE_LIMIT_BURST", &mut self.rate_limit.burst),
+            ("RATE_LIMIT_PER_MINUTE", &mut self.rate_limit.per_minute),
+            (
+                "OUTBOX_GLOBAL_PER_SECOND",
+                &mut self.outbox.global_per_second,
+            ),
+            ("OUTBOX_MAX_ATTEMPTS", &mut self.outbox.max_attempts),
+            ("FAMILY_MAX_MEMBERS", &mut self.family.max_members),
+            ("BALANCE_REFERRAL_BONUS", &mut self.balance.referral_bonus),
+        ];
+        for (name, field) in counts {
+            merge_parsed_env(env, name, "a number", field, &mut errors);
+        }
+        let chats = [
+            ("ADMIN_CHAT_ID", &mut self.admin_chat_id),
+            ("SUPPORT_CHAT_ID", &mut self.support_chat_id),
+        ];
+        for (name, field) in chats {
+            merge_parsed_env(env, name, "a chat id", field, &mut errors);
+        }
+        let flags = [
+            ("LOG_HASH_USER_IDS", &mut self.logging.hash_user_ids),
+            (
+                "REGISTRATION_REQUIRE_USERNAME",
+                &mut self.registration.require_username,
+            ),
+            ("REGISTRATION_DENY_BOTS", &mut self.registration.deny_bots),
+        ];
+        for (name, field) in flags {
+            merge_parsed_env(env, name, "true or false", field, &mut errors);
+        }
+
+        // ADMIN_IDS=123456789,987654321
+        if let Some(value) = env("ADMIN_IDS") {
+            errors.from_env.insert("ADMIN_IDS");
+            let mut ids = Vec::new();
+            for id in value.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+                match id.parse() {
+                    Ok(id) => ids.push(id),
+                    Err(_) => errors.push(format!(
+                        "ADMIN_IDS must be comma-separated user ids, got {}",
+                        id
+                    )),
+                }
+            }
+            self.admin_ids = Some(ids);
+        }
+
+        // LOG_MODULES=teloxide=warn,reqwest=info
+        if let Some(value) = env("LOG_MODULES") {
+            errors.from_env.insert("LOG_MODULES");
+            let mut modules = BTreeMap::new();
+            for entry in value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+            {
+                match entry.split_once('=') {
+                    Some((module, level)) => {
+                        modules.insert(module.trim().to_string(), level.trim().to_string());
+                    }
+                    None => errors.push(format!(
+                        "LOG_MODULES entries must look like module=level, got {}",
+                        entry
+                    )),
+                }
+            }
+            self.logging.modules = Some(modules);
+        }
+
+        errors
+    }
+
+    /// Validates the merged configuration, adding every problem found to `errors`.
+    fn validate(self, mut errors: Errors) -> Result<Config, MyError> {
+        let mut required = |value: Option<String>, env: &str, key: &str| match value {
+            Some(value) if !value.trim().is_empty() => value,
+            _ => {
+                errors.push(format!("{} or {} must be set", env, key));
+                String::new()
+            }
+        };
+        let telegram_token = required(self.telegram_token, "TELOXIDE_TOKEN", "telegram_token");
+        let panel_base_url = required(self.panel_base_url, "PANEL_BASE_URL", "panel_base_url");
+        let panel_api_token = required(
+            self.panel_api_token,
+            "REMNAWAVE_API_TOKEN",
+            "panel_api_token",
+        );
+
+        if !panel_base_url.is_empty()
+            && !panel_base_url.starts_with("http://")
+            && !panel_base_url.starts_with("https://")
+        {
+            errors.push(format!(
+                "{} must start with http:// or https://, got {}",
+                errors.name("PANEL_BASE_URL", "panel_base_url"),
+                panel_base_url
+            ));
+        }
+
+        let listen_addr = self
+            .http_listen_addr
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+        let http_listen_addr = listen_addr.parse().unwrap_or_else(|_| {
+            errors.push(format!(
+                "{} must be an address like 0.0.0.0:8080, got {}",
+                errors.name("HTTP_LISTEN_ADDR", "http_listen_addr"),
+                listen_addr
+            ));
+            SocketAddr::from(([0, 0, 0, 0], 8080))
+        });
+
+        let admin_chat_id = self.admin_chat_id.map(ChatId);
+        let support_chat_id = self.support_chat_id.map(ChatId);
+        let admin_ids = self
+            .admin_ids
+            .unwrap_or_default()
+            .into_iter()
+            .map(UserId)
+            .collect();
+        let webhook_secret = self.webhook_secret.filter(|secret| !secret.is_empty());
+        let logging = self.logging.validate(&mut errors);
+        let panel = self.panel.validate(&mut errors);
+        let rate_limit = self.rate_limit.validate(&mut errors);
+        let outbox = self.outbox.validate(&mut errors);
+        let registration = self.registration.validate(&mut errors);
+        let payments = self.payments.validate(&mut errors);
+        let family = self.family.validate(&mut errors);
+        let balance = self.balance.validate(&payments.plans, &mut errors);
+        let database_path = PathBuf::from(
+            self.database_path
+                .unwrap_or_else(|| DEFAULT_DATABASE_PATH.to_string()),
+        );
+
+        let chart_font_path = PathBuf::from(
+            self.chart_font_path
+                .unwrap_or_else(|| DEFAULT_CHART_FONT.to_string()),
+        );
+
+        if !errors.messages.is_empty() {
+            return Err(MyError::Config(errors.messages.join("; ")));
+        }
+
+        Ok(Config {
+            telegram_token,
+            panel_base_url,
+            panel_api_token,
+            http_listen_addr,
+            webhook_secret,
+            admin_chat_id,
+            support_chat_id,
+            admin_ids,
+            database_path,
+            chart_font_path,
+            logging,
+            panel,
+            rate_limit,
+            outbox,
+            registration,
+            payments,
+            family,
+            balance,
+        })
+    }
+}
+
+impl Config {
+    /// Chat that receives support tickets, if any.
+    pub fn support_chat(&self) -> Option<ChatId> {
+        self.support_chat_id.or(self.admin_chat_id)
+    }
+
+    /// Whether `chat` is the admin or the support chat, where admins may
+    /// use admin commands.
+    pub fn is_admin_chat(&self, chat: ChatId) -> bool {
+        self.admin_chat_id == Some(chat) || self.support_chat() == Some(chat)
+    }
+
+    /// Whether `user` may run admin commands.
+    pub fn is_admin(&self, user: UserId) -> bool {
+        self.admin_ids.contains(&user)
+    }
+
+    /// Loads and validates the configuration.
+    ///
+    /// Settings are layered, later layers overriding earlier ones:
+    /// 1. built-in defaults;
+    /// 2. the TOML file from `CONFIG_FILE` (defaults to `config.toml`,
+    ///    skipped if the default file does not exist);
+    /// 3. environment variables, including those from `.env`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MyError::Config` describing every missing or invalid setting.
+    pub fn load() -> Result<Self, MyError> {
+        let mut raw = match dotenv::var("CONFIG_FILE") {
+            Ok(path) => RawConfig::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                RawConfig::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => RawConfig::default(),
+        };
+        let errors = raw.merge_env(&|name| dotenv::var(name).ok());
+        raw.validate(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration from the TOML `file` and the environment `vars`.
+    fn load(file: &str, vars: &[(&str, &str)]) -> Result<Config, MyError> {
+        let mut raw: RawConfig = toml::from_str(file).unwrap();
+        let env = |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        };
+        let errors = raw.merge_env(&env);
+        raw.validate(errors)
+    }
+
+    const REQUIRED: &str = r#"
+        telegram_token = "token"
+        panel_base_url = "https://panel.example.com"
+        panel_api_token = "api"
+    "#;
+
+    fn error(file: &str, vars: &[(&str, &str)]) -> String {
+        match load(&format!("{}\n{}", REQUIRED, file), vars) {
+            Err(MyError::Config(error)) => error,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_file_over_defaults() {
+        let file = format!(
+            "{}\n[panel]\ntimeout_secs = 20\nmax_retries = 1\n[family]\nmax_members = 2",
+            REQUIRED
+        );
+        let config = load(&file, &[("PANEL_TIMEOUT_SECS", "5"), ("ADMIN_IDS", "1, 2")]).unwrap();
+
+        assert_eq!(config.panel.timeout, Duration::from_secs(5));
+        assert_eq!(config.panel.max_retries, 1);
+        assert_eq!(config.family.max_members, 2);
+        assert_eq!(config.admin_ids, vec![UserId(1), UserId(2)]);
+        assert_eq!(config.rate_limit.burst, 5);
+        assert_eq!(config.payments.currency, "XTR");
+        assert_eq!(config.http_listen_addr.to_string(), DEFAULT_LISTEN_ADDR);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<RawConfig>("telegram_tokn = \"x\"").is_err());
+        assert!(toml::from_str::<RawConfig>("[panel]\ntimeout = 1").is_err());
+        assert!(toml::from_str::<RawConfig>("[[payments.plans]]\nid = \"a\"").is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576"), Some(1048576));
+        assert_eq!(parse_size("512b"), Some(512));
+        assert_eq!(parse_size("10MB"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size(" 2 kb "), Some(2048));
+        assert_eq!(parse_size("1GB"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("MB"), None);
+        assert_eq!(parse_size("-1KB"), None);
+        assert_eq!(parse_size("99999999999GB"), None);
+    }
+
+    #[test]
+    fn requires_the_tokens() {
+        let error = match load("", &[]) {
+            Err(MyError::Config(error)) => error,
+            other => panic!("expected a config error, got {:?}", other),
+        };
+        assert!(error.contains("TELOXIDE_TOKEN or telegram_token must be set"));
+        assert!(error.contains("PANEL_BASE_URL or panel_base_url must be set"));
+        assert!(error.contains("REMNAWAVE_API_TOKEN or panel_api_token must be set"));
+    }
+
+    #[test]
+    fn errors_name_the_file_key_or_the_variable() {
+        let file = "[panel]\ntimeout_secs = 0\n[rate_limit]\nburst = 0";
+        let from_file = error(file, &[]);
+        assert!(from_file.contains("panel.timeout_secs must be greater than 0"));
+        assert!(from_file.contains("rate_limit.burst must be greater than 0"));
+
+        let from_env = error(file, &[("PANEL_TIMEOUT_SECS", "0")]);
+        assert!(from_env.contains("PANEL_TIMEOUT_SECS must be greater than 0"));
+        assert!(from_env.contains("rate_limit.burst must be greater than 0"));
+
+        let unparsable = error("", &[("OUTBOX_MAX_ATTEMPTS", "many")]);
+        assert_eq!(unparsable, "OUTBOX_MAX_ATTEMPTS must be a number, got many");
+
+        let admins = error("", &[("ADMIN_IDS", "1,bob")]);
+        assert_eq!(
+            admins,
+            "ADMIN_IDS must be comma-separated user ids, got bob"
+        );
+    }
+
+    #[test]
+    fn validates_every_section() {
+        let file = r#"
+            http_listen_addr = "nowhere"
+            [logging]
+            console_level = "loud"
+            max_size = "big"
+            rotation = "yearly"
+            format = "xml"
+            hash_user_ids = true
+            [panel]
+            breaker_threshold = 0
+            [rate_limit]
+            per_minute = 0
+            [outbox]
+            global_per_second = 0
+            max_attempts = 0
+            [registration]
+            mode = "closed"
+            [payments]
+            currency = "dollars"
+            [[payments.plans]]
+            id = "off"
+            title = "Off"
+            days = 0
+            price = 10
+            [family]
+            sync_interval_secs = 0
+            [balance]
+            top_up_amounts = [0]
+            renew_before_hours = 48
+            warn_before_hours = 24
+            check_interval_secs = 0
+        "#;
+        let error = error(file, &[]);
+        for expected in [
+            "http_listen_addr must be an address like 0.0.0.0:8080, got nowhere",
+            "logging.console_level must be one of off, error, warn, info, debug, trace, got loud",
+            "logging.max_size must be a size like 10MB, got big",
+            "logging.rotation must be one of size, hourly, daily, weekly, got yearly",
+            "logging.format must be pattern or json, got xml",
+            "logging.hash_salt must be set when logging.hash_user_ids is enabled",
+            "panel.breaker_threshold must be greater than 0",
+            "rate_limit.per_minute must be greater than 0",
+            "outbox.global_per_second must be greater than 0",
+            "outbox.max_attempts must be greater than 0",
+            "registration.mode must be one of open, invite, approval, got closed",
+            "payments.currency must be a currency code like XTR or USD, got DOLLARS",
+            "Plan id off is reserved",
+            "Plan off must have positive days and price",
+            "family.sync_interval_secs must be greater than 0",
+            "balance.top_up_amounts must be greater than 0",
+            "balance.warn_before_hours must be at least balance.renew_before_hours (48), got 24",
+            "balance.check_interval_secs must be greater than 0",
+        ] {
+            assert!(
+                error.contains(expected),
+                "{:?} not in {:?}",
+                expected,
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::config::Config;
+use crate::error::MyError;
+use crate::export;
+use crate::handlers;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::promo;
+use crate::registration::{self, Access, RegistrationState};
+use crate::stats;
+use crate::storage::Storage;
+use crate::support;
+use remnawave::api::types::users::UserData;
+use std::fmt;
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::InMemStorage;
+use teloxide::prelude::*;
+use teloxide::types::{Me, User};
+
+/// Menu screen opened by a `start=<screen>` link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screen {
+    Menu,
+    About,
+    Subscription,
+    Stats,
+    Export,
+}
+
+/// What a `t.me/<bot>?start=<payload>` link asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// `ref_<telegram id>`: the user was invited by another user.
+    Referral(UserId),
+    /// `promo_<code>`: a promo code extending the subscription or
+    /// crediting the balance.
+    Promo(String),
+    /// `inv_<code>`: an invite code for the `invite` registration mode.
+    Invite(String),
+    /// `gift_<code>`: a subscription paid for by another user, redeemed
+    /// by `/start` itself with [`crate::gift::redeem`].
+    Gift(String),
+    /// `fam_<code>`: an invite to a family, followed by `/start` itself
+    /// with [`crate::family::join`].
+    Family(String),
+    /// `support`: opens a support ticket.
+    Support,
+    Screen(Screen),
+}
+
+/// A kind of payload: its prefix and how to read the rest of it.
+struct Route {
+    prefix: &'static str,
+    parse: fn(&str) -> Option<Payload>,
+}
+
+/// Known payloads, tried in order. Telegram only allows `A-Z`, `a-z`,
+/// `0-9`, `_` and `-` in payloads, at most 64 of them.
+const ROUTES: &[Route] = &[
+    Route {
+        prefix: "ref_",
+        parse: |id| id.parse().ok().map(|id| Payload::Referral(UserId(id))),
+    },
+    Route {
+        prefix: "promo_",
+        parse: |code| parse_code(code).map(Payload::Promo),
+    },
+    Route {
+        prefix: "inv_",
+        parse: |code| parse_code(code).map(Payload::Invite),
+    },
+    Route {
+        prefix: "gift_",
+        parse: |code| parse_code(code).map(Payload::Gift),
+    },
+    Route {
+        prefix: "fam_",
+        parse: |code| parse_code(code).map(Payload::Family),
+    },
+    Route {
+        prefix: "support",
+        parse: |rest| rest.is_empty().then_some(Payload::Support),
+    },
+    Route {
+        prefix: "menu",
+        parse: |rest| rest.is_empty().then_some(Payload::Screen(Screen::Menu)),
+    },
+    Route {
+        prefix: "about",
+        parse: |rest| rest.is_empty().then_some(Payload::Screen(Screen::About)),
+    },
+    Route {
+        prefix: "sub",
+        parse: |rest| {
+            rest.is_empty()
+                .then_some(Payload::Screen(Screen::Subscription))
+        },
+    },
+    Route {
+        prefix: "stats",
+        parse: |rest| rest.is_empty().then_some(Payload::Screen(Screen::Stats)),
+    },
+    Route {
+        prefix: "export",
+        parse: |rest| rest.is_empty().then_some(Payload::Screen(Screen::Export)),
+    },
+];
+
+fn parse_code(code: &str) -> Option<String> {
+    let valid = !code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| code.to_uppercase())
+}
+
+impl Payload {
+    /// What the payload is, without any code it carries, for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payload::Referral(_) => "ref",
+            Payload::Promo(_) => "promo",
+            Payload::Invite(_) => "inv",
+            Payload::Gift(_) => "gift",
+            Payload::Family(_) => "fam",
+            Payload::Support => "support",
+            Payload::Screen(Screen::Menu) => "menu",
+            Payload::Screen(Screen::About) => "about",
+            Payload::Screen(Screen::Subscription) => "sub",
+            Payload::Screen(Screen::Stats) => "stats",
+            Payload::Screen(Screen::Export) => "export",
+        }
+    }
+
+    /// Reads the payload of a `/start` command, `None` if there is none or
+    /// it isn't known.
+    pub fn parse(payload: &str) -> Option<Self> {
+        let payload = payload.trim();
+        ROUTES.iter().find_map(|route| {
+            payload
+                .strip_prefix(route.prefix)
+                .and_then(|rest| (route.parse)(rest))
+        })
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Referral(user) => write!(f, "ref_{}", user.0),
+            Payload::Promo(code) => write!(f, "promo_{}", code),
+            Payload::Invite(code) => write!(f, "inv_{}", code),
+            Payload::Gift(code) => write!(f, "gift_{}", code),
+            Payload::Family(code) => write!(f, "fam_{}", code),
+            Payload::Support => f.write_str("support"),
+            Payload::Screen(Screen::Menu) => f.write_str("menu"),
+            Payload::Screen(Screen::About) => f.write_str("about"),
+            Payload::Screen(Screen::Subscription) => f.write_str("sub"),
+            Payload::Screen(Screen::Stats) => f.write_str("stats"),
+            Payload::Screen(Screen::Export) => f.write_str("export"),
+        }
+    }
+}
+
+/// `t.me` link opening the bot with `payload`.
+pub fn link(me: &Me, payload: &Payload) -> String {
+    format!("https://t.me/{}?start={}", me.username(), payload)
+}
+
+/// Handles the payload of a user with a subscription.
+///
+/// Returns `false` if the payload means nothing to them, so that `/start`
+/// shows the main menu.
+pub async fn open_for_subscriber(
+    bot: &Bot,
+    chat_id: ChatId,
+    user: &User,
+    panel_user: &UserData,
+    payload: Payload,
+    config: &Config,
+    storage: &Storage,
+) -> Result<bool, MyError> {
+    match payload {
+        Payload::Referral(_)
+        | Payload::Invite(_)
+        | Payload::Gift(_)
+        | Payload::Family(_)
+        | Payload::Screen(Screen::Menu) => {
+            return Ok(false);
+        }
+        Payload::Promo(code) => {
+            promo::redeem(bot, chat_id, user, Some(panel_user), &code, config, storage).await?
+        }
+        Payload::Support => support::start_ticket(bot, chat_id, user, config, storage).await?,
+        Payload::Screen(Screen::About) => {
+            handlers::send_about_me(bot, chat_id, user.id, None).await?
+        }
+        Payload::Screen(Screen::Subscription) => {
+            handlers::send_sub_link(bot, chat_id, user.id, None).await?
+        }
+        Payload::Screen(Screen::Stats) => stats::show(bot, chat_id, user.id, None, 7).await?,
+        Payload::Screen(Screen::Export) => export::send_picker(bot, chat_id, None).await?,
+    }
+    Ok(true)
+}
+
+/// Handles the payload of a user without a subscription.
+///
+/// Referrals are recorded and an invite code registers the user. Returns
+/// `false` if `/start` should go on with the usual greeting.
+pub async fn open_for_newcomer(
+    bot: &Bot,
+    chat_id: ChatId,
+    user: &User,
+    payload: Payload,
+    config: &Config,
+    storage: &Storage,
+    dialogues: Arc<InMemStorage<RegistrationState>>,
+) -> Result<bool, MyError> {
+    let messages = Messages::ru();
+    match payload {
+        Payload::Referral(referrer) => {
+            if referrer != user.id && storage.add_referral(user.id, referrer)? {
+                log::info!(
+                    "User {} was referred by {}",
+                    logger::user(user.id),
+                    logger::user(referrer)
+                );
+            }
+            Ok(false)
+        }
+        Payload::Invite(code) => {
+            if registration::access(config, storage, user)? != Access::NeedsInvite {
+                return Ok(false);
+            }
+            if !storage.redeem_invite(&code, user.id)? {
+                log::info!(
+                    "User {} opened a link with an invalid invite code",
+                    logger::user(user.id)
+                );
+                bot.send_message(chat_id, messages.registration_invite_invalid())
+                    .await?;
+                return Ok(false);
+            }
+            log::info!(
+                "User {} registered with an invite code",
+                logger::user(user.id)
+            );
+            registration::RegistrationDialogue::new(dialogues, chat_id)
+                .exit()
+                .await?;
+            bot.send_message(chat_id, messages.registration_invite_accepted())
+                .reply_markup(keyboards::new_user_confirmation())
+                .await?;
+            Ok(true)
+        }
+        Payload::Promo(code) => {
+            promo::redeem(bot, chat_id, user, None, &code, config, storage).await?;
+            Ok(false)
+        }
+        Payload::Support => {
+            support::start_ticket(bot, chat_id, user, config, storage).await?;
+            Ok(true)
+        }
+        Payload::Gift(_) | Payload::Family(_) | Payload::Screen(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(text: &str, payload: Payload) {
+        assert_eq!(Payload::parse(text), Some(payload.clone()));
+        assert_eq!(payload.to_string(), text);
+    }
+
+    #[test]
+    fn parses_referrals() {
+        assert_round_trip("ref_123456789", Payload::Referral(UserId(123456789)));
+        assert_eq!(Payload::parse("ref_"), None);
+        assert_eq!(Payload::parse("ref_abc"), None);
+    }
+
+    #[test]
+    fn parses_promo_codes() {
+        assert_round_trip("promo_SUMMER24", Payload::Promo("SUMMER24".to_string()));
+        assert_eq!(
+            Payload::parse("promo_summer24"),
+            Some(Payload::Promo("SUMMER24".to_string()))
+        );
+        assert_eq!(Payload::parse("promo_"), None);
+        assert_eq!(Payload::parse("promo_a-b"), None);
+    }
+
+    #[test]
+    fn parses_invite_codes() {
+        assert_round_trip("inv_AB23CD45", Payload::Invite("AB23CD45".to_string()));
+        assert_eq!(Payload::parse("inv_"), None);
+    }
+
+    #[test]
+    fn parses_gift_codes() {
+        assert_round_trip("gift_AB23CD45", Payload::Gift("AB23CD45".to_string()));
+        assert_eq!(Payload::parse("gift_"), None);
+    }
+
+    #[test]
+    fn parses_family_invites() {
+        assert_round_trip("fam_AB23CD45", Payload::Family("AB23CD45".to_string()));
+        assert_eq!(Payload::parse("family"), None);
+    }
+
+    #[test]
+    fn parses_support() {
+        assert_round_trip("support", Payload::Support);
+        assert_eq!(Payload::parse("support_me"), None);
+    }
+
+    #[test]
+    fn parses_screens() {
+        assert_round_trip("menu", Payload::Screen(Screen::Menu));
+        assert_round_trip("about", Payload::Screen(Screen::About));
+        assert_round_trip("sub", Payload::Screen(Screen::Subscription));
+        assert_round_trip("stats", Payload::Screen(Screen::Stats));
+        assert_round_trip("export", Payload::Screen(Screen::Export));
+        assert_eq!(
+            Payload::parse(" sub "),
+            Some(Payload::Screen(Screen::Subscription))
+        );
+        assert_eq!(Payload::parse("subscription"), None);
+    }
+
+    #[test]
+    fn kinds_leave_codes_out() {
+        let payload = Payload::Gift("SECRET42".to_string());
+        assert_eq!(payload.kind(), "gift");
+        assert!(payload.to_string().starts_with(payload.kind()));
+        assert_eq!(Payload::Promo("SECRET42".to_string()).kind(), "promo");
+    }
+
+    #[test]
+    fn ignores_unknown_payloads() {
+        assert_eq!(Payload::parse(""), None);
+        assert_eq!(Payload::parse("unknown"), None);
+    }
+}
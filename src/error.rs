@@ -19,6 +19,30 @@ pub enum MyError {
     #[error("Dotenv error: {0}")]
     DotenvError(#[from] dotenv::Error),
 
+    #[error("Panel API error: {0}")]
+    Panel(Box<remnawave::ApiError>),
+
+    #[error("Panel API call {0} timed out")]
+    PanelTimeout(String),
+
+    #[error("Panel is temporarily unavailable")]
+    PanelUnavailable,
+
+    #[error("No panel user is linked to this Telegram account")]
+    UserNotFound,
+
+    #[error("{0} panel users are linked to this Telegram account")]
+    MultipleUsersFound(usize),
+
+    #[error("Panel username {0} is already taken")]
+    UsernameTaken(String),
+
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+
     #[error("Custom error: {0}")]
     Custom(String),
 
@@ -31,3 +55,9 @@ pub enum MyError {
     #[error("SetLogger error: {0}")]
     SetLoggerError(#[from] log::SetLoggerError),
 }
+
+impl From<remnawave::ApiError> for MyError {
+    fn from(error: remnawave::ApiError) -> Self {
+        MyError::Panel(Box::new(error))
+    }
+}
@@ -0,0 +1,975 @@
+use crate::client::get_client;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::types::HandlerResult;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use percent_encoding::percent_decode_str;
+use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, MessageId};
+use url::Url;
+
+/// Config file format offered in the export picker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    SingBox,
+    Clash,
+    Xray,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::SingBox, Format::Clash, Format::Xray];
+
+    /// Identifier used in callback data.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::SingBox => "singbox",
+            Format::Clash => "clash",
+            Format::Xray => "xray",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.as_str() == value)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Format::SingBox => "sing-box",
+            Format::Clash => "Clash / Mihomo",
+            Format::Xray => "Xray",
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self {
+            Format::SingBox => "sing-box.json",
+            Format::Clash => "clash.yaml",
+            Format::Xray => "xray.json",
+        }
+    }
+
+    /// Client config with all `proxies`, the first one is the default.
+    pub fn render(self, proxies: &[Proxy]) -> String {
+        let tags = unique_tags(proxies);
+        match self {
+            Format::SingBox => to_pretty_json(&singbox_config(proxies, &tags)),
+            Format::Clash => to_yaml(&clash_config(proxies, &tags)),
+            Format::Xray => to_pretty_json(&xray_config(proxies, &tags)),
+        }
+    }
+}
+
+/// A single server parsed from a subscription link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub transport: Transport,
+    pub security: Security,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protocol {
+    Vless {
+        uuid: String,
+        flow: Option<String>,
+    },
+    Vmess {
+        uuid: String,
+        alter_id: u32,
+        cipher: String,
+    },
+    Trojan {
+        password: String,
+    },
+    Shadowsocks {
+        method: String,
+        password: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Ws { path: String, host: Option<String> },
+    HttpUpgrade { path: String, host: Option<String> },
+    Grpc { service_name: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Security {
+    None,
+    Tls {
+        sni: Option<String>,
+        alpn: Vec<String>,
+        fingerprint: Option<String>,
+        insecure: bool,
+    },
+    Reality {
+        sni: Option<String>,
+        fingerprint: Option<String>,
+        public_key: String,
+        short_id: String,
+    },
+}
+
+/// Why a subscription link was left out of the export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// Not a proxy link or a malformed one.
+    Invalid,
+    /// A protocol, transport or plugin that not every format supports.
+    Unsupported(String),
+}
+
+/// Parses a `vless://`, `vmess://`, `trojan://` or `ss://` link.
+pub fn parse_link(link: &str) -> Result<Proxy, LinkError> {
+    let link = link.trim();
+    let (scheme, _) = link.split_once("://").ok_or(LinkError::Invalid)?;
+    match scheme {
+        "vless" | "trojan" => parse_url_link(link),
+        "vmess" => parse_vmess(link),
+        "ss" => parse_shadowsocks(link),
+        other => Err(LinkError::Unsupported(other.to_string())),
+    }
+}
+
+fn parse_url_link(link: &str) -> Result<Proxy, LinkError> {
+    let url = Url::parse(link).map_err(|_| LinkError::Invalid)?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let param = |key: &str| params.get(key).filter(|value| !value.is_empty()).cloned();
+    let credential = decode(url.username());
+    if credential.is_empty() {
+        return Err(LinkError::Invalid);
+    }
+
+    let (protocol, default_security) = match url.scheme() {
+        "vless" => (
+            Protocol::Vless {
+                uuid: credential,
+                flow: param("flow"),
+            },
+            "none",
+        ),
+        _ => (
+            Protocol::Trojan {
+                password: credential,
+            },
+            "tls",
+        ),
+    };
+    let transport = transport(
+        param("type").as_deref().unwrap_or("tcp"),
+        param("headerType").as_deref(),
+        param("path"),
+        param("host"),
+        param("serviceName"),
+    )?;
+    let sni = param("sni");
+    let fingerprint = param("fp");
+    let security = match param("security").as_deref().unwrap_or(default_security) {
+        "none" => Security::None,
+        "tls" => Security::Tls {
+            sni,
+            alpn: param("alpn")
+                .map(|alpn| alpn.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            fingerprint,
+            insecure: matches!(
+                param("allowInsecure").or(param("insecure")).as_deref(),
+                Some("1" | "true")
+            ),
+        },
+        "reality" => Security::Reality {
+            sni,
+            fingerprint,
+            public_key: param("pbk").ok_or(LinkError::Invalid)?,
+            short_id: param("sid").unwrap_or_default(),
+        },
+        other => return Err(LinkError::Unsupported(other.to_string())),
+    };
+
+    Ok(Proxy {
+        name: url.fragment().map(decode).unwrap_or_default(),
+        server: host(&url)?,
+        port: url.port().ok_or(LinkError::Invalid)?,
+        protocol,
+        transport,
+        security,
+    })
+}
+
+fn parse_vmess(link: &str) -> Result<Proxy, LinkError> {
+    let payload = link.trim_start_matches("vmess://");
+    let config: Value = decode_base64(payload)
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .ok_or(LinkError::Invalid)?;
+    // Numbers are written either as JSON numbers or as strings.
+    let field = |key: &str| match config.get(key) {
+        Some(Value::String(value)) if !value.is_empty() => Some(value.clone()),
+        Some(Value::Number(value)) => Some(value.to_string()),
+        _ => None,
+    };
+
+    let path = field("path");
+    let transport = transport(
+        field("net").as_deref().unwrap_or("tcp"),
+        field("type").as_deref(),
+        path.clone(),
+        field("host"),
+        path,
+    )?;
+    let security = match field("tls").as_deref() {
+        None | Some("none") => Security::None,
+        Some("tls") => Security::Tls {
+            sni: field("sni"),
+            alpn: field("alpn")
+                .map(|alpn| alpn.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            fingerprint: field("fp"),
+            insecure: false,
+        },
+        Some(other) => return Err(LinkError::Unsupported(other.to_string())),
+    };
+
+    Ok(Proxy {
+        name: field("ps").unwrap_or_default(),
+        server: field("add").ok_or(LinkError::Invalid)?,
+        port: field("port")
+            .and_then(|port| port.parse().ok())
+            .ok_or(LinkError::Invalid)?,
+        protocol: Protocol::Vmess {
+            uuid: field("id").ok_or(LinkError::Invalid)?,
+            alter_id: field("aid").and_then(|aid| aid.parse().ok()).unwrap_or(0),
+            cipher: field("scy").unwrap_or_else(|| "auto".to_string()),
+        },
+        transport,
+        security,
+    })
+}
+
+/// Parses both SIP002 (`ss://userinfo@host:port#name`) and legacy
+/// (`ss://base64(method:password@host:port)#name`) links.
+fn parse_shadowsocks(link: &str) -> Result<Proxy, LinkError> {
+    let (body, name) = match link.split_once('#') {
+        Some((body, name)) => (body, decode(name)),
+        None => (link, String::new()),
+    };
+    let body = body.trim_start_matches("ss://");
+
+    let (userinfo, server, port) = if body.contains('@') {
+        let url = Url::parse(&format!("ss://{}", body)).map_err(|_| LinkError::Invalid)?;
+        if url.query_pairs().any(|(key, _)| key == "plugin") {
+            return Err(LinkError::Unsupported("plugin".to_string()));
+        }
+        let userinfo = match url.password() {
+            Some(password) => format!("{}:{}", decode(url.username()), decode(password)),
+            None => {
+                let username = decode(url.username());
+                decode_base64(&username).unwrap_or(username)
+            }
+        };
+        (userinfo, host(&url)?, url.port().ok_or(LinkError::Invalid)?)
+    } else {
+        let decoded = decode_base64(body.trim_end_matches('/')).ok_or(LinkError::Invalid)?;
+        let (userinfo, address) = decoded.rsplit_once('@').ok_or(LinkError::Invalid)?;
+        let (server, port) = address.rsplit_once(':').ok_or(LinkError::Invalid)?;
+        (
+            userinfo.to_string(),
+            server.trim_matches(['[', ']']).to_string(),
+            port.parse().map_err(|_| LinkError::Invalid)?,
+        )
+    };
+    let (method, password) = userinfo.split_once(':').ok_or(LinkError::Invalid)?;
+
+    Ok(Proxy {
+        name,
+        server,
+        port,
+        protocol: Protocol::Shadowsocks {
+            method: method.to_string(),
+            password: password.to_string(),
+        },
+        transport: Transport::Tcp,
+        security: Security::None,
+    })
+}
+
+fn transport(
+    network: &str,
+    header_type: Option<&str>,
+    path: Option<String>,
+    host: Option<String>,
+    service_name: Option<String>,
+) -> Result<Transport, LinkError> {
+    let path_or_root = || path.clone().unwrap_or_else(|| "/".to_string());
+    match network {
+        "tcp" | "raw" => match header_type {
+            None | Some("none") => Ok(Transport::Tcp),
+            Some(other) => Err(LinkError::Unsupported(format!("tcp/{}", other))),
+        },
+        "ws" => Ok(Transport::Ws {
+            path: path_or_root(),
+            host,
+        }),
+        "httpupgrade" => Ok(Transport::HttpUpgrade {
+            path: path_or_root(),
+            host,
+        }),
+        "grpc" => Ok(Transport::Grpc {
+            service_name: service_name.unwrap_or_default(),
+        }),
+        other => Err(LinkError::Unsupported(other.to_string())),
+    }
+}
+
+fn host(url: &Url) -> Result<String, LinkError> {
+    url.host_str()
+        .filter(|host| !host.is_empty())
+        .map(|host| host.trim_matches(['[', ']']).to_string())
+        .ok_or(LinkError::Invalid)
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+fn decode_base64(value: &str) -> Option<String> {
+    let value = value.trim();
+    [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+        .iter()
+        .find_map(|engine| engine.decode(value).ok())
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+/// Proxy names made unique, since every format refers to servers by name.
+fn unique_tags(proxies: &[Proxy]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    proxies
+        .iter()
+        .enumerate()
+        .map(|(index, proxy)| {
+            let base = if proxy.name.is_empty() {
+                format!("proxy-{}", index + 1)
+            } else {
+                proxy.name.clone()
+            };
+            let mut tag = base.clone();
+            let mut suffix = 2;
+            while !seen.insert(tag.clone()) {
+                tag = format!("{} {}", base, suffix);
+                suffix += 1;
+            }
+            tag
+        })
+        .collect()
+}
+
+fn singbox_config(proxies: &[Proxy], tags: &[String]) -> Value {
+    let mut outbounds = vec![json!({
+        "type": "selector",
+        "tag": "proxy",
+        "outbounds": tags,
+        "default": tags.first(),
+    })];
+    outbounds.extend(
+        proxies
+            .iter()
+            .zip(tags)
+            .map(|(proxy, tag)| singbox_outbound(proxy, tag)),
+    );
+    outbounds.push(json!({ "type": "direct", "tag": "direct" }));
+
+    json!({
+        "log": { "level": "warn" },
+        "inbounds": [{
+            "type": "mixed",
+            "tag": "mixed-in",
+            "listen": "127.0.0.1",
+            "listen_port": 2080,
+        }],
+        "outbounds": outbounds,
+        "route": { "final": "proxy" },
+    })
+}
+
+fn singbox_outbound(proxy: &Proxy, tag: &str) -> Value {
+    let mut outbound = json!({
+        "tag": tag,
+        "server": proxy.server,
+        "server_port": proxy.port,
+    });
+    match &proxy.protocol {
+        Protocol::Vless { uuid, flow } => {
+            outbound["type"] = json!("vless");
+            outbound["uuid"] = json!(uuid);
+            if let Some(flow) = flow {
+                outbound["flow"] = json!(flow);
+            }
+        }
+        Protocol::Vmess {
+            uuid,
+            alter_id,
+            cipher,
+        } => {
+            outbound["type"] = json!("vmess");
+            outbound["uuid"] = json!(uuid);
+            outbound["alter_id"] = json!(alter_id);
+            outbound["security"] = json!(cipher);
+        }
+        Protocol::Trojan { password } => {
+            outbound["type"] = json!("trojan");
+            outbound["password"] = json!(password);
+        }
+        Protocol::Shadowsocks { method, password } => {
+            outbound["type"] = json!("shadowsocks");
+            outbound["method"] = json!(method);
+            outbound["password"] = json!(password);
+        }
+    }
+
+    match &proxy.security {
+        Security::None => {}
+        Security::Tls {
+            sni,
+            alpn,
+            fingerprint,
+            insecure,
+        } => {
+            let mut tls = json!({
+                "enabled": true,
+                "server_name": sni.as_deref().unwrap_or(&proxy.server),
+                "insecure": insecure,
+            });
+            if !alpn.is_empty() {
+                tls["alpn"] = json!(alpn);
+            }
+            if let Some(fingerprint) = fingerprint {
+                tls["utls"] = json!({ "enabled": true, "fingerprint": fingerprint });
+            }
+            outbound["tls"] = tls;
+        }
+        Security::Reality {
+            sni,
+            fingerprint,
+            public_key,
+            short_id,
+        } => {
+            outbound["tls"] = json!({
+                "enabled": true,
+                "server_name": sni.as_deref().unwrap_or(&proxy.server),
+                "utls": {
+                    "enabled": true,
+                    "fingerprint": fingerprint.as_deref().unwrap_or("chrome"),
+                },
+                "reality": {
+                    "enabled": true,
+                    "public_key": public_key,
+                    "short_id": short_id,
+                },
+            });
+        }
+    }
+
+    match &proxy.transport {
+        Transport::Tcp => {}
+        Transport::Ws { path, host } => {
+            let mut transport = json!({ "type": "ws", "path": path });
+            if let Some(host) = host {
+                transport["headers"] = json!({ "Host": host });
+            }
+            outbound["transport"] = transport;
+        }
+        Transport::HttpUpgrade { path, host } => {
+            let mut transport = json!({ "type": "httpupgrade", "path": path });
+            if let Some(host) = host {
+                transport["host"] = json!(host);
+            }
+            outbound["transport"] = transport;
+        }
+        Transport::Grpc { service_name } => {
+            outbound["transport"] = json!({ "type": "grpc", "service_name": service_name });
+        }
+    }
+    outbound
+}
+
+fn clash_config(proxies: &[Proxy], tags: &[String]) -> Value {
+    json!({
+        "mixed-port": 7890,
+        "allow-lan": false,
+        "mode": "rule",
+        "log-level": "warning",
+        "proxies": proxies
+            .iter()
+            .zip(tags)
+            .map(|(proxy, tag)| clash_proxy(proxy, tag))
+            .collect::<Vec<_>>(),
+        "proxy-groups": [{
+            "name": "PROXY",
+            "type": "select",
+            "proxies": tags,
+        }],
+        "rules": ["MATCH,PROXY"],
+    })
+}
+
+fn clash_proxy(proxy: &Proxy, tag: &str) -> Value {
+    let mut entry = json!({ "name": tag });
+    let sni_key = match &proxy.protocol {
+        Protocol::Vless { .. } => "servername",
+        Protocol::Vmess { .. } => "servername",
+        _ => "sni",
+    };
+    entry["type"] = json!(match proxy.protocol {
+        Protocol::Vless { .. } => "vless",
+        Protocol::Vmess { .. } => "vmess",
+        Protocol::Trojan { .. } => "trojan",
+        Protocol::Shadowsocks { .. } => "ss",
+    });
+    entry["server"] = json!(proxy.server);
+    entry["port"] = json!(proxy.port);
+    entry["udp"] = json!(true);
+
+    match &proxy.protocol {
+        Protocol::Vless { uuid, flow } => {
+            entry["uuid"] = json!(uuid);
+            if let Some(flow) = flow {
+                entry["flow"] = json!(flow);
+            }
+        }
+        Protocol::Vmess {
+            uuid,
+            alter_id,
+            cipher,
+        } => {
+            entry["uuid"] = json!(uuid);
+            entry["alterId"] = json!(alter_id);
+            entry["cipher"] = json!(cipher);
+        }
+        Protocol::Trojan { password } => entry["password"] = json!(password),
+        Protocol::Shadowsocks { method, password } => {
+            entry["cipher"] = json!(method);
+            entry["password"] = json!(password);
+        }
+    }
+
+    match &proxy.security {
+        Security::None => {}
+        Security::Tls {
+            sni,
+            alpn,
+            fingerprint,
+            insecure,
+        } => {
+            entry["tls"] = json!(true);
+            if let Some(sni) = sni {
+                entry[sni_key] = json!(sni);
+            }
+            if !alpn.is_empty() {
+                entry["alpn"] = json!(alpn);
+            }
+            if let Some(fingerprint) = fingerprint {
+                entry["client-fingerprint"] = json!(fingerprint);
+            }
+            entry["skip-cert-verify"] = json!(insecure);
+        }
+        Security::Reality {
+            sni,
+            fingerprint,
+            public_key,
+            short_id,
+        } => {
+            entry["tls"] = json!(true);
+            if let Some(sni) = sni {
+                entry[sni_key] = json!(sni);
+            }
+            entry["client-fingerprint"] = json!(fingerprint.as_deref().unwrap_or("chrome"));
+            entry["reality-opts"] = json!({ "public-key": public_key, "short-id": short_id });
+        }
+    }
+
+    match &proxy.transport {
+        Transport::Tcp => {}
+        Transport::Ws { path, host } | Transport::HttpUpgrade { path, host } => {
+            let mut options = json!({ "path": path });
+            if let Some(host) = host {
+                options["headers"] = json!({ "Host": host });
+            }
+            if matches!(proxy.transport, Transport::HttpUpgrade { .. }) {
+                options["v2ray-http-upgrade"] = json!(true);
+            }
+            entry["network"] = json!("ws");
+            entry["ws-opts"] = options;
+        }
+        Transport::Grpc { service_name } => {
+            entry["network"] = json!("grpc");
+            entry["grpc-opts"] = json!({ "grpc-service-name": service_name });
+        }
+    }
+    entry
+}
+
+fn xray_config(proxies: &[Proxy], tags: &[String]) -> Value {
+    let mut outbounds: Vec<Value> = proxies
+        .iter()
+        .zip(tags)
+        .map(|(proxy, tag)| xray_outbound(proxy, tag))
+        .collect();
+    outbounds.push(json!({ "tag": "direct", "protocol": "freedom" }));
+
+    json!({
+        "log": { "loglevel": "warning" },
+        "inbounds": [
+            {
+                "tag": "socks-in",
+                "listen": "127.0.0.1",
+                "port": 10808,
+                "protocol": "socks",
+                "settings": { "udp": true },
+            },
+            {
+                "tag": "http-in",
+                "listen": "127.0.0.1",
+                "port": 10809,
+                "protocol": "http",
+            },
+        ],
+        "outbounds": outbounds,
+    })
+}
+
+fn xray_outbound(proxy: &Proxy, tag: &str) -> Value {
+    let (protocol, settings) = match &proxy.protocol {
+        Protocol::Vless { uuid, flow } => {
+            let mut user = json!({ "id": uuid, "encryption": "none" });
+            if let Some(flow) = flow {
+                user["flow"] = json!(flow);
+            }
+            (
+                "vless",
+                json!({ "vnext": [{ "address": proxy.server, "port": proxy.port, "users": [user] }] }),
+            )
+        }
+        Protocol::Vmess {
+            uuid,
+            alter_id,
+            cipher,
+        } => (
+            "vmess",
+            json!({ "vnext": [{
+                "address": proxy.server,
+                "port": proxy.port,
+                "users": [{ "id": uuid, "alterId": alter_id, "security": cipher }],
+            }] }),
+        ),
+        Protocol::Trojan { password } => (
+            "trojan",
+            json!({ "servers": [{ "address": proxy.server, "port": proxy.port, "password": password }] }),
+        ),
+        Protocol::Shadowsocks { method, password } => (
+            "shadowsocks",
+            json!({ "servers": [{
+                "address": proxy.server,
+                "port": proxy.port,
+                "method": method,
+                "password": password,
+            }] }),
+        ),
+    };
+
+    let mut stream = json!({});
+    match &proxy.transport {
+        Transport::Tcp => stream["network"] = json!("tcp"),
+        Transport::Ws { path, host } => {
+            let mut ws = json!({ "path": path });
+            if let Some(host) = host {
+                ws["headers"] = json!({ "Host": host });
+            }
+            stream["network"] = json!("ws");
+            stream["wsSettings"] = ws;
+        }
+        Transport::HttpUpgrade { path, host } => {
+            let mut upgrade = json!({ "path": path });
+            if let Some(host) = host {
+                upgrade["host"] = json!(host);
+            }
+            stream["network"] = json!("httpupgrade");
+            stream["httpupgradeSettings"] = upgrade;
+        }
+        Transport::Grpc { service_name } => {
+            stream["network"] = json!("grpc");
+            stream["grpcSettings"] = json!({ "serviceName": service_name });
+        }
+    }
+    match &proxy.security {
+        Security::None => stream["security"] = json!("none"),
+        Security::Tls {
+            sni,
+            alpn,
+            fingerprint,
+            insecure,
+        } => {
+            let mut tls = json!({
+                "serverName": sni.as_deref().unwrap_or(&proxy.server),
+                "allowInsecure": insecure,
+            });
+            if !alpn.is_empty() {
+                tls["alpn"] = json!(alpn);
+            }
+            if let Some(fingerprint) = fingerprint {
+                tls["fingerprint"] = json!(fingerprint);
+            }
+            stream["security"] = json!("tls");
+            stream["tlsSettings"] = tls;
+        }
+        Security::Reality {
+            sni,
+            fingerprint,
+            public_key,
+            short_id,
+        } => {
+            stream["security"] = json!("reality");
+            stream["realitySettings"] = json!({
+                "serverName": sni.as_deref().unwrap_or(&proxy.server),
+                "fingerprint": fingerprint.as_deref().unwrap_or("chrome"),
+                "publicKey": public_key,
+                "shortId": short_id,
+            });
+        }
+    }
+
+    json!({
+        "tag": tag,
+        "protocol": protocol,
+        "settings": settings,
+        "streamSettings": stream,
+    })
+}
+
+fn to_pretty_json(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_default()
+}
+
+fn to_yaml(value: &Value) -> String {
+    serde_yaml::to_string(value).unwrap_or_default()
+}
+
+/// Sends the export format picker, or puts it in place of `message_id` if
+/// given.
+pub async fn send_picker(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+) -> HandlerResult {
+    let text = Messages::ru().export_pick_format();
+    match message_id {
+        Some(id) => {
+            bot.edit_message_text(chat_id, id, text)
+                .reply_markup(keyboards::export_formats())
+                .await?;
+        }
+        None => {
+            bot.send_message(chat_id, text)
+                .reply_markup(keyboards::export_formats())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Export picker (`export`) and generation of a config file
+/// (`export:<format>`).
+pub async fn handle_callback(bot: &Bot, q: &CallbackQuery, data: &str) -> HandlerResult {
+    let user_id = q.from.id;
+    log::info!("User {} called {}", logger::user(user_id), data);
+    metrics::record_callback("export");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+
+    let Some(format) = data.strip_prefix("export:").and_then(Format::parse) else {
+        return send_picker(bot, chat_id, q.message.as_ref().map(|msg| msg.id())).await;
+    };
+
+    let user = get_client()
+        .find_user_by_telegram_id(user_id.0)
+        .await
+        .into_user()?;
+    let links = get_client().get_subscription_links(user.uuid).await?;
+
+    let mut proxies = Vec::new();
+    let mut skipped = 0;
+    for link in &links {
+        match parse_link(link) {
+            Ok(proxy) => proxies.push(proxy),
+            Err(e) => {
+                log::debug!("Skipping subscription link of {}: {:?}", user.uuid, e);
+                skipped += 1;
+            }
+        }
+    }
+
+    if proxies.is_empty() {
+        bot.send_message(chat_id, Messages::ru().export_empty())
+            .reply_markup(keyboards::back_to_main_menu())
+            .await?;
+        return Ok(());
+    }
+
+    let file =
+        InputFile::memory(format.render(&proxies).into_bytes()).file_name(format.file_name());
+    bot.send_document(chat_id, file)
+        .caption(Messages::ru().export_ready(format.label(), proxies.len(), skipped))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reality() -> Proxy {
+        parse_link(
+            "vless://1b7e5f4a-0000-4000-8000-000000000001@de.example.com:443\
+             ?type=tcp&security=reality&sni=www.google.com&fp=chrome\
+             &pbk=PUBKEY&sid=ab12&flow=xtls-rprx-vision#%F0%9F%87%A9%F0%9F%87%AA%20Germany",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_vless_reality() {
+        assert_eq!(
+            reality(),
+            Proxy {
+                name: "🇩🇪 Germany".to_string(),
+                server: "de.example.com".to_string(),
+                port: 443,
+                protocol: Protocol::Vless {
+                    uuid: "1b7e5f4a-0000-4000-8000-000000000001".to_string(),
+                    flow: Some("xtls-rprx-vision".to_string()),
+                },
+                transport: Transport::Tcp,
+                security: Security::Reality {
+                    sni: Some("www.google.com".to_string()),
+                    fingerprint: Some("chrome".to_string()),
+                    public_key: "PUBKEY".to_string(),
+                    short_id: "ab12".to_string(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn parses_trojan_vmess_and_shadowsocks() {
+        let trojan = parse_link(
+            "trojan://p%40ss@[2001:db8::1]:8443?type=ws&path=%2Fws&host=cdn.example.com#nl",
+        )
+        .unwrap();
+        assert_eq!(trojan.server, "2001:db8::1");
+        assert_eq!(
+            trojan.protocol,
+            Protocol::Trojan {
+                password: "p@ss".to_string()
+            }
+        );
+        assert!(matches!(trojan.security, Security::Tls { .. }));
+        assert_eq!(
+            trojan.transport,
+            Transport::Ws {
+                path: "/ws".to_string(),
+                host: Some("cdn.example.com".to_string())
+            }
+        );
+
+        let vmess_json = r#"{"v":"2","ps":"fi","add":"fi.example.com","port":"443","id":"uuid","aid":0,"net":"grpc","path":"svc","tls":"tls","sni":"fi.example.com"}"#;
+        let vmess = parse_link(&format!("vmess://{}", STANDARD.encode(vmess_json))).unwrap();
+        assert_eq!(vmess.port, 443);
+        assert_eq!(
+            vmess.transport,
+            Transport::Grpc {
+                service_name: "svc".to_string()
+            }
+        );
+
+        let sip002 = format!(
+            "ss://{}@1.2.3.4:8388#ss",
+            URL_SAFE_NO_PAD.encode("chacha20-ietf-poly1305:secret")
+        );
+        let legacy = format!(
+            "ss://{}#ss",
+            STANDARD.encode("chacha20-ietf-poly1305:secret@1.2.3.4:8388")
+        );
+        assert_eq!(parse_link(&sip002).unwrap(), parse_link(&legacy).unwrap());
+        assert_eq!(
+            parse_link("ss://2022-blake3-aes-128-gcm:a%2Bb@1.2.3.4:8388#ss")
+                .unwrap()
+                .protocol,
+            Protocol::Shadowsocks {
+                method: "2022-blake3-aes-128-gcm".to_string(),
+                password: "a+b".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_links() {
+        assert_eq!(
+            parse_link("vless://id@host:443?type=xhttp#x"),
+            Err(LinkError::Unsupported("xhttp".to_string()))
+        );
+        assert_eq!(
+            parse_link("hysteria2://pass@host:443"),
+            Err(LinkError::Unsupported("hysteria2".to_string()))
+        );
+        assert_eq!(
+            parse_link("vless://id@host#no-port"),
+            Err(LinkError::Invalid)
+        );
+        assert_eq!(parse_link("not a link"), Err(LinkError::Invalid));
+    }
+
+    #[test]
+    fn renders_every_format() {
+        let proxies = [reality(), reality()];
+
+        let singbox: Value = serde_json::from_str(&Format::SingBox.render(&proxies)).unwrap();
+        assert_eq!(
+            singbox["outbounds"][0]["outbounds"],
+            json!(["🇩🇪 Germany", "🇩🇪 Germany 2"])
+        );
+        assert_eq!(
+            singbox["outbounds"][1]["tls"]["reality"]["public_key"],
+            "PUBKEY"
+        );
+
+        let xray: Value = serde_json::from_str(&Format::Xray.render(&proxies)).unwrap();
+        assert_eq!(
+            xray["outbounds"][0]["streamSettings"]["security"],
+            "reality"
+        );
+        assert_eq!(
+            xray["outbounds"][0]["settings"]["vnext"][0]["users"][0]["flow"],
+            "xtls-rprx-vision"
+        );
+
+        let clash: Value = serde_yaml::from_str(&Format::Clash.render(&proxies[..1])).unwrap();
+        assert_eq!(clash["mixed-port"], 7890);
+        assert_eq!(clash["proxies"][0]["name"], "🇩🇪 Germany");
+        assert_eq!(clash["proxies"][0]["type"], "vless");
+        assert_eq!(clash["proxies"][0]["server"], "de.example.com");
+        assert_eq!(clash["proxies"][0]["reality-opts"]["public-key"], "PUBKEY");
+        assert_eq!(clash["rules"], json!(["MATCH,PROXY"]));
+    }
+}
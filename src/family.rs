@@ -0,0 +1,679 @@
+use crate::audit;
+use crate::client::{PanelClient, get_client};
+use crate::config::Config;
+use crate::deep_link::{self, Payload};
+use crate::error::MyError;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::outbox::{Outbox, OutgoingMessage};
+use crate::registration;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use crate::users::{self, Provisioned};
+use chrono::Utc;
+use remnawave::api::types::users::UserData;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardMarkup, Me, MessageId, User};
+
+/// The panel user of `telegram_id`, `None` if they have none.
+async fn find_panel_user(
+    client: &PanelClient,
+    telegram_id: UserId,
+) -> Result<Option<UserData>, MyError> {
+    match client
+        .find_user_by_telegram_id(telegram_id.0)
+        .await
+        .into_user()
+    {
+        Ok(user) => Ok(Some(user)),
+        Err(MyError::UserNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `member` already has the expiry and device limit of `owner`.
+fn follows(member: &UserData, owner: &UserData) -> bool {
+    member.expire_at == owner.expire_at && member.hwid_device_limit == owner.hwid_device_limit
+}
+
+/// Sends the family screen of `user`, or puts it in place of `message_id`
+/// if given: the family they are in, the family they own with its members
+/// and their usage, or an offer to create one.
+async fn send_screen(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    user: UserId,
+    me: &Me,
+    config: &Config,
+    storage: &Storage,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    let client = get_client();
+    let max_members = config.family.max_members;
+    let (text, keyboard) = if let Some(owner) = storage.family_of(user)? {
+        let owner = find_panel_user(&client, owner)
+            .await?
+            .ok_or(MyError::UserNotFound)?;
+        (
+            messages.family_member(&owner.username, owner.expire_at),
+            keyboards::family_member(),
+        )
+    } else if let Some(code) = storage.family_invite(user)? {
+        let mut members = Vec::new();
+        for member in storage.family_members(user)? {
+            members.push((member, find_panel_user(&client, member).await?));
+        }
+        let link = deep_link::link(me, &Payload::Family(code));
+        (
+            messages.family_owner(&link, &members, max_members),
+            keyboards::family_owner(&members),
+        )
+    } else if max_members == 0 {
+        (
+            messages.family_unavailable(),
+            keyboards::back_to_main_menu(),
+        )
+    } else {
+        (
+            messages.family_intro(max_members),
+            keyboards::family_intro(),
+        )
+    };
+    reply(bot, chat_id, message_id, text, keyboard).await
+}
+
+async fn reply(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    text: String,
+    keyboard: InlineKeyboardMarkup,
+) -> HandlerResult {
+    match message_id {
+        Some(id) => {
+            bot.edit_message_text(chat_id, id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        None => {
+            bot.send_message(chat_id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Ends the family subscription of `member`: their panel user expires now
+/// and they leave the family of `owner`. They stay in it if the panel
+/// fails.
+async fn end_membership(
+    client: &PanelClient,
+    owner: UserId,
+    member: UserId,
+    actor: UserId,
+    storage: &Storage,
+) -> Result<(), MyError> {
+    if let Some(before) = find_panel_user(client, member).await? {
+        let result = {
+            let _guard = users::locks().lock(member.0).await;
+            client.set_user_expiry(before.uuid, Utc::now()).await
+        };
+        audit::record(
+            storage,
+            actor,
+            "family_remove",
+            Some(&before),
+            result.as_ref().map(Some),
+        );
+        result?;
+    }
+    storage.remove_family_member(owner, member)?;
+    log::info!(
+        "User {} left the family of {}",
+        logger::user(member),
+        logger::user(owner)
+    );
+    Ok(())
+}
+
+/// The family screen (`family`) and its buttons: `family:create`,
+/// `family:relink`, `family:remove:<telegram id>` for owners and
+/// `family:leave` for members.
+pub async fn handle_callback(
+    bot: &Bot,
+    q: &CallbackQuery,
+    data: &str,
+    me: &Me,
+    config: &Config,
+    storage: &Storage,
+    outbox: &Outbox,
+) -> HandlerResult {
+    let user = q.from.id;
+    log::info!("User {} called {}", logger::user(user), data);
+    metrics::record_callback("family");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    let messages = Messages::ru();
+
+    match data {
+        "family:create" if config.family.max_members > 0 => {
+            if storage.family_of(user)?.is_none() {
+                // Families share the plan of their owner, who needs one.
+                get_client()
+                    .find_user_by_telegram_id(user.0)
+                    .await
+                    .into_user()?;
+                if storage.create_family(user, &registration::generate_code())? {
+                    log::info!("User {} created a family", logger::user(user));
+                }
+            }
+        }
+        "family:relink" => {
+            if storage.family_invite(user)?.is_some() {
+                storage.set_family_invite(user, &registration::generate_code())?;
+            }
+        }
+        "family:leave" => {
+            if let Some(owner) = storage.family_of(user)? {
+                end_membership(&get_client(), owner, user, user, storage).await?;
+                outbox.enqueue(OutgoingMessage::text(
+                    ChatId::from(owner),
+                    messages.family_member_left(&q.from.full_name()),
+                ));
+                bot.send_message(chat_id, messages.family_left())
+                    .reply_markup(keyboards::back_to_main_menu())
+                    .await?;
+                return Ok(());
+            }
+        }
+        data => {
+            let member = data
+                .strip_prefix("family:remove:")
+                .and_then(|id| id.parse().ok())
+                .map(UserId);
+            if let Some(member) = member
+                && storage.family_of(member)? == Some(user)
+            {
+                end_membership(&get_client(), user, member, user, storage).await?;
+                outbox.enqueue(OutgoingMessage::text(
+                    ChatId::from(member),
+                    messages.family_removed(),
+                ));
+            }
+        }
+    }
+    let message_id = q.message.as_ref().map(|msg| msg.id());
+    send_screen(bot, chat_id, message_id, user, me, config, storage).await
+}
+
+/// Outcome of [`enroll`].
+#[derive(Debug)]
+enum Enrollment {
+    /// No family has the invite, or families are turned off.
+    Invalid,
+    /// The invite is the user's own.
+    OwnLink,
+    /// The user is already in a family or owns one.
+    AlreadyMember,
+    /// The user has an active subscription of their own.
+    HasSubscription,
+    /// The family has no free places.
+    Full,
+    /// The user joined the family of `owner`, whose plan they now follow.
+    Joined {
+        owner: UserId,
+        owner_plan: Box<UserData>,
+        after: Box<UserData>,
+    },
+}
+
+/// Adds `user` to the family with invite `code`, giving them a panel user
+/// with the expiry and device limit of its owner. The membership is
+/// undone if the panel fails.
+async fn enroll(
+    client: &PanelClient,
+    user: &User,
+    code: &str,
+    max_members: u32,
+    storage: &Storage,
+) -> Result<Enrollment, MyError> {
+    let owner = match storage.family_by_invite(code)? {
+        Some(owner) if max_members > 0 => owner,
+        _ => return Ok(Enrollment::Invalid),
+    };
+    if owner == user.id {
+        return Ok(Enrollment::OwnLink);
+    }
+    if storage.family_of(user.id)?.is_some() || storage.family_invite(user.id)?.is_some() {
+        return Ok(Enrollment::AlreadyMember);
+    }
+    let Some(owner_plan) = find_panel_user(client, owner).await? else {
+        return Ok(Enrollment::Invalid);
+    };
+    if let Some(own) = find_panel_user(client, user.id).await?
+        && own.expire_at > Utc::now()
+    {
+        return Ok(Enrollment::HasSubscription);
+    }
+    if !storage.add_family_member(owner, user.id, max_members)? {
+        return Ok(Enrollment::Full);
+    }
+
+    let result =
+        match users::ensure_user(client, users::locks(), user.id.0, user.username.as_deref()).await
+        {
+            Ok(provisioned) => {
+                let before = match provisioned {
+                    Provisioned::Created(created) => {
+                        audit::record(storage, user.id, "family_join", None, Ok(Some(&created)));
+                        created
+                    }
+                    Provisioned::Existing(existing) => existing,
+                };
+                let result = {
+                    let _guard = users::locks().lock(user.id.0).await;
+                    client
+                        .set_user_plan(
+                            before.uuid,
+                            owner_plan.expire_at,
+                            owner_plan.hwid_device_limit,
+                        )
+                        .await
+                };
+                audit::record(
+                    storage,
+                    user.id,
+                    "family_join",
+                    Some(&before),
+                    result.as_ref().map(Some),
+                );
+                result
+            }
+            Err(e) => {
+                audit::record(storage, user.id, "family_join", None, Err(&e));
+                Err(e)
+            }
+        };
+    match result {
+        Ok(after) => Ok(Enrollment::Joined {
+            owner,
+            owner_plan: Box::new(owner_plan),
+            after: Box::new(after),
+        }),
+        Err(e) => {
+            storage.remove_family_member(owner, user.id)?;
+            Err(e)
+        }
+    }
+}
+
+/// Adds `user` to the family with invite `code` and gives them a
+/// subscription following the plan of its owner, who is notified.
+///
+/// Families bypass the registration mode: the owner vouches for their
+/// members. Users with a subscription of their own that is still active
+/// can't join.
+pub async fn join(
+    bot: &Bot,
+    chat_id: ChatId,
+    user: &User,
+    code: &str,
+    config: &Config,
+    storage: &Storage,
+    outbox: &Outbox,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    let enrollment = enroll(
+        &get_client(),
+        user,
+        code,
+        config.family.max_members,
+        storage,
+    )
+    .await?;
+    let text = match enrollment {
+        Enrollment::Invalid => {
+            log::info!(
+                "User {} opened an invalid family invite",
+                logger::user(user.id)
+            );
+            messages.family_invalid()
+        }
+        Enrollment::OwnLink => messages.family_own_link(),
+        Enrollment::AlreadyMember => messages.family_already_member(),
+        Enrollment::HasSubscription => messages.family_has_subscription(),
+        Enrollment::Full => messages.family_full(),
+        Enrollment::Joined {
+            owner,
+            owner_plan,
+            after,
+        } => {
+            log::info!(
+                "User {} joined the family of {}",
+                logger::user(user.id),
+                logger::user(owner)
+            );
+            bot.send_message(
+                chat_id,
+                messages.family_joined(&owner_plan.username, after.expire_at),
+            )
+            .reply_markup(keyboards::back_to_main_menu())
+            .await?;
+            outbox.enqueue(OutgoingMessage::text(
+                ChatId::from(owner),
+                messages.family_member_joined(&user.full_name()),
+            ));
+            return Ok(());
+        }
+    };
+    bot.send_message(chat_id, text).await?;
+    Ok(())
+}
+
+/// Gives the members of the family of `owner` the owner's expiry and
+/// device limit, returning how many of them were changed. A member the
+/// panel fails for is logged and skipped until the next sync.
+async fn sync_family(
+    client: &PanelClient,
+    owner: UserId,
+    storage: &Storage,
+) -> Result<usize, MyError> {
+    let Some(plan) = find_panel_user(client, owner).await? else {
+        log::warn!(
+            "Owner {} of a family has no subscription, members are left as they are",
+            logger::user(owner)
+        );
+        return Ok(0);
+    };
+    let mut synced = 0;
+    for member in storage.family_members(owner)? {
+        let before = match find_panel_user(client, member).await {
+            Ok(Some(before)) => before,
+            Ok(None) => continue,
+            Err(e) => {
+                log_sync_failure(owner, member, &e);
+                continue;
+            }
+        };
+        if follows(&before, &plan) {
+            continue;
+        }
+        let result = {
+            let _guard = users::locks().lock(member.0).await;
+            client
+                .set_user_plan(before.uuid, plan.expire_at, plan.hwid_device_limit)
+                .await
+        };
+        audit::record(
+            storage,
+            owner,
+            "family_sync",
+            Some(&before),
+            result.as_ref().map(Some),
+        );
+        match result {
+            Ok(_) => synced += 1,
+            Err(e) => log_sync_failure(owner, member, &e),
+        }
+    }
+    Ok(synced)
+}
+
+fn log_sync_failure(owner: UserId, member: UserId, e: &MyError) {
+    metrics::record_error(e);
+    log::error!(
+        "Failed to sync member {} of the family of {}: {}",
+        logger::user(member),
+        logger::user(owner),
+        e
+    );
+}
+
+/// Keeps the members of every family on the plan of its owner, checking
+/// every `interval`, so that renewals and changes made in the panel reach
+/// them.
+pub async fn run_sync(storage: Arc<Storage>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let owners = match storage.family_owners() {
+            Ok(owners) => owners,
+            Err(e) => {
+                log::error!("Failed to list families: {}", e);
+                continue;
+            }
+        };
+        let client = get_client();
+        for owner in owners {
+            match sync_family(&client, owner, &storage).await {
+                Ok(0) => {}
+                Ok(synced) => log::info!(
+                    "Synced {} members of the family of {}",
+                    synced,
+                    logger::user(owner)
+                ),
+                Err(e) => {
+                    metrics::record_error(&e);
+                    log::error!(
+                        "Failed to sync the family of {}: {}",
+                        logger::user(owner),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_panel::TestPanel;
+    use crate::users::{telegram_user, test_user};
+    use chrono::{DateTime, TimeZone};
+
+    const OWNER: UserId = UserId(1);
+
+    fn date(year: i32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    /// A panel user of `telegram_id` expiring at the start of `year`.
+    fn panel_user(telegram_id: u64, year: i32, devices: Option<usize>) -> UserData {
+        let mut user = test_user(&format!("user{}", telegram_id), Some(telegram_id as i64));
+        user.expire_at = date(year);
+        user.hwid_device_limit = devices;
+        user
+    }
+
+    /// A panel with the owner on a plan until 2031 with 3 devices and the
+    /// other `users`, and storage with the owner's family `CODE`.
+    async fn setup(users: Vec<UserData>) -> (TestPanel, Arc<Storage>) {
+        let mut users = users;
+        users.push(panel_user(OWNER.0, 2031, Some(3)));
+        let storage = Storage::open_in_memory().unwrap();
+        storage.create_family(OWNER, "CODE").unwrap();
+        (TestPanel::start(users).await, storage)
+    }
+
+    async fn enroll_user(panel: &TestPanel, storage: &Storage, id: u64) -> Enrollment {
+        enroll(&panel.client, &telegram_user(id), "CODE", 2, storage)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn follows_compares_expiry_and_device_limit() {
+        let owner = panel_user(1, 2031, Some(3));
+
+        assert!(follows(&panel_user(10, 2031, Some(3)), &owner));
+        assert!(!follows(&panel_user(10, 2030, Some(3)), &owner));
+        assert!(!follows(&panel_user(10, 2031, None), &owner));
+    }
+
+    #[tokio::test]
+    async fn joining_gives_the_owner_plan() {
+        let (panel, storage) = setup(vec![panel_user(11, 2020, None)]).await;
+
+        let new = enroll_user(&panel, &storage, 10).await;
+        let expired = enroll_user(&panel, &storage, 11).await;
+
+        assert!(matches!(new, Enrollment::Joined { owner, .. } if owner == OWNER));
+        assert!(matches!(expired, Enrollment::Joined { .. }));
+        for id in [10, 11] {
+            let member = panel.user(id).unwrap();
+            assert_eq!(member.expire_at, date(2031));
+            assert_eq!(member.hwid_device_limit, Some(3));
+        }
+        assert_eq!(
+            storage.family_members(OWNER).unwrap(),
+            vec![UserId(10), UserId(11)]
+        );
+    }
+
+    #[tokio::test]
+    async fn joining_is_refused() {
+        let (panel, storage) = setup(vec![panel_user(11, 2030, None)]).await;
+        storage.add_family_member(OWNER, UserId(12), 2).unwrap();
+        storage.create_family(UserId(13), "OTHER").unwrap();
+
+        assert!(matches!(
+            enroll(&panel.client, &telegram_user(10), "NOPE", 2, &storage).await,
+            Ok(Enrollment::Invalid)
+        ));
+        assert!(matches!(
+            enroll(&panel.client, &telegram_user(10), "CODE", 0, &storage).await,
+            Ok(Enrollment::Invalid)
+        ));
+        assert!(matches!(
+            enroll_user(&panel, &storage, OWNER.0).await,
+            Enrollment::OwnLink
+        ));
+        assert!(matches!(
+            enroll_user(&panel, &storage, 12).await,
+            Enrollment::AlreadyMember
+        ));
+        assert!(matches!(
+            enroll_user(&panel, &storage, 13).await,
+            Enrollment::AlreadyMember
+        ));
+        assert!(matches!(
+            enroll_user(&panel, &storage, 11).await,
+            Enrollment::HasSubscription
+        ));
+        assert!(matches!(
+            enroll_user(&panel, &storage, 10).await,
+            Enrollment::Joined { .. }
+        ));
+        assert!(matches!(
+            enroll_user(&panel, &storage, 14).await,
+            Enrollment::Full
+        ));
+        assert_eq!(panel.user(14), None);
+    }
+
+    #[tokio::test]
+    async fn joining_is_undone_when_the_panel_fails() {
+        let member = panel_user(10, 2020, None);
+        let (panel, storage) = setup(vec![member.clone()]).await;
+        panel.fail_updates(member.uuid);
+
+        let result = enroll(&panel.client, &telegram_user(10), "CODE", 2, &storage).await;
+
+        assert!(result.is_err());
+        assert_eq!(storage.family_of(UserId(10)).unwrap(), None);
+        assert_eq!(panel.user(10).unwrap().expire_at, date(2020));
+    }
+
+    #[tokio::test]
+    async fn ending_a_membership_expires_the_member() {
+        let (panel, storage) = setup(vec![
+            panel_user(10, 2031, Some(3)),
+            panel_user(11, 2031, Some(3)),
+        ])
+        .await;
+        storage.add_family_member(OWNER, UserId(10), 2).unwrap();
+        storage.add_family_member(OWNER, UserId(11), 2).unwrap();
+        panel.fail_updates(panel.user(11).unwrap().uuid);
+
+        end_membership(&panel.client, OWNER, UserId(10), OWNER, &storage)
+            .await
+            .unwrap();
+        let failed = end_membership(&panel.client, OWNER, UserId(11), UserId(11), &storage).await;
+
+        assert!(panel.user(10).unwrap().expire_at <= Utc::now());
+        assert_eq!(storage.family_of(UserId(10)).unwrap(), None);
+        assert!(failed.is_err());
+        assert_eq!(panel.user(11).unwrap().expire_at, date(2031));
+        assert_eq!(storage.family_of(UserId(11)).unwrap(), Some(OWNER));
+    }
+
+    #[tokio::test]
+    async fn sync_skips_failing_members() {
+        let (panel, storage) = setup(vec![
+            panel_user(10, 2030, None),
+            panel_user(11, 2030, None),
+            panel_user(12, 2031, Some(3)),
+        ])
+        .await;
+        for member in [10, 11, 12] {
+            storage.add_family_member(OWNER, UserId(member), 3).unwrap();
+        }
+        panel.fail_updates(panel.user(10).unwrap().uuid);
+
+        let synced = sync_family(&panel.client, OWNER, &storage).await.unwrap();
+
+        assert_eq!(synced, 1);
+        assert!(follows(
+            &panel.user(11).unwrap(),
+            &panel.user(OWNER.0 as i64).unwrap()
+        ));
+        assert_eq!(panel.user(10).unwrap().expire_at, date(2030));
+        let updates = panel
+            .requests()
+            .iter()
+            .filter(|request| request.starts_with("update"))
+            .count();
+        assert_eq!(updates, 2);
+    }
+
+    #[test]
+    fn families_are_limited_and_exclusive() {
+        let storage = Storage::open_in_memory().unwrap();
+        let owner = UserId(1);
+        assert!(storage.create_family(owner, "CODE").unwrap());
+        assert!(!storage.create_family(owner, "OTHER").unwrap());
+        assert!(storage.create_family(UserId(2), "SECOND").unwrap());
+        assert_eq!(storage.family_by_invite("CODE").unwrap(), Some(owner));
+
+        assert!(storage.add_family_member(owner, UserId(10), 2).unwrap());
+        assert!(!storage.add_family_member(UserId(2), UserId(10), 2).unwrap());
+        assert!(storage.add_family_member(owner, UserId(11), 2).unwrap());
+        assert!(!storage.add_family_member(owner, UserId(12), 2).unwrap());
+        assert_eq!(
+            storage.family_members(owner).unwrap(),
+            vec![UserId(10), UserId(11)]
+        );
+        assert_eq!(storage.family_of(UserId(11)).unwrap(), Some(owner));
+
+        assert!(!storage.remove_family_member(UserId(2), UserId(11)).unwrap());
+        assert!(storage.remove_family_member(owner, UserId(11)).unwrap());
+        assert_eq!(storage.family_of(UserId(11)).unwrap(), None);
+        assert!(storage.add_family_member(owner, UserId(12), 2).unwrap());
+
+        storage.set_family_invite(owner, "RENEWED").unwrap();
+        assert_eq!(storage.family_by_invite("CODE").unwrap(), None);
+        assert_eq!(
+            storage.family_invite(owner).unwrap().as_deref(),
+            Some("RENEWED")
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use crate::audit;
+use crate::client::{PanelClient, UserLookup, get_client};
+use crate::error::MyError;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use crate::users;
+use chrono::{Duration, Utc};
+use remnawave::api::types::common::UserStatus;
+use remnawave::api::types::users::UserData;
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::{Dialogue, GetChatId, InMemStorage};
+use teloxide::prelude::*;
+use teloxide::types::InlineKeyboardMarkup;
+use uuid::Uuid;
+
+pub type FindDialogue = Dialogue<FindState, InMemStorage<FindState>>;
+
+/// Results shown on one page of the `/find` list.
+const PAGE_SIZE: usize = 5;
+
+/// How much the "extend" button adds to a subscription.
+const EXTEND_BY_DAYS: i64 = 30;
+
+/// The last `/find` of an admin, kept to page through the results.
+#[derive(Clone, Debug, Default)]
+pub enum FindState {
+    #[default]
+    Idle,
+    Results {
+        query: String,
+        users: Vec<(Uuid, String)>,
+    },
+}
+
+/// Panel users matching `query`.
+///
+/// A UUID is looked up directly, a number as a Telegram ID and then as a
+/// username, anything with `@` inside as an email, the rest as a username
+/// with an optional leading `@`.
+pub(crate) async fn search(client: &PanelClient, query: &str) -> Result<Vec<UserData>, MyError> {
+    if let Ok(uuid) = query.parse::<Uuid>() {
+        return Ok(client.get_user(uuid).await?.into_iter().collect());
+    }
+    if let Ok(telegram_id) = query.parse::<u64>() {
+        match client.find_user_by_telegram_id(telegram_id).await {
+            UserLookup::Found(user) => return Ok(vec![*user]),
+            UserLookup::MultipleFound(users) => return Ok(users),
+            UserLookup::NotFound => {}
+            UserLookup::PanelError(e) => return Err(e),
+        }
+    }
+    if query.find('@').is_some_and(|at| at > 0) {
+        return client.get_users_by_email(query).await;
+    }
+    let username = query.trim_start_matches('@');
+    Ok(client
+        .get_user_by_username(username)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+pub(crate) fn label(user: &UserData) -> String {
+    match user.telegram_id {
+        Some(id) => format!("{} · {} · {}", user.username, id, user.status),
+        None => format!("{} · {}", user.username, user.status),
+    }
+}
+
+fn pages(total: usize) -> usize {
+    total.div_ceil(PAGE_SIZE).max(1)
+}
+
+fn page_of(users: &[(Uuid, String)], page: usize) -> &[(Uuid, String)] {
+    let start = (page * PAGE_SIZE).min(users.len());
+    &users[start..(start + PAGE_SIZE).min(users.len())]
+}
+
+/// Handles the admin `/find <query>` command.
+pub async fn start(bot: Bot, msg: Message, query: String, dialogue: FindDialogue) -> HandlerResult {
+    if let Some(user) = &msg.from {
+        log::info!("Admin {} called /find", logger::user(user.id));
+    }
+    metrics::record_command("find");
+    let messages = Messages::ru();
+
+    let query = query.trim().to_string();
+    if query.is_empty() {
+        bot.send_message(msg.chat.id, messages.find_usage()).await?;
+        return Ok(());
+    }
+
+    let found = match search(&get_client(), &query).await {
+        Ok(found) => found,
+        Err(e) => {
+            log::error!("Search for {:?} failed: {}", query, e);
+            bot.send_message(msg.chat.id, messages.find_failed(&e.to_string()))
+                .await?;
+            return Ok(());
+        }
+    };
+    if found.is_empty() {
+        dialogue.exit().await?;
+        bot.send_message(msg.chat.id, messages.find_nothing(&query))
+            .await?;
+        return Ok(());
+    }
+
+    let users: Vec<_> = found.iter().map(|user| (user.uuid, label(user))).collect();
+    let pages = pages(users.len());
+    bot.send_message(
+        msg.chat.id,
+        messages.find_results(&query, users.len(), 0, pages),
+    )
+    .reply_markup(keyboards::find_results(page_of(&users, 0), 0, pages))
+    .await?;
+    dialogue.update(FindState::Results { query, users }).await?;
+    Ok(())
+}
+
+/// Replaces the text and the keyboard of the message with the pressed button.
+async fn edit(
+    bot: &Bot,
+    q: &CallbackQuery,
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> HandlerResult {
+    let (Some(chat_id), Some(msg)) = (q.chat_id(), &q.message) else {
+        return Ok(());
+    };
+    let request = bot.edit_message_text(chat_id, msg.id(), text);
+    match keyboard {
+        Some(keyboard) => request.reply_markup(keyboard).await?,
+        None => request.await?,
+    };
+    Ok(())
+}
+
+/// The management card of a panel user.
+async fn card(user: &UserData) -> (String, InlineKeyboardMarkup) {
+    let devices = match get_client().count_user_devices(user.uuid).await {
+        Ok(devices) => Some(devices),
+        Err(e) => {
+            log::warn!("Failed to count devices of {}: {}", user.uuid, e);
+            None
+        }
+    };
+    (
+        Messages::ru().admin_user_card(user, devices),
+        keyboards::admin_user_card(user.uuid, user.status != UserStatus::Disabled),
+    )
+}
+
+/// Handles the `find:*` buttons of the results list and the user cards.
+pub async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: FindDialogue,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+    let data = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("find:"))
+        .unwrap_or_default();
+    metrics::record_callback("find");
+    let messages = Messages::ru();
+
+    let Some((action, argument)) = data.split_once(':') else {
+        return Ok(());
+    };
+
+    if action == "page" {
+        let Some(FindState::Results { query, users }) = dialogue.get().await? else {
+            return edit(&bot, &q, messages.find_outdated(), None).await;
+        };
+        let pages = pages(users.len());
+        let page = argument.parse::<usize>().unwrap_or(0).min(pages - 1);
+        return edit(
+            &bot,
+            &q,
+            messages.find_results(&query, users.len(), page, pages),
+            Some(keyboards::find_results(page_of(&users, page), page, pages)),
+        )
+        .await;
+    }
+
+    let Ok(uuid) = argument.parse::<Uuid>() else {
+        return edit(&bot, &q, messages.find_outdated(), None).await;
+    };
+    let client = get_client();
+    let before = match client.get_user(uuid).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return edit(&bot, &q, messages.find_user_gone(), None).await,
+        Err(e) => return edit(&bot, &q, messages.find_failed(&e.to_string()), None).await,
+    };
+
+    let result = match action {
+        "user" => {
+            let (text, keyboard) = card(&before).await;
+            return edit(&bot, &q, text, Some(keyboard)).await;
+        }
+        "delete" => {
+            return edit(
+                &bot,
+                &q,
+                messages.find_delete_confirm(&before.username),
+                Some(keyboards::find_delete_confirm(uuid)),
+            )
+            .await;
+        }
+        "delete_yes" => {
+            let _guard = match before.telegram_id {
+                Some(id) => Some(users::locks().lock(id as u64).await),
+                None => None,
+            };
+            let result = client.delete_user(uuid).await;
+            audit::record(
+                &storage,
+                q.from.id,
+                "admin_delete",
+                Some(&before),
+                result.as_ref().map(|_| None),
+            );
+            return match result {
+                Ok(_) => edit(&bot, &q, messages.find_deleted(&before.username), None).await,
+                Err(e) => edit(&bot, &q, messages.find_failed(&e.to_string()), None).await,
+            };
+        }
+        "enable" => client.enable_user(uuid).await,
+        "disable" => client.disable_user(uuid).await,
+        "extend" => {
+            let from = before.expire_at.max(Utc::now());
+            client
+                .set_user_expiry(uuid, from + Duration::days(EXTEND_BY_DAYS))
+                .await
+        }
+        "reset" => client.reset_user_traffic(uuid).await,
+        "regen" => {
+            let _guard = match before.telegram_id {
+                Some(id) => Some(users::locks().lock(id as u64).await),
+                None => None,
+            };
+            client.revoke_user_subscription(uuid).await
+        }
+        _ => return edit(&bot, &q, messages.find_outdated(), None).await,
+    };
+
+    log::info!(
+        "Admin {} applied {} to {}",
+        logger::user(q.from.id),
+        action,
+        uuid
+    );
+    audit::record(
+        &storage,
+        q.from.id,
+        &format!("admin_{}", action),
+        Some(&before),
+        result.as_ref().map(Some),
+    );
+    match result {
+        Ok(after) => {
+            let (text, keyboard) = card(&after).await;
+            edit(&bot, &q, messages.find_action_done(&text), Some(keyboard)).await
+        }
+        Err(e) => {
+            let (text, keyboard) = card(&before).await;
+            edit(
+                &bot,
+                &q,
+                format!("{}\n\n{}", messages.find_failed(&e.to_string()), text),
+                Some(keyboard),
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_panel::TestPanel;
+    use crate::users::test_user;
+
+    fn usernames(users: &[UserData]) -> Vec<&str> {
+        users.iter().map(|user| user.username.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn search_by_telegram_id_returns_every_linked_user_at_once() {
+        let panel = TestPanel::start(vec![
+            test_user("alice", Some(42)),
+            test_user("alice_old", Some(42)),
+            test_user("bob", Some(7)),
+        ])
+        .await;
+
+        let found = search(&panel.client, "42").await.unwrap();
+
+        assert_eq!(usernames(&found), vec!["alice", "alice_old"]);
+        assert_eq!(panel.requests(), vec!["telegram 42"]);
+    }
+
+    #[tokio::test]
+    async fn search_falls_back_from_telegram_id_to_username() {
+        let panel = TestPanel::start(vec![test_user("1234", None)]).await;
+
+        let found = search(&panel.client, "1234").await.unwrap();
+
+        assert_eq!(usernames(&found), vec!["1234"]);
+        assert_eq!(panel.requests(), vec!["telegram 1234", "username 1234"]);
+    }
+
+    #[tokio::test]
+    async fn search_by_uuid_email_and_username() {
+        let alice = test_user("alice", Some(42));
+        let mut bob = test_user("bob", None);
+        bob.email = Some("bob@example.com".to_string());
+        let uuid = alice.uuid;
+        let panel = TestPanel::start(vec![alice, bob]).await;
+
+        let by_uuid = search(&panel.client, &uuid.to_string()).await.unwrap();
+        let by_email = search(&panel.client, "bob@example.com").await.unwrap();
+        let by_username = search(&panel.client, "@alice").await.unwrap();
+        let missing = search(&panel.client, "carol").await.unwrap();
+
+        assert_eq!(usernames(&by_uuid), vec!["alice"]);
+        assert_eq!(usernames(&by_email), vec!["bob"]);
+        assert_eq!(usernames(&by_username), vec!["alice"]);
+        assert!(missing.is_empty());
+        assert_eq!(
+            panel.requests(),
+            vec![
+                format!("uuid {}", uuid),
+                "email bob@example.com".to_string(),
+                "username alice".to_string(),
+                "username carol".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn pages_round_up_and_never_drop_to_zero() {
+        assert_eq!(pages(0), 1);
+        assert_eq!(pages(1), 1);
+        assert_eq!(pages(PAGE_SIZE), 1);
+        assert_eq!(pages(PAGE_SIZE + 1), 2);
+        assert_eq!(pages(3 * PAGE_SIZE), 3);
+    }
+
+    #[test]
+    fn page_of_slices_and_clamps() {
+        let users: Vec<_> = (0..PAGE_SIZE + 2)
+            .map(|i| (Uuid::nil(), i.to_string()))
+            .collect();
+
+        assert_eq!(page_of(&users, 0), &users[..PAGE_SIZE]);
+        assert_eq!(page_of(&users, 1), &users[PAGE_SIZE..]);
+        assert!(page_of(&users, 2).is_empty());
+        assert!(page_of(&[], 0).is_empty());
+    }
+}
@@ -0,0 +1,330 @@
+use crate::audit;
+use crate::balance;
+use crate::client::get_client;
+use crate::config::{Config, Plan};
+use crate::deep_link::{self, Payload};
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::outbox::{Outbox, OutgoingMessage};
+use crate::registration;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use crate::users::{self, Provisioned};
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::prelude::*;
+use teloxide::types::{LabeledPrice, Me, MessageId, PreCheckoutQuery, SuccessfulPayment, User};
+
+/// Prefix of the invoice payloads of gifts, followed by the plan id.
+const PAYLOAD_PREFIX: &str = "gift:";
+
+/// A paid subscription for someone else, redeemed once through its link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gift {
+    pub code: String,
+    /// Id of the plan that was bought.
+    pub plan: String,
+    /// Days of subscription the recipient gets.
+    pub days: u32,
+    /// Who paid for the gift.
+    pub giver: UserId,
+    /// Telegram charge id of the payment.
+    pub charge_id: String,
+    /// Amount paid, in the smallest units of `currency`.
+    pub amount: u32,
+    pub currency: String,
+    /// Who got the gift, if anyone yet.
+    pub redeemed_by: Option<UserId>,
+}
+
+/// The plan an invoice `payload` was issued for, if it is still on sale.
+fn plan_of<'a>(config: &'a Config, payload: &str) -> Option<&'a Plan> {
+    let id = payload.strip_prefix(PAYLOAD_PREFIX)?;
+    config.payments.plan(id)
+}
+
+/// Sends the plan picker, or puts it in place of `message_id` if given.
+async fn send_plans(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    config: &Config,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    let (text, keyboard) = if config.payments.plans.is_empty() {
+        (messages.gift_unavailable(), keyboards::back_to_main_menu())
+    } else {
+        (
+            messages.gift_pick_plan(),
+            keyboards::gift_plans(&config.payments),
+        )
+    };
+    match message_id {
+        Some(id) => {
+            bot.edit_message_text(chat_id, id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        None => {
+            bot.send_message(chat_id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Plan picker (`gift`) and the invoice of a plan (`gift:<plan id>`).
+pub async fn handle_callback(
+    bot: &Bot,
+    q: &CallbackQuery,
+    data: &str,
+    config: &Config,
+) -> HandlerResult {
+    log::info!("User {} called {}", logger::user(q.from.id), data);
+    metrics::record_callback("gift");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    let message_id = q.message.as_ref().map(|msg| msg.id());
+
+    let Some(plan) = plan_of(config, data) else {
+        return send_plans(bot, chat_id, message_id, config).await;
+    };
+    bot.answer_callback_query(q.id.clone()).await?;
+    let messages = Messages::ru();
+    let mut invoice = bot.send_invoice(
+        chat_id,
+        messages.gift_invoice_title(&plan.title),
+        messages.gift_invoice_description(plan.days),
+        format!("{}{}", PAYLOAD_PREFIX, plan.id),
+        config.payments.currency.clone(),
+        [LabeledPrice::new(plan.title.clone(), plan.price)],
+    );
+    if let Some(token) = &config.payments.provider_token {
+        invoice = invoice.provider_token(token.clone());
+    }
+    invoice.await?;
+    Ok(())
+}
+
+/// Confirms a checkout if its plan is still on sale at the invoiced price.
+pub async fn pre_checkout(bot: Bot, q: PreCheckoutQuery, config: Arc<Config>) -> HandlerResult {
+    let valid = plan_of(&config, &q.invoice_payload)
+        .is_some_and(|plan| plan.price == q.total_amount && config.payments.currency == q.currency);
+    log::info!(
+        "User {} checks out {} ({})",
+        logger::user(q.from.id),
+        q.invoice_payload,
+        if valid { "accepted" } else { "declined" }
+    );
+    let mut answer = bot.answer_pre_checkout_query(q.id, valid);
+    if !valid {
+        answer = answer.error_message(Messages::ru().gift_plan_gone());
+    }
+    answer.await?;
+    Ok(())
+}
+
+/// Turns a successful payment into a gift and sends its link to the giver.
+///
+/// The first payment of a referred user earns their referrer a bonus.
+pub async fn paid(
+    bot: Bot,
+    msg: Message,
+    payment: SuccessfulPayment,
+    me: Me,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+) -> HandlerResult {
+    let Some(giver) = &msg.from else {
+        return Ok(());
+    };
+    metrics::record_command("gift_paid");
+    let messages = Messages::ru();
+    let charge_id = payment.telegram_payment_charge_id.0;
+
+    let Some(plan) = plan_of(&config, &payment.invoice_payload) else {
+        log::error!(
+            "User {} paid {} {} for unknown {}, charge {}",
+            logger::user(giver.id),
+            payment.total_amount,
+            payment.currency,
+            payment.invoice_payload,
+            charge_id
+        );
+        bot.send_message(msg.chat.id, messages.gift_payment_unknown())
+            .reply_markup(keyboards::support())
+            .await?;
+        return Ok(());
+    };
+    let gift = Gift {
+        code: registration::generate_code(),
+        plan: plan.id.clone(),
+        days: plan.days,
+        giver: giver.id,
+        charge_id,
+        amount: payment.total_amount,
+        currency: payment.currency,
+        redeemed_by: None,
+    };
+    if !storage.create_gift(&gift)? {
+        log::warn!("Payment {} already has a gift", gift.charge_id);
+        return Ok(());
+    }
+    log::info!(
+        "User {} bought a gift of plan {}, charge {}",
+        logger::user(giver.id),
+        gift.plan,
+        gift.charge_id
+    );
+    let link = deep_link::link(&me, &Payload::Gift(gift.code.clone()));
+    bot.send_message(
+        msg.chat.id,
+        messages.gift_paid(gift.days, &gift.code, &link),
+    )
+    .await?;
+    balance::credit_referrer(giver.id, &config, &storage, &outbox)?;
+    Ok(())
+}
+
+/// Gives gift `code` to `user`, creating their subscription or extending
+/// the one they have, and lets the giver know.
+///
+/// Gifts bypass the registration mode: they were paid for. The gift is
+/// given back if the panel fails, and family members can't redeem it.
+pub async fn redeem(
+    bot: &Bot,
+    chat_id: ChatId,
+    user: &User,
+    code: &str,
+    storage: &Storage,
+    outbox: &Outbox,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    let gift = storage
+        .gift(code)?
+        .filter(|gift| gift.redeemed_by.is_none());
+    let Some(gift) = gift else {
+        log::info!("User {} opened an invalid gift", logger::user(user.id));
+        bot.send_message(chat_id, messages.gift_invalid()).await?;
+        return Ok(());
+    };
+    if gift.giver == user.id {
+        bot.send_message(chat_id, messages.gift_own()).await?;
+        return Ok(());
+    }
+    // Members follow the plan of their family, which would undo the gift.
+    if storage.family_of(user.id)?.is_some() {
+        bot.send_message(chat_id, messages.family_member_days())
+            .await?;
+        return Ok(());
+    }
+    if !storage.redeem_gift(code, user.id)? {
+        bot.send_message(chat_id, messages.gift_invalid()).await?;
+        return Ok(());
+    }
+
+    let client = get_client();
+    let days = Duration::days(i64::from(gift.days));
+    let result = match users::ensure_user(
+        &*client,
+        users::locks(),
+        user.id.0,
+        user.username.as_deref(),
+    )
+    .await
+    {
+        Ok(provisioned) => {
+            let (before, from) = match provisioned {
+                Provisioned::Created(created) => {
+                    audit::record(storage, user.id, "gift", None, Ok(Some(&created)));
+                    (created, Utc::now())
+                }
+                Provisioned::Existing(existing) => {
+                    let from = existing.expire_at.max(Utc::now());
+                    (existing, from)
+                }
+            };
+            let result = {
+                let _guard = users::locks().lock(user.id.0).await;
+                client.set_user_expiry(before.uuid, from + days).await
+            };
+            audit::record(
+                storage,
+                user.id,
+                "gift",
+                Some(&before),
+                result.as_ref().map(Some),
+            );
+            result
+        }
+        Err(e) => {
+            audit::record(storage, user.id, "gift", None, Err(&e));
+            Err(e)
+        }
+    };
+    let after = match result {
+        Ok(after) => after,
+        Err(e) => {
+            storage.revert_gift(code)?;
+            return Err(e);
+        }
+    };
+    log::info!(
+        "User {} redeemed a gift of {} for {} days, charge {}",
+        logger::user(user.id),
+        logger::user(gift.giver),
+        gift.days,
+        gift.charge_id
+    );
+    bot.send_message(chat_id, messages.gift_redeemed(gift.days, after.expire_at))
+        .reply_markup(keyboards::back_to_main_menu())
+        .await?;
+    outbox.enqueue(OutgoingMessage::text(
+        ChatId::from(gift.giver),
+        messages.gift_redeemed_giver(gift.days, &user.full_name()),
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gift(code: &str) -> Gift {
+        Gift {
+            code: code.to_string(),
+            plan: "month".to_string(),
+            days: 30,
+            giver: UserId(1),
+            charge_id: format!("charge-{}", code),
+            amount: 100,
+            currency: "XTR".to_string(),
+            redeemed_by: None,
+        }
+    }
+
+    #[test]
+    fn gifts_are_redeemed_once() {
+        let storage = Storage::open_in_memory().unwrap();
+        assert!(storage.create_gift(&gift("CODE")).unwrap());
+        assert!(!storage.create_gift(&gift("CODE")).unwrap());
+
+        assert!(storage.redeem_gift("CODE", UserId(2)).unwrap());
+        assert!(!storage.redeem_gift("CODE", UserId(3)).unwrap());
+        assert_eq!(
+            storage.gift("CODE").unwrap().unwrap().redeemed_by,
+            Some(UserId(2))
+        );
+
+        storage.revert_gift("CODE").unwrap();
+        assert_eq!(storage.gift("CODE").unwrap(), Some(gift("CODE")));
+        assert!(storage.redeem_gift("CODE", UserId(3)).unwrap());
+        assert!(!storage.redeem_gift("OTHER", UserId(3)).unwrap());
+    }
+}
@@ -1,15 +1,31 @@
-use crate::client::get_client;
+use crate::audit;
+use crate::balance;
+use crate::client::{UserLookup, get_client};
+use crate::config::Config;
+use crate::deep_link::{self, Payload, Screen};
 use crate::error::MyError;
+use crate::export;
+use crate::family;
+use crate::gift;
 use crate::keyboards;
+use crate::logger;
 use crate::messages::Messages;
-use crate::types::{Command, HandlerResult};
-use chrono::{TimeZone, Utc};
+use crate::metrics;
+use crate::outbox::Outbox;
+use crate::rate_limit::Throttled;
+use crate::registration::{self, Access, RegistrationState};
+use crate::stats;
+use crate::storage::Storage;
+use crate::support;
+use crate::types::{AdminCommand, Command, HandlerResult};
+use crate::users::{self, Provisioned};
 use remnawave::CreateUserRequestDto;
-use teloxide::dispatching::dialogue::GetChatId;
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::{GetChatId, InMemStorage};
 use teloxide::utils::command::BotCommands;
 use teloxide::{
     prelude::*,
-    types::{CallbackQuery, Message, MessageId},
+    types::{CallbackQuery, Me, Message, MessageId, ReplyParameters, UpdateKind},
 };
 
 /// Extracts the user id from a `Message` or returns a default UserId if none exists.
@@ -25,7 +41,7 @@ fn get_user_id(msg: &Message) -> UserId {
     msg.from.as_ref().map(|user| user.id).unwrap_or(UserId(0))
 }
 
-async fn send_main_menu(
+pub(crate) async fn send_main_menu(
     bot: &Bot,
     chat_id: ChatId,
     message_id: Option<MessageId>,
@@ -45,22 +61,41 @@ async fn send_main_menu(
 /// Sends or edits an error message with back button.
 ///
 /// If message_id is Some, edits the existing message; otherwise sends a new one.
+/// The text depends on the error: a missing subscription offers to create one,
+/// several linked subscriptions and an unavailable panel are explained,
+/// anything else gets the generic error text.
 async fn send_error(
     bot: &Bot,
     chat_id: ChatId,
     context: &str,
     message_id: Option<MessageId>,
+    error: &MyError,
 ) -> ResponseResult<()> {
-    let error_msg = Messages::ru().error(context);
+    let (error_msg, keyboard) = match error {
+        MyError::UserNotFound => (
+            Messages::ru().user_not_found(),
+            keyboards::new_user_confirmation(),
+        ),
+        MyError::MultipleUsersFound(_) => (
+            Messages::ru().multiple_users_found(),
+            keyboards::back_to_main_menu(),
+        ),
+        MyError::PanelUnavailable => (
+            Messages::ru().panel_unavailable(),
+            keyboards::back_to_main_menu(),
+        ),
+        _ => (
+            Messages::ru().error(context),
+            keyboards::back_to_main_menu(),
+        ),
+    };
     if let Some(mid) = message_id {
         bot.edit_message_text(chat_id, mid, error_msg)
-            .reply_markup(keyboards::back_to_main_menu())
-            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
             .await?;
     } else {
         bot.send_message(chat_id, error_msg)
-            .reply_markup(keyboards::back_to_main_menu())
-            .parse_mode(teloxide::types::ParseMode::MarkdownV2)
+            .reply_markup(keyboard)
             .await?;
     }
     Ok(())
@@ -68,33 +103,114 @@ async fn send_error(
 
 /// Handles the `/start` command.
 ///
-/// Sends a welcome message and shows the main menu if the user exists, or prompts for creation if not.
+/// Shows the main menu if the user exists, or a welcome message prompting for creation if not.
+/// New users who may not register yet are asked for an invite code or approval instead.
+/// If the panel can't tell whether the user exists, reports an error instead.
+/// A deep-link `payload` is followed first; see [`deep_link`]. Gifts and
+/// family invites are followed whether or not the user has a subscription.
 ///
 /// # Arguments
 ///
 /// * `bot` - The bot handle.
 /// * `msg` - The received `Message`.
+/// * `payload` - The text after `/start`, set by `t.me/<bot>?start=` links.
+/// * `config` - Registration mode and admins.
+/// * `storage` - Registrations of users.
+/// * `outbox` - Notifications of gift givers and family owners.
+/// * `dialogues` - Users asked for an invite code.
 ///
 /// # Returns
 ///
 /// A `HandlerResult`.
-pub async fn start(bot: Bot, msg: Message) -> HandlerResult {
+pub async fn start(
+    bot: Bot,
+    msg: Message,
+    payload: String,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+    dialogues: Arc<InMemStorage<RegistrationState>>,
+) -> HandlerResult {
     let user_id = get_user_id(&msg);
-    log::info!("User {} called /start", user_id);
+    let raw_payload = payload;
+    let payload = Payload::parse(&raw_payload);
+    // Payloads may carry codes anyone can redeem: only their kind is logged.
+    log::info!(
+        "User {} called /start {}",
+        logger::user(user_id),
+        match &payload {
+            Some(payload) => payload.kind(),
+            None if raw_payload.trim().is_empty() => "without a payload",
+            None => "with an unknown payload",
+        }
+    );
+    metrics::record_command("start");
+    let Some(user) = &msg.from else {
+        return Ok(());
+    };
+    match &payload {
+        Some(Payload::Gift(code)) => {
+            return gift::redeem(&bot, msg.chat.id, user, code, &storage, &outbox).await;
+        }
+        Some(Payload::Family(code)) => {
+            return family::join(&bot, msg.chat.id, user, code, &config, &storage, &outbox).await;
+        }
+        _ => {}
+    }
 
     let client = get_client();
-    match client
-        .users
-        .get_user_by_telegram_id(user_id.0.to_string())
-        .await
-    {
-        Ok(_user) => {
-            send_main_menu(&bot, msg.chat.id, None).await?;
+    match client.find_user_by_telegram_id(user_id.0).await.into_user() {
+        Ok(panel_user) => {
+            let opened = match payload {
+                Some(payload) => {
+                    deep_link::open_for_subscriber(
+                        &bot,
+                        msg.chat.id,
+                        user,
+                        &panel_user,
+                        payload,
+                        &config,
+                        &storage,
+                    )
+                    .await?
+                }
+                None => false,
+            };
+            if !opened {
+                send_main_menu(&bot, msg.chat.id, None).await?;
+            }
         }
-        Err(_) => {
-            bot.send_message(msg.chat.id, Messages::ru().welcome_prompt())
-                .reply_markup(keyboards::new_user_confirmation())
-                .await?;
+        Err(MyError::UserNotFound) => {
+            let opened = match payload {
+                Some(payload) => {
+                    deep_link::open_for_newcomer(
+                        &bot,
+                        msg.chat.id,
+                        user,
+                        payload,
+                        &config,
+                        &storage,
+                        dialogues.clone(),
+                    )
+                    .await?
+                }
+                None => false,
+            };
+            if !opened {
+                let access = registration::access(&config, &storage, user)?;
+                registration::welcome(&bot, msg.chat.id, None, access, dialogues).await?;
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to get user info: {}", e);
+            send_error(
+                &bot,
+                msg.chat.id,
+                "получении информации о пользователе",
+                None,
+                &e,
+            )
+            .await?;
         }
     };
     Ok(())
@@ -110,12 +226,109 @@ pub async fn start(bot: Bot, msg: Message) -> HandlerResult {
 /// # Returns
 ///
 /// A `HandlerResult` indicating the success or failure of the operation.
-pub async fn help(bot: Bot, msg: Message) -> HandlerResult {
+pub async fn help(bot: Bot, msg: Message, config: Arc<Config>) -> HandlerResult {
     let user_id = get_user_id(&msg);
-    log::info!("User {} called /help", user_id);
+    log::info!("User {} called /help", logger::user(user_id));
+    metrics::record_command("help");
 
-    bot.send_message(msg.chat.id, Command::descriptions().to_string())
-        .await?;
+    let mut text = Command::descriptions().to_string();
+    if config.is_admin(user_id) {
+        text.push_str("\n\n");
+        text.push_str(&AdminCommand::descriptions().to_string());
+    }
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+/// Answers an update rejected by the rate limiter.
+///
+/// Only the first rejection in a row is answered, so that flooding the bot
+/// doesn't make it flood Telegram in turn. Button presses get a popup,
+/// messages a reply.
+///
+/// # Arguments
+///
+/// * `bot` - The bot handle.
+/// * `update` - The rejected `Update`.
+/// * `throttled` - When the action is allowed again.
+///
+/// # Returns
+///
+/// A `HandlerResult`.
+pub async fn slow_down(bot: Bot, update: Update, throttled: Throttled) -> HandlerResult {
+    let text = Messages::ru().slow_down(throttled.retry_after.as_secs_f64().ceil() as u64);
+    match update.kind {
+        UpdateKind::CallbackQuery(q) => {
+            let request = bot.answer_callback_query(q.id);
+            if throttled.notify {
+                request.text(text).await?;
+            } else {
+                request.await?;
+            }
+        }
+        UpdateKind::Message(msg) if throttled.notify => {
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Answers a user command sent in a group with a link to the private chat.
+///
+/// A `/start` payload is carried over to the link.
+///
+/// # Arguments
+///
+/// * `bot` - The bot handle.
+/// * `msg` - The received `Message`.
+/// * `cmd` - The command.
+/// * `me` - The bot account, for the link.
+///
+/// # Returns
+///
+/// A `HandlerResult`.
+pub async fn private_only(bot: Bot, msg: Message, cmd: Command, me: Me) -> HandlerResult {
+    let user_id = get_user_id(&msg);
+    log::info!(
+        "User {} sent a command in group {}",
+        logger::user(user_id),
+        msg.chat.id
+    );
+    metrics::record_command("group");
+
+    let payload = match cmd {
+        Command::Start(payload) => Payload::parse(&payload),
+        Command::Help => None,
+    };
+    let link = deep_link::link(&me, &payload.unwrap_or(Payload::Screen(Screen::Menu)));
+    let messages = Messages::ru();
+    let mut reply = bot
+        .send_message(msg.chat.id, messages.private_only())
+        .reply_parameters(ReplyParameters::new(msg.id));
+    if let Some(keyboard) = keyboards::open_link(messages.inline_open_bot(), &link) {
+        reply = reply.reply_markup(keyboard);
+    }
+    reply.await?;
+    Ok(())
+}
+
+/// Answers a user button pressed under a message in a group by opening
+/// the private chat with the bot.
+pub async fn private_only_callback(bot: Bot, q: CallbackQuery, me: Me) -> HandlerResult {
+    log::info!(
+        "User {} pressed {:?} outside a private chat",
+        logger::user(q.from.id),
+        q.data
+    );
+    metrics::record_callback("group");
+
+    let link = deep_link::link(&me, &Payload::Screen(Screen::Menu));
+    let answer = bot.answer_callback_query(q.id.clone());
+    match link.parse() {
+        Ok(url) => answer.url(url).await?,
+        Err(_) => answer.text(Messages::ru().private_only()).await?,
+    };
     Ok(())
 }
 
@@ -136,27 +349,101 @@ pub async fn invalid_input(bot: Bot, msg: Message) -> HandlerResult {
 
     log::warn!(
         "User {} entered an incorrect value: {}",
-        user_id,
+        logger::user(user_id),
         user_input
     );
     bot.send_message(chat_id, Messages::ru().invalid_input())
+        .reply_markup(keyboards::support())
         .await?;
     Ok(())
 }
 
+/// A screen or action of the user menu, read from callback data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Callback {
+    CreateNewUser,
+    Stats,
+    Export,
+    Gift,
+    Balance,
+    Family,
+    Support,
+    SupportClose,
+    RequestAccess,
+    AboutMe,
+    SubLink,
+    RecreateSubLink,
+    DeleteMe,
+    BackToMainMenu,
+}
+
+impl Callback {
+    /// The callback `data` stands for, `None` if it is unknown.
+    fn parse(data: &str) -> Option<Self> {
+        let (screen, _) = data.split_once(':').unwrap_or((data, ""));
+        let callback = match screen {
+            "stats" if data != screen => Callback::Stats,
+            "export" => Callback::Export,
+            "gift" => Callback::Gift,
+            "balance" => Callback::Balance,
+            "family" => Callback::Family,
+            _ if data != screen => return None,
+            "create_new_user" => Callback::CreateNewUser,
+            "support" => Callback::Support,
+            "support_close" => Callback::SupportClose,
+            "request_access" => Callback::RequestAccess,
+            "show_about_me" => Callback::AboutMe,
+            "show_sub_link" => Callback::SubLink,
+            "recreate_sub_link" => Callback::RecreateSubLink,
+            "delete_me" => Callback::DeleteMe,
+            "back_to_main_menu" => Callback::BackToMainMenu,
+            _ => return None,
+        };
+        Some(callback)
+    }
+}
+
 /// Unified handler for all callback queries.
 ///
 /// Dispatches the callback based on the data in the query.
-pub async fn handle_callback(bot: Bot, q: CallbackQuery) -> HandlerResult {
+/// Actions that change the subscription are written to the audit log.
+pub async fn handle_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    me: Me,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+    dialogues: Arc<InMemStorage<RegistrationState>>,
+) -> HandlerResult {
     let data = q.data.as_deref().unwrap_or("");
-    let result = match data {
-        "create_new_user" => create_new_user(&bot, &q).await,
-        "show_about_me" => show_about_me(&bot, &q).await,
-        "show_sub_link" => show_sub_link(&bot, &q).await,
-        "recreate_sub_link" => recreate_sub_link(&bot, &q).await,
-        "delete_me" => delete_me(&bot, &q).await,
-        "back_to_main_menu" => back_to_main_menu(&bot, &q).await,
-        _ => {
+    let result = match Callback::parse(data) {
+        Some(Callback::CreateNewUser) => {
+            create_new_user(&bot, &q, &config, &storage, dialogues).await
+        }
+        Some(Callback::Stats) => stats::handle_callback(&bot, &q, data).await,
+        Some(Callback::Export) => export::handle_callback(&bot, &q, data).await,
+        Some(Callback::Gift) => gift::handle_callback(&bot, &q, data, &config).await,
+        Some(Callback::Balance) => {
+            balance::handle_callback(&bot, &q, data, &config, &storage).await
+        }
+        Some(Callback::Family) => {
+            family::handle_callback(&bot, &q, data, &me, &config, &storage, &outbox).await
+        }
+        Some(Callback::Support) => support::open(&bot, &q, &config, &storage).await,
+        Some(Callback::SupportClose) => {
+            support::close_by_user(&bot, &q, &config, &storage, &outbox).await
+        }
+        Some(Callback::RequestAccess) => {
+            registration::request_access(&bot, &q, &config, &storage, &outbox, dialogues).await
+        }
+        Some(Callback::AboutMe) => show_about_me(&bot, &q).await,
+        Some(Callback::SubLink) => show_sub_link(&bot, &q).await,
+        Some(Callback::RecreateSubLink) => recreate_sub_link(&bot, &q, &storage).await,
+        Some(Callback::DeleteMe) => delete_me(&bot, &q, &storage).await,
+        Some(Callback::BackToMainMenu) => back_to_main_menu(&bot, &q).await,
+        None => {
+            metrics::record_callback("unknown");
             if let Some(ref msg) = q.message {
                 bot.edit_message_text(q.chat_id().unwrap(), msg.id(), "Неизвестная команда.")
                     .await?;
@@ -169,58 +456,84 @@ pub async fn handle_callback(bot: Bot, q: CallbackQuery) -> HandlerResult {
 
     if let Err(e) = result {
         log::error!("Callback error: {}", e);
+        metrics::record_error(&e);
         if let Some(ref msg) = q.message {
             send_error(
                 &bot,
                 q.chat_id().unwrap(),
                 "обработке запроса",
                 Some(msg.id()),
+                &e,
             )
             .await?;
         } else if let Some(chat_id) = q.chat_id() {
-            send_error(&bot, chat_id, "обработке запроса", None).await?;
+            send_error(&bot, chat_id, "обработке запроса", None, &e).await?;
         }
     }
     Ok(())
 }
 
-async fn create_new_user(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
+async fn create_new_user(
+    bot: &Bot,
+    q: &CallbackQuery,
+    config: &Config,
+    storage: &Storage,
+    dialogues: Arc<InMemStorage<RegistrationState>>,
+) -> HandlerResult {
     let user_id = q.from.id;
-    log::info!("User {} called create_new_user", user_id);
-
-    let telegram_id: i64 = user_id
-        .0
-        .try_into()
-        .map_err(|_| MyError::Custom("User ID too large for i64".to_string()))?;
+    log::info!("User {} called create_new_user", logger::user(user_id));
+    metrics::record_callback("create_new_user");
 
     let client = get_client();
-    let new_user = CreateUserRequestDto {
-        username: q.from.username.clone().unwrap_or(user_id.to_string()),
-        status: remnawave::api::types::common::UserStatus::Active,
-        short_uuid: None,
-        trojan_password: None,
-        vless_uuid: None,
-        ss_password: None,
-        traffic_limit_bytes: None,
-        traffic_limit_strategy: remnawave::api::types::common::TrafficLimitStrategy::NoReset,
-        expire_at: Utc.with_ymd_and_hms(2099, 1, 1, 0, 0, 0).unwrap(),
-        created_at: None,
-        last_traffic_reset_at: None,
-        description: None,
-        tag: None,
-        telegram_id: Some(telegram_id),
-        email: None,
-        hwid_device_limit: None,
-        active_internal_squads: None,
-    };
-
-    match client.users.create_user(new_user).await {
-        Ok(user_data) => {
-            log::info!("User {} created successfully", user_id);
-            let success_msg = format!(
-                "Ваша подписка создана\\! Ссылка: `{}`",
-                user_data.response.subscription_url
-            );
+    let access = registration::access(config, storage, &q.from)?;
+    if access != Access::Allowed {
+        // Users who already have a subscription just get their link.
+        match client.find_user_by_telegram_id(user_id.0).await {
+            UserLookup::Found(_) | UserLookup::MultipleFound(_) => {}
+            UserLookup::PanelError(e) => return Err(e),
+            UserLookup::NotFound => {
+                log::info!(
+                    "User {} may not register yet: {:?}",
+                    logger::user(user_id),
+                    access
+                );
+                let Some(chat_id) = q.chat_id() else {
+                    return Ok(());
+                };
+                let message_id = q.message.as_ref().map(|msg| msg.id());
+                return registration::welcome(bot, chat_id, message_id, access, dialogues).await;
+            }
+        }
+    }
+    match users::ensure_user(
+        &*client,
+        users::locks(),
+        user_id.0,
+        q.from.username.as_deref(),
+    )
+    .await
+    {
+        Ok(provisioned) => {
+            let success_msg = match provisioned {
+                Provisioned::Created(user_data) => {
+                    log::info!("User {} created successfully", logger::user(user_id));
+                    audit::record(
+                        storage,
+                        user_id,
+                        "create_new_user",
+                        None,
+                        Ok(Some(&user_data)),
+                    );
+                    format!(
+                        "Ваша подписка создана\\! Ссылка: `{}`",
+                        user_data.subscription_url
+                    )
+                }
+                Provisioned::Existing(user_data) => {
+                    log::info!("User {} already exists", logger::user(user_id));
+                    format!("Ваша ссылка на подписку: `{}`", user_data.subscription_url)
+                }
+            };
             if let Some(ref msg) = q.message {
                 bot.edit_message_text(q.chat_id().unwrap(), msg.id(), success_msg)
                     .reply_markup(keyboards::back_to_main_menu())
@@ -235,44 +548,46 @@ async fn create_new_user(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
         }
         Err(e) => {
             log::error!("Failed to create user: {}", e);
+            audit::record(storage, user_id, "create_new_user", None, Err(&e));
             if let Some(ref msg) = q.message {
                 send_error(
                     bot,
                     q.chat_id().unwrap(),
                     "создании пользователя",
                     Some(msg.id()),
+                    &e,
                 )
                 .await?;
             } else if let Some(chat_id) = q.chat_id() {
-                send_error(bot, chat_id, "создании пользователя", None).await?;
+                send_error(bot, chat_id, "создании пользователя", None, &e).await?;
             }
         }
     };
     Ok(())
 }
 
-async fn recreate_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
+async fn recreate_sub_link(bot: &Bot, q: &CallbackQuery, storage: &Storage) -> HandlerResult {
     let user_id = q.from.id;
-    log::info!("User {} called recreate_sub_link", user_id);
+    log::info!("User {} called recreate_sub_link", logger::user(user_id));
+    metrics::record_callback("recreate_sub_link");
 
     let telegram_id: i64 = user_id
         .0
         .try_into()
         .map_err(|_| MyError::Custom("User ID too large for i64".to_string()))?;
 
+    let _guard = users::locks().lock(user_id.0).await;
     let client = get_client();
-    match client
-        .users
-        .get_user_by_telegram_id(user_id.0.to_string())
-        .await
-    {
-        Ok(user) => {
-            let user_data = &user.response[0];
+    match client.find_user_by_telegram_id(user_id.0).await.into_user() {
+        Ok(user_data) => {
             let user_uuid = user_data.uuid;
 
-            match client.users.delete_user(user_uuid).await {
+            match client.delete_user(user_uuid).await {
                 Ok(_) => {
-                    log::info!("User {} deleted successfully (during recreation)", user_id);
+                    log::info!(
+                        "User {} deleted successfully (during recreation)",
+                        logger::user(user_id)
+                    );
                     let squads: Vec<String> = user_data
                         .active_internal_squads
                         .iter()
@@ -290,20 +605,32 @@ async fn recreate_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                         expire_at: user_data.expire_at,
                         created_at: Some(user_data.created_at),
                         last_traffic_reset_at: user_data.last_traffic_reset_at,
-                        description: user_data.description.clone(),
-                        tag: user_data.tag.clone(),
-                        telegram_id: Some(telegram_id),
-                        email: user_data.email.clone(),
+                        description: Some(user_data.description.clone()),
+                        tag: Some(user_data.tag.clone()),
+                        telegram_id: Some(Some(telegram_id)),
+                        email: Some(user_data.email.clone()),
                         hwid_device_limit: user_data.hwid_device_limit,
                         active_internal_squads: Some(squads),
+                        uuid: None,
+                        external_squad_uuid: Some(user_data.external_squad_uuid),
                     };
 
-                    match client.users.create_user(new_user).await {
-                        Ok(user_data) => {
-                            log::info!("User {} created successfully (during recreation)", user_id);
+                    match client.create_user(new_user).await {
+                        Ok(new_user) => {
+                            log::info!(
+                                "User {} created successfully (during recreation)",
+                                logger::user(user_id)
+                            );
+                            audit::record(
+                                storage,
+                                user_id,
+                                "recreate_sub_link",
+                                Some(&user_data),
+                                Ok(Some(&new_user.response)),
+                            );
                             let success_msg = format!(
                                 "Новая ссылка на вашу подписку: `{}`",
-                                user_data.response.subscription_url
+                                new_user.response.subscription_url
                             );
                             if let Some(ref msg) = q.message {
                                 bot.edit_message_text(q.chat_id().unwrap(), msg.id(), success_msg)
@@ -319,32 +646,48 @@ async fn recreate_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                         }
                         Err(e) => {
                             log::error!("Failed to recreate user: {}", e);
+                            audit::record(
+                                storage,
+                                user_id,
+                                "recreate_sub_link",
+                                Some(&user_data),
+                                Err(&e),
+                            );
                             if let Some(ref msg) = q.message {
                                 send_error(
                                     bot,
                                     q.chat_id().unwrap(),
                                     "создании пользователя",
                                     Some(msg.id()),
+                                    &e,
                                 )
                                 .await?;
                             } else if let Some(chat_id) = q.chat_id() {
-                                send_error(bot, chat_id, "создании пользователя", None).await?;
+                                send_error(bot, chat_id, "создании пользователя", None, &e).await?;
                             }
                         }
                     }
                 }
                 Err(e) => {
                     log::error!("Failed to delete client: {}", e);
+                    audit::record(
+                        storage,
+                        user_id,
+                        "recreate_sub_link",
+                        Some(&user_data),
+                        Err(&e),
+                    );
                     if let Some(ref msg) = q.message {
                         send_error(
                             bot,
                             q.chat_id().unwrap(),
                             "удалении пользователя",
                             Some(msg.id()),
+                            &e,
                         )
                         .await?;
                     } else if let Some(chat_id) = q.chat_id() {
-                        send_error(bot, chat_id, "удалении пользователя", None).await?;
+                        send_error(bot, chat_id, "удалении пользователя", None, &e).await?;
                     }
                 }
             }
@@ -357,10 +700,18 @@ async fn recreate_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                     q.chat_id().unwrap(),
                     "получении информации о пользователе",
                     Some(msg.id()),
+                    &e,
                 )
                 .await?;
             } else if let Some(chat_id) = q.chat_id() {
-                send_error(bot, chat_id, "получении информации о пользователе", None).await?;
+                send_error(
+                    bot,
+                    chat_id,
+                    "получении информации о пользователе",
+                    None,
+                    &e,
+                )
+                .await?;
             }
         }
     };
@@ -369,14 +720,11 @@ async fn recreate_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
 
 async fn back_to_main_menu(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
     let user_id = q.from.id;
-    log::info!("User {} called back_to_main_menu", user_id);
+    log::info!("User {} called back_to_main_menu", logger::user(user_id));
+    metrics::record_callback("back_to_main_menu");
 
     let client = get_client();
-    match client
-        .users
-        .get_user_by_telegram_id(user_id.0.to_string())
-        .await
-    {
+    match client.find_user_by_telegram_id(user_id.0).await.into_user() {
         Ok(_user) => {
             if let Some(ref msg) = q.message {
                 bot.edit_message_text(q.chat_id().unwrap(), msg.id(), "Главное меню:")
@@ -386,7 +734,7 @@ async fn back_to_main_menu(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                 send_main_menu(bot, chat_id, None).await?;
             }
         }
-        Err(_) => {
+        Err(MyError::UserNotFound) => {
             let welcome_msg = Messages::ru().welcome_prompt();
             if let Some(ref msg) = q.message {
                 bot.edit_message_text(q.chat_id().unwrap(), msg.id(), welcome_msg)
@@ -398,22 +746,59 @@ async fn back_to_main_menu(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                     .await?;
             }
         }
+        Err(e) => {
+            log::error!("Failed to get user info: {}", e);
+            if let Some(ref msg) = q.message {
+                send_error(
+                    bot,
+                    q.chat_id().unwrap(),
+                    "получении информации о пользователе",
+                    Some(msg.id()),
+                    &e,
+                )
+                .await?;
+            } else if let Some(chat_id) = q.chat_id() {
+                send_error(
+                    bot,
+                    chat_id,
+                    "получении информации о пользователе",
+                    None,
+                    &e,
+                )
+                .await?;
+            }
+        }
     };
     Ok(())
 }
 
 async fn show_about_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
     let user_id = q.from.id;
-    log::info!("User {} called show_about_me", user_id);
+    log::info!("User {} called show_about_me", logger::user(user_id));
+    metrics::record_callback("show_about_me");
 
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    send_about_me(
+        bot,
+        chat_id,
+        user_id,
+        q.message.as_ref().map(|msg| msg.id()),
+    )
+    .await
+}
+
+/// Sends the profile of `user_id`, or puts it in place of `message_id` if given.
+pub(crate) async fn send_about_me(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    message_id: Option<MessageId>,
+) -> HandlerResult {
     let client = get_client();
-    match client
-        .users
-        .get_user_by_telegram_id(user_id.0.to_string())
-        .await
-    {
-        Ok(user) => {
-            let user_data = &user.response[0];
+    match client.find_user_by_telegram_id(user_id.0).await.into_user() {
+        Ok(user_data) => {
             let info = format!(
                 "🔑 *Профиль пользователя*\n Имя пользователя: `{}`\n Статус: `{}`\n📲 *Идентификаторы*\n Telegram ID: `{}`\n Email: `{}`\n📊 *Трафик*\n Использовано за все время: `{}`\n Лимит трафика: `{}`\n🖥 *Подключения и агенты*\n Последний UserAgent: `{}`\n Первое подключение: `{}`\n⏰ *Срок действия подписки*\n Активно до: `{}`\n📥 *Ссылки*\n Подписка: `{}`\n HAPP Crypto Link: `{}`",
                 user_data.username,
@@ -431,12 +816,12 @@ async fn show_about_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                 user_data.subscription_url,
                 user_data.happ.crypto_link
             );
-            if let Some(ref msg) = q.message {
-                bot.edit_message_text(q.chat_id().unwrap(), msg.id(), info)
+            if let Some(mid) = message_id {
+                bot.edit_message_text(chat_id, mid, info)
                     .reply_markup(keyboards::back_to_main_menu())
                     .parse_mode(teloxide::types::ParseMode::MarkdownV2)
                     .await?;
-            } else if let Some(chat_id) = q.chat_id() {
+            } else {
                 bot.send_message(chat_id, info)
                     .reply_markup(keyboards::back_to_main_menu())
                     .parse_mode(teloxide::types::ParseMode::MarkdownV2)
@@ -445,37 +830,33 @@ async fn show_about_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
         }
         Err(e) => {
             log::error!("Failed to get user info: {}", e);
-            if let Some(ref msg) = q.message {
-                send_error(
-                    bot,
-                    q.chat_id().unwrap(),
-                    "получении информации о пользователе",
-                    Some(msg.id()),
-                )
-                .await?;
-            } else if let Some(chat_id) = q.chat_id() {
-                send_error(bot, chat_id, "получении информации о пользователе", None).await?;
-            }
+            send_error(
+                bot,
+                chat_id,
+                "получении информации о пользователе",
+                message_id,
+                &e,
+            )
+            .await?;
         }
     };
     Ok(())
 }
 
-async fn delete_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
+async fn delete_me(bot: &Bot, q: &CallbackQuery, storage: &Storage) -> HandlerResult {
     let user_id = q.from.id;
-    log::info!("User {} called delete_me", user_id);
+    log::info!("User {} called delete_me", logger::user(user_id));
+    metrics::record_callback("delete_me");
 
+    let _guard = users::locks().lock(user_id.0).await;
     let client = get_client();
-    match client
-        .users
-        .get_user_by_telegram_id(user_id.0.to_string())
-        .await
-    {
-        Ok(user) => {
-            let user_uuid = user.response[0].uuid;
-            match client.users.delete_user(user_uuid).await {
+    match client.find_user_by_telegram_id(user_id.0).await.into_user() {
+        Ok(user_data) => {
+            let user_uuid = user_data.uuid;
+            match client.delete_user(user_uuid).await {
                 Ok(_) => {
-                    log::info!("User {} deleted successfully", user_id);
+                    log::info!("User {} deleted successfully", logger::user(user_id));
+                    audit::record(storage, user_id, "delete_me", Some(&user_data), Ok(None));
                     let success_msg = "Ваша подписка успешно удалена, для повторного создания подписки используйте команду /start";
                     if let Some(ref msg) = q.message {
                         bot.edit_message_text(q.chat_id().unwrap(), msg.id(), success_msg)
@@ -486,16 +867,18 @@ async fn delete_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                 }
                 Err(e) => {
                     log::error!("Failed to delete user: {}", e);
+                    audit::record(storage, user_id, "delete_me", Some(&user_data), Err(&e));
                     if let Some(ref msg) = q.message {
                         send_error(
                             bot,
                             q.chat_id().unwrap(),
                             "удалении пользователя",
                             Some(msg.id()),
+                            &e,
                         )
                         .await?;
                     } else if let Some(chat_id) = q.chat_id() {
-                        send_error(bot, chat_id, "удалении пользователя", None).await?;
+                        send_error(bot, chat_id, "удалении пользователя", None, &e).await?;
                     }
                 }
             }
@@ -508,10 +891,18 @@ async fn delete_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
                     q.chat_id().unwrap(),
                     "получении информации о пользователе",
                     Some(msg.id()),
+                    &e,
                 )
                 .await?;
             } else if let Some(chat_id) = q.chat_id() {
-                send_error(bot, chat_id, "получении информации о пользователе", None).await?;
+                send_error(
+                    bot,
+                    chat_id,
+                    "получении информации о пользователе",
+                    None,
+                    &e,
+                )
+                .await?;
             }
         }
     };
@@ -520,25 +911,39 @@ async fn delete_me(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
 
 async fn show_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
     let user_id = q.from.id;
-    log::info!("User {} called show_sub_link", user_id);
+    log::info!("User {} called show_sub_link", logger::user(user_id));
+    metrics::record_callback("show_sub_link");
 
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    send_sub_link(
+        bot,
+        chat_id,
+        user_id,
+        q.message.as_ref().map(|msg| msg.id()),
+    )
+    .await
+}
+
+/// Sends the subscription link of `user_id`, or puts it in place of
+/// `message_id` if given.
+pub(crate) async fn send_sub_link(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    message_id: Option<MessageId>,
+) -> HandlerResult {
     let client = get_client();
-    match client
-        .users
-        .get_user_by_telegram_id(user_id.0.to_string())
-        .await
-    {
-        Ok(user) => {
-            let success_msg = format!(
-                "Ваша ссылка на подписку: `{}`",
-                user.response[0].subscription_url
-            );
-            if let Some(ref msg) = q.message {
-                bot.edit_message_text(q.chat_id().unwrap(), msg.id(), success_msg)
+    match client.find_user_by_telegram_id(user_id.0).await.into_user() {
+        Ok(user_data) => {
+            let success_msg = format!("Ваша ссылка на подписку: `{}`", user_data.subscription_url);
+            if let Some(mid) = message_id {
+                bot.edit_message_text(chat_id, mid, success_msg)
                     .reply_markup(keyboards::back_to_main_menu())
                     .parse_mode(teloxide::types::ParseMode::MarkdownV2)
                     .await?;
-            } else if let Some(chat_id) = q.chat_id() {
+            } else {
                 bot.send_message(chat_id, success_msg)
                     .reply_markup(keyboards::back_to_main_menu())
                     .parse_mode(teloxide::types::ParseMode::MarkdownV2)
@@ -547,18 +952,45 @@ async fn show_sub_link(bot: &Bot, q: &CallbackQuery) -> HandlerResult {
         }
         Err(e) => {
             log::error!("Failed to get subscription link: {}", e);
-            if let Some(ref msg) = q.message {
-                send_error(
-                    bot,
-                    q.chat_id().unwrap(),
-                    "получении ссылки на подписку",
-                    Some(msg.id()),
-                )
-                .await?;
-            } else if let Some(chat_id) = q.chat_id() {
-                send_error(bot, chat_id, "получении ссылки на подписку", None).await?;
-            }
+            send_error(bot, chat_id, "получении ссылки на подписку", message_id, &e).await?;
         }
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::InlineKeyboardButtonKind;
+
+    #[test]
+    fn parses_callbacks() {
+        assert_eq!(Callback::parse("stats:30"), Some(Callback::Stats));
+        assert_eq!(Callback::parse("stats"), None);
+        assert_eq!(Callback::parse("export"), Some(Callback::Export));
+        assert_eq!(Callback::parse("family:leave"), Some(Callback::Family));
+        assert_eq!(
+            Callback::parse("balance:topup:100"),
+            Some(Callback::Balance)
+        );
+        assert_eq!(Callback::parse("delete_me"), Some(Callback::DeleteMe));
+        assert_eq!(Callback::parse("delete_me:now"), None);
+        assert_eq!(Callback::parse("unknown"), None);
+    }
+
+    #[test]
+    fn main_menu_buttons_are_handled() {
+        let callbacks: Vec<_> = keyboards::main_menu()
+            .inline_keyboard
+            .into_iter()
+            .flatten()
+            .map(|button| match button.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => Callback::parse(&data),
+                kind => panic!("unexpected button {:?}", kind),
+            })
+            .collect();
+        assert!(callbacks.iter().all(Option::is_some));
+        assert!(callbacks.contains(&Some(Callback::Family)));
+        assert!(callbacks.contains(&Some(Callback::Balance)));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::client::get_client;
+use crate::error::MyError;
+use crate::metrics;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+use teloxide::prelude::*;
+
+/// How often the probes run.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a heartbeat or a successful probe is considered fresh.
+const STALE_AFTER_SECS: i64 = 90;
+
+/// Liveness and readiness state shared between the bot and the HTTP server.
+///
+/// All timestamps are unix seconds, `0` meaning "never".
+#[derive(Default)]
+pub struct Health {
+    dispatcher_running: AtomicBool,
+    last_heartbeat: AtomicI64,
+    /// When Telegram last had no updates waiting for the dispatcher.
+    last_caught_up: AtomicI64,
+    last_telegram_ok: AtomicI64,
+    last_panel_ok: AtomicI64,
+}
+
+/// Body of the `/healthz` and `/readyz` responses.
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub dispatcher_running: bool,
+    pub last_heartbeat: i64,
+    pub last_caught_up: i64,
+    pub last_telegram_ok: i64,
+    pub last_panel_ok: i64,
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+fn is_fresh(timestamp: i64) -> bool {
+    now() - timestamp <= STALE_AFTER_SECS
+}
+
+impl Health {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Marks the dispatcher as started or stopped.
+    pub fn set_dispatcher_running(&self, running: bool) {
+        self.dispatcher_running.store(running, Ordering::Relaxed);
+    }
+
+    /// Records that the dispatcher is making progress. Only the dispatcher
+    /// beats, on every update it handles.
+    pub fn beat(&self) {
+        self.last_heartbeat.store(now(), Ordering::Relaxed);
+    }
+
+    /// The process is alive and the dispatcher loop keeps ticking: it
+    /// handled an update recently, or it has no updates waiting.
+    pub fn is_alive(&self) -> bool {
+        self.dispatcher_running.load(Ordering::Relaxed)
+            && (is_fresh(self.last_heartbeat.load(Ordering::Relaxed))
+                || is_fresh(self.last_caught_up.load(Ordering::Relaxed)))
+    }
+
+    /// Telegram and the panel both answered recently.
+    pub fn is_ready(&self) -> bool {
+        self.is_alive()
+            && is_fresh(self.last_telegram_ok.load(Ordering::Relaxed))
+            && is_fresh(self.last_panel_ok.load(Ordering::Relaxed))
+    }
+
+    fn report(&self, ok: bool) -> HealthReport {
+        HealthReport {
+            ok,
+            dispatcher_running: self.dispatcher_running.load(Ordering::Relaxed),
+            last_heartbeat: self.last_heartbeat.load(Ordering::Relaxed),
+            last_caught_up: self.last_caught_up.load(Ordering::Relaxed),
+            last_telegram_ok: self.last_telegram_ok.load(Ordering::Relaxed),
+            last_panel_ok: self.last_panel_ok.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Runs the Telegram/panel probes forever.
+    ///
+    /// Telegram is probed with `getMe`, the panel with an authorized
+    /// health request, so an expired API token makes the bot not ready.
+    /// `getWebhookInfo` tells whether updates are waiting, which a quiet
+    /// but working dispatcher never leaves.
+    pub async fn run_probes(self: Arc<Self>, bot: Bot) {
+        let mut interval = tokio::time::interval(PROBE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            match bot.get_webhook_info().await {
+                Ok(info) if info.pending_update_count == 0 => {
+                    self.last_caught_up.store(now(), Ordering::Relaxed)
+                }
+                Ok(info) => log::debug!(
+                    "Liveness probe: {} updates are waiting",
+                    info.pending_update_count
+                ),
+                Err(e) => log::warn!("Liveness probe: Telegram getWebhookInfo failed: {}", e),
+            }
+
+            match bot.get_me().await {
+                Ok(_) => self.last_telegram_ok.store(now(), Ordering::Relaxed),
+                Err(e) => {
+                    log::warn!("Readiness probe: Telegram getMe failed: {}", e);
+                    metrics::record_error(&MyError::from(e));
+                }
+            }
+
+            let client = get_client();
+            match client
+                .call_once("get_remnawave_health", |api| async move {
+                    api.system.get_remnawave_health().await
+                })
+                .await
+            {
+                Ok(_) => self.last_panel_ok.store(now(), Ordering::Relaxed),
+                Err(e) => log::warn!("Readiness probe: panel health check failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Axum handler for `GET /healthz`.
+pub async fn healthz(State(health): State<Arc<Health>>) -> (StatusCode, Json<HealthReport>) {
+    respond(&health, health.is_alive())
+}
+
+/// Axum handler for `GET /readyz`.
+pub async fn readyz(State(health): State<Arc<Health>>) -> (StatusCode, Json<HealthReport>) {
+    respond(&health, health.is_ready())
+}
+
+fn respond(health: &Health, ok: bool) -> (StatusCode, Json<HealthReport>) {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health.report(ok)))
+}
+
+/// Implements the `healthcheck` subcommand used as a Docker `HEALTHCHECK`.
+///
+/// Queries `/readyz` of the running bot on `listen_addr`, connecting to the
+/// loopback interface when the bot listens on all interfaces.
+///
+/// # Errors
+///
+/// Returns an error if the bot is unreachable or reports that it is not ready.
+pub async fn check(listen_addr: SocketAddr) -> Result<(), MyError> {
+    let mut addr = listen_addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/readyz", addr))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .map_err(|e| MyError::Custom(format!("Health check request failed: {}", e)))?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(MyError::Custom(format!(
+            "Bot is not ready: {}",
+            response.text().await.unwrap_or_default()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Health whose events happened the given seconds ago, `-1` for never.
+    fn health(heartbeat: i64, caught_up: i64, telegram: i64, panel: i64) -> Health {
+        let health = Health::default();
+        health.set_dispatcher_running(true);
+        let at = |ago: i64| if ago < 0 { 0 } else { now() - ago };
+        health
+            .last_heartbeat
+            .store(at(heartbeat), Ordering::Relaxed);
+        health
+            .last_caught_up
+            .store(at(caught_up), Ordering::Relaxed);
+        health
+            .last_telegram_ok
+            .store(at(telegram), Ordering::Relaxed);
+        health.last_panel_ok.store(at(panel), Ordering::Relaxed);
+        health
+    }
+
+    #[test]
+    fn alive_while_the_dispatcher_keeps_up() {
+        assert!(health(10, -1, -1, -1).is_alive());
+        assert!(health(-1, 10, -1, -1).is_alive());
+        assert!(!health(-1, -1, 10, 10).is_alive());
+        assert!(!health(STALE_AFTER_SECS + 1, STALE_AFTER_SECS + 1, 10, 10).is_alive());
+
+        let stopped = health(10, 10, 10, 10);
+        stopped.set_dispatcher_running(false);
+        assert!(!stopped.is_alive());
+    }
+
+    #[test]
+    fn ready_once_telegram_and_the_panel_answered() {
+        assert!(health(10, 10, 10, 10).is_ready());
+        assert!(!health(10, 10, -1, 10).is_ready());
+        assert!(!health(10, 10, 10, STALE_AFTER_SECS + 1).is_ready());
+        assert!(!health(-1, -1, 10, 10).is_ready());
+    }
+}
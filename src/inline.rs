@@ -0,0 +1,251 @@
+use crate::client::get_client;
+use crate::config::Config;
+use crate::deep_link::{self, Payload, Screen};
+use crate::find;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use remnawave::api::types::users::UserData;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText, Me,
+};
+
+/// How long answers are reused, by the bot and by Telegram.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Number of cached answers after which expired ones are dropped.
+const MAX_CACHED: usize = 1_000;
+
+/// Shortest query that admins get user lookups for.
+const MIN_LOOKUP_LENGTH: usize = 3;
+
+/// User lookups shown at most.
+const MAX_LOOKUPS: usize = 5;
+
+/// A result card of an inline query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Card {
+    /// A referral link of the user.
+    Referral,
+    /// Setup instructions with a link to the bot.
+    HowTo,
+    /// Panel users matching the query, for admins.
+    Lookup(String),
+}
+
+/// Cards answering `query` of a user.
+fn cards(is_admin: bool, query: &str) -> Vec<Card> {
+    let query = query.trim();
+    let mut cards = vec![Card::Referral, Card::HowTo];
+    if is_admin && query.chars().count() >= MIN_LOOKUP_LENGTH {
+        cards.insert(0, Card::Lookup(query.to_string()));
+    }
+    cards
+}
+
+/// A cached answer and when it was made.
+type CachedAnswer = (Instant, Vec<InlineQueryResult>);
+
+/// Inline answers kept for a while, so that typing doesn't hit the panel
+/// on every key press.
+///
+/// Answers are keyed by the user who asked, as they carry the user's own
+/// referral link and, for admins, panel data: one user is never served
+/// the answer of another.
+#[derive(Default)]
+pub struct InlineCache {
+    answers: Mutex<HashMap<(UserId, String), CachedAnswer>>,
+}
+
+impl InlineCache {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn get(&self, user: UserId, query: &str, now: Instant) -> Option<Vec<InlineQueryResult>> {
+        let answers = self.answers.lock().unwrap();
+        answers
+            .get(&(user, query.to_string()))
+            .filter(|(at, _)| now.duration_since(*at) < CACHE_TTL)
+            .map(|(_, results)| results.clone())
+    }
+
+    fn put(&self, user: UserId, query: &str, results: Vec<InlineQueryResult>, now: Instant) {
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() >= MAX_CACHED {
+            answers.retain(|_, (at, _)| now.duration_since(*at) < CACHE_TTL);
+        }
+        answers.insert((user, query.to_string()), (now, results));
+    }
+}
+
+fn article(
+    id: impl Into<String>,
+    title: impl Into<String>,
+    text: String,
+) -> InlineQueryResultArticle {
+    InlineQueryResultArticle::new(
+        id,
+        title,
+        InputMessageContent::Text(InputMessageContentText::new(text)),
+    )
+}
+
+/// The lookup card of a panel user. The label with the Telegram ID is
+/// only shown to the admin; the message sent to the chat is a summary
+/// without the UUID, Telegram ID or email.
+fn lookup(user: &UserData) -> InlineQueryResultArticle {
+    article(
+        format!("user:{}", user.uuid),
+        user.username.clone(),
+        Messages::ru().inline_lookup(user),
+    )
+    .description(find::label(user))
+}
+
+async fn results(card: Card, user: UserId, me: &Me, storage: &Storage) -> Vec<InlineQueryResult> {
+    let messages = Messages::ru();
+    match card {
+        Card::Referral => {
+            let link = deep_link::link(me, &Payload::Referral(user));
+            let referred = storage.referral_count(user).unwrap_or_else(|e| {
+                log::error!("Failed to count referrals of {}: {}", logger::user(user), e);
+                0
+            });
+            let mut card = article(
+                "referral",
+                messages.inline_referral_title(),
+                messages.inline_referral(&link),
+            )
+            .description(messages.inline_referral_description(referred));
+            if let Some(keyboard) = keyboards::open_link(messages.inline_open_bot(), &link) {
+                card = card.reply_markup(keyboard);
+            }
+            vec![card.into()]
+        }
+        Card::HowTo => {
+            let link = deep_link::link(me, &Payload::Screen(Screen::Menu));
+            let mut card = article(
+                "how_to",
+                messages.inline_how_to_title(),
+                messages.inline_how_to(&link),
+            )
+            .description(messages.inline_how_to_description());
+            if let Some(keyboard) = keyboards::open_link(messages.inline_open_bot(), &link) {
+                card = card.reply_markup(keyboard);
+            }
+            vec![card.into()]
+        }
+        Card::Lookup(query) => match find::search(&get_client(), &query).await {
+            Ok(users) => users
+                .iter()
+                .take(MAX_LOOKUPS)
+                .map(|found| lookup(found).into())
+                .collect(),
+            Err(e) => {
+                log::error!("Inline lookup of {:?} failed: {}", query, e);
+                Vec::new()
+            }
+        },
+    }
+}
+
+/// Answers an inline query with the cards of its sender.
+///
+/// Answers are personal: Telegram caches them per user too.
+pub async fn answer(
+    bot: Bot,
+    q: InlineQuery,
+    me: Me,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    cache: Arc<InlineCache>,
+) -> HandlerResult {
+    let user = q.from.id;
+    log::debug!(
+        "User {} sent inline query {:?}",
+        logger::user(user),
+        q.query
+    );
+    metrics::record_command("inline");
+    let query = q.query.trim();
+
+    let now = Instant::now();
+    let answer = match cache.get(user, query, now) {
+        Some(answer) => answer,
+        None => {
+            let mut answer = Vec::new();
+            for card in cards(config.is_admin(user), query) {
+                answer.extend(results(card, user, &me, &storage).await);
+            }
+            cache.put(user, query, answer.clone(), now);
+            answer
+        }
+    };
+
+    bot.answer_inline_query(q.id, answer)
+        .cache_time(CACHE_TTL.as_secs() as u32)
+        .is_personal(true)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::test_user;
+
+    #[test]
+    fn only_admins_get_lookups() {
+        assert_eq!(cards(false, "alice"), vec![Card::Referral, Card::HowTo]);
+        assert_eq!(cards(true, "al"), vec![Card::Referral, Card::HowTo]);
+        assert_eq!(
+            cards(true, " alice "),
+            vec![
+                Card::Lookup("alice".to_string()),
+                Card::Referral,
+                Card::HowTo
+            ]
+        );
+    }
+
+    #[test]
+    fn lookups_send_no_identifiers() {
+        let mut user = test_user("alice", Some(123456789));
+        user.email = Some("alice@example.com".to_string());
+
+        let InputMessageContent::Text(content) = lookup(&user).input_message_content else {
+            panic!("lookup cards are text");
+        };
+
+        assert!(content.message_text.contains("alice"));
+        for secret in [
+            user.uuid.to_string(),
+            "123456789".to_string(),
+            "alice@example.com".to_string(),
+        ] {
+            assert!(!content.message_text.contains(&secret));
+        }
+    }
+
+    #[test]
+    fn cached_answers_are_personal_and_expire() {
+        let cache = InlineCache::new();
+        let now = Instant::now();
+        let answer: Vec<InlineQueryResult> =
+            vec![article("referral", "title", "ref_1".to_string()).into()];
+        cache.put(UserId(1), "", answer.clone(), now);
+
+        assert_eq!(cache.get(UserId(1), "", now), Some(answer));
+        assert_eq!(cache.get(UserId(2), "", now), None);
+        assert_eq!(cache.get(UserId(1), "other", now), None);
+        assert_eq!(cache.get(UserId(1), "", now + CACHE_TTL), None);
+    }
+}
@@ -1,5 +1,9 @@
+use crate::config::{Config, PaymentsConfig};
+use crate::export::Format;
 use crate::messages::Messages;
-use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use remnawave::api::types::users::UserData;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+use uuid::Uuid;
 
 pub fn main_menu() -> InlineKeyboardMarkup {
     InlineKeyboardMarkup::new(vec![
@@ -19,6 +23,15 @@ pub fn main_menu() -> InlineKeyboardMarkup {
             "Удалить подписку",
             "delete_me",
         )],
+        vec![InlineKeyboardButton::callback("Статистика", "stats:7")],
+        vec![InlineKeyboardButton::callback(
+            "Скачать конфигурацию",
+            "export",
+        )],
+        vec![InlineKeyboardButton::callback("Подарить подписку", "gift")],
+        vec![InlineKeyboardButton::callback("Баланс", "balance")],
+        vec![InlineKeyboardButton::callback("Семья", "family")],
+        vec![InlineKeyboardButton::callback("Поддержка", "support")],
     ])
 }
 
@@ -35,3 +48,319 @@ pub fn new_user_confirmation() -> InlineKeyboardMarkup {
         "create_new_user",
     )]])
 }
+
+pub fn broadcast_cancel() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "Отмена",
+        "broadcast:cancel",
+    )]])
+}
+
+pub fn broadcast_targets() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![
+        vec![InlineKeyboardButton::callback(
+            "Все пользователи",
+            "broadcast:target:all",
+        )],
+        vec![
+            InlineKeyboardButton::callback("Активные", "broadcast:target:active"),
+            InlineKeyboardButton::callback("Истёкшие", "broadcast:target:expired"),
+        ],
+        vec![
+            InlineKeyboardButton::callback("По тарифу", "broadcast:tags"),
+            InlineKeyboardButton::callback("По скваду", "broadcast:squads"),
+        ],
+        vec![InlineKeyboardButton::callback("Отмена", "broadcast:cancel")],
+    ])
+}
+
+pub fn broadcast_tags(tags: &[String]) -> InlineKeyboardMarkup {
+    let mut rows: Vec<_> = tags
+        .iter()
+        .map(|tag| {
+            vec![InlineKeyboardButton::callback(
+                tag.clone(),
+                format!("broadcast:tag:{}", tag),
+            )]
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback(
+        Messages::ru().back(),
+        "broadcast:targets",
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+pub fn broadcast_squads(squads: &[(Uuid, String)]) -> InlineKeyboardMarkup {
+    let mut rows: Vec<_> = squads
+        .iter()
+        .map(|(uuid, name)| {
+            vec![InlineKeyboardButton::callback(
+                name.clone(),
+                format!("broadcast:squad:{}", uuid),
+            )]
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback(
+        Messages::ru().back(),
+        "broadcast:targets",
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+pub fn broadcast_confirm() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Отправить", "broadcast:confirm"),
+        InlineKeyboardButton::callback("Отмена", "broadcast:cancel"),
+    ]])
+}
+
+pub fn find_results(users: &[(Uuid, String)], page: usize, pages: usize) -> InlineKeyboardMarkup {
+    let mut rows: Vec<_> = users
+        .iter()
+        .map(|(uuid, label)| {
+            vec![InlineKeyboardButton::callback(
+                label.clone(),
+                format!("find:user:{}", uuid),
+            )]
+        })
+        .collect();
+    let mut navigation = Vec::new();
+    if page > 0 {
+        navigation.push(InlineKeyboardButton::callback(
+            "◀️",
+            format!("find:page:{}", page - 1),
+        ));
+    }
+    if page + 1 < pages {
+        navigation.push(InlineKeyboardButton::callback(
+            "▶️",
+            format!("find:page:{}", page + 1),
+        ));
+    }
+    if !navigation.is_empty() {
+        rows.push(navigation);
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+pub fn admin_user_card(uuid: Uuid, enabled: bool) -> InlineKeyboardMarkup {
+    let toggle = if enabled {
+        InlineKeyboardButton::callback("⏸ Отключить", format!("find:disable:{}", uuid))
+    } else {
+        InlineKeyboardButton::callback("▶️ Включить", format!("find:enable:{}", uuid))
+    };
+    InlineKeyboardMarkup::new(vec![
+        vec![
+            toggle,
+            InlineKeyboardButton::callback("📅 +30 дней", format!("find:extend:{}", uuid)),
+        ],
+        vec![
+            InlineKeyboardButton::callback("🔄 Сбросить трафик", format!("find:reset:{}", uuid)),
+            InlineKeyboardButton::callback("🔗 Новая ссылка", format!("find:regen:{}", uuid)),
+        ],
+        vec![InlineKeyboardButton::callback(
+            "🗑 Удалить",
+            format!("find:delete:{}", uuid),
+        )],
+        vec![InlineKeyboardButton::callback(
+            "⬅️ К результатам",
+            "find:page:0",
+        )],
+    ])
+}
+
+pub fn find_delete_confirm(uuid: Uuid) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("Удалить", format!("find:delete_yes:{}", uuid)),
+        InlineKeyboardButton::callback("Отмена", format!("find:user:{}", uuid)),
+    ]])
+}
+
+pub fn registration_request() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "📨 Отправить заявку",
+        "request_access",
+    )]])
+}
+
+pub fn registration_decision(user: UserId) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("✅ Одобрить", format!("registration:approve:{}", user)),
+        InlineKeyboardButton::callback("🚫 Отклонить", format!("registration:reject:{}", user)),
+    ]])
+}
+
+pub fn support() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "🆘 Написать в поддержку",
+        "support",
+    )]])
+}
+
+pub fn support_close() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "Закрыть обращение",
+        "support_close",
+    )]])
+}
+
+pub fn support_ticket(id: i64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "🔒 Закрыть обращение",
+        format!("support:close:{}", id),
+    )]])
+}
+
+pub fn stats(days: u32) -> InlineKeyboardMarkup {
+    let period = |period: u32, label: &str| {
+        let label = if period == days {
+            format!("✅ {}", label)
+        } else {
+            label.to_string()
+        };
+        InlineKeyboardButton::callback(label, format!("stats:{}", period))
+    };
+    InlineKeyboardMarkup::new(vec![
+        vec![period(7, "7 дней"), period(30, "30 дней")],
+        vec![InlineKeyboardButton::callback(
+            Messages::ru().back(),
+            "stats:back",
+        )],
+    ])
+}
+
+pub fn export_formats() -> InlineKeyboardMarkup {
+    let mut rows: Vec<_> = Format::ALL
+        .into_iter()
+        .map(|format| {
+            vec![InlineKeyboardButton::callback(
+                format.label(),
+                format!("export:{}", format.as_str()),
+            )]
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback(
+        Messages::ru().back(),
+        "back_to_main_menu",
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+pub fn gift_plans(payments: &PaymentsConfig) -> InlineKeyboardMarkup {
+    let messages = Messages::ru();
+    let mut rows: Vec<_> = payments
+        .plans
+        .iter()
+        .map(|plan| {
+            vec![InlineKeyboardButton::callback(
+                messages.gift_plan_button(&plan.title, plan.price, &payments.currency),
+                format!("gift:{}", plan.id),
+            )]
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback(
+        messages.back(),
+        "back_to_main_menu",
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+pub fn family_intro() -> InlineKeyboardMarkup {
+    let messages = Messages::ru();
+    InlineKeyboardMarkup::new(vec![
+        vec![InlineKeyboardButton::callback(
+            messages.family_create(),
+            "family:create",
+        )],
+        vec![InlineKeyboardButton::callback(
+            messages.back(),
+            "back_to_main_menu",
+        )],
+    ])
+}
+
+/// Buttons removing each of `members`, renewing the invite link and going
+/// back.
+pub fn family_owner(members: &[(UserId, Option<UserData>)]) -> InlineKeyboardMarkup {
+    let messages = Messages::ru();
+    let mut rows: Vec<_> = members
+        .iter()
+        .map(|(member, panel_user)| {
+            let name = match panel_user {
+                Some(user) => user.username.clone(),
+                None => member.0.to_string(),
+            };
+            vec![InlineKeyboardButton::callback(
+                messages.family_remove_button(&name),
+                format!("family:remove:{}", member.0),
+            )]
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback(
+        messages.family_relink(),
+        "family:relink",
+    )]);
+    rows.push(vec![InlineKeyboardButton::callback(
+        messages.back(),
+        "back_to_main_menu",
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+pub fn family_member() -> InlineKeyboardMarkup {
+    let messages = Messages::ru();
+    InlineKeyboardMarkup::new(vec![
+        vec![InlineKeyboardButton::callback(
+            messages.family_leave(),
+            "family:leave",
+        )],
+        vec![InlineKeyboardButton::callback(
+            messages.back(),
+            "back_to_main_menu",
+        )],
+    ])
+}
+
+/// Top-ups, a renewal button for each plan (`renewal` is the plan that is
+/// on) and going back.
+pub fn balance(config: &Config, renewal: Option<&str>) -> InlineKeyboardMarkup {
+    let messages = Messages::ru();
+    let currency = &config.payments.currency;
+    let mut rows: Vec<_> = config
+        .balance
+        .top_up_amounts
+        .iter()
+        .map(|amount| {
+            vec![InlineKeyboardButton::callback(
+                messages.top_up_button(*amount, currency),
+                format!("balance:topup:{}", amount),
+            )]
+        })
+        .collect();
+    for plan in &config.payments.plans {
+        rows.push(vec![InlineKeyboardButton::callback(
+            messages.renew_button(&plan.title, renewal == Some(plan.id.as_str())),
+            format!("balance:renew:{}", plan.id),
+        )]);
+    }
+    if renewal.is_some() {
+        rows.push(vec![InlineKeyboardButton::callback(
+            messages.renew_off_button(),
+            "balance:renew:off",
+        )]);
+    }
+    rows.push(vec![InlineKeyboardButton::callback(
+        messages.back(),
+        "back_to_main_menu",
+    )]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// A single button opening `link`, `None` if it isn't a valid URL.
+pub fn open_link(label: &str, link: &str) -> Option<InlineKeyboardMarkup> {
+    let url = link.parse().ok()?;
+    Some(InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::url(label, url),
+    ]]))
+}
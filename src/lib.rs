@@ -1,22 +1,54 @@
+pub mod audit;
+pub mod balance;
+pub mod ban;
+pub mod broadcast;
 pub mod client;
+pub mod commands;
+pub mod config;
+pub mod deep_link;
 pub mod error;
+pub mod export;
+pub mod family;
+pub mod find;
+pub mod gift;
 pub mod handlers;
+pub mod health;
+pub mod inline;
 pub mod keyboards;
 pub mod logger;
 pub mod messages;
+pub mod metrics;
+pub mod outbox;
+pub mod promo;
+pub mod rate_limit;
+pub mod registration;
 pub mod schema;
+pub mod server;
+pub mod stats;
+pub mod storage;
+pub mod support;
+#[cfg(test)]
+mod test_panel;
 pub mod types;
+pub mod users;
+pub mod webhook;
 
+pub use config::Config;
 pub use error::MyError;
-pub use types::{Command, HandlerResult};
+pub use types::{AdminCommand, Command, HandlerResult};
 
-use teloxide::dispatching::Dispatcher;
+use std::sync::Arc;
+use teloxide::dispatching::{Dispatcher, dialogue::InMemStorage};
 
 /// Starts the GlebusVPN bot and dispatches updates.
 ///
-/// This function initializes the bot using the environment configuration,
-/// sets up the dispatcher with the schema, and enables a control-C handler
-/// for graceful shutdown. It then starts dispatching updates asynchronously.
+/// This function initializes the bot, the panel client, the database and the
+/// outgoing message queue from `config`, registers the command menus, starts
+/// the HTTP server (health checks, panel webhooks, metrics), the family
+/// plan sync, automatic renewals and the readiness probes in the
+/// background, sets up the dispatcher with the schema,
+/// and enables a control-C handler for graceful shutdown. It then starts
+/// dispatching updates asynchronously.
 ///
 /// # Returns
 ///
@@ -24,18 +56,71 @@ use teloxide::dispatching::Dispatcher;
 ///
 /// # Errors
 ///
-/// This function may return an error if the environment configuration is
-/// invalid or if the bot fails to start.
-pub async fn run() -> Result<(), MyError> {
+/// This function may return an error if the panel client cannot be created,
+/// the database cannot be opened or if the bot fails to start.
+pub async fn run(config: Config) -> Result<(), MyError> {
     log::info!("Starting GlebusVPN bot...");
 
-    let bot = teloxide::Bot::from_env();
+    client::init_client(&config)?;
+    stats::load_font(&config.chart_font_path);
+    let storage = storage::Storage::open(&config.database_path)?;
+    let config = Arc::new(config);
+    let bot = teloxide::Bot::new(&config.telegram_token);
+    let outbox = outbox::Outbox::start(bot.clone(), storage.clone(), &config.outbox);
 
+    tokio::spawn(commands::register(bot.clone(), config.clone()));
+
+    if config.family.max_members > 0 {
+        tokio::spawn(family::run_sync(
+            storage.clone(),
+            config.family.sync_interval,
+        ));
+    }
+
+    if !config.payments.plans.is_empty() {
+        tokio::spawn(balance::run_renewals(
+            config.clone(),
+            storage.clone(),
+            outbox.clone(),
+        ));
+    }
+
+    let health = health::Health::new();
+    tokio::spawn(health.clone().run_probes(bot.clone()));
+
+    let server_outbox = outbox.clone();
+    let server_config = config.clone();
+    let server_health = health.clone();
+    tokio::spawn(async move {
+        if let Err(e) = server::serve(server_outbox, server_config, server_health).await {
+            log::error!("HTTP server error: {}", e);
+        }
+    });
+
+    let rate_limiter = rate_limit::RateLimiter::new(&config.rate_limit);
+
+    health.set_dispatcher_running(true);
     Dispatcher::builder(bot, schema::schema())
+        .dependencies(dptree::deps![
+            config,
+            health.clone(),
+            rate_limiter,
+            storage,
+            outbox,
+            InMemStorage::<broadcast::BroadcastState>::new(),
+            InMemStorage::<find::FindState>::new(),
+            InMemStorage::<registration::RegistrationState>::new(),
+            inline::InlineCache::new()
+        ])
+        .error_handler(Arc::new(|error: MyError| async move {
+            metrics::record_error(&error);
+            log::error!("Error from the update handler: {}", error);
+        }))
         .enable_ctrlc_handler()
         .build()
         .dispatch()
         .await;
+    health.set_dispatcher_running(false);
 
     Ok(())
 }
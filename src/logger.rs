@@ -1,42 +1,139 @@
+use hmac::{Hmac, Mac};
 use log::LevelFilter;
 use log4rs::{
-    append::{console::ConsoleAppender, file::FileAppender},
-    config::{Appender, Config, Root},
-    encode::pattern::PatternEncoder,
+    append::{
+        console::ConsoleAppender,
+        rolling_file::{
+            RollingFileAppender,
+            policy::compound::{
+                CompoundPolicy,
+                roll::fixed_window::FixedWindowRoller,
+                trigger::{
+                    Trigger,
+                    size::SizeTrigger,
+                    time::{TimeTrigger, TimeTriggerConfig, TimeTriggerInterval},
+                },
+            },
+        },
+    },
+    config::{Appender, Config, Logger, Root},
+    encode::{Encode, json::JsonEncoder, pattern::PatternEncoder},
     filter::threshold::ThresholdFilter,
 };
+use once_cell::sync::OnceCell;
+use sha2::Sha256;
+use std::fmt;
+use teloxide::types::UserId;
 
+use crate::config::{LogFormat, LogRotation, LoggingConfig};
 use crate::error::MyError;
 
-pub fn init_logger() -> Result<(), MyError> {
-    let console_log_level = LevelFilter::Info;
-    let file_log_level = LevelFilter::Trace;
+/// Key of the user id hashes, set when hashing is enabled.
+static USER_HASH_KEY: OnceCell<String> = OnceCell::new();
+
+/// A Telegram user id as it should appear in logs.
+///
+/// Displays the id itself, or a keyed hash of it when
+/// `LOG_HASH_USER_IDS` is enabled.
+pub struct LogUser(pub u64);
+
+impl fmt::Display for LogUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match USER_HASH_KEY.get() {
+            Some(key) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+                    .expect("HMAC accepts keys of any length");
+                mac.update(self.0.to_string().as_bytes());
+                let hash = hex::encode(mac.finalize().into_bytes());
+                write!(f, "u:{}", &hash[..12])
+            }
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Wraps a Telegram user id for logging, see [`LogUser`].
+pub fn user(id: UserId) -> LogUser {
+    LogUser(id.0)
+}
+
+fn encoder(format: LogFormat) -> Box<dyn Encode> {
+    match format {
+        LogFormat::Pattern => Box::new(PatternEncoder::new("{d} - {l} - {m}{n}")),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    }
+}
+
+fn trigger(rotation: LogRotation) -> Box<dyn Trigger> {
+    let interval = match rotation {
+        LogRotation::Size(limit) => return Box::new(SizeTrigger::new(limit)),
+        LogRotation::Hourly => TimeTriggerInterval::Hour(1),
+        LogRotation::Daily => TimeTriggerInterval::Day(1),
+        LogRotation::Weekly => TimeTriggerInterval::Week(1),
+    };
+    Box::new(TimeTrigger::new(TimeTriggerConfig {
+        interval,
+        modulate: true,
+        max_random_delay: 0,
+    }))
+}
+
+/// Initializes logging.
+///
+/// If `config.config_file` is set, the log4rs YAML file is used as is.
+/// Otherwise logs go to the console and to a rolling file, rotated by size
+/// or time and keeping `config.retention` old files next to it.
+pub fn init_logger(config: &LoggingConfig) -> Result<(), MyError> {
+    if config.hash_user_ids {
+        USER_HASH_KEY.get_or_init(|| config.hash_salt.clone());
+    }
+
+    if let Some(path) = &config.config_file {
+        return log4rs::init_file(path, Default::default()).map_err(|e| {
+            MyError::Config(format!("cannot load log config {}: {}", path.display(), e))
+        });
+    }
 
     let console_appender = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} - {l} - {m}{n}")))
+        .encoder(encoder(config.format))
         .build();
 
-    let file_appender = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} - {l} - {m}{n}")))
-        .build("log/glebus_vpn_bot.log")?;
+    let file_path = config.file_path.display().to_string();
+    let roller = FixedWindowRoller::builder()
+        .build(&format!("{}.{{}}", file_path), config.retention.max(1))
+        .map_err(|e| MyError::Custom(format!("Failed to create log roller: {}", e)))?;
+    let file_appender = RollingFileAppender::builder()
+        .encoder(encoder(config.format))
+        .build(
+            &config.file_path,
+            Box::new(CompoundPolicy::new(
+                trigger(config.rotation),
+                Box::new(roller),
+            )),
+        )?;
 
-    let config = Config::builder()
+    let mut builder = Config::builder()
         .appender(
             Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(console_log_level)))
+                .filter(Box::new(ThresholdFilter::new(config.console_level)))
                 .build("console_appender", Box::new(console_appender)),
         )
         .appender(
             Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(file_log_level)))
+                .filter(Box::new(ThresholdFilter::new(config.file_level)))
                 .build("file_appender", Box::new(file_appender)),
-        )
-        .build(
-            Root::builder()
-                .appender("console_appender")
-                .appender("file_appender")
-                .build(LevelFilter::Trace),
-        )?;
+        );
+
+    for (module, level) in &config.module_levels {
+        builder = builder.logger(Logger::builder().build(module, *level));
+    }
+
+    let config = builder.build(
+        Root::builder()
+            .appender("console_appender")
+            .appender("file_appender")
+            .build(LevelFilter::Trace),
+    )?;
 
     log4rs::init_config(config)?;
     Ok(())
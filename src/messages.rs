@@ -1,6 +1,31 @@
+use crate::balance::{LedgerEntry, LedgerKind};
+use crate::config::Plan;
+use chrono::{DateTime, Utc};
+use remnawave::api::types::users::UserData;
+use teloxide::types::UserId;
+
+/// Formats a number of bytes as `1.23 ГБ`.
+pub fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["Б", "КБ", "МБ", "ГБ", "ТБ"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
 pub struct Messages;
 
 impl Messages {
+    /// Languages the bot has texts for, as IETF language tags.
+    pub const LANGUAGES: &[&str] = &["ru"];
+
     pub fn ru() -> RussianMessages {
         RussianMessages
     }
@@ -28,6 +53,32 @@ impl RussianMessages {
         )
     }
 
+    pub fn user_not_found(&self) -> String {
+        "🤷 У вас пока нет подписки на GlebusVPN.\n\n\
+         Хотите создать её? 😊"
+            .to_string()
+    }
+
+    pub fn multiple_users_found(&self) -> String {
+        "⚠️ К вашему аккаунту привязано несколько подписок. 😕\n\n\
+         Свяжитесь с администратором, чтобы разобраться."
+            .to_string()
+    }
+
+    pub fn panel_unavailable(&self) -> String {
+        "🛠 Сервис временно недоступен. 😕\n\n\
+         Мы уже знаем о проблеме, попробуйте через пару минут. 🔄"
+            .to_string()
+    }
+
+    pub fn slow_down(&self, seconds: u64) -> String {
+        format!(
+            "🐢 Не так быстро! Слишком много запросов.\n\n\
+             Попробуйте ещё раз через {} с. ⏳",
+            seconds
+        )
+    }
+
     pub fn new_user_confirmed(&self) -> String {
         "🚀 Давай!".to_string()
     }
@@ -35,4 +86,829 @@ impl RussianMessages {
     pub fn back(&self) -> String {
         "⬅️ Вернуться".to_string()
     }
+
+    pub fn subscription_expired(&self) -> String {
+        "⏰ Срок действия вашей подписки истёк.\n\n\
+         Свяжитесь с администратором, чтобы продлить её."
+            .to_string()
+    }
+
+    pub fn subscription_expires_in(&self, hours: u32) -> String {
+        format!(
+            "⏳ Ваша подписка истекает через {} ч.\n\n\
+             Свяжитесь с администратором, чтобы продлить её.",
+            hours
+        )
+    }
+
+    pub fn traffic_limit_reached(&self) -> String {
+        "📊 Вы израсходовали весь доступный трафик.\n\n\
+         Подключение будет недоступно до сброса лимита."
+            .to_string()
+    }
+
+    pub fn subscription_disabled(&self) -> String {
+        "🚫 Ваша подписка отключена администратором.".to_string()
+    }
+
+    pub fn subscription_enabled(&self) -> String {
+        "✅ Ваша подписка снова активна.".to_string()
+    }
+
+    pub fn node_connection_lost(&self, name: &str, address: &str) -> String {
+        format!("🔴 Потеряно соединение с нодой {} ({})", name, address)
+    }
+
+    pub fn node_connection_restored(&self, name: &str, address: &str) -> String {
+        format!("🟢 Соединение с нодой {} ({}) восстановлено", name, address)
+    }
+
+    pub fn node_disabled(&self, name: &str, address: &str) -> String {
+        format!("⏸ Нода {} ({}) отключена", name, address)
+    }
+
+    pub fn node_enabled(&self, name: &str, address: &str) -> String {
+        format!("▶️ Нода {} ({}) включена", name, address)
+    }
+
+    pub fn broadcast_prompt(&self) -> String {
+        "📣 Отправьте сообщение для рассылки: текст или фото с подписью. \
+         Форматирование сохранится."
+            .to_string()
+    }
+
+    pub fn broadcast_invalid_content(&self) -> String {
+        "⚠️ Рассылать можно только текст или фото с подписью.".to_string()
+    }
+
+    pub fn broadcast_choose_target(&self) -> String {
+        "👥 Кому отправить рассылку?".to_string()
+    }
+
+    pub fn broadcast_choose_tag(&self) -> String {
+        "🏷 Выберите тариф:".to_string()
+    }
+
+    pub fn broadcast_choose_squad(&self) -> String {
+        "🧩 Выберите сквад:".to_string()
+    }
+
+    pub fn broadcast_no_options(&self) -> String {
+        "🤷 В панели нет ни одного варианта, выберите других получателей.".to_string()
+    }
+
+    pub fn broadcast_preview(&self, target: &str, recipients: usize) -> String {
+        format!(
+            "👆 Так будет выглядеть рассылка.\n\n\
+             Получатели: {}\n\
+             Количество: {}\n\n\
+             Отправить?",
+            target, recipients
+        )
+    }
+
+    pub fn broadcast_no_recipients(&self, target: &str) -> String {
+        format!(
+            "🤷 Получателей нет ({}). Выберите других получателей.",
+            target
+        )
+    }
+
+    pub fn broadcast_started(&self, recipients: usize) -> String {
+        format!("🚀 Рассылка запущена, получателей: {}.", recipients)
+    }
+
+    pub fn broadcast_finished(&self, delivered: usize, blocked: usize, failed: usize) -> String {
+        format!(
+            "✅ Рассылка завершена.\n\n\
+             Доставлено: {}\n\
+             Заблокировали бота: {}\n\
+             Ошибки: {}",
+            delivered, blocked, failed
+        )
+    }
+
+    pub fn broadcast_cancelled(&self) -> String {
+        "❌ Рассылка отменена.".to_string()
+    }
+
+    pub fn broadcast_outdated(&self) -> String {
+        "⌛ Эта рассылка уже неактуальна, начните заново с /broadcast.".to_string()
+    }
+
+    pub fn find_usage(&self) -> String {
+        "🔎 Использование: /find <Telegram ID, username, email или UUID>".to_string()
+    }
+
+    pub fn find_nothing(&self, query: &str) -> String {
+        format!("🤷 По запросу «{}» никого не нашлось.", query)
+    }
+
+    pub fn find_results(&self, query: &str, total: usize, page: usize, pages: usize) -> String {
+        format!(
+            "🔎 По запросу «{}» найдено: {}.\nСтраница {} из {}.",
+            query,
+            total,
+            page + 1,
+            pages
+        )
+    }
+
+    pub fn admin_user_card(&self, user: &UserData, devices: Option<usize>) -> String {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "—".to_string());
+        let traffic_limit = if user.traffic_limit_bytes > 0 {
+            format_bytes(user.traffic_limit_bytes)
+        } else {
+            "без лимита".to_string()
+        };
+        let device_limit = or_dash(user.hwid_device_limit.map(|limit| limit.to_string()));
+        format!(
+            "👤 {}\n\
+             UUID: {}\n\
+             Telegram ID: {}\n\
+             Email: {}\n\n\
+             Статус: {}\n\
+             Трафик: {} из {}\n\
+             Активна до: {}\n\
+             Устройства: {} (лимит: {})",
+            user.username,
+            user.uuid,
+            or_dash(user.telegram_id.map(|id| id.to_string())),
+            or_dash(user.email.clone()),
+            user.status,
+            format_bytes(user.used_traffic_bytes),
+            traffic_limit,
+            user.expire_at.format("%Y-%m-%d %H:%M UTC"),
+            or_dash(devices.map(|count| count.to_string())),
+            device_limit
+        )
+    }
+
+    pub fn find_action_done(&self, card: &str) -> String {
+        format!("✅ Готово.\n\n{}", card)
+    }
+
+    pub fn find_user_gone(&self) -> String {
+        "🤷 Этого пользователя уже нет в панели.".to_string()
+    }
+
+    pub fn find_delete_confirm(&self, username: &str) -> String {
+        format!(
+            "🗑 Удалить пользователя {}? Подписка перестанет работать.",
+            username
+        )
+    }
+
+    pub fn find_deleted(&self, username: &str) -> String {
+        format!("🗑 Пользователь {} удалён.", username)
+    }
+
+    pub fn find_failed(&self, error: &str) -> String {
+        format!("❌ Не получилось: {}", error)
+    }
+
+    pub fn find_outdated(&self) -> String {
+        "⌛ Эти результаты устарели, повторите /find.".to_string()
+    }
+
+    pub fn audit_usage(&self, argument: &str) -> String {
+        format!(
+            "❓ Непонятный аргумент «{}».\n\
+             Использование: /audit [actor=<Telegram ID>] [target=<UUID>] [action=<действие>] \
+             [days=<дней>] [format=csv|json]",
+            argument
+        )
+    }
+
+    pub fn audit_empty(&self) -> String {
+        "📭 В журнале нет подходящих записей.".to_string()
+    }
+
+    pub fn audit_entries(&self, count: usize, entries: &str) -> String {
+        format!("📜 Последние записи журнала ({}):\n\n{}", count, entries)
+    }
+
+    pub fn audit_export(&self, count: usize) -> String {
+        format!("📜 Записей в выгрузке: {}", count)
+    }
+
+    pub fn registration_invite_required(&self) -> String {
+        "🔒 GlebusVPN работает по приглашениям.\n\n\
+         Отправьте код приглашения следующим сообщением."
+            .to_string()
+    }
+
+    pub fn registration_invite_invalid(&self) -> String {
+        "❌ Код не подошёл: он неверный или уже использован. Попробуйте ещё раз.".to_string()
+    }
+
+    pub fn registration_invite_accepted(&self) -> String {
+        "✅ Код принят! Теперь можно создать подписку 🚀".to_string()
+    }
+
+    pub fn registration_approval_required(&self) -> String {
+        "🔒 Доступ к GlebusVPN выдаётся после одобрения администратором.\n\n\
+         Отправить заявку?"
+            .to_string()
+    }
+
+    pub fn registration_request_sent(&self) -> String {
+        "📨 Заявка отправлена. Мы сообщим, когда её рассмотрят.".to_string()
+    }
+
+    pub fn registration_refused(&self) -> String {
+        "🚫 К сожалению, с этого аккаунта нельзя зарегистрироваться. \
+         Если у вас нет имени пользователя в Telegram, добавьте его в настройках."
+            .to_string()
+    }
+
+    pub fn registration_pending(&self) -> String {
+        "⏳ Ваша заявка ещё на рассмотрении.".to_string()
+    }
+
+    pub fn registration_rejected(&self) -> String {
+        "🚫 К сожалению, ваша заявка на доступ отклонена.".to_string()
+    }
+
+    pub fn registration_approved(&self) -> String {
+        "🎉 Ваша заявка одобрена! Теперь можно создать подписку 🚀".to_string()
+    }
+
+    pub fn registration_request(&self, name: &str, username: Option<&str>, id: UserId) -> String {
+        match username {
+            Some(username) => format!(
+                "📨 Заявка на доступ\n{} (@{}), Telegram ID: {}",
+                name, username, id
+            ),
+            None => format!("📨 Заявка на доступ\n{}, Telegram ID: {}", name, id),
+        }
+    }
+
+    pub fn registration_decided(&self, id: UserId, approved: bool, admin: &str) -> String {
+        if approved {
+            format!("✅ Заявка {} одобрена ({}).", id, admin)
+        } else {
+            format!("🚫 Заявка {} отклонена ({}).", id, admin)
+        }
+    }
+
+    pub fn registration_already_decided(&self) -> String {
+        "Эта заявка уже рассмотрена.".to_string()
+    }
+
+    pub fn invite_usage(&self) -> String {
+        "🎟 Использование: /invite [число использований]".to_string()
+    }
+
+    pub fn invite_created(&self, code: &str, uses: u32, link: &str) -> String {
+        format!(
+            "🎟 Код приглашения: {}\nИспользований: {}\nСсылка: {}",
+            code, uses, link
+        )
+    }
+
+    pub fn promo_usage(&self) -> String {
+        "🎁 Использование: /promo <дней> [число использований] — продление подписки,\n\
+         /promo balance <сумма> [число использований] — пополнение баланса"
+            .to_string()
+    }
+
+    pub fn promo_reward_days(&self, days: u32) -> String {
+        format!("Продлевает подписку на {} дн.", days)
+    }
+
+    pub fn promo_reward_credit(&self, amount: u32, currency: &str) -> String {
+        format!("Пополняет баланс на {} {}", amount, currency)
+    }
+
+    pub fn promo_created(&self, code: &str, reward: &str, uses: u32, link: &str) -> String {
+        format!(
+            "🎁 Промокод: {}\n{}\nИспользований: {}\nСсылка: {}",
+            code, reward, uses, link
+        )
+    }
+
+    pub fn promo_invalid(&self) -> String {
+        "❌ Промокод не подошёл: он неверный, закончился или уже был использован вами.".to_string()
+    }
+
+    pub fn promo_redeemed(&self, days: u32, expire_at: DateTime<Utc>) -> String {
+        format!(
+            "🎁 Промокод применён: подписка продлена на {} дн., до {}.",
+            days,
+            expire_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+
+    pub fn promo_credited(&self, amount: u32, currency: &str, balance: i64) -> String {
+        format!(
+            "🎁 Промокод применён: на баланс зачислено {} {}, теперь на нём {} {}.",
+            amount, currency, balance, currency
+        )
+    }
+
+    pub fn promo_needs_subscription(&self) -> String {
+        "🎁 Промокод можно применить после создания подписки: \
+         создайте её и откройте ссылку с промокодом ещё раз."
+            .to_string()
+    }
+
+    pub fn gift_pick_plan(&self) -> String {
+        "🎁 Подарите подписку близким: выберите тариф, оплатите его и \
+         перешлите получателю ссылку на подарок."
+            .to_string()
+    }
+
+    pub fn gift_unavailable(&self) -> String {
+        "🎁 Подарки сейчас недоступны.".to_string()
+    }
+
+    pub fn gift_plan_button(&self, title: &str, price: u32, currency: &str) -> String {
+        format!("{} — {} {}", title, price, currency)
+    }
+
+    pub fn gift_invoice_title(&self, title: &str) -> String {
+        format!("Подарочная подписка: {}", title)
+    }
+
+    pub fn gift_invoice_description(&self, days: u32) -> String {
+        format!(
+            "Подписка на VPN на {} дн. в подарок. После оплаты вы получите \
+             ссылку, которую нужно переслать получателю.",
+            days
+        )
+    }
+
+    pub fn gift_plan_gone(&self) -> String {
+        "Этот тариф больше не продаётся, выберите другой.".to_string()
+    }
+
+    pub fn gift_paid(&self, days: u32, code: &str, link: &str) -> String {
+        format!(
+            "🎁 Спасибо за покупку! Подарок на {} дн. готов.\n\
+             Перешлите получателю ссылку: {}\n\
+             Код подарка: {}\n\
+             Ссылку можно открыть только один раз, мы сообщим, когда ею воспользуются.",
+            days, link, code
+        )
+    }
+
+    pub fn gift_payment_unknown(&self) -> String {
+        "❗ Оплата получена, но подарок не удалось оформить. \
+         Напишите в поддержку, мы всё исправим."
+            .to_string()
+    }
+
+    pub fn gift_invalid(&self) -> String {
+        "❌ Подарок не найден или уже был получен.".to_string()
+    }
+
+    pub fn gift_own(&self) -> String {
+        "🎁 Это ваш подарок: перешлите ссылку на него получателю.".to_string()
+    }
+
+    pub fn gift_redeemed(&self, days: u32, expire_at: DateTime<Utc>) -> String {
+        format!(
+            "🎁 Вам подарили подписку на {} дн.! Она действует до {}.",
+            days,
+            expire_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+
+    pub fn gift_redeemed_giver(&self, days: u32, recipient: &str) -> String {
+        format!(
+            "🎁 {} получил(а) ваш подарок: подписку на {} дн.",
+            recipient, days
+        )
+    }
+
+    pub fn family_unavailable(&self) -> String {
+        "👨‍👩‍👧 Семейные подписки сейчас недоступны.".to_string()
+    }
+
+    pub fn family_intro(&self, max_members: u32) -> String {
+        format!(
+            "👨‍👩‍👧 Семейная подписка: пригласите до {} близких по ссылке. \
+             Каждый получит свою подписку, которая действует столько же, \
+             сколько ваша, и с тем же лимитом устройств.",
+            max_members
+        )
+    }
+
+    pub fn family_create(&self) -> String {
+        "Создать семью".to_string()
+    }
+
+    pub fn family_owner(
+        &self,
+        link: &str,
+        members: &[(UserId, Option<UserData>)],
+        max_members: u32,
+    ) -> String {
+        let mut text = format!(
+            "👨‍👩‍👧 Ваша семья: {} из {} участников.\nСсылка-приглашение: {}\n",
+            members.len(),
+            max_members,
+            link
+        );
+        for (member, panel_user) in members {
+            text.push('\n');
+            text.push_str(&match panel_user {
+                Some(user) => format!(
+                    "• {} — трафик {}, активна до {}",
+                    user.username,
+                    format_bytes(user.used_traffic_bytes),
+                    user.expire_at.format("%Y-%m-%d")
+                ),
+                None => format!("• {} — подписки нет", member.0),
+            });
+        }
+        text
+    }
+
+    pub fn family_member(&self, owner: &str, expire_at: DateTime<Utc>) -> String {
+        format!(
+            "👨‍👩‍👧 Вы в семье {}. Подписка действует до {}, пока её продлевает владелец.",
+            owner,
+            expire_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+
+    pub fn family_remove_button(&self, name: &str) -> String {
+        format!("Удалить {}", name)
+    }
+
+    pub fn family_relink(&self) -> String {
+        "Новая ссылка-приглашение".to_string()
+    }
+
+    pub fn family_leave(&self) -> String {
+        "Покинуть семью".to_string()
+    }
+
+    pub fn family_invalid(&self) -> String {
+        "❌ Приглашение в семью не найдено или устарело.".to_string()
+    }
+
+    pub fn family_own_link(&self) -> String {
+        "👨‍👩‍👧 Это приглашение в вашу семью: перешлите его близким.".to_string()
+    }
+
+    pub fn family_already_member(&self) -> String {
+        "👨‍👩‍👧 Вы уже состоите в семье или владеете своей.".to_string()
+    }
+
+    pub fn family_has_subscription(&self) -> String {
+        "👨‍👩‍👧 У вас уже есть действующая подписка. \
+         Вступить в семью можно, когда она закончится."
+            .to_string()
+    }
+
+    pub fn family_full(&self) -> String {
+        "👨‍👩‍👧 В этой семье уже нет свободных мест.".to_string()
+    }
+
+    pub fn family_joined(&self, owner: &str, expire_at: DateTime<Utc>) -> String {
+        format!(
+            "👨‍👩‍👧 Вы вступили в семью {}! Подписка действует до {}.",
+            owner,
+            expire_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+
+    pub fn family_member_joined(&self, name: &str) -> String {
+        format!("👨‍👩‍👧 {} вступил(а) в вашу семью.", name)
+    }
+
+    pub fn family_removed(&self) -> String {
+        "👨‍👩‍👧 Владелец удалил вас из семьи, семейная подписка больше не действует.".to_string()
+    }
+
+    pub fn family_member_left(&self, name: &str) -> String {
+        format!("👨‍👩‍👧 {} покинул(а) вашу семью.", name)
+    }
+
+    pub fn family_left(&self) -> String {
+        "👨‍👩‍👧 Вы покинули семью, семейная подписка больше не действует.".to_string()
+    }
+
+    pub fn family_member_days(&self) -> String {
+        "👨‍👩‍👧 Вашу подписку продлевает владелец семьи, поэтому добавить к ней дни нельзя. \
+         Передайте код тому, у кого своя подписка."
+            .to_string()
+    }
+
+    pub fn balance(
+        &self,
+        balance: i64,
+        currency: &str,
+        renewal: Option<&Plan>,
+        ledger: &[LedgerEntry],
+    ) -> String {
+        let mut text = format!("💰 Баланс: {} {}\n", balance, currency);
+        text.push_str(&match renewal {
+            Some(plan) => format!(
+                "Автопродление: {} ({} дн. за {} {})",
+                plan.title, plan.days, plan.price, currency
+            ),
+            None => "Автопродление выключено".to_string(),
+        });
+        if !ledger.is_empty() {
+            text.push_str("\n\nПоследние операции:");
+            for entry in ledger {
+                let kind = match entry.kind {
+                    LedgerKind::TopUp => "пополнение",
+                    LedgerKind::Referral => "бонус за приглашение",
+                    LedgerKind::Promo => "промокод",
+                    LedgerKind::Renewal => "автопродление",
+                };
+                text.push_str(&format!(
+                    "\n{} {:+} {} — {}",
+                    entry.at.format("%Y-%m-%d"),
+                    entry.amount,
+                    currency,
+                    kind
+                ));
+            }
+        }
+        text
+    }
+
+    pub fn top_up_button(&self, amount: u32, currency: &str) -> String {
+        format!("Пополнить на {} {}", amount, currency)
+    }
+
+    pub fn renew_button(&self, title: &str, enabled: bool) -> String {
+        if enabled {
+            format!("✅ Автопродление: {}", title)
+        } else {
+            format!("Автопродление: {}", title)
+        }
+    }
+
+    pub fn renew_off_button(&self) -> String {
+        "Отключить автопродление".to_string()
+    }
+
+    pub fn top_up_title(&self) -> String {
+        "Пополнение баланса".to_string()
+    }
+
+    pub fn top_up_description(&self, amount: u32, currency: &str) -> String {
+        format!(
+            "Пополнение баланса GlebusVPN на {} {}. С баланса оплачивается автопродление подписки.",
+            amount, currency
+        )
+    }
+
+    pub fn top_up_unavailable(&self) -> String {
+        "Эта сумма пополнения больше недоступна, выберите другую.".to_string()
+    }
+
+    pub fn topped_up(&self, amount: u32, currency: &str, balance: i64) -> String {
+        format!(
+            "💰 Баланс пополнен на {} {}, теперь на нём {} {}.",
+            amount, currency, balance, currency
+        )
+    }
+
+    pub fn referral_bonus(&self, amount: u32, currency: &str, balance: i64) -> String {
+        format!(
+            "💰 Приглашённый вами пользователь оплатил подписку: на баланс зачислено {} {}, \
+             теперь на нём {} {}.",
+            amount, currency, balance, currency
+        )
+    }
+
+    pub fn renewal_warning(
+        &self,
+        plan: &Plan,
+        currency: &str,
+        balance: i64,
+        expire_at: DateTime<Utc>,
+    ) -> String {
+        format!(
+            "⚠️ Подписка заканчивается {}, а на балансе {} {} — не хватает для \
+             автопродления тарифом «{}» за {} {}. Пополните баланс.",
+            expire_at.format("%Y-%m-%d %H:%M UTC"),
+            balance,
+            currency,
+            plan.title,
+            plan.price,
+            currency
+        )
+    }
+
+    pub fn renewal_family_member(&self) -> String {
+        "Семейную подписку продлевает владелец семьи.".to_string()
+    }
+
+    pub fn renewed(
+        &self,
+        plan: &Plan,
+        currency: &str,
+        balance: i64,
+        expire_at: DateTime<Utc>,
+    ) -> String {
+        format!(
+            "💰 Подписка продлена тарифом «{}» до {}. С баланса списано {} {}, осталось {} {}.",
+            plan.title,
+            expire_at.format("%Y-%m-%d %H:%M UTC"),
+            plan.price,
+            currency,
+            balance,
+            currency
+        )
+    }
+
+    pub fn ban_usage(&self) -> String {
+        "⛔ Использование: /ban <Telegram ID> [причина]".to_string()
+    }
+
+    pub fn ban_admin_refused(&self) -> String {
+        "⛔ Администратора заблокировать нельзя.".to_string()
+    }
+
+    pub fn ban_done(&self, id: UserId, reason: Option<&str>, panel_disabled: bool) -> String {
+        let mut text = format!("⛔ Пользователь {} заблокирован.", id);
+        if let Some(reason) = reason {
+            text.push_str(&format!("\nПричина: {}", reason));
+        }
+        if panel_disabled {
+            text.push_str("\nЕго подписка в панели отключена.");
+        }
+        text
+    }
+
+    pub fn unban_usage(&self) -> String {
+        "✅ Использование: /unban <Telegram ID>".to_string()
+    }
+
+    pub fn unban_not_banned(&self, id: UserId) -> String {
+        format!("🤷 Пользователь {} не заблокирован.", id)
+    }
+
+    pub fn unban_done(&self, id: UserId, panel_enabled: bool) -> String {
+        if panel_enabled {
+            format!(
+                "✅ Пользователь {} разблокирован, его подписка в панели снова включена.",
+                id
+            )
+        } else {
+            format!("✅ Пользователь {} разблокирован.", id)
+        }
+    }
+
+    pub fn support_unavailable(&self) -> String {
+        "😔 Поддержка сейчас недоступна, попробуйте позже.".to_string()
+    }
+
+    pub fn support_opened(&self, id: i64) -> String {
+        format!(
+            "🆘 Обращение #{} открыто.\n\n\
+             Опишите проблему одним или несколькими сообщениями — можно \
+             прикладывать скриншоты и файлы с логами. Ответ придёт в этот чат.",
+            id
+        )
+    }
+
+    pub fn support_ticket_header(
+        &self,
+        id: i64,
+        name: &str,
+        username: Option<&str>,
+        user: UserId,
+        panel_info: &str,
+    ) -> String {
+        let from = match username {
+            Some(username) => format!("{} (@{})", name, username),
+            None => name.to_string(),
+        };
+        format!(
+            "🆘 Обращение #{}\nОт: {}, Telegram ID: {}\n\n{}\n\n\
+             Отвечайте на сообщения обращения, чтобы ответ дошёл до пользователя.",
+            id, from, user, panel_info
+        )
+    }
+
+    pub fn support_no_subscription(&self) -> String {
+        "Подписки в панели нет.".to_string()
+    }
+
+    pub fn support_panel_error(&self) -> String {
+        "Не удалось получить данные из панели.".to_string()
+    }
+
+    pub fn support_closed(&self, id: i64) -> String {
+        format!(
+            "🔒 Обращение #{} закрыто. Если понадобится помощь — нажмите «Поддержка» в меню.",
+            id
+        )
+    }
+
+    pub fn support_closed_by_user(&self, id: i64) -> String {
+        format!("🔒 Пользователь закрыл обращение #{}.", id)
+    }
+
+    pub fn support_closed_by_admin(&self, id: i64, admin: &str) -> String {
+        format!("🔒 Обращение #{} закрыто ({}).", id, admin)
+    }
+
+    pub fn support_reply_to_closed(&self, id: i64) -> String {
+        format!("🔒 Обращение #{} уже закрыто, ответ не отправлен.", id)
+    }
+
+    pub fn support_delivery_failed(&self, error: &str) -> String {
+        format!("⚠️ Не удалось доставить ответ пользователю: {}", error)
+    }
+
+    pub fn stats(&self, days: u32, total: u64, nodes: &[(String, u64)]) -> String {
+        let mut text = format!("📊 Трафик за {} дней: {}", days, format_bytes(total as i64));
+        if !nodes.is_empty() {
+            text.push_str("\n\nПо серверам:");
+            for (node, total) in nodes {
+                text.push_str(&format!("\n• {}: {}", node, format_bytes(*total as i64)));
+            }
+        }
+        text
+    }
+
+    pub fn export_pick_format(&self) -> &'static str {
+        "Выберите формат файла конфигурации для вашего VPN-клиента:\n\n\
+         • sing-box — sing-box, Hiddify, NekoBox\n\
+         • Clash / Mihomo — Clash Verge, FlClash, Mihomo\n\
+         • Xray — v2rayN, v2rayNG и Xray-core"
+    }
+
+    pub fn export_ready(&self, format: &str, servers: usize, skipped: usize) -> String {
+        let mut text = format!("📄 Конфигурация {}, серверов: {}.", format, servers);
+        if skipped > 0 {
+            text.push_str(&format!(
+                "\nПропущено серверов с неподдерживаемыми протоколами: {}.",
+                skipped
+            ));
+        }
+        text.push_str("\n\nНе передавайте этот файл другим людям.");
+        text
+    }
+
+    pub fn export_empty(&self) -> &'static str {
+        "В вашей подписке нет серверов, которые можно выгрузить в файл. \
+         Используйте ссылку на подписку."
+    }
+
+    pub fn inline_open_bot(&self) -> &'static str {
+        "🚀 Открыть GlebusVPN"
+    }
+
+    pub fn inline_referral_title(&self) -> &'static str {
+        "Пригласить в GlebusVPN"
+    }
+
+    pub fn inline_referral_description(&self, referred: u64) -> String {
+        format!("Ваша ссылка-приглашение. Приглашено: {}", referred)
+    }
+
+    pub fn inline_referral(&self, link: &str) -> String {
+        format!(
+            "🔐 Пользуюсь GlebusVPN и приглашаю тебя!\n\n\
+             Подключиться можно через бота: {}",
+            link
+        )
+    }
+
+    pub fn inline_how_to_title(&self) -> &'static str {
+        "Как подключиться"
+    }
+
+    pub fn inline_lookup(&self, user: &UserData) -> String {
+        format!(
+            "👤 {}\nСтатус: {}\nАктивна до: {}",
+            user.username,
+            user.status,
+            user.expire_at.format("%Y-%m-%d")
+        )
+    }
+
+    pub fn inline_how_to_description(&self) -> &'static str {
+        "Инструкция по настройке VPN"
+    }
+
+    pub fn inline_how_to(&self, link: &str) -> String {
+        format!(
+            "📖 Как подключиться к GlebusVPN:\n\n\
+             1. Откройте бота: {}\n\
+             2. Нажмите «Старт» и создайте подписку.\n\
+             3. Установите VPN-клиент: Happ, v2rayNG, Hiddify или Clash Verge.\n\
+             4. Добавьте в него ссылку на подписку из бота \
+             или скачайте файл конфигурации.\n\
+             5. Включите VPN 🚀",
+            link
+        )
+    }
+
+    pub fn private_only(&self) -> &'static str {
+        "🔒 Подписка и личные данные доступны только в личном чате с ботом. \
+         Продолжим там?"
+    }
 }
@@ -0,0 +1,164 @@
+use crate::error::MyError;
+use std::future::Future;
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use once_cell::sync::Lazy;
+    use prometheus::{
+        Encoder, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder, register_histogram_vec,
+        register_int_counter_vec, register_int_gauge_vec,
+    };
+
+    pub static COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "bot_commands_total",
+            "Number of handled bot commands",
+            &["command"]
+        )
+        .expect("Failed to register bot_commands_total")
+    });
+
+    pub static CALLBACKS: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "bot_callbacks_total",
+            "Number of handled callback actions",
+            &["action"]
+        )
+        .expect("Failed to register bot_callbacks_total")
+    });
+
+    pub static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "bot_rate_limited_total",
+            "Number of updates rejected by the per-user rate limiter",
+            &["action"]
+        )
+        .expect("Failed to register bot_rate_limited_total")
+    });
+
+    pub static PANEL_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+        register_histogram_vec!(
+            "panel_api_duration_seconds",
+            "Latency of Remnawave panel API calls",
+            &["operation"]
+        )
+        .expect("Failed to register panel_api_duration_seconds")
+    });
+
+    pub static PANEL_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "panel_api_errors_total",
+            "Number of failed Remnawave panel API calls",
+            &["operation"]
+        )
+        .expect("Failed to register panel_api_errors_total")
+    });
+
+    pub static TELEGRAM_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "telegram_api_errors_total",
+            "Number of Telegram API errors",
+            &["kind"]
+        )
+        .expect("Failed to register telegram_api_errors_total")
+    });
+
+    pub static QUEUE_SIZE: Lazy<IntGaugeVec> = Lazy::new(|| {
+        register_int_gauge_vec!(
+            "scheduler_queue_size",
+            "Number of jobs waiting in a scheduler queue",
+            &["queue"]
+        )
+        .expect("Failed to register scheduler_queue_size")
+    });
+
+    pub fn render() -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Counts a handled bot command, e.g. `start`.
+pub fn record_command(command: &str) {
+    #[cfg(feature = "metrics")]
+    imp::COMMANDS.with_label_values(&[command]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = command;
+}
+
+/// Counts an update rejected by the rate limiter.
+pub fn record_rate_limited(action: &str) {
+    #[cfg(feature = "metrics")]
+    imp::RATE_LIMITED.with_label_values(&[action]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = action;
+}
+
+/// Counts a handled callback action, e.g. `show_sub_link`.
+pub fn record_callback(action: &str) {
+    #[cfg(feature = "metrics")]
+    imp::CALLBACKS.with_label_values(&[action]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = action;
+}
+
+/// Counts an error by kind if it came from the Telegram API.
+pub fn record_error(error: &MyError) {
+    #[cfg(feature = "metrics")]
+    if let MyError::Teloxide(e) = error {
+        let kind = match e {
+            teloxide::RequestError::Api(_) => "api",
+            teloxide::RequestError::MigrateToChatId(_) => "migrate_to_chat_id",
+            teloxide::RequestError::RetryAfter(_) => "retry_after",
+            teloxide::RequestError::Network(_) => "network",
+            teloxide::RequestError::InvalidJson { .. } => "invalid_json",
+            teloxide::RequestError::Io(_) => "io",
+        };
+        imp::TELEGRAM_ERRORS.with_label_values(&[kind]).inc();
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = error;
+}
+
+/// Sets the current number of jobs waiting in `queue`.
+pub fn set_queue_size(queue: &str, size: usize) {
+    #[cfg(feature = "metrics")]
+    imp::QUEUE_SIZE
+        .with_label_values(&[queue])
+        .set(i64::try_from(size).unwrap_or(i64::MAX));
+    #[cfg(not(feature = "metrics"))]
+    let _ = (queue, size);
+}
+
+/// Awaits a panel API call, recording its latency and failure under `operation`.
+pub async fn track_panel<T, E>(
+    operation: &str,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    #[cfg(feature = "metrics")]
+    {
+        let timer = imp::PANEL_DURATION
+            .with_label_values(&[operation])
+            .start_timer();
+        let result = call.await;
+        timer.observe_duration();
+        if result.is_err() {
+            imp::PANEL_ERRORS.with_label_values(&[operation]).inc();
+        }
+        result
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = operation;
+        call.await
+    }
+}
+
+/// Axum handler for `GET /metrics` in the Prometheus text format.
+#[cfg(feature = "metrics")]
+pub async fn render() -> String {
+    imp::render()
+}
@@ -0,0 +1,535 @@
+use crate::config::OutboxConfig;
+use crate::error::MyError;
+use crate::logger::LogUser;
+use crate::metrics;
+use crate::storage::Storage;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use teloxide::payloads::{SendMessageSetters, SendPhotoSetters};
+use teloxide::prelude::*;
+use teloxide::types::{
+    FileId, InlineKeyboardMarkup, InputFile, MessageEntity, MessageId, ParseMode, ReplyParameters,
+};
+use teloxide::{ApiError, RequestError};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Delay before the first retry of a message that failed on the network;
+/// doubles with every attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// What a message consists of.
+#[derive(Debug, Clone)]
+pub enum MessageContent {
+    Text(String),
+    Photo {
+        photo: FileId,
+        caption: Option<String>,
+    },
+}
+
+/// A message waiting to be sent.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub chat_id: ChatId,
+    pub content: MessageContent,
+    /// Formatting of the text or the caption, instead of `parse_mode`.
+    pub entities: Option<Vec<MessageEntity>>,
+    pub parse_mode: Option<ParseMode>,
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+    /// Message in the same chat this one answers.
+    pub reply_to: Option<MessageId>,
+}
+
+impl OutgoingMessage {
+    pub fn new(chat_id: ChatId, content: MessageContent) -> Self {
+        Self {
+            chat_id,
+            content,
+            entities: None,
+            parse_mode: None,
+            reply_markup: None,
+            reply_to: None,
+        }
+    }
+
+    pub fn text(chat_id: ChatId, text: impl Into<String>) -> Self {
+        Self::new(chat_id, MessageContent::Text(text.into()))
+    }
+
+    pub fn entities(mut self, entities: Vec<MessageEntity>) -> Self {
+        self.entities = Some(entities);
+        self
+    }
+
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = Some(parse_mode);
+        self
+    }
+
+    pub fn reply_markup(mut self, reply_markup: InlineKeyboardMarkup) -> Self {
+        self.reply_markup = Some(reply_markup);
+        self
+    }
+
+    pub fn reply_to(mut self, message_id: MessageId) -> Self {
+        self.reply_to = Some(message_id);
+        self
+    }
+}
+
+/// What happened to a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    Delivered,
+    /// The user blocked the bot or deleted their account.
+    Blocked,
+    /// Sending failed for another reason, or too many times.
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct Job {
+    message: OutgoingMessage,
+    /// Failed attempts so far.
+    attempt: u32,
+    /// Not to be sent before this moment, set when retrying.
+    not_before: Option<Instant>,
+    done: Option<oneshot::Sender<Delivery>>,
+}
+
+impl Job {
+    fn finish(self, delivery: Delivery) {
+        if let Some(done) = self.done {
+            let _ = done.send(delivery);
+        }
+    }
+}
+
+/// Order in which queued messages go out.
+///
+/// Messages are sent first in, first out, except that a chat that got a
+/// message less than `per_chat_interval` ago is skipped for now. Messages
+/// to the same chat keep their order. Nothing is sent while paused.
+#[derive(Default)]
+struct Scheduler {
+    pending: VecDeque<Job>,
+    next_allowed: HashMap<ChatId, Instant>,
+    per_chat_interval: Duration,
+    paused_until: Option<Instant>,
+}
+
+/// Result of [`Scheduler::next`].
+enum Next {
+    Ready(Job),
+    /// Nothing can be sent before the given moment.
+    Wait(Instant),
+    Empty,
+}
+
+impl Scheduler {
+    fn push(&mut self, job: Job) {
+        if let Some(not_before) = job.not_before {
+            let next = self
+                .next_allowed
+                .entry(job.message.chat_id)
+                .or_insert(not_before);
+            *next = (*next).max(not_before);
+        }
+        // A retried message was queued before everything else for its chat.
+        if job.attempt > 0 || job.not_before.is_some() {
+            self.pending.push_front(job);
+        } else {
+            self.pending.push_back(job);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Holds back every chat until `until`.
+    fn pause(&mut self, until: Instant) {
+        self.paused_until = Some(self.paused_until.map_or(until, |paused| paused.max(until)));
+    }
+
+    fn next(&mut self, now: Instant) -> Next {
+        self.next_allowed.retain(|_, next| *next > now);
+        self.paused_until = self.paused_until.filter(|until| *until > now);
+        if let Some(until) = self.paused_until {
+            return if self.pending.is_empty() {
+                Next::Empty
+            } else {
+                Next::Wait(until)
+            };
+        }
+
+        let ready = self
+            .pending
+            .iter()
+            .position(|job| !self.next_allowed.contains_key(&job.message.chat_id));
+        match ready.and_then(|index| self.pending.remove(index)) {
+            Some(job) => {
+                self.next_allowed
+                    .insert(job.message.chat_id, now + self.per_chat_interval);
+                Next::Ready(job)
+            }
+            None => self
+                .pending
+                .iter()
+                .filter_map(|job| self.next_allowed.get(&job.message.chat_id))
+                .min()
+                .map_or(Next::Empty, |&at| Next::Wait(at)),
+        }
+    }
+}
+
+/// Central queue of outgoing messages.
+///
+/// Enforces the global and per-chat flood limits of Telegram, stops sending
+/// for as long as Telegram asks on `RetryAfter`, retries network errors and marks users
+/// who blocked the bot in [`Storage`]. Bulk and background messages
+/// (notifications, broadcasts) go through it; replies to the user's own
+/// actions are sent directly.
+pub struct Outbox {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl Outbox {
+    /// Starts the queue worker in the background.
+    pub fn start(bot: Bot, storage: Arc<Storage>, config: &OutboxConfig) -> Arc<Self> {
+        let (jobs, receiver) = mpsc::unbounded_channel();
+        let worker = Worker {
+            bot,
+            storage,
+            jobs: jobs.clone(),
+            max_attempts: config.max_attempts,
+            paused_until: Mutex::new(None),
+        };
+        let scheduler = Scheduler {
+            per_chat_interval: config.per_chat_interval,
+            ..Default::default()
+        };
+        let interval = Duration::from_secs(1) / config.global_per_second.max(1);
+        tokio::spawn(worker.run(receiver, scheduler, interval));
+        Arc::new(Self { jobs })
+    }
+
+    fn push(&self, message: OutgoingMessage, done: Option<oneshot::Sender<Delivery>>) {
+        let job = Job {
+            message,
+            attempt: 0,
+            not_before: None,
+            done,
+        };
+        if self.jobs.send(job).is_err() {
+            log::error!("Outbox worker is gone, message dropped");
+        }
+    }
+
+    /// Queues a message without waiting for it to be sent.
+    pub fn enqueue(&self, message: OutgoingMessage) {
+        self.push(message, None);
+    }
+
+    /// Queues a message and waits until it is sent or given up on.
+    pub async fn send(&self, message: OutgoingMessage) -> Delivery {
+        let (done, result) = oneshot::channel();
+        self.push(message, Some(done));
+        result
+            .await
+            .unwrap_or_else(|_| Delivery::Failed("outbox worker is gone".to_string()))
+    }
+
+    /// Queues all `messages` at once and waits until every one of them is
+    /// sent or given up on. Results are in the order of `messages`.
+    pub async fn send_all(&self, messages: Vec<OutgoingMessage>) -> Vec<Delivery> {
+        let results: Vec<_> = messages
+            .into_iter()
+            .map(|message| {
+                let (done, result) = oneshot::channel();
+                self.push(message, Some(done));
+                result
+            })
+            .collect();
+
+        let mut deliveries = Vec::with_capacity(results.len());
+        for result in results {
+            deliveries.push(
+                result
+                    .await
+                    .unwrap_or_else(|_| Delivery::Failed("outbox worker is gone".to_string())),
+            );
+        }
+        deliveries
+    }
+}
+
+/// Whether the error means the bot can no longer write to the chat.
+fn is_blocked(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(
+            ApiError::BotBlocked
+            | ApiError::UserDeactivated
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::BotKickedFromChannel
+            | ApiError::CantInitiateConversation,
+        ) => true,
+        RequestError::Api(ApiError::Unknown(text)) => text.starts_with("Forbidden"),
+        _ => false,
+    }
+}
+
+struct Worker {
+    bot: Bot,
+    storage: Arc<Storage>,
+    /// Used to put retried messages back into the queue.
+    jobs: mpsc::UnboundedSender<Job>,
+    max_attempts: u32,
+    /// Set by a `RetryAfter`, which holds for the whole bot, and handed
+    /// over to the scheduler by the queue loop.
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl Worker {
+    async fn run(
+        self,
+        mut receiver: mpsc::UnboundedReceiver<Job>,
+        mut scheduler: Scheduler,
+        interval: Duration,
+    ) {
+        let worker = Arc::new(self);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            while let Ok(job) = receiver.try_recv() {
+                scheduler.push(job);
+            }
+            if let Some(until) = worker.paused_until.lock().unwrap().take() {
+                scheduler.pause(until);
+            }
+            metrics::set_queue_size("outbox", scheduler.len());
+
+            match scheduler.next(Instant::now()) {
+                Next::Ready(job) => {
+                    ticker.tick().await;
+                    tokio::spawn(worker.clone().deliver(job));
+                }
+                Next::Wait(until) => {
+                    tokio::select! {
+                        job = receiver.recv() => match job {
+                            Some(job) => scheduler.push(job),
+                            None => break,
+                        },
+                        _ = tokio::time::sleep_until(until) => {}
+                    }
+                }
+                Next::Empty => match receiver.recv().await {
+                    Some(job) => scheduler.push(job),
+                    None => break,
+                },
+            }
+        }
+    }
+
+    fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        *paused_until = Some(paused_until.map_or(until, |paused| paused.max(until)));
+    }
+
+    fn retry(&self, mut job: Job, delay: Duration) {
+        job.not_before = Some(Instant::now() + delay);
+        if let Err(e) = self.jobs.send(job) {
+            e.0.finish(Delivery::Failed("outbox worker is gone".to_string()));
+        }
+    }
+
+    async fn send(&self, message: &OutgoingMessage) -> Result<(), RequestError> {
+        match &message.content {
+            MessageContent::Text(text) => {
+                let mut request = self.bot.send_message(message.chat_id, text.clone());
+                if let Some(entities) = message.entities.clone() {
+                    request = request.entities(entities);
+                }
+                if let Some(parse_mode) = message.parse_mode {
+                    request = request.parse_mode(parse_mode);
+                }
+                if let Some(reply_markup) = message.reply_markup.clone() {
+                    request = request.reply_markup(reply_markup);
+                }
+                if let Some(reply_to) = message.reply_to {
+                    request = request.reply_parameters(
+                        ReplyParameters::new(reply_to).allow_sending_without_reply(),
+                    );
+                }
+                request.await?;
+            }
+            MessageContent::Photo { photo, caption } => {
+                let mut request = self
+                    .bot
+                    .send_photo(message.chat_id, InputFile::file_id(photo.clone()));
+                if let Some(caption) = caption.clone() {
+                    request = request.caption(caption);
+                }
+                if let Some(entities) = message.entities.clone() {
+                    request = request.caption_entities(entities);
+                }
+                if let Some(parse_mode) = message.parse_mode {
+                    request = request.parse_mode(parse_mode);
+                }
+                if let Some(reply_markup) = message.reply_markup.clone() {
+                    request = request.reply_markup(reply_markup);
+                }
+                if let Some(reply_to) = message.reply_to {
+                    request = request.reply_parameters(
+                        ReplyParameters::new(reply_to).allow_sending_without_reply(),
+                    );
+                }
+                request.await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn deliver(self: Arc<Self>, mut job: Job) {
+        let message = &job.message;
+        match self.send(message).await {
+            Ok(()) => job.finish(Delivery::Delivered),
+            Err(RequestError::RetryAfter(seconds)) if job.attempt + 1 < self.max_attempts => {
+                log::warn!(
+                    "Telegram asked to retry after {}s sending to {}",
+                    seconds.seconds(),
+                    LogUser(message.chat_id.0 as u64)
+                );
+                // Paused before the job goes back, as its arrival wakes the queue loop.
+                self.pause(seconds.duration());
+                job.attempt += 1;
+                self.retry(job, seconds.duration());
+            }
+            Err(e) if is_blocked(&e) => {
+                let chat_id = message.chat_id;
+                log::info!("Chat {} blocked the bot", LogUser(chat_id.0 as u64));
+                if chat_id.is_user()
+                    && let Err(e) = self.storage.mark_blocked(chat_id)
+                {
+                    log::error!(
+                        "Failed to mark user {} as blocked: {}",
+                        LogUser(chat_id.0 as u64),
+                        e
+                    );
+                }
+                job.finish(Delivery::Blocked);
+            }
+            Err(e @ (RequestError::Network(_) | RequestError::Io(_)))
+                if job.attempt + 1 < self.max_attempts =>
+            {
+                log::warn!(
+                    "Failed to send to {}, retrying: {}",
+                    LogUser(message.chat_id.0 as u64),
+                    e
+                );
+                let delay = RETRY_BASE_DELAY * 2u32.pow(job.attempt);
+                job.attempt += 1;
+                self.retry(job, delay);
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to send to {}: {}",
+                    LogUser(message.chat_id.0 as u64),
+                    e
+                );
+                let text = e.to_string();
+                metrics::record_error(&MyError::from(e));
+                job.finish(Delivery::Failed(text));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(chat: i64) -> Job {
+        Job {
+            message: OutgoingMessage::text(ChatId(chat), "test"),
+            attempt: 0,
+            not_before: None,
+            done: None,
+        }
+    }
+
+    fn scheduler() -> Scheduler {
+        Scheduler {
+            per_chat_interval: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    fn chat(next: Next) -> i64 {
+        match next {
+            Next::Ready(job) => job.message.chat_id.0,
+            _ => panic!("expected a ready job"),
+        }
+    }
+
+    #[test]
+    fn skips_chats_within_interval() {
+        let mut scheduler = scheduler();
+        let now = Instant::now();
+        scheduler.push(job(1));
+        scheduler.push(job(1));
+        scheduler.push(job(2));
+
+        assert_eq!(chat(scheduler.next(now)), 1);
+        assert_eq!(chat(scheduler.next(now)), 2);
+        assert!(
+            matches!(scheduler.next(now), Next::Wait(at) if at == now + Duration::from_secs(1))
+        );
+        assert_eq!(chat(scheduler.next(now + Duration::from_secs(1))), 1);
+        assert!(matches!(scheduler.next(now), Next::Empty));
+    }
+
+    #[test]
+    fn retried_job_waits_and_goes_first() {
+        let mut scheduler = scheduler();
+        let now = Instant::now();
+        scheduler.push(job(1));
+        let mut retried = job(1);
+        retried.message.content = MessageContent::Text("retried".to_string());
+        retried.not_before = Some(now + Duration::from_secs(5));
+        scheduler.push(retried);
+
+        assert!(
+            matches!(scheduler.next(now), Next::Wait(at) if at == now + Duration::from_secs(5))
+        );
+        match scheduler.next(now + Duration::from_secs(5)) {
+            Next::Ready(job) => {
+                assert!(
+                    matches!(job.message.content, MessageContent::Text(text) if text == "retried")
+                )
+            }
+            _ => panic!("expected a ready job"),
+        }
+    }
+
+    #[test]
+    fn pause_holds_back_every_chat() {
+        let mut scheduler = scheduler();
+        let now = Instant::now();
+        scheduler.push(job(1));
+        scheduler.push(job(2));
+        scheduler.pause(now + Duration::from_secs(5));
+        scheduler.pause(now + Duration::from_secs(3));
+
+        assert!(
+            matches!(scheduler.next(now), Next::Wait(at) if at == now + Duration::from_secs(5))
+        );
+        assert_eq!(chat(scheduler.next(now + Duration::from_secs(5))), 1);
+        assert_eq!(chat(scheduler.next(now + Duration::from_secs(5))), 2);
+    }
+}
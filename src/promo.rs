@@ -0,0 +1,210 @@
+use crate::audit;
+use crate::balance::{LedgerKind, Posting};
+use crate::client::get_client;
+use crate::config::Config;
+use crate::deep_link::{self, Payload};
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::registration;
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use crate::users;
+use chrono::{Duration, Utc};
+use remnawave::api::types::users::UserData;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{Me, User};
+
+/// What a promo code grants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reward {
+    /// Days added to the subscription.
+    Days(u32),
+    /// An amount credited to the balance, see [`crate::balance`].
+    Credit(u32),
+}
+
+/// Reads `<days> [uses]` or `balance <amount> [uses]` of the `/promo`
+/// command.
+fn parse_args(args: &str) -> Option<(Reward, u32)> {
+    let mut args = args.split_whitespace().peekable();
+    let credit = args.next_if_eq(&"balance").is_some();
+    let value = args.next()?.parse().ok().filter(|value| *value > 0)?;
+    let reward = if credit {
+        Reward::Credit(value)
+    } else {
+        Reward::Days(value)
+    };
+    let uses = match args.next() {
+        Some(uses) => uses.parse().ok().filter(|uses| *uses > 0)?,
+        None => 1,
+    };
+    args.next().is_none().then_some((reward, uses))
+}
+
+/// Handles the admin `/promo <days> [uses]` and `/promo balance <amount>
+/// [uses]` commands by generating a promo code and its link.
+pub async fn create(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    me: Me,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    let Some(admin) = &msg.from else {
+        return Ok(());
+    };
+    log::info!("Admin {} called /promo {}", logger::user(admin.id), args);
+    metrics::record_command("promo");
+    let messages = Messages::ru();
+
+    let Some((reward, uses)) = parse_args(&args) else {
+        bot.send_message(msg.chat.id, messages.promo_usage())
+            .await?;
+        return Ok(());
+    };
+    let code = registration::generate_code();
+    storage.create_promo(&code, reward, admin.id, uses)?;
+    let link = deep_link::link(&me, &Payload::Promo(code.clone()));
+    let reward = match reward {
+        Reward::Days(days) => messages.promo_reward_days(days),
+        Reward::Credit(amount) => messages.promo_reward_credit(amount, &config.payments.currency),
+    };
+    bot.send_message(
+        msg.chat.id,
+        messages.promo_created(&code, &reward, uses, &link),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Redeems promo `code` for `user`, extending their subscription or
+/// crediting their balance. Only the balance can be credited to users
+/// without a `panel_user`.
+///
+/// A use is given back if there is no subscription to extend, if the panel
+/// fails to extend it or if `user` is a family member, whose days the
+/// family sync would undo.
+pub async fn redeem(
+    bot: &Bot,
+    chat_id: ChatId,
+    user: &User,
+    panel_user: Option<&UserData>,
+    code: &str,
+    config: &Config,
+    storage: &Storage,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    let Some(reward) = storage.redeem_promo(code, user.id)? else {
+        log::info!("User {} sent an invalid promo code", logger::user(user.id));
+        bot.send_message(chat_id, messages.promo_invalid())
+            .reply_markup(keyboards::back_to_main_menu())
+            .await?;
+        return Ok(());
+    };
+    let (days, panel_user) = match (reward, panel_user) {
+        (Reward::Days(_), None) => {
+            storage.revert_promo(code, user.id)?;
+            bot.send_message(chat_id, messages.promo_needs_subscription())
+                .await?;
+            return Ok(());
+        }
+        // Members follow the plan of their family, which would undo the days.
+        (Reward::Days(_), Some(_)) if storage.family_of(user.id)?.is_some() => {
+            storage.revert_promo(code, user.id)?;
+            bot.send_message(chat_id, messages.family_member_days())
+                .reply_markup(keyboards::back_to_main_menu())
+                .await?;
+            return Ok(());
+        }
+        (Reward::Days(days), Some(panel_user)) => (days, panel_user),
+        (Reward::Credit(amount), _) => {
+            let currency = &config.payments.currency;
+            let balance = match storage.post(user.id, amount.into(), LedgerKind::Promo, code)? {
+                Posting::Done(balance) => balance,
+                Posting::Duplicate | Posting::Insufficient => storage.balance(user.id)?,
+            };
+            log::info!(
+                "User {} redeemed a promo code for {} {}",
+                logger::user(user.id),
+                amount,
+                currency
+            );
+            bot.send_message(chat_id, messages.promo_credited(amount, currency, balance))
+                .reply_markup(keyboards::back_to_main_menu())
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let result = {
+        let _guard = users::locks().lock(user.id.0).await;
+        let from = panel_user.expire_at.max(Utc::now());
+        get_client()
+            .set_user_expiry(panel_user.uuid, from + Duration::days(i64::from(days)))
+            .await
+    };
+    audit::record(
+        storage,
+        user.id,
+        "promo",
+        Some(panel_user),
+        result.as_ref().map(Some),
+    );
+    let after = match result {
+        Ok(after) => after,
+        Err(e) => {
+            storage.revert_promo(code, user.id)?;
+            return Err(e);
+        }
+    };
+    log::info!(
+        "User {} redeemed a promo code for {} days",
+        logger::user(user.id),
+        days
+    );
+    bot.send_message(chat_id, messages.promo_redeemed(days, after.expire_at))
+        .reply_markup(keyboards::back_to_main_menu())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_days_and_uses() {
+        assert_eq!(parse_args("30"), Some((Reward::Days(30), 1)));
+        assert_eq!(parse_args(" 7 100 "), Some((Reward::Days(7), 100)));
+        assert_eq!(
+            parse_args("balance 250 10"),
+            Some((Reward::Credit(250), 10))
+        );
+        assert_eq!(parse_args("balance"), None);
+        assert_eq!(parse_args(""), None);
+        assert_eq!(parse_args("0"), None);
+        assert_eq!(parse_args("30 0"), None);
+        assert_eq!(parse_args("30 5 extra"), None);
+    }
+
+    #[test]
+    fn promo_codes_are_redeemed_once_per_user() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .create_promo("CODE", Reward::Days(30), UserId(1), 2)
+            .unwrap();
+        let days = Some(Reward::Days(30));
+
+        assert_eq!(storage.redeem_promo("CODE", UserId(10)).unwrap(), days);
+        assert_eq!(storage.redeem_promo("CODE", UserId(10)).unwrap(), None);
+        storage.revert_promo("CODE", UserId(10)).unwrap();
+        assert_eq!(storage.redeem_promo("CODE", UserId(10)).unwrap(), days);
+        assert_eq!(storage.redeem_promo("CODE", UserId(11)).unwrap(), days);
+        assert_eq!(storage.redeem_promo("CODE", UserId(12)).unwrap(), None);
+        assert_eq!(storage.redeem_promo("OTHER", UserId(12)).unwrap(), None);
+    }
+}
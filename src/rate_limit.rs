@@ -0,0 +1,329 @@
+use crate::config::RateLimitConfig;
+use crate::types::{AdminCommand, Command};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::types::{Update, UpdateKind, UserId};
+use teloxide::utils::command::BotCommands;
+
+/// Actions that call the panel several times or change the subscription,
+/// limited to one per `expensive_cooldown`.
+const EXPENSIVE_ACTIONS: &[&str] = &["create_new_user", "recreate_sub_link", "delete_me"];
+
+/// Number of buckets after which full ones are forgotten.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Refill rule of a token bucket.
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+    capacity: f64,
+    /// Tokens added per second.
+    refill_rate: f64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the user was already told to slow down since the bucket ran out.
+    notified: bool,
+}
+
+/// An update rejected by the rate limiter.
+#[derive(Debug, Clone, Copy)]
+pub struct Throttled {
+    /// How long until the action is allowed again.
+    pub retry_after: Duration,
+    /// Whether the user should be told, which happens once per rejection streak.
+    pub notify: bool,
+}
+
+/// Token buckets per Telegram ID and action.
+///
+/// Ordinary actions share the `burst`/`per_minute` rule, expensive ones
+/// get a single token refilled every `expensive_cooldown`.
+pub struct RateLimiter {
+    normal: Rule,
+    expensive: Rule,
+    buckets: Mutex<HashMap<(u64, String), Bucket>>,
+}
+
+impl Rule {
+    fn is_full(&self, bucket: &Bucket, now: Instant) -> bool {
+        bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.refill_rate
+            >= self.capacity
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            normal: Rule {
+                capacity: f64::from(config.burst),
+                refill_rate: f64::from(config.per_minute) / 60.0,
+            },
+            expensive: Rule {
+                capacity: 1.0,
+                refill_rate: 1.0 / config.expensive_cooldown.as_secs_f64().max(0.001),
+            },
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn rule(&self, action: &str) -> Rule {
+        if EXPENSIVE_ACTIONS.contains(&action) {
+            self.expensive
+        } else {
+            self.normal
+        }
+    }
+
+    /// Takes a token for `action` of `user`, or says how long to wait.
+    pub fn check(&self, user: UserId, action: &str) -> Result<(), Throttled> {
+        self.check_at(user, action, Instant::now())
+    }
+
+    fn check_at(&self, user: UserId, action: &str, now: Instant) -> Result<(), Throttled> {
+        let rule = self.rule(action);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|(_, action), bucket| !self.rule(action).is_full(bucket, now));
+        }
+
+        let bucket = buckets
+            .entry((user.0, action.to_string()))
+            .or_insert(Bucket {
+                tokens: rule.capacity,
+                updated: now,
+                notified: false,
+            });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rule.refill_rate).min(rule.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.notified = false;
+            Ok(())
+        } else {
+            let notify = !bucket.notified;
+            bucket.notified = true;
+            Err(Throttled {
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rule.refill_rate),
+                notify,
+            })
+        }
+    }
+}
+
+/// Action of plain messages, whose sender may be in a conversation that
+/// is exempt from limits, see [`crate::schema`].
+pub const MESSAGE: &str = "message";
+
+/// The user and the action of an update, if it is limited at all.
+///
+/// Known commands are keyed by name, other messages share the
+/// [`MESSAGE`] action, button presses are keyed by the screen their
+/// callback data starts with. Messages outside private chats aren't
+/// limited: support replies and albums come in bursts, and the bot doesn't
+/// talk back in groups.
+pub fn action(update: &Update) -> Option<(UserId, String)> {
+    match &update.kind {
+        // Payments are never dropped: the user has already been charged.
+        UpdateKind::Message(msg) if msg.successful_payment().is_some() => None,
+        UpdateKind::Message(msg) if !msg.chat.is_private() => None,
+        UpdateKind::Message(msg) => {
+            let user = msg.from.as_ref()?.id;
+            let command = msg
+                .text()
+                .and_then(|text| text.strip_prefix('/'))
+                .map(|command| {
+                    let command = command.split_whitespace().next().unwrap_or_default();
+                    let command = command.split('@').next().unwrap_or_default();
+                    command.to_lowercase()
+                })
+                .filter(|command| is_command(command));
+            Some((user, command.unwrap_or_else(|| MESSAGE.to_string())))
+        }
+        UpdateKind::CallbackQuery(q) => {
+            let data = q.data.as_deref().unwrap_or_default();
+            let screen = data.split(':').next().unwrap_or_default();
+            Some((q.from.id, screen.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `name` is a command of the bot, so that made-up ones don't
+/// get buckets of their own.
+fn is_command(name: &str) -> bool {
+    Command::bot_commands()
+        .into_iter()
+        .chain(AdminCommand::bot_commands())
+        .any(|command| command.command.trim_start_matches('/') == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> Arc<RateLimiter> {
+        RateLimiter::new(&RateLimitConfig {
+            burst: 3,
+            per_minute: 60,
+            expensive_cooldown: Duration::from_secs(30),
+        })
+    }
+
+    #[test]
+    fn allows_burst_then_throttles() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(UserId(1), "start", now).is_ok());
+        }
+        let throttled = limiter.check_at(UserId(1), "start", now).unwrap_err();
+
+        assert!(throttled.notify);
+        assert_eq!(throttled.retry_after, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn notifies_once_per_streak() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.check_at(UserId(1), "start", now).unwrap();
+        }
+
+        assert!(
+            limiter
+                .check_at(UserId(1), "start", now)
+                .unwrap_err()
+                .notify
+        );
+        assert!(
+            !limiter
+                .check_at(UserId(1), "start", now)
+                .unwrap_err()
+                .notify
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(UserId(1), "start", later).is_ok());
+        assert!(
+            limiter
+                .check_at(UserId(1), "start", later)
+                .unwrap_err()
+                .notify
+        );
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.check_at(UserId(1), "start", now).unwrap();
+        }
+
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.check_at(UserId(1), "start", later).is_ok());
+        assert!(limiter.check_at(UserId(1), "start", later).is_ok());
+        assert!(limiter.check_at(UserId(1), "start", later).is_err());
+    }
+
+    #[test]
+    fn users_and_actions_are_independent() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.check_at(UserId(1), "start", now).unwrap();
+        }
+
+        assert!(limiter.check_at(UserId(2), "start", now).is_ok());
+        assert!(limiter.check_at(UserId(1), "show_sub_link", now).is_ok());
+    }
+
+    #[test]
+    fn expensive_actions_have_cooldown() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(
+            limiter
+                .check_at(UserId(1), "recreate_sub_link", now)
+                .is_ok()
+        );
+        let throttled = limiter
+            .check_at(
+                UserId(1),
+                "recreate_sub_link",
+                now + Duration::from_secs(10),
+            )
+            .unwrap_err();
+        assert_eq!(throttled.retry_after.as_secs_f64().round(), 20.0);
+        assert!(
+            limiter
+                .check_at(
+                    UserId(1),
+                    "recreate_sub_link",
+                    now + Duration::from_secs(31)
+                )
+                .is_ok()
+        );
+    }
+
+    fn update(json: serde_json::Value) -> Update {
+        serde_json::from_str(&json.to_string()).unwrap()
+    }
+
+    fn message(chat: serde_json::Value, text: &str) -> Update {
+        update(serde_json::json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": chat,
+                "from": { "id": 7, "is_bot": false, "first_name": "A" },
+                "text": text
+            }
+        }))
+    }
+
+    fn private(text: &str) -> Option<(UserId, String)> {
+        let chat = serde_json::json!({ "id": 7, "type": "private", "first_name": "A" });
+        action(&message(chat, text))
+    }
+
+    #[test]
+    fn keys_actions_by_command_and_screen() {
+        let key = |action: &str| Some((UserId(7), action.to_string()));
+        assert_eq!(private("/start ref_1"), key("start"));
+        assert_eq!(private("/Start@bot"), key("start"));
+        assert_eq!(private("/a1"), key(MESSAGE));
+        assert_eq!(private("hello"), key(MESSAGE));
+
+        let group = message(
+            serde_json::json!({ "id": -100, "type": "supergroup", "title": "Support" }),
+            "/start",
+        );
+        assert_eq!(action(&group), None);
+
+        let callback = |data: &str| {
+            action(&update(serde_json::json!({
+                "update_id": 1,
+                "callback_query": {
+                    "id": "1",
+                    "from": { "id": 7, "is_bot": false, "first_name": "A" },
+                    "chat_instance": "1",
+                    "data": data
+                }
+            })))
+        };
+        assert_eq!(callback("stats:7"), callback("stats:30"));
+        assert_eq!(callback("find:page:2"), key("find"));
+        assert_eq!(callback("delete_me"), key("delete_me"));
+    }
+}
@@ -0,0 +1,469 @@
+use crate::config::{Config, RegistrationConfig, RegistrationMode};
+use crate::deep_link::{self, Payload};
+use crate::error::MyError;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::outbox::{Outbox, OutgoingMessage};
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use rand::seq::IndexedRandom;
+use std::str::FromStr;
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::{Dialogue, GetChatId, InMemStorage};
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardMarkup, Me, MessageId, User};
+
+pub type RegistrationDialogue = Dialogue<RegistrationState, InMemStorage<RegistrationState>>;
+
+/// Characters of invite codes, without ones that are easy to confuse.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+
+/// Whether the bot waits for an invite code from the user.
+#[derive(Clone, Debug, Default)]
+pub enum RegistrationState {
+    #[default]
+    Idle,
+    AwaitInviteCode,
+}
+
+/// Where a registration stands, as kept in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl RegistrationStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RegistrationStatus::Pending => "pending",
+            RegistrationStatus::Approved => "approved",
+            RegistrationStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl FromStr for RegistrationStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(RegistrationStatus::Pending),
+            "approved" => Ok(RegistrationStatus::Approved),
+            "rejected" => Ok(RegistrationStatus::Rejected),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a user without a subscription may create one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allowed,
+    /// The account matches a rule that refuses registration.
+    Refused,
+    NeedsInvite,
+    NeedsApproval,
+    Pending,
+    Rejected,
+}
+
+fn decide(mode: RegistrationMode, status: Option<RegistrationStatus>) -> Access {
+    match (mode, status) {
+        (RegistrationMode::Open, _) | (_, Some(RegistrationStatus::Approved)) => Access::Allowed,
+        (RegistrationMode::Invite, _) => Access::NeedsInvite,
+        (RegistrationMode::Approval, None) => Access::NeedsApproval,
+        (RegistrationMode::Approval, Some(RegistrationStatus::Pending)) => Access::Pending,
+        (RegistrationMode::Approval, Some(RegistrationStatus::Rejected)) => Access::Rejected,
+    }
+}
+
+/// Whether the account breaks one of the configured registration rules.
+fn is_refused(config: &RegistrationConfig, user: &User) -> bool {
+    (config.deny_bots && user.is_bot) || (config.require_username && user.username.is_none())
+}
+
+/// Whether `user` may create a subscription under the configured
+/// registration rules and mode.
+///
+/// Admins always may.
+pub fn access(config: &Config, storage: &Storage, user: &User) -> Result<Access, MyError> {
+    if config.is_admin(user.id) {
+        return Ok(Access::Allowed);
+    }
+    if is_refused(&config.registration, user) {
+        return Ok(Access::Refused);
+    }
+    if config.registration.mode == RegistrationMode::Open {
+        return Ok(Access::Allowed);
+    }
+    Ok(decide(
+        config.registration.mode,
+        storage.registration_status(user.id)?,
+    ))
+}
+
+/// A random invite or promo code.
+pub(crate) fn generate_code() -> String {
+    let mut rng = rand::rng();
+    (0..CODE_LENGTH)
+        .map(|_| char::from(*CODE_ALPHABET.choose(&mut rng).unwrap()))
+        .collect()
+}
+
+/// Sends `text`, or puts it in place of `message_id` if given.
+async fn reply(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> HandlerResult {
+    match (message_id, keyboard) {
+        (Some(id), Some(keyboard)) => {
+            bot.edit_message_text(chat_id, id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        (Some(id), None) => {
+            bot.edit_message_text(chat_id, id, text).await?;
+        }
+        (None, Some(keyboard)) => {
+            bot.send_message(chat_id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        (None, None) => {
+            bot.send_message(chat_id, text).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Greets a user without a subscription according to their `access`.
+///
+/// Allowed users get the button creating a subscription, others are asked
+/// for an invite code or to request approval, or told how their request
+/// stands.
+pub async fn welcome(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: Option<MessageId>,
+    access: Access,
+    dialogues: Arc<InMemStorage<RegistrationState>>,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    let (text, keyboard) = match access {
+        Access::Allowed => (
+            messages.welcome_prompt(),
+            Some(keyboards::new_user_confirmation()),
+        ),
+        Access::NeedsInvite => {
+            RegistrationDialogue::new(dialogues, chat_id)
+                .update(RegistrationState::AwaitInviteCode)
+                .await?;
+            (messages.registration_invite_required(), None)
+        }
+        Access::NeedsApproval => (
+            messages.registration_approval_required(),
+            Some(keyboards::registration_request()),
+        ),
+        Access::Refused => (messages.registration_refused(), None),
+        Access::Pending => (messages.registration_pending(), None),
+        Access::Rejected => (messages.registration_rejected(), None),
+    };
+    reply(bot, chat_id, message_id, text, keyboard).await
+}
+
+/// Receives an invite code from a user asked for one.
+pub async fn receive_invite_code(
+    bot: Bot,
+    msg: Message,
+    dialogue: RegistrationDialogue,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    let Some(user) = &msg.from else {
+        return Ok(());
+    };
+    let messages = Messages::ru();
+    let code = msg.text().unwrap_or_default().trim().to_uppercase();
+
+    if code.is_empty() || !storage.redeem_invite(&code, user.id)? {
+        log::info!("User {} sent an invalid invite code", logger::user(user.id));
+        bot.send_message(msg.chat.id, messages.registration_invite_invalid())
+            .await?;
+        return Ok(());
+    }
+
+    log::info!(
+        "User {} registered with an invite code",
+        logger::user(user.id)
+    );
+    dialogue.exit().await?;
+    bot.send_message(msg.chat.id, messages.registration_invite_accepted())
+        .reply_markup(keyboards::new_user_confirmation())
+        .await?;
+    Ok(())
+}
+
+/// Chats that receive registration requests: the admin chat if configured,
+/// otherwise every admin.
+fn approval_chats(config: &Config) -> Vec<ChatId> {
+    match config.admin_chat_id {
+        Some(chat) => vec![chat],
+        None => config
+            .admin_ids
+            .iter()
+            .map(|&id| ChatId::from(id))
+            .collect(),
+    }
+}
+
+/// Handles the "request access" button by sending the request to the admins.
+pub async fn request_access(
+    bot: &Bot,
+    q: &CallbackQuery,
+    config: &Config,
+    storage: &Storage,
+    outbox: &Outbox,
+    dialogues: Arc<InMemStorage<RegistrationState>>,
+) -> HandlerResult {
+    let user = &q.from;
+    log::info!("User {} called request_access", logger::user(user.id));
+    metrics::record_callback("request_access");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    let message_id = q.message.as_ref().map(|msg| msg.id());
+    let messages = Messages::ru();
+
+    match access(config, storage, user)? {
+        Access::NeedsApproval => {}
+        access => {
+            return welcome(bot, chat_id, message_id, access, dialogues).await;
+        }
+    }
+    if storage.request_registration(user.id)? {
+        for chat in approval_chats(config) {
+            outbox.enqueue(
+                OutgoingMessage::text(
+                    chat,
+                    messages.registration_request(
+                        &user.full_name(),
+                        user.username.as_deref(),
+                        user.id,
+                    ),
+                )
+                .reply_markup(keyboards::registration_decision(user.id)),
+            );
+        }
+    }
+    reply(
+        bot,
+        chat_id,
+        message_id,
+        messages.registration_request_sent(),
+        None,
+    )
+    .await
+}
+
+/// Handles the `registration:approve|reject:<id>` buttons of the admins.
+pub async fn handle_decision(
+    bot: Bot,
+    q: CallbackQuery,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+    metrics::record_callback("registration");
+    let messages = Messages::ru();
+    let decision = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("registration:"))
+        .and_then(|data| data.split_once(':'));
+    let (approved, user) = match decision {
+        Some(("approve", id)) => (true, id),
+        Some(("reject", id)) => (false, id),
+        _ => return Ok(()),
+    };
+    let Ok(user) = user.parse().map(UserId) else {
+        return Ok(());
+    };
+    let (Some(chat_id), Some(msg)) = (q.chat_id(), &q.message) else {
+        return Ok(());
+    };
+
+    if !storage.decide_registration(user, approved, q.from.id)? {
+        bot.edit_message_text(chat_id, msg.id(), messages.registration_already_decided())
+            .await?;
+        return Ok(());
+    }
+    log::info!(
+        "Admin {} {} the registration of {}",
+        logger::user(q.from.id),
+        if approved { "approved" } else { "rejected" },
+        logger::user(user)
+    );
+    bot.edit_message_text(
+        chat_id,
+        msg.id(),
+        messages.registration_decided(user, approved, &q.from.full_name()),
+    )
+    .await?;
+
+    let notification = if approved {
+        OutgoingMessage::text(ChatId::from(user), messages.registration_approved())
+            .reply_markup(keyboards::new_user_confirmation())
+    } else {
+        OutgoingMessage::text(ChatId::from(user), messages.registration_rejected())
+    };
+    outbox.enqueue(notification);
+    Ok(())
+}
+
+/// Handles the admin `/invite [uses]` command by generating an invite code
+/// and its link.
+pub async fn create_invite(
+    bot: Bot,
+    msg: Message,
+    uses: String,
+    me: Me,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    let Some(admin) = &msg.from else {
+        return Ok(());
+    };
+    log::info!("Admin {} called /invite", logger::user(admin.id));
+    metrics::record_command("invite");
+    let messages = Messages::ru();
+
+    let uses = match uses.trim() {
+        "" => 1,
+        uses => match uses.parse::<u32>() {
+            Ok(uses) if uses > 0 => uses,
+            _ => {
+                bot.send_message(msg.chat.id, messages.invite_usage())
+                    .await?;
+                return Ok(());
+            }
+        },
+    };
+    let code = generate_code();
+    storage.create_invite(&code, admin.id, uses)?;
+    let link = deep_link::link(&me, &Payload::Invite(code.clone()));
+    bot.send_message(msg.chat.id, messages.invite_created(&code, uses, &link))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::telegram_user;
+
+    #[test]
+    fn decides_access_by_mode_and_status() {
+        use RegistrationStatus::*;
+
+        assert_eq!(decide(RegistrationMode::Open, None), Access::Allowed);
+        assert_eq!(decide(RegistrationMode::Invite, None), Access::NeedsInvite);
+        assert_eq!(
+            decide(RegistrationMode::Invite, Some(Approved)),
+            Access::Allowed
+        );
+        assert_eq!(
+            decide(RegistrationMode::Approval, None),
+            Access::NeedsApproval
+        );
+        assert_eq!(
+            decide(RegistrationMode::Approval, Some(Pending)),
+            Access::Pending
+        );
+        assert_eq!(
+            decide(RegistrationMode::Approval, Some(Rejected)),
+            Access::Rejected
+        );
+        assert_eq!(
+            decide(RegistrationMode::Approval, Some(Approved)),
+            Access::Allowed
+        );
+    }
+
+    #[test]
+    fn refuses_accounts_by_rules() {
+        let config = RegistrationConfig {
+            mode: RegistrationMode::Open,
+            require_username: true,
+            deny_bots: true,
+        };
+        let user = |username: Option<&str>, is_bot| User {
+            is_bot,
+            username: username.map(str::to_string),
+            ..telegram_user(10)
+        };
+
+        assert!(!is_refused(&config, &user(Some("test"), false)));
+        assert!(is_refused(&config, &user(None, false)));
+        assert!(is_refused(&config, &user(Some("test_bot"), true)));
+        assert!(!is_refused(
+            &RegistrationConfig {
+                require_username: false,
+                ..config
+            },
+            &user(None, false)
+        ));
+    }
+
+    #[test]
+    fn invite_codes_run_out() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage.create_invite("CODE", UserId(1), 2).unwrap();
+
+        assert!(storage.redeem_invite("CODE", UserId(10)).unwrap());
+        assert!(storage.redeem_invite("CODE", UserId(11)).unwrap());
+        assert!(!storage.redeem_invite("CODE", UserId(12)).unwrap());
+        assert!(!storage.redeem_invite("OTHER", UserId(12)).unwrap());
+
+        assert_eq!(
+            storage.registration_status(UserId(11)).unwrap(),
+            Some(RegistrationStatus::Approved)
+        );
+        assert_eq!(storage.registration_status(UserId(12)).unwrap(), None);
+    }
+
+    #[test]
+    fn requests_are_decided_once() {
+        let storage = Storage::open_in_memory().unwrap();
+
+        assert!(storage.request_registration(UserId(10)).unwrap());
+        assert!(!storage.request_registration(UserId(10)).unwrap());
+        assert!(
+            storage
+                .decide_registration(UserId(10), false, UserId(1))
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .decide_registration(UserId(10), true, UserId(2))
+                .unwrap()
+        );
+        assert_eq!(
+            storage.registration_status(UserId(10)).unwrap(),
+            Some(RegistrationStatus::Rejected)
+        );
+    }
+
+    #[test]
+    fn generates_readable_codes() {
+        let code = generate_code();
+
+        assert_eq!(code.len(), CODE_LENGTH);
+        assert!(code.bytes().all(|c| CODE_ALPHABET.contains(&c)));
+    }
+}
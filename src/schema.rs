@@ -1,27 +1,314 @@
 use super::handlers;
+use crate::audit;
+use crate::balance;
+use crate::ban;
+use crate::broadcast::{self, BroadcastState};
+use crate::config::Config;
 use crate::error::MyError;
+use crate::find::{self, FindState};
+use crate::gift;
+use crate::health::Health;
+use crate::inline;
+use crate::promo;
+use crate::rate_limit::{self, RateLimiter};
+use crate::registration::{self, RegistrationState};
+use crate::storage::Storage;
+use crate::support;
+use crate::{logger, metrics};
 use dptree::case;
-use teloxide::{dispatching::UpdateHandler, prelude::*};
+use std::sync::Arc;
+use teloxide::{
+    dispatching::{
+        UpdateHandler,
+        dialogue::{GetChatId, InMemStorage, Storage as _},
+    },
+    prelude::*,
+    types::{PreCheckoutQuery, SuccessfulPayment},
+};
 
 /// A root update handler for the bot.
 ///
 /// It handles the following commands:
 /// - `/help`: shows the help message
-/// - `/start`: starts the VPN setup process
+/// - `/start [payload]`: starts the VPN setup process, following a deep link
+/// - `/broadcast`: admin only, starts the broadcast dialogue
+/// - `/find <query>`: admin only, searches panel users
+/// - `/audit [filters]`: admin only, shows or exports the audit log
+/// - `/invite [uses]`: admin only, generates an invite code
+/// - `/promo <days> [uses]`: admin only, generates a promo code
+/// - `/ban <tg id> [reason]`, `/unban <tg id>`: admin only, manage the ban list
 ///
-/// All other messages and callback queries are handled accordingly.
+/// Replies in the support chat to ticket messages are relayed to the
+/// user, and messages of users with an open ticket to the support chat.
+/// All other messages and callback queries are handled accordingly, inline
+/// queries are answered with result cards.
+/// Checkouts of gifts and top-ups are confirmed while they are on sale;
+/// successful payments turn into gift links or balance credits.
+/// Users are served in private chats only: their commands and buttons in
+/// groups get a link to the private chat instead, so that subscription data
+/// never ends up in a group. Admin commands and buttons work in private and
+/// in the configured admin and support chats.
+/// Every update also counts as a dispatcher heartbeat for `/healthz`.
+/// Updates of banned users go no further; other senders are recorded as
+/// active users of the bot.
+/// Updates of users who exceed their rate limit are answered with a
+/// "slow down" reply and go no further; support tickets and dialogues
+/// aren't limited.
 pub fn schema() -> UpdateHandler<MyError> {
     let command_handler = teloxide::filter_command::<super::Command, _>()
         .branch(case![super::Command::Help].endpoint(handlers::help))
-        .branch(case![super::Command::Start].endpoint(handlers::start));
+        .branch(case![super::Command::Start(payload)].endpoint(handlers::start));
+
+    let admin_command_handler = teloxide::filter_command::<super::AdminCommand, _>()
+        .filter(is_admin_message)
+        .branch(
+            case![super::AdminCommand::Broadcast]
+                .enter_dialogue::<Message, InMemStorage<BroadcastState>, BroadcastState>()
+                .endpoint(broadcast::start),
+        )
+        .branch(
+            case![super::AdminCommand::Find(query)]
+                .enter_dialogue::<Message, InMemStorage<FindState>, FindState>()
+                .endpoint(find::start),
+        )
+        .branch(case![super::AdminCommand::Audit(args)].endpoint(audit::command))
+        .branch(case![super::AdminCommand::Invite(uses)].endpoint(registration::create_invite))
+        .branch(case![super::AdminCommand::Promo(args)].endpoint(promo::create))
+        .branch(case![super::AdminCommand::Ban(args)].endpoint(ban::ban))
+        .branch(case![super::AdminCommand::Unban(args)].endpoint(ban::unban));
+
+    let dialogue_handler = dptree::entry()
+        .branch(
+            dptree::entry()
+                .filter(is_admin_message)
+                .enter_dialogue::<Message, InMemStorage<BroadcastState>, BroadcastState>()
+                .branch(case![BroadcastState::ReceiveContent].endpoint(broadcast::receive_content)),
+        )
+        .branch(
+            dptree::filter(is_private_message)
+                .enter_dialogue::<Message, InMemStorage<RegistrationState>, RegistrationState>()
+                .branch(
+                    case![RegistrationState::AwaitInviteCode]
+                        .endpoint(registration::receive_invite_code),
+                ),
+        );
+
+    let support_reply_handler =
+        dptree::filter_map(|msg: Message, config: Arc<Config>, storage: Arc<Storage>| {
+            support::answered_ticket(&msg, &config, &storage)
+        })
+        .endpoint(support::relay_from_support);
+
+    let ticket_handler = dptree::filter_map(|msg: Message, storage: Arc<Storage>| {
+        let user = msg.from.as_ref()?;
+        match storage.find_open_ticket(user.id) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                log::error!(
+                    "Failed to look up the ticket of {}: {}",
+                    logger::user(user.id),
+                    e
+                );
+                None
+            }
+        }
+    })
+    .endpoint(support::relay_from_user);
+
+    // User commands in groups only point to the private chat.
+    let group_command_handler =
+        teloxide::filter_command::<super::Command, _>().endpoint(handlers::private_only);
+
+    let payment_handler = dptree::filter_map(|msg: Message| msg.successful_payment().cloned())
+        .branch(
+            dptree::filter(|payment: SuccessfulPayment| {
+                payment.invoice_payload.starts_with(balance::PAYLOAD_PREFIX)
+            })
+            .endpoint(balance::paid),
+        )
+        .branch(dptree::endpoint(gift::paid));
 
     let message_handler = Update::filter_message()
-        .branch(command_handler)
-        .branch(dptree::endpoint(handlers::invalid_input));
+        .branch(payment_handler)
+        .branch(dptree::filter(is_private_message).branch(command_handler))
+        .branch(admin_command_handler)
+        .branch(group_command_handler)
+        .branch(support_reply_handler)
+        .branch(dialogue_handler)
+        .branch(
+            dptree::filter(is_private_message)
+                .branch(ticket_handler)
+                .branch(dptree::endpoint(handlers::invalid_input)),
+        );
+
+    let broadcast_callback_handler = dptree::filter(|q: CallbackQuery, config: Arc<Config>| {
+        is_admin_callback(&q, &config)
+            && q.data
+                .as_deref()
+                .is_some_and(|data| data.starts_with("broadcast:"))
+    })
+    .enter_dialogue::<CallbackQuery, InMemStorage<BroadcastState>, BroadcastState>()
+    .endpoint(broadcast::handle_callback);
+
+    let find_callback_handler = dptree::filter(|q: CallbackQuery, config: Arc<Config>| {
+        is_admin_callback(&q, &config)
+            && q.data
+                .as_deref()
+                .is_some_and(|data| data.starts_with("find:"))
+    })
+    .enter_dialogue::<CallbackQuery, InMemStorage<FindState>, FindState>()
+    .endpoint(find::handle_callback);
+
+    let registration_callback_handler = dptree::filter(|q: CallbackQuery, config: Arc<Config>| {
+        is_admin_callback(&q, &config)
+            && q.data
+                .as_deref()
+                .is_some_and(|data| data.starts_with("registration:"))
+    })
+    .endpoint(registration::handle_decision);
+
+    let support_callback_handler = dptree::filter(|q: CallbackQuery, config: Arc<Config>| {
+        q.chat_id().is_some()
+            && q.chat_id() == config.support_chat()
+            && q.data
+                .as_deref()
+                .is_some_and(|data| data.starts_with("support:"))
+    })
+    .endpoint(support::handle_support_callback);
+
+    let callback_handler = Update::filter_callback_query()
+        .branch(broadcast_callback_handler)
+        .branch(find_callback_handler)
+        .branch(registration_callback_handler)
+        .branch(support_callback_handler)
+        .branch(dptree::filter(is_private_callback).endpoint(handlers::handle_callback))
+        .branch(dptree::endpoint(handlers::private_only_callback));
+
+    let inline_handler = Update::filter_inline_query().endpoint(inline::answer);
 
-    let callback_handler = Update::filter_callback_query().endpoint(handlers::handle_callback);
+    let pre_checkout_handler = Update::filter_pre_checkout_query()
+        .branch(
+            dptree::filter(|q: PreCheckoutQuery| {
+                q.invoice_payload.starts_with(balance::PAYLOAD_PREFIX)
+            })
+            .endpoint(balance::pre_checkout),
+        )
+        .branch(dptree::endpoint(gift::pre_checkout));
+
+    let rate_limit_handler = dptree::filter_map_async(
+        |update: Update,
+         limiter: Arc<RateLimiter>,
+         config: Arc<Config>,
+         storage: Arc<Storage>,
+         registrations: Arc<InMemStorage<RegistrationState>>| async move {
+            let (user, action) = rate_limit::action(&update)?;
+            if action == rate_limit::MESSAGE
+                && is_conversation(user, &update, &config, &storage, registrations).await
+            {
+                return None;
+            }
+            let throttled = limiter.check(user, &action).err()?;
+            log::debug!("User {} is rate limited on {}", logger::user(user), action);
+            metrics::record_rate_limited(&action);
+            Some(throttled)
+        },
+    )
+    .endpoint(handlers::slow_down);
 
     dptree::entry()
+        .inspect(|health: Arc<Health>| health.beat())
+        .filter(|update: Update, storage: Arc<Storage>| {
+            let Some(user) = update.from() else {
+                return true;
+            };
+            match storage.is_banned(user.id) {
+                Ok(banned) => {
+                    if banned {
+                        log::debug!("Ignoring banned user {}", logger::user(user.id));
+                    }
+                    !banned
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to check the ban of {}: {}",
+                        logger::user(user.id),
+                        e
+                    );
+                    true
+                }
+            }
+        })
+        .inspect(|update: Update, storage: Arc<Storage>| {
+            if let Some(user) = update.from()
+                && let Err(e) =
+                    storage.touch_user(user, update.chat().is_some_and(|chat| chat.is_private()))
+            {
+                log::error!("Failed to record user {}: {}", logger::user(user.id), e);
+            }
+        })
+        .branch(rate_limit_handler)
         .branch(message_handler)
         .branch(callback_handler)
+        .branch(inline_handler)
+        .branch(pre_checkout_handler)
+}
+
+/// Lets through messages from the admins listed in the configuration,
+/// sent in private or in one of the configured admin chats.
+fn is_admin_message(msg: Message, config: Arc<Config>) -> bool {
+    msg.from
+        .as_ref()
+        .is_some_and(|user| config.is_admin(user.id))
+        && (msg.chat.is_private() || config.is_admin_chat(msg.chat.id))
+}
+
+/// Lets through button presses of admins, in private or in one of the
+/// configured admin chats.
+fn is_admin_callback(q: &CallbackQuery, config: &Config) -> bool {
+    config.is_admin(q.from.id)
+        && q.message
+            .as_ref()
+            .is_none_or(|msg| msg.chat().is_private() || config.is_admin_chat(msg.chat().id))
+}
+
+/// Lets through messages of private chats, the only place where
+/// subscription data is shown.
+fn is_private_message(msg: Message) -> bool {
+    msg.chat.is_private()
+}
+
+/// Lets through button presses under messages of private chats.
+fn is_private_callback(q: CallbackQuery) -> bool {
+    q.message.as_ref().is_none_or(|msg| msg.chat().is_private())
+}
+
+/// Whether a plain message of `user` belongs to a conversation, which
+/// comes in bursts and isn't rate limited: a support ticket, an admin
+/// dialogue or the registration dialogue.
+async fn is_conversation(
+    user: UserId,
+    update: &Update,
+    config: &Config,
+    storage: &Storage,
+    registrations: Arc<InMemStorage<RegistrationState>>,
+) -> bool {
+    if config.is_admin(user) {
+        return true;
+    }
+    match storage.find_open_ticket(user) {
+        Ok(Some(_)) => return true,
+        Ok(None) => {}
+        Err(e) => log::error!(
+            "Failed to look up the ticket of {}: {}",
+            logger::user(user),
+            e
+        ),
+    }
+    let Some(chat_id) = update.chat_id() else {
+        return false;
+    };
+    matches!(
+        registrations.get_dialogue(chat_id).await,
+        Ok(Some(RegistrationState::AwaitInviteCode))
+    )
 }
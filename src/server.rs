@@ -0,0 +1,64 @@
+use crate::config::Config;
+use crate::error::MyError;
+use crate::health::{self, Health};
+use crate::outbox::Outbox;
+use crate::webhook::{self, WebhookState};
+use axum::{
+    Router,
+    routing::{get, post},
+};
+use std::sync::Arc;
+
+/// Starts the HTTP server of the bot.
+///
+/// The server exposes:
+/// - `GET /healthz` and `GET /readyz` for container orchestration;
+/// - `POST /webhook/remnawave` when a webhook secret is configured;
+/// - `GET /metrics` when the bot is built with the `metrics` feature.
+///
+/// It listens on the configured `http_listen_addr`.
+///
+/// # Errors
+///
+/// Returns an error if the listener cannot be bound.
+pub async fn serve(
+    outbox: Arc<Outbox>,
+    config: Arc<Config>,
+    health: Arc<Health>,
+) -> Result<(), MyError> {
+    let mut app = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .with_state(health);
+
+    match &config.webhook_secret {
+        Some(secret) => {
+            let state = WebhookState {
+                outbox,
+                secret: secret.clone(),
+                admin_chat_id: config.admin_chat_id,
+            };
+
+            app = app.merge(
+                Router::new()
+                    .route("/webhook/remnawave", post(webhook::receive))
+                    .with_state(state),
+            );
+        }
+        None => {
+            log::info!("Webhook secret is not set, panel webhooks are disabled");
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        app = app.route("/metrics", get(crate::metrics::render));
+    }
+
+    let addr = config.http_listen_addr;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    log::info!("HTTP server listening on {}", addr);
+
+    axum::serve(listener, app).await?;
+    Ok(())
+}
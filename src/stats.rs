@@ -0,0 +1,354 @@
+use crate::client::get_client;
+use crate::error::MyError;
+use crate::handlers;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::types::HandlerResult;
+use chrono::{Duration, NaiveDate, Utc};
+use once_cell::sync::OnceCell;
+use plotters::coord::ranged1d::SegmentValue;
+use plotters::prelude::*;
+use plotters::style::FontStyle;
+use remnawave::api::types::users::UsageData;
+use std::collections::BTreeMap;
+use std::path::Path;
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, MaybeInaccessibleMessage};
+
+const CHART_WIDTH: u32 = 800;
+const CHART_HEIGHT: u32 = 400;
+const BAR_COLOR: RGBColor = RGBColor(0x3d, 0x7e, 0xd8);
+
+/// Whether the chart font was loaded, so that charts can be drawn.
+static FONT_LOADED: OnceCell<bool> = OnceCell::new();
+
+/// Loads the font of the traffic charts.
+///
+/// Without it the statistics are sent as text only.
+pub fn load_font(path: &Path) {
+    let loaded = match std::fs::read(path) {
+        Ok(bytes) => {
+            let bytes: &'static [u8] = Box::leak(bytes.into_boxed_slice());
+            match plotters::style::register_font("sans-serif", FontStyle::Normal, bytes) {
+                Ok(()) => true,
+                Err(_) => {
+                    log::warn!(
+                        "{} is not a valid font, charts are disabled",
+                        path.display()
+                    );
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            log::warn!(
+                "Cannot read chart font {}: {}, charts are disabled",
+                path.display(),
+                e
+            );
+            false
+        }
+    };
+    let _ = FONT_LOADED.set(loaded);
+}
+
+/// The day of a usage entry, `None` if its date can't be read.
+fn entry_date(entry: &UsageData) -> Option<NaiveDate> {
+    entry
+        .date
+        .get(..10)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+}
+
+/// Entries of `usage` from the `days` days starting at `start`.
+fn within(usage: &[UsageData], start: NaiveDate, days: u32) -> impl Iterator<Item = &UsageData> {
+    let end = start + Duration::days(i64::from(days));
+    usage
+        .iter()
+        .filter(move |entry| entry_date(entry).is_some_and(|date| start <= date && date < end))
+}
+
+/// Traffic of every day from `start`, zero for days without traffic.
+fn daily_totals(usage: &[UsageData], start: NaiveDate, days: u32) -> Vec<(NaiveDate, u64)> {
+    let mut totals: BTreeMap<NaiveDate, u64> = (0..days)
+        .map(|day| (start + Duration::days(i64::from(day)), 0))
+        .collect();
+    for entry in within(usage, start, days) {
+        if let Some(total) = entry_date(entry).and_then(|date| totals.get_mut(&date)) {
+            *total += entry.total as u64;
+        }
+    }
+    totals.into_iter().collect()
+}
+
+/// Traffic per node over the same days as [`daily_totals`], largest first.
+fn node_totals(usage: &[UsageData], start: NaiveDate, days: u32) -> Vec<(String, u64)> {
+    let mut totals: BTreeMap<&str, u64> = BTreeMap::new();
+    for entry in within(usage, start, days) {
+        *totals.entry(&entry.node_name).or_default() += entry.total as u64;
+    }
+    let mut totals: Vec<_> = totals
+        .into_iter()
+        .map(|(node, total)| (node.to_string(), total))
+        .collect();
+    totals.sort_by_key(|(_, total)| std::cmp::Reverse(*total));
+    totals
+}
+
+/// The largest unit in which `bytes` is at least one, and its size.
+fn unit_for(bytes: u64) -> (&'static str, f64) {
+    const UNITS: [(&str, f64); 4] = [
+        ("ГБ", 1024.0 * 1024.0 * 1024.0),
+        ("МБ", 1024.0 * 1024.0),
+        ("КБ", 1024.0),
+        ("Б", 1.0),
+    ];
+    UNITS
+        .into_iter()
+        .find(|(_, size)| bytes as f64 >= *size)
+        .unwrap_or(UNITS[3])
+}
+
+fn chart_error(e: impl std::fmt::Display) -> MyError {
+    MyError::Custom(format!("Failed to draw the chart: {}", e))
+}
+
+/// Draws a bar chart of the daily traffic as a PNG.
+fn render_chart(days: &[(NaiveDate, u64)]) -> Result<Vec<u8>, MyError> {
+    let mut buffer = vec![0u8; (CHART_WIDTH * CHART_HEIGHT * 3) as usize];
+    {
+        let root = BitMapBackend::with_buffer(&mut buffer, (CHART_WIDTH, CHART_HEIGHT))
+            .into_drawing_area();
+        root.fill(&WHITE).map_err(chart_error)?;
+
+        let max = days.iter().map(|(_, total)| *total).max().unwrap_or(0);
+        let (unit, size) = unit_for(max);
+        let top = (max as f64 / size * 1.1).max(1.0);
+        let mut chart = ChartBuilder::on(&root)
+            .margin(16)
+            .x_label_area_size(32)
+            .y_label_area_size(56)
+            .build_cartesian_2d((0..days.len()).into_segmented(), 0.0..top)
+            .map_err(chart_error)?;
+
+        let date_label = |x: &SegmentValue<usize>| match x {
+            SegmentValue::CenterOf(day) => days
+                .get(*day)
+                .map(|(date, _)| date.format("%d.%m").to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        chart
+            .configure_mesh()
+            .disable_x_mesh()
+            .x_labels(days.len().min(10))
+            .x_label_formatter(&date_label)
+            .y_desc(unit)
+            .y_label_formatter(&|y| format!("{:.1}", y))
+            .label_style(("sans-serif", 14))
+            .draw()
+            .map_err(chart_error)?;
+
+        chart
+            .draw_series(days.iter().enumerate().map(|(day, (_, total))| {
+                let mut bar = Rectangle::new(
+                    [
+                        (SegmentValue::Exact(day), 0.0),
+                        (SegmentValue::Exact(day + 1), *total as f64 / size),
+                    ],
+                    BAR_COLOR.filled(),
+                );
+                bar.set_margin(0, 0, 3, 3);
+                bar
+            }))
+            .map_err(chart_error)?;
+        root.present().map_err(chart_error)?;
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, CHART_WIDTH, CHART_HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(chart_error)?;
+    writer.write_image_data(&buffer).map_err(chart_error)?;
+    writer.finish().map_err(chart_error)?;
+    Ok(png)
+}
+
+/// Handles the `stats:*` buttons: the traffic of the user for the last
+/// 7 or 30 days as a chart with totals per node, or `stats:back` to
+/// return to the main menu.
+pub async fn handle_callback(bot: &Bot, q: &CallbackQuery, data: &str) -> HandlerResult {
+    let user_id = q.from.id;
+    log::info!("User {} called {}", logger::user(user_id), data);
+    metrics::record_callback("stats");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    let message = q.message.as_ref();
+
+    let days = match data.strip_prefix("stats:") {
+        Some("back") => {
+            if let Some(msg) = message {
+                bot.delete_message(chat_id, msg.id()).await?;
+            }
+            return handlers::send_main_menu(bot, chat_id, None)
+                .await
+                .map_err(Into::into);
+        }
+        Some("30") => 30,
+        _ => 7,
+    };
+    show(bot, chat_id, user_id, message, days).await
+}
+
+/// Sends the traffic statistics of `user_id` for the last `days`, or puts
+/// them in place of `message` if given.
+pub async fn show(
+    bot: &Bot,
+    chat_id: ChatId,
+    user_id: UserId,
+    message: Option<&MaybeInaccessibleMessage>,
+    days: u32,
+) -> HandlerResult {
+    let is_photo = message
+        .and_then(|msg| msg.regular_message())
+        .is_some_and(|msg| msg.photo().is_some());
+    let user = get_client()
+        .find_user_by_telegram_id(user_id.0)
+        .await
+        .into_user()?;
+    let today = Utc::now().date_naive();
+    let start = today - Duration::days(i64::from(days) - 1);
+    let usage = get_client()
+        .get_user_usage(
+            user.uuid,
+            start.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc(),
+            Utc::now(),
+        )
+        .await?;
+
+    let daily = daily_totals(&usage, start, days);
+    let total = daily.iter().map(|(_, total)| total).sum();
+    let caption = Messages::ru().stats(days, total, &node_totals(&usage, start, days));
+    let keyboard = keyboards::stats(days);
+
+    let chart = if FONT_LOADED.get().copied().unwrap_or(false) {
+        match tokio::task::spawn_blocking(move || render_chart(&daily)).await {
+            Ok(Ok(chart)) => Some(chart),
+            Ok(Err(e)) => {
+                log::error!("{}", e);
+                None
+            }
+            Err(e) => {
+                log::error!("Chart rendering panicked: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    match (chart, message) {
+        (Some(chart), Some(msg)) if is_photo => {
+            let photo = InputMediaPhoto::new(InputFile::memory(chart).file_name("stats.png"))
+                .caption(caption);
+            bot.edit_message_media(chat_id, msg.id(), InputMedia::Photo(photo))
+                .reply_markup(keyboard)
+                .await?;
+        }
+        (Some(chart), _) => {
+            if let Some(msg) = message {
+                bot.delete_message(chat_id, msg.id()).await?;
+            }
+            bot.send_photo(chat_id, InputFile::memory(chart).file_name("stats.png"))
+                .caption(caption)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        (None, Some(msg)) if !is_photo => {
+            bot.edit_message_text(chat_id, msg.id(), caption)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        (None, _) => {
+            if let Some(msg) = message {
+                bot.delete_message(chat_id, msg.id()).await?;
+            }
+            bot.send_message(chat_id, caption)
+                .reply_markup(keyboard)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn usage(node: &str, date: &str, total: usize) -> UsageData {
+        UsageData {
+            user_uuid: Uuid::nil(),
+            node_uuid: Uuid::nil(),
+            node_name: node.to_string(),
+            country_code: "DE".to_string(),
+            total,
+            date: date.to_string(),
+        }
+    }
+
+    #[test]
+    fn sums_traffic_per_day_and_node() {
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let usage = [
+            usage("de", "2024-01-01", 100),
+            usage("nl", "2024-01-01T00:00:00.000Z", 50),
+            usage("de", "2024-01-03", 300),
+            usage("de", "2023-12-31", 1000),
+        ];
+
+        let daily = daily_totals(&usage, start, 3);
+        assert_eq!(
+            daily.iter().map(|(_, total)| *total).collect::<Vec<_>>(),
+            vec![150, 0, 300]
+        );
+        assert_eq!(daily[2].0, NaiveDate::from_ymd_opt(2024, 1, 3).unwrap());
+
+        assert_eq!(
+            node_totals(&usage, start, 3),
+            vec![("de".to_string(), 400), ("nl".to_string(), 50)]
+        );
+        assert_eq!(
+            node_totals(&usage, start, 2),
+            vec![("de".to_string(), 100), ("nl".to_string(), 50)]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs DejaVu Sans at DEFAULT_CHART_FONT"]
+    fn renders_png() {
+        let font = Path::new(crate::config::DEFAULT_CHART_FONT);
+        assert!(font.exists(), "{} is missing", font.display());
+        load_font(font);
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let days: Vec<_> = (0..30)
+            .map(|day| (start + Duration::days(day), day as u64 * 1024 * 1024))
+            .collect();
+
+        let png = render_chart(&days).unwrap();
+
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn picks_readable_units() {
+        assert_eq!(unit_for(0).0, "Б");
+        assert_eq!(unit_for(2048).0, "КБ");
+        assert_eq!(unit_for(5 * 1024 * 1024 * 1024).0, "ГБ");
+    }
+}
@@ -0,0 +1,1036 @@
+use crate::audit::{AuditEntry, AuditFilter};
+use crate::balance::{LedgerEntry, LedgerKind, Posting};
+use crate::ban::Ban;
+use crate::error::MyError;
+use crate::gift::Gift;
+use crate::promo::Reward;
+use crate::registration::RegistrationStatus;
+use crate::support::Ticket;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use teloxide::types::{ChatId, MessageId, User, UserId};
+
+/// Schema migrations, applied in order. The number of applied ones is kept
+/// in `PRAGMA user_version`, so new migrations must only be appended.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE bot_users (
+        telegram_id INTEGER PRIMARY KEY,
+        username TEXT,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        blocked_at INTEGER
+    );",
+    "CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at INTEGER NOT NULL,
+        actor_id INTEGER NOT NULL,
+        target_uuid TEXT,
+        action TEXT NOT NULL,
+        before TEXT,
+        after TEXT,
+        outcome TEXT NOT NULL
+    );
+    CREATE INDEX audit_log_actor ON audit_log (actor_id);
+    CREATE INDEX audit_log_target ON audit_log (target_uuid);",
+    "CREATE TABLE invite_codes (
+        code TEXT PRIMARY KEY,
+        created_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        max_uses INTEGER NOT NULL,
+        uses INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE registrations (
+        telegram_id INTEGER PRIMARY KEY,
+        status TEXT NOT NULL,
+        invite_code TEXT REFERENCES invite_codes (code),
+        requested_at INTEGER NOT NULL,
+        decided_by INTEGER,
+        decided_at INTEGER
+    );",
+    "CREATE TABLE bans (
+        telegram_id INTEGER PRIMARY KEY,
+        reason TEXT,
+        banned_by INTEGER NOT NULL,
+        banned_at INTEGER NOT NULL,
+        disabled_uuid TEXT
+    );",
+    "CREATE TABLE tickets (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        telegram_id INTEGER NOT NULL,
+        open INTEGER NOT NULL,
+        opened_at INTEGER NOT NULL,
+        closed_at INTEGER,
+        header_message_id INTEGER
+    );
+    CREATE UNIQUE INDEX tickets_open ON tickets (telegram_id) WHERE open;
+    CREATE TABLE ticket_messages (
+        chat_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        ticket_id INTEGER NOT NULL REFERENCES tickets (id),
+        PRIMARY KEY (chat_id, message_id)
+    );",
+    "CREATE TABLE referrals (
+        telegram_id INTEGER PRIMARY KEY,
+        referrer_id INTEGER NOT NULL,
+        referred_at INTEGER NOT NULL
+    );
+    CREATE INDEX referrals_referrer ON referrals (referrer_id);
+    CREATE TABLE promo_codes (
+        code TEXT PRIMARY KEY,
+        days INTEGER NOT NULL,
+        created_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        max_uses INTEGER NOT NULL,
+        uses INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE promo_redemptions (
+        code TEXT NOT NULL REFERENCES promo_codes (code),
+        telegram_id INTEGER NOT NULL,
+        redeemed_at INTEGER NOT NULL,
+        PRIMARY KEY (code, telegram_id)
+    );",
+    "CREATE TABLE gifts (
+        code TEXT PRIMARY KEY,
+        plan TEXT NOT NULL,
+        days INTEGER NOT NULL,
+        giver_id INTEGER NOT NULL,
+        charge_id TEXT NOT NULL UNIQUE,
+        amount INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        paid_at INTEGER NOT NULL,
+        redeemed_by INTEGER,
+        redeemed_at INTEGER
+    );
+    CREATE INDEX gifts_giver ON gifts (giver_id);",
+    "CREATE TABLE families (
+        owner_id INTEGER PRIMARY KEY,
+        invite_code TEXT NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE family_members (
+        telegram_id INTEGER PRIMARY KEY,
+        owner_id INTEGER NOT NULL REFERENCES families (owner_id),
+        joined_at INTEGER NOT NULL
+    );
+    CREATE INDEX family_members_owner ON family_members (owner_id);",
+    "CREATE TABLE balances (
+        telegram_id INTEGER PRIMARY KEY,
+        balance INTEGER NOT NULL DEFAULT 0 CHECK (balance >= 0),
+        auto_renew_plan TEXT,
+        warned_expiry INTEGER
+    );
+    CREATE TABLE ledger (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        telegram_id INTEGER NOT NULL,
+        amount INTEGER NOT NULL,
+        balance INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        reference TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        UNIQUE (telegram_id, kind, reference)
+    );
+    ALTER TABLE promo_codes ADD COLUMN credit INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE bot_users ADD COLUMN private_chat INTEGER NOT NULL DEFAULT 0;",
+];
+
+/// Persistent state of the bot in a SQLite database.
+///
+/// Queries are short, so a single connection behind a mutex is shared by
+/// all handlers.
+pub struct Storage {
+    conn: Mutex<Connection>,
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Reads `id, open, header_message_id` of a ticket of `user`.
+fn ticket_from_row(row: &rusqlite::Row<'_>, user: UserId) -> rusqlite::Result<Ticket> {
+    Ok(Ticket {
+        id: row.get(0)?,
+        user,
+        open: row.get(1)?,
+        header: row.get::<_, Option<i32>>(2)?.map(MessageId),
+    })
+}
+
+impl Storage {
+    /// Opens the database at `path`, creating it and its directory if
+    /// needed, and applies pending migrations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be opened or migrated.
+    pub fn open(path: &Path) -> Result<Arc<Self>, MyError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::init(conn)
+    }
+
+    /// Opens a fresh database in memory.
+    pub fn open_in_memory() -> Result<Arc<Self>, MyError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Arc<Self>, MyError> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let applied: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let tx = conn.transaction()?;
+        for (version, migration) in (1..).zip(MIGRATIONS).skip(applied as usize) {
+            log::info!("Applying database migration {}", version);
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", version)?;
+        }
+        tx.commit()?;
+        Ok(Arc::new(Self {
+            conn: Mutex::new(conn),
+        }))
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Records that `user` interacted with the bot. An update from the
+    /// `private` chat also means the bot is not blocked by them.
+    pub fn touch_user(&self, user: &User, private: bool) -> Result<(), MyError> {
+        let now = now();
+        self.conn().execute(
+            "INSERT INTO bot_users (telegram_id, username, first_seen, last_seen, private_chat)
+             VALUES (?1, ?2, ?3, ?3, ?4)
+             ON CONFLICT (telegram_id) DO UPDATE
+             SET username = excluded.username, last_seen = excluded.last_seen,
+                 private_chat = private_chat OR excluded.private_chat,
+                 blocked_at = CASE WHEN excluded.private_chat THEN NULL ELSE blocked_at END",
+            params![user.id.0 as i64, user.username, now, private],
+        )?;
+        Ok(())
+    }
+
+    /// Marks the user with the private chat `chat_id` as having blocked the bot.
+    pub fn mark_blocked(&self, chat_id: ChatId) -> Result<(), MyError> {
+        let now = now();
+        self.conn().execute(
+            "INSERT INTO bot_users (telegram_id, first_seen, last_seen, blocked_at)
+             VALUES (?1, ?2, ?2, ?2)
+             ON CONFLICT (telegram_id) DO UPDATE SET blocked_at = excluded.blocked_at",
+            params![chat_id.0, now],
+        )?;
+        Ok(())
+    }
+
+    /// Whether the user with the private chat `chat_id` blocked the bot.
+    pub fn is_blocked(&self, chat_id: ChatId) -> Result<bool, MyError> {
+        let blocked_at: Option<Option<i64>> = self
+            .conn()
+            .query_row(
+                "SELECT blocked_at FROM bot_users WHERE telegram_id = ?1",
+                params![chat_id.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(matches!(blocked_at, Some(Some(_))))
+    }
+
+    /// Telegram IDs of users who talked to the bot in a private chat and
+    /// didn't block it.
+    pub fn private_chat_user_ids(&self) -> Result<Vec<i64>, MyError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT telegram_id FROM bot_users WHERE private_chat AND blocked_at IS NULL",
+        )?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    /// Telegram IDs of users who blocked the bot.
+    pub fn blocked_user_ids(&self) -> Result<HashSet<i64>, MyError> {
+        let conn = self.conn();
+        let mut statement =
+            conn.prepare("SELECT telegram_id FROM bot_users WHERE blocked_at IS NOT NULL")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    /// Appends an entry to the audit log.
+    pub fn record_audit(&self, entry: &AuditEntry) -> Result<(), MyError> {
+        self.conn().execute(
+            "INSERT INTO audit_log (at, actor_id, target_uuid, action, before, after, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.at,
+                entry.actor_id as i64,
+                entry.target_uuid.map(|uuid| uuid.to_string()),
+                entry.action,
+                entry.before,
+                entry.after,
+                entry.outcome,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Audit entries matching `filter`, newest first, at most `limit` of them.
+    pub fn audit_entries(
+        &self,
+        filter: &AuditFilter,
+        limit: Option<usize>,
+    ) -> Result<Vec<AuditEntry>, MyError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT at, actor_id, target_uuid, action, before, after, outcome FROM audit_log
+             WHERE (?1 IS NULL OR actor_id = ?1)
+               AND (?2 IS NULL OR target_uuid = ?2)
+               AND (?3 IS NULL OR action = ?3)
+               AND (?4 IS NULL OR at >= ?4)
+             ORDER BY id DESC
+             LIMIT ?5",
+        )?;
+        let entries = statement
+            .query_map(
+                params![
+                    filter.actor_id.map(|id| id as i64),
+                    filter.target_uuid.map(|uuid| uuid.to_string()),
+                    filter.action,
+                    filter.since,
+                    limit.map_or(-1, |limit| limit as i64),
+                ],
+                |row| {
+                    let target_uuid: Option<String> = row.get(2)?;
+                    Ok(AuditEntry {
+                        at: row.get(0)?,
+                        actor_id: row.get::<_, i64>(1)? as u64,
+                        target_uuid: target_uuid.and_then(|uuid| uuid.parse().ok()),
+                        action: row.get(3)?,
+                        before: row.get(4)?,
+                        after: row.get(5)?,
+                        outcome: row.get(6)?,
+                    })
+                },
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(entries)
+    }
+
+    /// Where the registration of `user` stands, if they ever registered.
+    pub fn registration_status(&self, user: UserId) -> Result<Option<RegistrationStatus>, MyError> {
+        let status: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT status FROM registrations WHERE telegram_id = ?1",
+                params![user.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(status.and_then(|status| status.parse().ok()))
+    }
+
+    /// Records a pending registration request of `user`.
+    ///
+    /// Returns `false` if the user already has one, whatever its status.
+    pub fn request_registration(&self, user: UserId) -> Result<bool, MyError> {
+        let inserted = self.conn().execute(
+            "INSERT INTO registrations (telegram_id, status, requested_at)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT (telegram_id) DO NOTHING",
+            params![user.0 as i64, RegistrationStatus::Pending.as_str(), now()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Approves or rejects the pending request of `user`.
+    ///
+    /// Returns `false` if there is no pending request, e.g. because another
+    /// admin already decided.
+    pub fn decide_registration(
+        &self,
+        user: UserId,
+        approved: bool,
+        admin: UserId,
+    ) -> Result<bool, MyError> {
+        let status = if approved {
+            RegistrationStatus::Approved
+        } else {
+            RegistrationStatus::Rejected
+        };
+        let updated = self.conn().execute(
+            "UPDATE registrations SET status = ?2, decided_by = ?3, decided_at = ?4
+             WHERE telegram_id = ?1 AND status = ?5",
+            params![
+                user.0 as i64,
+                status.as_str(),
+                admin.0 as i64,
+                now(),
+                RegistrationStatus::Pending.as_str()
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Stores an invite code that may be used `max_uses` times.
+    pub fn create_invite(&self, code: &str, admin: UserId, max_uses: u32) -> Result<(), MyError> {
+        self.conn().execute(
+            "INSERT INTO invite_codes (code, created_by, created_at, max_uses)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![code, admin.0 as i64, now(), max_uses],
+        )?;
+        Ok(())
+    }
+
+    /// Uses up one use of `code` and approves `user` with it.
+    ///
+    /// Returns `false` if the code doesn't exist or is used up.
+    pub fn redeem_invite(&self, code: &str, user: UserId) -> Result<bool, MyError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let updated = tx.execute(
+            "UPDATE invite_codes SET uses = uses + 1 WHERE code = ?1 AND uses < max_uses",
+            params![code],
+        )?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        let now = now();
+        tx.execute(
+            "INSERT INTO registrations (telegram_id, status, invite_code, requested_at, decided_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT (telegram_id) DO UPDATE
+             SET status = excluded.status, invite_code = excluded.invite_code,
+                 decided_by = NULL, decided_at = excluded.decided_at",
+            params![
+                user.0 as i64,
+                RegistrationStatus::Approved.as_str(),
+                code,
+                now
+            ],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Records that `user` came through a referral link of `referrer`.
+    ///
+    /// Returns `false` if `user` was already referred by someone.
+    pub fn add_referral(&self, user: UserId, referrer: UserId) -> Result<bool, MyError> {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO referrals (telegram_id, referrer_id, referred_at)
+             VALUES (?1, ?2, ?3)",
+            params![user.0 as i64, referrer.0 as i64, now()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Number of users who came through a referral link of `referrer`.
+    pub fn referral_count(&self, referrer: UserId) -> Result<u64, MyError> {
+        let count: i64 = self.conn().query_row(
+            "SELECT COUNT(*) FROM referrals WHERE referrer_id = ?1",
+            params![referrer.0 as i64],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    /// Who invited `user`, if anyone.
+    pub fn referrer_of(&self, user: UserId) -> Result<Option<UserId>, MyError> {
+        let referrer: Option<i64> = self
+            .conn()
+            .query_row(
+                "SELECT referrer_id FROM referrals WHERE telegram_id = ?1",
+                params![user.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(referrer.map(|id| UserId(id as u64)))
+    }
+
+    pub fn create_promo(
+        &self,
+        code: &str,
+        reward: Reward,
+        admin: UserId,
+        max_uses: u32,
+    ) -> Result<(), MyError> {
+        let (days, credit) = match reward {
+            Reward::Days(days) => (days, 0),
+            Reward::Credit(credit) => (0, credit),
+        };
+        self.conn().execute(
+            "INSERT INTO promo_codes (code, days, credit, created_by, created_at, max_uses)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![code, days, credit, admin.0 as i64, now(), max_uses],
+        )?;
+        Ok(())
+    }
+
+    /// Uses up one use of promo `code` for `user`, returning what it
+    /// grants.
+    ///
+    /// Returns `None` if the code doesn't exist, is used up or was already
+    /// redeemed by `user`.
+    pub fn redeem_promo(&self, code: &str, user: UserId) -> Result<Option<Reward>, MyError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let reward: Option<(u32, u32)> = tx
+            .query_row(
+                "UPDATE promo_codes SET uses = uses + 1
+                 WHERE code = ?1 AND uses < max_uses
+                 RETURNING days, credit",
+                params![code],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((days, credit)) = reward else {
+            return Ok(None);
+        };
+        let inserted = tx.execute(
+            "INSERT OR IGNORE INTO promo_redemptions (code, telegram_id, redeemed_at)
+             VALUES (?1, ?2, ?3)",
+            params![code, user.0 as i64, now()],
+        )?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+        tx.commit()?;
+        Ok(Some(if credit > 0 {
+            Reward::Credit(credit)
+        } else {
+            Reward::Days(days)
+        }))
+    }
+
+    /// Gives back a use of promo `code` redeemed by `user` whose
+    /// subscription couldn't be extended.
+    pub fn revert_promo(&self, code: &str, user: UserId) -> Result<(), MyError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM promo_redemptions WHERE code = ?1 AND telegram_id = ?2",
+            params![code, user.0 as i64],
+        )?;
+        if deleted > 0 {
+            tx.execute(
+                "UPDATE promo_codes SET uses = uses - 1 WHERE code = ?1",
+                params![code],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Stores a paid gift.
+    ///
+    /// Returns `false` if a gift for the same payment already exists.
+    pub fn create_gift(&self, gift: &Gift) -> Result<bool, MyError> {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO gifts
+             (code, plan, days, giver_id, charge_id, amount, currency, paid_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                gift.code,
+                gift.plan,
+                gift.days,
+                gift.giver.0 as i64,
+                gift.charge_id,
+                gift.amount,
+                gift.currency,
+                now()
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// The gift with `code`, redeemed or not.
+    pub fn gift(&self, code: &str) -> Result<Option<Gift>, MyError> {
+        let gift = self
+            .conn()
+            .query_row(
+                "SELECT code, plan, days, giver_id, charge_id, amount, currency, redeemed_by
+                 FROM gifts WHERE code = ?1",
+                params![code],
+                |row| {
+                    Ok(Gift {
+                        code: row.get(0)?,
+                        plan: row.get(1)?,
+                        days: row.get(2)?,
+                        giver: UserId(row.get::<_, i64>(3)? as u64),
+                        charge_id: row.get(4)?,
+                        amount: row.get(5)?,
+                        currency: row.get(6)?,
+                        redeemed_by: row.get::<_, Option<i64>>(7)?.map(|id| UserId(id as u64)),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(gift)
+    }
+
+    /// Marks gift `code` as redeemed by `user`.
+    ///
+    /// Returns `false` if it doesn't exist or was already redeemed.
+    pub fn redeem_gift(&self, code: &str, user: UserId) -> Result<bool, MyError> {
+        let updated = self.conn().execute(
+            "UPDATE gifts SET redeemed_by = ?2, redeemed_at = ?3
+             WHERE code = ?1 AND redeemed_by IS NULL",
+            params![code, user.0 as i64, now()],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Makes gift `code` redeemable again after the subscription of its
+    /// recipient couldn't be set up.
+    pub fn revert_gift(&self, code: &str) -> Result<(), MyError> {
+        self.conn().execute(
+            "UPDATE gifts SET redeemed_by = NULL, redeemed_at = NULL WHERE code = ?1",
+            params![code],
+        )?;
+        Ok(())
+    }
+
+    /// Creates a family owned by `owner` with invite `code`.
+    ///
+    /// Returns `false` if `owner` already has a family.
+    pub fn create_family(&self, owner: UserId, code: &str) -> Result<bool, MyError> {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO families (owner_id, invite_code, created_at)
+             VALUES (?1, ?2, ?3)",
+            params![owner.0 as i64, code, now()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Invite code of the family owned by `owner`, if they have one.
+    pub fn family_invite(&self, owner: UserId) -> Result<Option<String>, MyError> {
+        let code = self
+            .conn()
+            .query_row(
+                "SELECT invite_code FROM families WHERE owner_id = ?1",
+                params![owner.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(code)
+    }
+
+    /// Replaces the invite code of the family of `owner`, so that old
+    /// links stop working.
+    pub fn set_family_invite(&self, owner: UserId, code: &str) -> Result<(), MyError> {
+        self.conn().execute(
+            "UPDATE families SET invite_code = ?2 WHERE owner_id = ?1",
+            params![owner.0 as i64, code],
+        )?;
+        Ok(())
+    }
+
+    /// Owner of the family with invite `code`.
+    pub fn family_by_invite(&self, code: &str) -> Result<Option<UserId>, MyError> {
+        let owner: Option<i64> = self
+            .conn()
+            .query_row(
+                "SELECT owner_id FROM families WHERE invite_code = ?1",
+                params![code],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(owner.map(|id| UserId(id as u64)))
+    }
+
+    /// Owners of all families.
+    pub fn family_owners(&self) -> Result<Vec<UserId>, MyError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT owner_id FROM families ORDER BY owner_id")?;
+        let owners = stmt
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .map(|id| id.map(|id| UserId(id as u64)))
+            .collect::<Result<_, _>>()?;
+        Ok(owners)
+    }
+
+    /// Members of the family of `owner`, in the order they joined.
+    pub fn family_members(&self, owner: UserId) -> Result<Vec<UserId>, MyError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT telegram_id FROM family_members WHERE owner_id = ?1
+             ORDER BY joined_at, telegram_id",
+        )?;
+        let members = stmt
+            .query_map(params![owner.0 as i64], |row| row.get::<_, i64>(0))?
+            .map(|id| id.map(|id| UserId(id as u64)))
+            .collect::<Result<_, _>>()?;
+        Ok(members)
+    }
+
+    /// Owner of the family `user` is a member of.
+    pub fn family_of(&self, user: UserId) -> Result<Option<UserId>, MyError> {
+        let owner: Option<i64> = self
+            .conn()
+            .query_row(
+                "SELECT owner_id FROM family_members WHERE telegram_id = ?1",
+                params![user.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(owner.map(|id| UserId(id as u64)))
+    }
+
+    /// Adds `user` to the family of `owner` unless it already has
+    /// `max_members` members.
+    ///
+    /// Returns `false` if the family is full or `user` is already in a
+    /// family.
+    pub fn add_family_member(
+        &self,
+        owner: UserId,
+        user: UserId,
+        max_members: u32,
+    ) -> Result<bool, MyError> {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO family_members (telegram_id, owner_id, joined_at)
+             SELECT ?2, ?1, ?3
+             WHERE (SELECT COUNT(*) FROM family_members WHERE owner_id = ?1) < ?4",
+            params![owner.0 as i64, user.0 as i64, now(), max_members],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Removes `user` from the family of `owner`.
+    ///
+    /// Returns `false` if they weren't a member of it.
+    pub fn remove_family_member(&self, owner: UserId, user: UserId) -> Result<bool, MyError> {
+        let deleted = self.conn().execute(
+            "DELETE FROM family_members WHERE telegram_id = ?1 AND owner_id = ?2",
+            params![user.0 as i64, owner.0 as i64],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Balance of `user`, `0` if they never had one.
+    pub fn balance(&self, user: UserId) -> Result<i64, MyError> {
+        let balance = self
+            .conn()
+            .query_row(
+                "SELECT balance FROM balances WHERE telegram_id = ?1",
+                params![user.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(balance.unwrap_or(0))
+    }
+
+    /// Changes the balance of `user` by `amount` and records it in the
+    /// ledger, both or neither.
+    ///
+    /// `reference` names what the change is for, e.g. a payment; a change
+    /// of the same kind and reference is only made once. The balance
+    /// never goes below zero.
+    pub fn post(
+        &self,
+        user: UserId,
+        amount: i64,
+        kind: LedgerKind,
+        reference: &str,
+    ) -> Result<Posting, MyError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let duplicate = tx
+            .query_row(
+                "SELECT 1 FROM ledger WHERE telegram_id = ?1 AND kind = ?2 AND reference = ?3",
+                params![user.0 as i64, kind.as_str(), reference],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if duplicate {
+            return Ok(Posting::Duplicate);
+        }
+        tx.execute(
+            "INSERT OR IGNORE INTO balances (telegram_id) VALUES (?1)",
+            params![user.0 as i64],
+        )?;
+        let balance: Option<i64> = tx
+            .query_row(
+                "UPDATE balances SET balance = balance + ?2
+                 WHERE telegram_id = ?1 AND balance + ?2 >= 0
+                 RETURNING balance",
+                params![user.0 as i64, amount],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(balance) = balance else {
+            return Ok(Posting::Insufficient);
+        };
+        tx.execute(
+            "INSERT INTO ledger (telegram_id, amount, balance, kind, reference, at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                user.0 as i64,
+                amount,
+                balance,
+                kind.as_str(),
+                reference,
+                now()
+            ],
+        )?;
+        tx.commit()?;
+        Ok(Posting::Done(balance))
+    }
+
+    /// The latest `limit` ledger entries of `user`, newest first.
+    pub fn ledger(&self, user: UserId, limit: u32) -> Result<Vec<LedgerEntry>, MyError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT amount, balance, kind, at FROM ledger WHERE telegram_id = ?1
+             ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![user.0 as i64, limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (amount, balance, kind, at) = row?;
+            let Some(kind) = LedgerKind::parse(&kind) else {
+                continue;
+            };
+            entries.push(LedgerEntry {
+                amount,
+                balance,
+                kind,
+                at: chrono::DateTime::from_timestamp(at, 0).unwrap_or_default(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Plan that the subscription of `user` is renewed with, if any.
+    pub fn auto_renew_plan(&self, user: UserId) -> Result<Option<String>, MyError> {
+        let plan = self
+            .conn()
+            .query_row(
+                "SELECT auto_renew_plan FROM balances WHERE telegram_id = ?1",
+                params![user.0 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(plan.flatten())
+    }
+
+    /// Renews the subscription of `user` with `plan` from now on, or stops
+    /// renewing it.
+    pub fn set_auto_renew(&self, user: UserId, plan: Option<&str>) -> Result<(), MyError> {
+        self.conn().execute(
+            "INSERT INTO balances (telegram_id, auto_renew_plan) VALUES (?1, ?2)
+             ON CONFLICT (telegram_id) DO UPDATE
+             SET auto_renew_plan = excluded.auto_renew_plan, warned_expiry = NULL",
+            params![user.0 as i64, plan],
+        )?;
+        Ok(())
+    }
+
+    /// Users with automatic renewal and their plans, except family
+    /// members, whose plan is their owner's, and banned users.
+    pub fn auto_renewals(&self) -> Result<Vec<(UserId, String)>, MyError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT telegram_id, auto_renew_plan FROM balances
+             WHERE auto_renew_plan IS NOT NULL
+               AND telegram_id NOT IN (SELECT telegram_id FROM family_members)
+               AND telegram_id NOT IN (SELECT telegram_id FROM bans)
+             ORDER BY telegram_id",
+        )?;
+        let renewals = stmt
+            .query_map([], |row| {
+                Ok((UserId(row.get::<_, i64>(0)? as u64), row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(renewals)
+    }
+
+    /// Records that `user` was warned about the renewal of the
+    /// subscription expiring at `expiry`.
+    ///
+    /// Returns `false` if they already were.
+    pub fn mark_renewal_warned(&self, user: UserId, expiry: i64) -> Result<bool, MyError> {
+        let updated = self.conn().execute(
+            "UPDATE balances SET warned_expiry = ?2
+             WHERE telegram_id = ?1 AND warned_expiry IS NOT ?2",
+            params![user.0 as i64, expiry],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Bans `user`. A repeated ban replaces the reason and the admin but
+    /// keeps the panel user disabled by the earlier one, which a second
+    /// ban finds disabled already.
+    pub fn ban(&self, user: UserId, ban: &Ban) -> Result<(), MyError> {
+        self.conn().execute(
+            "INSERT INTO bans (telegram_id, reason, banned_by, banned_at, disabled_uuid)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (telegram_id) DO UPDATE
+             SET reason = excluded.reason, banned_by = excluded.banned_by,
+                 banned_at = excluded.banned_at,
+                 disabled_uuid = COALESCE(disabled_uuid, excluded.disabled_uuid)",
+            params![
+                user.0 as i64,
+                ban.reason,
+                ban.banned_by.0 as i64,
+                ban.banned_at,
+                ban.disabled_uuid.map(|uuid| uuid.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Lifts the ban of `user`, returning it if there was one.
+    pub fn unban(&self, user: UserId) -> Result<Option<Ban>, MyError> {
+        let ban = self
+            .conn()
+            .query_row(
+                "DELETE FROM bans WHERE telegram_id = ?1
+                 RETURNING reason, banned_by, banned_at, disabled_uuid",
+                params![user.0 as i64],
+                |row| {
+                    let disabled_uuid: Option<String> = row.get(3)?;
+                    Ok(Ban {
+                        reason: row.get(0)?,
+                        banned_by: UserId(row.get::<_, i64>(1)? as u64),
+                        banned_at: row.get(2)?,
+                        disabled_uuid: disabled_uuid.and_then(|uuid| uuid.parse().ok()),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(ban)
+    }
+
+    /// Whether `user` is banned.
+    pub fn is_banned(&self, user: UserId) -> Result<bool, MyError> {
+        let banned = self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM bans WHERE telegram_id = ?1",
+                params![user.0 as i64],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(banned.is_some())
+    }
+
+    /// The open support ticket of `user`, opening one if there is none.
+    ///
+    /// The flag says whether the ticket was just opened.
+    pub fn open_ticket(&self, user: UserId) -> Result<(Ticket, bool), MyError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let inserted = tx.execute(
+            "INSERT INTO tickets (telegram_id, open, opened_at) VALUES (?1, 1, ?2)
+             ON CONFLICT (telegram_id) WHERE open DO NOTHING",
+            params![user.0 as i64, now()],
+        )?;
+        let ticket = tx.query_row(
+            "SELECT id, open, header_message_id FROM tickets WHERE telegram_id = ?1 AND open",
+            params![user.0 as i64],
+            |row| ticket_from_row(row, user),
+        )?;
+        tx.commit()?;
+        Ok((ticket, inserted > 0))
+    }
+
+    /// The open support ticket of `user`, if any.
+    pub fn find_open_ticket(&self, user: UserId) -> Result<Option<Ticket>, MyError> {
+        let ticket = self
+            .conn()
+            .query_row(
+                "SELECT id, open, header_message_id FROM tickets WHERE telegram_id = ?1 AND open",
+                params![user.0 as i64],
+                |row| ticket_from_row(row, user),
+            )
+            .optional()?;
+        Ok(ticket)
+    }
+
+    /// The support ticket with the given ID.
+    pub fn ticket(&self, id: i64) -> Result<Option<Ticket>, MyError> {
+        let ticket = self
+            .conn()
+            .query_row(
+                "SELECT id, open, header_message_id, telegram_id FROM tickets WHERE id = ?1",
+                params![id],
+                |row| ticket_from_row(row, UserId(row.get::<_, i64>(3)? as u64)),
+            )
+            .optional()?;
+        Ok(ticket)
+    }
+
+    /// Stores the message that introduces a ticket in the support chat.
+    pub fn set_ticket_header(
+        &self,
+        id: i64,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), MyError> {
+        self.conn().execute(
+            "UPDATE tickets SET header_message_id = ?2 WHERE id = ?1",
+            params![id, message_id.0],
+        )?;
+        self.link_ticket_message(chat_id, message_id, id)
+    }
+
+    /// Closes a support ticket; `false` if it was already closed.
+    pub fn close_ticket(&self, id: i64) -> Result<bool, MyError> {
+        let updated = self.conn().execute(
+            "UPDATE tickets SET open = 0, closed_at = ?2 WHERE id = ?1 AND open",
+            params![id, now()],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Remembers that a message in the support chat belongs to a ticket,
+    /// so that replies to it reach the user.
+    pub fn link_ticket_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        ticket_id: i64,
+    ) -> Result<(), MyError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO ticket_messages (chat_id, message_id, ticket_id)
+             VALUES (?1, ?2, ?3)",
+            params![chat_id.0, message_id.0, ticket_id],
+        )?;
+        Ok(())
+    }
+
+    /// The ticket a message in the support chat belongs to.
+    pub fn ticket_by_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Result<Option<Ticket>, MyError> {
+        let id: Option<i64> = self
+            .conn()
+            .query_row(
+                "SELECT ticket_id FROM ticket_messages WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id.0, message_id.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match id {
+            Some(id) => self.ticket(id),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::client::get_client;
+use crate::config::Config;
+use crate::error::MyError;
+use crate::keyboards;
+use crate::logger;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::outbox::{Outbox, OutgoingMessage};
+use crate::storage::Storage;
+use crate::types::HandlerResult;
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, ReplyParameters, User, UserId};
+
+/// A support ticket of a user.
+///
+/// While a user has an open ticket, everything they write to the bot is
+/// copied to the support chat, and replies of the support to those
+/// messages are copied back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ticket {
+    pub id: i64,
+    pub user: UserId,
+    pub open: bool,
+    /// Message that introduces the ticket in the support chat.
+    pub header: Option<MessageId>,
+}
+
+/// Panel account of `user`, as shown to the support.
+async fn panel_info(user: UserId) -> String {
+    let messages = Messages::ru();
+    match get_client()
+        .find_user_by_telegram_id(user.0)
+        .await
+        .into_user()
+    {
+        Ok(user) => messages.admin_user_card(&user, None),
+        Err(MyError::UserNotFound) => messages.support_no_subscription(),
+        Err(e) => {
+            log::error!("Failed to fetch the panel user of a ticket: {}", e);
+            messages.support_panel_error()
+        }
+    }
+}
+
+/// Handles the "support" button by opening a ticket.
+///
+/// A new ticket is announced in the support chat with the panel account
+/// of the user attached.
+pub async fn open(
+    bot: &Bot,
+    q: &CallbackQuery,
+    config: &Config,
+    storage: &Storage,
+) -> HandlerResult {
+    log::info!("User {} called support", logger::user(q.from.id));
+    metrics::record_callback("support");
+    let Some(chat_id) = q.chat_id() else {
+        return Ok(());
+    };
+    start_ticket(bot, chat_id, &q.from, config, storage).await
+}
+
+/// Opens a ticket of `user`, or reminds them of the open one, in `chat_id`.
+pub async fn start_ticket(
+    bot: &Bot,
+    chat_id: ChatId,
+    user: &User,
+    config: &Config,
+    storage: &Storage,
+) -> HandlerResult {
+    let messages = Messages::ru();
+
+    let Some(support_chat) = config.support_chat() else {
+        bot.send_message(chat_id, messages.support_unavailable())
+            .reply_markup(keyboards::back_to_main_menu())
+            .await?;
+        return Ok(());
+    };
+
+    let (ticket, created) = storage.open_ticket(user.id)?;
+    if created {
+        log::info!(
+            "User {} opened support ticket #{}",
+            logger::user(user.id),
+            ticket.id
+        );
+    }
+    // Also retried for a ticket whose announcement failed before.
+    if ticket.header.is_none() {
+        announce(bot, support_chat, &ticket, user, storage).await?;
+    }
+    bot.send_message(chat_id, messages.support_opened(ticket.id))
+        .reply_markup(keyboards::support_close())
+        .await?;
+    Ok(())
+}
+
+/// Posts the header of `ticket` in the support chat and returns it.
+async fn announce(
+    bot: &Bot,
+    support_chat: ChatId,
+    ticket: &Ticket,
+    user: &User,
+    storage: &Storage,
+) -> Result<MessageId, MyError> {
+    let header = Messages::ru().support_ticket_header(
+        ticket.id,
+        &user.full_name(),
+        user.username.as_deref(),
+        user.id,
+        &panel_info(user.id).await,
+    );
+    let sent = bot
+        .send_message(support_chat, header)
+        .reply_markup(keyboards::support_ticket(ticket.id))
+        .await?;
+    storage.set_ticket_header(ticket.id, support_chat, sent.id)?;
+    Ok(sent.id)
+}
+
+/// Copies a message of a user with an open ticket to the support chat.
+pub async fn relay_from_user(
+    bot: Bot,
+    msg: Message,
+    ticket: Ticket,
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    let Some(support_chat) = config.support_chat() else {
+        return Ok(());
+    };
+    metrics::record_command("support_message");
+
+    let header = match (ticket.header, &msg.from) {
+        (None, Some(user)) => announce(&bot, support_chat, &ticket, user, &storage)
+            .await
+            .inspect_err(|e| log::error!("Failed to announce ticket #{}: {}", ticket.id, e))
+            .ok(),
+        (header, _) => header,
+    };
+    let mut copy = bot.copy_message(support_chat, msg.chat.id, msg.id);
+    if let Some(header) = header {
+        copy = copy.reply_parameters(ReplyParameters::new(header).allow_sending_without_reply());
+    }
+    let copied = copy.await?;
+    storage.link_ticket_message(support_chat, copied, ticket.id)?;
+    Ok(())
+}
+
+/// The ticket a message in the support chat answers, if any.
+///
+/// Closed tickets are returned too, so that the support learns the answer
+/// wasn't delivered.
+pub fn answered_ticket(msg: &Message, config: &Config, storage: &Storage) -> Option<Ticket> {
+    if Some(msg.chat.id) != config.support_chat() {
+        return None;
+    }
+    let replied = msg.reply_to_message()?;
+    match storage.ticket_by_message(msg.chat.id, replied.id) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            log::error!("Failed to look up the ticket of a reply: {}", e);
+            None
+        }
+    }
+}
+
+/// Copies a reply of the support back to the user of the ticket.
+pub async fn relay_from_support(
+    bot: Bot,
+    msg: Message,
+    ticket: Ticket,
+    storage: Arc<Storage>,
+) -> HandlerResult {
+    let messages = Messages::ru();
+    if !ticket.open {
+        bot.send_message(msg.chat.id, messages.support_reply_to_closed(ticket.id))
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    match bot
+        .copy_message(ChatId::from(ticket.user), msg.chat.id, msg.id)
+        .await
+    {
+        Ok(_) => {
+            storage.link_ticket_message(msg.chat.id, msg.id, ticket.id)?;
+            if let Some(admin) = &msg.from {
+                log::info!(
+                    "Admin {} answered support ticket #{}",
+                    logger::user(admin.id),
+                    ticket.id
+                );
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to relay an answer to ticket #{}: {}", ticket.id, e);
+            bot.send_message(
+                msg.chat.id,
+                messages.support_delivery_failed(&e.to_string()),
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles the "close ticket" button of a user.
+pub async fn close_by_user(
+    bot: &Bot,
+    q: &CallbackQuery,
+    config: &Config,
+    storage: &Storage,
+    outbox: &Outbox,
+) -> HandlerResult {
+    let user = q.from.id;
+    log::info!("User {} called support_close", logger::user(user));
+    metrics::record_callback("support_close");
+    let messages = Messages::ru();
+
+    let Some(ticket) = storage.find_open_ticket(user)? else {
+        if let (Some(chat_id), Some(msg)) = (q.chat_id(), &q.message) {
+            bot.edit_message_reply_markup(chat_id, msg.id()).await?;
+        }
+        return Ok(());
+    };
+    storage.close_ticket(ticket.id)?;
+    log::info!(
+        "User {} closed support ticket #{}",
+        logger::user(user),
+        ticket.id
+    );
+
+    if let (Some(chat_id), Some(msg)) = (q.chat_id(), &q.message) {
+        bot.edit_message_text(chat_id, msg.id(), messages.support_closed(ticket.id))
+            .reply_markup(keyboards::back_to_main_menu())
+            .await?;
+    }
+    if let Some(support_chat) = config.support_chat() {
+        let mut notification =
+            OutgoingMessage::text(support_chat, messages.support_closed_by_user(ticket.id));
+        if let Some(header) = ticket.header {
+            notification = notification.reply_to(header);
+        }
+        outbox.enqueue(notification);
+    }
+    Ok(())
+}
+
+/// Handles the `support:close:<id>` button in the support chat.
+pub async fn handle_support_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    storage: Arc<Storage>,
+    outbox: Arc<Outbox>,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+    metrics::record_callback("support_admin");
+    let messages = Messages::ru();
+    let Some(id) = q
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("support:close:"))
+        .and_then(|id| id.parse::<i64>().ok())
+    else {
+        return Ok(());
+    };
+    let (Some(chat_id), Some(msg)) = (q.chat_id(), &q.message) else {
+        return Ok(());
+    };
+
+    bot.edit_message_reply_markup(chat_id, msg.id()).await?;
+    let Some(ticket) = storage.ticket(id)? else {
+        return Ok(());
+    };
+    if !storage.close_ticket(ticket.id)? {
+        return Ok(());
+    }
+    log::info!(
+        "Admin {} closed support ticket #{}",
+        logger::user(q.from.id),
+        ticket.id
+    );
+    bot.send_message(
+        chat_id,
+        messages.support_closed_by_admin(ticket.id, &q.from.full_name()),
+    )
+    .reply_parameters(ReplyParameters::new(msg.id()))
+    .await?;
+    outbox.enqueue(OutgoingMessage::text(
+        ChatId::from(ticket.user),
+        messages.support_closed(ticket.id),
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_open_ticket_per_user() {
+        let storage = Storage::open_in_memory().unwrap();
+
+        let (ticket, created) = storage.open_ticket(UserId(10)).unwrap();
+        assert!(created);
+        let (again, created) = storage.open_ticket(UserId(10)).unwrap();
+        assert!(!created);
+        assert_eq!(again, ticket);
+
+        assert!(storage.close_ticket(ticket.id).unwrap());
+        assert!(!storage.close_ticket(ticket.id).unwrap());
+        assert_eq!(storage.find_open_ticket(UserId(10)).unwrap(), None);
+
+        let (next, created) = storage.open_ticket(UserId(10)).unwrap();
+        assert!(created);
+        assert_ne!(next.id, ticket.id);
+    }
+
+    #[test]
+    fn tickets_are_unannounced_until_their_header_is_set() {
+        let storage = Storage::open_in_memory().unwrap();
+        let (ticket, _) = storage.open_ticket(UserId(10)).unwrap();
+        assert_eq!(ticket.header, None);
+
+        let (again, created) = storage.open_ticket(UserId(10)).unwrap();
+        assert!(!created);
+        assert_eq!(again.header, None);
+
+        storage
+            .set_ticket_header(ticket.id, ChatId(-100), MessageId(1))
+            .unwrap();
+        let (announced, _) = storage.open_ticket(UserId(10)).unwrap();
+        assert_eq!(announced.header, Some(MessageId(1)));
+    }
+
+    #[test]
+    fn replies_find_their_ticket() {
+        let storage = Storage::open_in_memory().unwrap();
+        let chat = ChatId(-100);
+        let (ticket, _) = storage.open_ticket(UserId(10)).unwrap();
+
+        storage
+            .set_ticket_header(ticket.id, chat, MessageId(1))
+            .unwrap();
+        storage
+            .link_ticket_message(chat, MessageId(2), ticket.id)
+            .unwrap();
+
+        let header = storage.ticket_by_message(chat, MessageId(1)).unwrap();
+        assert_eq!(header.unwrap().header, Some(MessageId(1)));
+        assert_eq!(
+            storage
+                .ticket_by_message(chat, MessageId(2))
+                .unwrap()
+                .map(|ticket| ticket.user),
+            Some(UserId(10))
+        );
+        assert_eq!(storage.ticket_by_message(chat, MessageId(3)).unwrap(), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! A fake panel for tests: an HTTP server answering the user lookups,
+//! creations and updates of [`PanelClient`] from a list of users.
+
+use crate::client::PanelClient;
+use crate::config::PanelConfig;
+use crate::users::test_user;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use remnawave::api::types::users::UserData;
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Default)]
+struct Panel {
+    users: Mutex<Vec<UserData>>,
+    requests: Mutex<Vec<String>>,
+    failing: Mutex<HashSet<Uuid>>,
+}
+
+type Reply = (StatusCode, Json<Value>);
+
+impl Panel {
+    /// Records `request` and answers with the users matching `filter`: all
+    /// of them if `many`, else the first one.
+    fn find(&self, request: String, many: bool, filter: impl Fn(&UserData) -> bool) -> Reply {
+        self.requests.lock().unwrap().push(request);
+        let users = self.users.lock().unwrap();
+        let found: Vec<_> = users.iter().filter(|user| filter(user)).collect();
+        match (found.first(), many) {
+            (None, _) => not_found(),
+            (Some(_), true) => (StatusCode::OK, Json(json!({ "response": found }))),
+            (Some(user), false) => (StatusCode::OK, Json(json!({ "response": user }))),
+        }
+    }
+}
+
+fn not_found() -> Reply {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "message": "User not found" })),
+    )
+}
+
+async fn by_telegram_id(State(panel): State<Arc<Panel>>, Path(id): Path<i64>) -> Reply {
+    panel.find(format!("telegram {}", id), true, |user| {
+        user.telegram_id == Some(id)
+    })
+}
+
+async fn by_username(State(panel): State<Arc<Panel>>, Path(name): Path<String>) -> Reply {
+    panel.find(format!("username {}", name), false, |user| {
+        user.username == name
+    })
+}
+
+async fn by_email(State(panel): State<Arc<Panel>>, Path(email): Path<String>) -> Reply {
+    panel.find(format!("email {}", email), true, |user| {
+        user.email.as_deref() == Some(email.as_str())
+    })
+}
+
+async fn by_uuid(State(panel): State<Arc<Panel>>, Path(uuid): Path<Uuid>) -> Reply {
+    panel.find(format!("uuid {}", uuid), false, |user| user.uuid == uuid)
+}
+
+async fn create(State(panel): State<Arc<Panel>>, Json(request): Json<Value>) -> Reply {
+    let username = request["username"].as_str().unwrap_or_default();
+    panel
+        .requests
+        .lock()
+        .unwrap()
+        .push(format!("create {}", username));
+    let mut user = test_user(username, request["telegramId"].as_i64());
+    if let Ok(expire_at) = serde_json::from_value(request["expireAt"].clone()) {
+        user.expire_at = expire_at;
+    }
+    panel.users.lock().unwrap().push(user.clone());
+    (StatusCode::CREATED, Json(json!({ "response": user })))
+}
+
+async fn update(State(panel): State<Arc<Panel>>, Json(request): Json<Value>) -> Reply {
+    let Ok(uuid) = serde_json::from_value::<Uuid>(request["uuid"].clone()) else {
+        return not_found();
+    };
+    panel
+        .requests
+        .lock()
+        .unwrap()
+        .push(format!("update {}", uuid));
+    if panel.failing.lock().unwrap().contains(&uuid) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "message": "injected failure" })),
+        );
+    }
+    let mut users = panel.users.lock().unwrap();
+    let Some(user) = users.iter_mut().find(|user| user.uuid == uuid) else {
+        return not_found();
+    };
+    if let Ok(expire_at) = serde_json::from_value(request["expireAt"].clone()) {
+        user.expire_at = expire_at;
+    }
+    if let Some(limit) = request.get("hwidDeviceLimit") {
+        user.hwid_device_limit = serde_json::from_value(limit.clone()).unwrap();
+    }
+    (StatusCode::OK, Json(json!({ "response": user })))
+}
+
+/// A running fake panel with a client pointed at it.
+pub(crate) struct TestPanel {
+    panel: Arc<Panel>,
+    pub client: PanelClient,
+}
+
+impl TestPanel {
+    /// Starts a panel with `users`.
+    pub async fn start(users: Vec<UserData>) -> Self {
+        let panel = Arc::new(Panel {
+            users: Mutex::new(users),
+            ..Default::default()
+        });
+        let app = Router::new()
+            .route("/api/users", post(create).patch(update))
+            .route("/api/users/by-telegram-id/{id}", get(by_telegram_id))
+            .route("/api/users/by-username/{name}", get(by_username))
+            .route("/api/users/by-email/{email}", get(by_email))
+            .route("/api/users/{uuid}", get(by_uuid))
+            .with_state(panel.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = PanelConfig {
+            timeout: Duration::from_millis(500),
+            max_retries: 0,
+            retry_base_delay: Duration::from_millis(1),
+            breaker_threshold: 10,
+            breaker_open_for: Duration::from_millis(100),
+        };
+        let client = PanelClient::new(&format!("http://{}", addr), "token", &config).unwrap();
+        Self { panel, client }
+    }
+
+    /// The user linked to `telegram_id`.
+    pub fn user(&self, telegram_id: i64) -> Option<UserData> {
+        let users = self.panel.users.lock().unwrap();
+        users
+            .iter()
+            .find(|user| user.telegram_id == Some(telegram_id))
+            .cloned()
+    }
+
+    /// Requests served so far, like `telegram 42` or `update <uuid>`.
+    pub fn requests(&self) -> Vec<String> {
+        self.panel.requests.lock().unwrap().clone()
+    }
+
+    /// Makes every later update of user `uuid` fail.
+    pub fn fail_updates(&self, uuid: Uuid) {
+        self.panel.failing.lock().unwrap().insert(uuid);
+    }
+}
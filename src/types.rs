@@ -7,7 +7,29 @@ pub enum Command {
     #[command(description = "Показывает этот текст.")]
     Help,
     #[command(description = "Запускает операцию добавления подключений к GlebusVPN.")]
-    Start,
+    Start(String),
+}
+
+/// Commands available to the admins listed in the configuration.
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "Команды администратора:")]
+pub enum AdminCommand {
+    #[command(description = "Рассылка сообщения всем пользователям.")]
+    Broadcast,
+    #[command(description = "Поиск пользователя: /find <uuid, Telegram ID, email или имя>.")]
+    Find(String),
+    #[command(description = "Журнал действий: /audit [actor= target= action= days= format=].")]
+    Audit(String),
+    #[command(description = "Код приглашения: /invite [число использований].")]
+    Invite(String),
+    #[command(
+        description = "Промокод: /promo <дней> [число использований] или /promo balance <сумма> [число использований]."
+    )]
+    Promo(String),
+    #[command(description = "Блокировка: /ban <Telegram ID> [причина].")]
+    Ban(String),
+    #[command(description = "Снятие блокировки: /unban <Telegram ID>.")]
+    Unban(String),
 }
 
 pub type HandlerResult = Result<(), MyError>;
@@ -0,0 +1,401 @@
+use crate::client::PanelClient;
+use crate::error::MyError;
+use crate::logger::LogUser;
+use chrono::{TimeZone, Utc};
+use once_cell::sync::Lazy;
+use remnawave::CreateUserRequestDto;
+use remnawave::api::types::users::UserData;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+/// Locks shared by all handlers that change a user's panel account.
+static LOCKS: Lazy<UserLocks> = Lazy::new(UserLocks::default);
+
+/// Returns the per-Telegram-ID operation locks of the bot.
+pub fn locks() -> &'static UserLocks {
+    &LOCKS
+}
+
+/// One async lock per Telegram ID, so that operations on the same
+/// account run one after another while different accounts don't wait
+/// for each other.
+///
+/// Locks are created on demand and dropped when nobody holds or waits
+/// for them.
+#[derive(Default)]
+pub struct UserLocks {
+    locks: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Holds the lock of one Telegram ID until dropped.
+pub struct UserLockGuard<'a> {
+    locks: &'a UserLocks,
+    telegram_id: u64,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl UserLocks {
+    /// Waits until no other operation on `telegram_id` is running.
+    pub async fn lock(&self, telegram_id: u64) -> UserLockGuard<'_> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(telegram_id)
+            .or_default()
+            .clone();
+        UserLockGuard {
+            locks: self,
+            telegram_id,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+impl Drop for UserLockGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.locks.locks.lock().unwrap();
+        // The map holds one reference; anything more is a waiting operation.
+        if locks
+            .get(&self.telegram_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.telegram_id);
+        }
+    }
+}
+
+/// Where panel users live, as far as creating them is concerned.
+pub trait UserDirectory {
+    type User;
+
+    /// Returns the user linked to `telegram_id`, if any.
+    fn find(
+        &self,
+        telegram_id: u64,
+    ) -> impl Future<Output = Result<Option<Self::User>, MyError>> + Send;
+
+    /// Creates a user linked to `telegram_id`, failing with
+    /// `MyError::UsernameTaken` if `username` is in use.
+    fn create(
+        &self,
+        username: &str,
+        telegram_id: u64,
+    ) -> impl Future<Output = Result<Self::User, MyError>> + Send;
+}
+
+impl UserDirectory for PanelClient {
+    type User = UserData;
+
+    async fn find(&self, telegram_id: u64) -> Result<Option<UserData>, MyError> {
+        match self.find_user_by_telegram_id(telegram_id).await.into_user() {
+            Ok(user) => Ok(Some(user)),
+            Err(MyError::UserNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn create(&self, username: &str, telegram_id: u64) -> Result<UserData, MyError> {
+        let telegram_id: i64 = telegram_id
+            .try_into()
+            .map_err(|_| MyError::Custom("User ID too large for i64".to_string()))?;
+        let request = CreateUserRequestDto {
+            username: username.to_string(),
+            status: remnawave::api::types::common::UserStatus::Active,
+            short_uuid: None,
+            trojan_password: None,
+            vless_uuid: None,
+            ss_password: None,
+            traffic_limit_bytes: None,
+            traffic_limit_strategy: remnawave::api::types::common::TrafficLimitStrategy::NoReset,
+            expire_at: Utc.with_ymd_and_hms(2099, 1, 1, 0, 0, 0).unwrap(),
+            created_at: None,
+            last_traffic_reset_at: None,
+            description: None,
+            tag: None,
+            telegram_id: Some(Some(telegram_id)),
+            email: None,
+            hwid_device_limit: None,
+            active_internal_squads: None,
+            uuid: None,
+            external_squad_uuid: None,
+        };
+        self.create_user(request).await.map(|user| user.response)
+    }
+}
+
+/// Outcome of [`ensure_user`].
+#[derive(Debug)]
+pub enum Provisioned<U> {
+    /// A new user was created.
+    Created(U),
+    /// The account already had a user, nothing was created.
+    Existing(U),
+}
+
+/// Usernames to try for a new user, in order: the Telegram username,
+/// the numeric Telegram ID, and the ID with a `tg_` prefix in case
+/// someone took the bare number.
+fn username_candidates(username: Option<&str>, telegram_id: u64) -> Vec<String> {
+    username
+        .map(str::to_string)
+        .into_iter()
+        .chain([telegram_id.to_string(), format!("tg_{}", telegram_id)])
+        .collect()
+}
+
+/// Returns the user linked to `telegram_id`, creating it if there is none.
+///
+/// Runs under the account's lock, so concurrent calls for the same
+/// Telegram ID create at most one user. Usernames are tried as described
+/// in [`username_candidates`]; after each collision the account is looked
+/// up again, in case the taken name is ours from an earlier attempt whose
+/// response was lost.
+///
+/// # Errors
+///
+/// Returns the panel error, or `MyError::UsernameTaken` if every candidate
+/// username is in use.
+pub async fn ensure_user<D: UserDirectory>(
+    directory: &D,
+    locks: &UserLocks,
+    telegram_id: u64,
+    username: Option<&str>,
+) -> Result<Provisioned<D::User>, MyError> {
+    let _guard = locks.lock(telegram_id).await;
+
+    if let Some(user) = directory.find(telegram_id).await? {
+        return Ok(Provisioned::Existing(user));
+    }
+
+    let mut last_error = None;
+    for candidate in username_candidates(username, telegram_id) {
+        match directory.create(&candidate, telegram_id).await {
+            Ok(user) => return Ok(Provisioned::Created(user)),
+            Err(MyError::UsernameTaken(name)) => {
+                log::warn!(
+                    "Username {} is taken, user {} gets another one",
+                    name,
+                    LogUser(telegram_id)
+                );
+                if let Some(user) = directory.find(telegram_id).await? {
+                    return Ok(Provisioned::Existing(user));
+                }
+                last_error = Some(MyError::UsernameTaken(name));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or(MyError::UserNotFound))
+}
+
+/// A Telegram user `id` named `user<id>`, for tests.
+#[cfg(test)]
+pub(crate) fn telegram_user(id: u64) -> teloxide::types::User {
+    teloxide::types::User {
+        id: teloxide::types::UserId(id),
+        is_bot: false,
+        first_name: "Test".to_string(),
+        last_name: None,
+        username: Some(format!("user{}", id)),
+        language_code: None,
+        is_premium: false,
+        added_to_attachment_menu: false,
+    }
+}
+
+/// A panel user named `username` linked to `telegram_id`, for tests.
+#[cfg(test)]
+pub(crate) fn test_user(username: &str, telegram_id: Option<i64>) -> UserData {
+    serde_json::from_value(serde_json::json!({
+        "uuid": uuid::Uuid::new_v4(),
+        "shortUuid": "short",
+        "username": username,
+        "status": "ACTIVE",
+        "usedTrafficBytes": 0,
+        "lifetimeUsedTrafficBytes": 0,
+        "trafficLimitBytes": 0,
+        "expireAt": "2030-01-01T00:00:00Z",
+        "trojanPassword": "password",
+        "vlessUuid": uuid::Uuid::new_v4(),
+        "ssPassword": "password",
+        "telegramId": telegram_id,
+        "createdAt": "2024-01-01T00:00:00Z",
+        "updatedAt": "2024-01-01T00:00:00Z",
+        "activeInternalSquads": [],
+        "subscriptionUrl": "https://example.com/sub",
+        "happ": { "cryptoLink": "happ://crypt" },
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// In-memory panel: usernames by Telegram ID, plus usernames
+    /// taken by accounts outside the bot.
+    #[derive(Default)]
+    struct FakeDirectory {
+        users: Mutex<HashMap<u64, String>>,
+        taken: Vec<String>,
+        creates: AtomicUsize,
+    }
+
+    impl UserDirectory for FakeDirectory {
+        type User = String;
+
+        async fn find(&self, telegram_id: u64) -> Result<Option<String>, MyError> {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            Ok(self.users.lock().unwrap().get(&telegram_id).cloned())
+        }
+
+        async fn create(&self, username: &str, telegram_id: u64) -> Result<String, MyError> {
+            self.creates.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut users = self.users.lock().unwrap();
+            if self.taken.iter().any(|name| name == username)
+                || users.values().any(|name| name == username)
+            {
+                return Err(MyError::UsernameTaken(username.to_string()));
+            }
+            users.insert(telegram_id, username.to_string());
+            Ok(username.to_string())
+        }
+    }
+
+    fn created(result: &Provisioned<String>) -> bool {
+        matches!(result, Provisioned::Created(_))
+    }
+
+    #[tokio::test]
+    async fn concurrent_callbacks_create_one_user() {
+        let directory = Arc::new(FakeDirectory::default());
+        let locks = Arc::new(UserLocks::default());
+
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let directory = directory.clone();
+                let locks = locks.clone();
+                tokio::spawn(async move {
+                    ensure_user(&*directory, &locks, 42, Some("alice"))
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+
+        assert_eq!(results.iter().filter(|r| created(r)).count(), 1);
+        assert_eq!(directory.creates.load(Ordering::SeqCst), 1);
+        assert_eq!(directory.users.lock().unwrap().len(), 1);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn existing_user_is_returned() {
+        let directory = FakeDirectory::default();
+        directory.users.lock().unwrap().insert(42, "alice".into());
+
+        let result = ensure_user(&directory, &UserLocks::default(), 42, Some("alice"))
+            .await
+            .unwrap();
+
+        assert!(matches!(result, Provisioned::Existing(name) if name == "alice"));
+        assert_eq!(directory.creates.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn taken_username_falls_back_to_telegram_id() {
+        let directory = FakeDirectory {
+            taken: vec!["alice".into()],
+            ..Default::default()
+        };
+
+        let result = ensure_user(&directory, &UserLocks::default(), 42, Some("alice"))
+            .await
+            .unwrap();
+
+        assert!(matches!(result, Provisioned::Created(name) if name == "42"));
+    }
+
+    #[tokio::test]
+    async fn taken_telegram_id_falls_back_to_prefixed_id() {
+        let directory = FakeDirectory {
+            taken: vec!["alice".into(), "42".into()],
+            ..Default::default()
+        };
+
+        let result = ensure_user(&directory, &UserLocks::default(), 42, Some("alice"))
+            .await
+            .unwrap();
+
+        assert!(matches!(result, Provisioned::Created(name) if name == "tg_42"));
+    }
+
+    #[tokio::test]
+    async fn missing_username_uses_telegram_id() {
+        let directory = FakeDirectory::default();
+
+        let result = ensure_user(&directory, &UserLocks::default(), 42, None)
+            .await
+            .unwrap();
+
+        assert!(matches!(result, Provisioned::Created(name) if name == "42"));
+    }
+
+    #[tokio::test]
+    async fn same_username_from_different_accounts() {
+        let directory = Arc::new(FakeDirectory::default());
+        let locks = Arc::new(UserLocks::default());
+
+        let tasks: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|telegram_id| {
+                let directory = directory.clone();
+                let locks = locks.clone();
+                tokio::spawn(async move {
+                    ensure_user(&*directory, &locks, telegram_id, Some("alice"))
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert!(created(&task.await.unwrap()));
+        }
+
+        let users = directory.users.lock().unwrap();
+        let mut names: Vec<_> = users.values().cloned().collect();
+        names.sort();
+        assert!(names.contains(&"alice".to_string()));
+        assert_eq!(names.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn all_usernames_taken() {
+        let directory = FakeDirectory {
+            taken: vec!["alice".into(), "42".into(), "tg_42".into()],
+            ..Default::default()
+        };
+
+        let error = ensure_user(&directory, &UserLocks::default(), 42, Some("alice"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, MyError::UsernameTaken(name) if name == "tg_42"));
+    }
+}
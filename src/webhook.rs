@@ -0,0 +1,351 @@
+use crate::error::MyError;
+use crate::logger;
+use crate::messages::Messages;
+use crate::outbox::{Outbox, OutgoingMessage};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use teloxide::prelude::*;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header carrying the hex-encoded HMAC-SHA256 of the request body.
+pub const SIGNATURE_HEADER: &str = "x-remnawave-signature";
+
+/// Shared state of the webhook endpoint.
+#[derive(Clone)]
+pub struct WebhookState {
+    pub outbox: Arc<Outbox>,
+    pub secret: String,
+    pub admin_chat_id: Option<ChatId>,
+}
+
+/// A webhook event as sent by the Remnawave panel.
+#[derive(Debug, Deserialize)]
+pub struct WebhookEvent {
+    pub event: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// The subset of the panel user object the bot needs to notify the user.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventUser {
+    telegram_id: Option<i64>,
+}
+
+/// The subset of the panel node object used in admin notifications.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventNode {
+    name: String,
+    address: String,
+}
+
+/// Checks that `signature` is the hex-encoded HMAC-SHA256 of `body` keyed with `secret`.
+///
+/// The comparison is done in constant time.
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(expected) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let Ok(mut mac) = HmacSha256::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+/// Axum handler for `POST /webhook/remnawave`.
+///
+/// Rejects requests with a missing or invalid signature, otherwise parses the
+/// event and forwards it to the affected user or to the admin chat.
+pub async fn receive(
+    State(state): State<WebhookState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    if !verify_signature(&state.secret, &body, signature) {
+        log::warn!("Rejected panel webhook with invalid signature");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let event: WebhookEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(e) => {
+            log::warn!("Failed to parse panel webhook: {}", e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    log::info!("Received panel webhook event {}", event.event);
+
+    if let Err(e) = dispatch_event(&state, &event) {
+        crate::metrics::record_error(&e);
+        log::error!("Failed to handle panel webhook {}: {}", event.event, e);
+    }
+
+    StatusCode::OK
+}
+
+/// Queues the notification an event calls for, if any.
+fn dispatch_event(state: &WebhookState, event: &WebhookEvent) -> Result<(), MyError> {
+    if let Some((chat_id, text)) = notification(event, state.admin_chat_id)? {
+        state.outbox.enqueue(OutgoingMessage::text(chat_id, text));
+        log::info!(
+            "Queued {} notification to {}",
+            event.event,
+            logger::LogUser(chat_id.0 as u64)
+        );
+    }
+    Ok(())
+}
+
+/// The chat an event is reported to and the text, depending on its scope:
+/// user events go to the affected user, node events to the admin chat.
+fn notification(
+    event: &WebhookEvent,
+    admin_chat_id: Option<ChatId>,
+) -> Result<Option<(ChatId, String)>, MyError> {
+    match event.event.split_once('.') {
+        Some(("user", name)) => user_notification(name, &event.data),
+        Some(("node", name)) => node_notification(name, &event.data, admin_chat_id),
+        _ => {
+            log::debug!("Ignoring panel webhook event {}", event.event);
+            Ok(None)
+        }
+    }
+}
+
+fn user_notification(
+    name: &str,
+    data: &serde_json::Value,
+) -> Result<Option<(ChatId, String)>, MyError> {
+    let messages = Messages::ru();
+    let text = match name {
+        "expired" => messages.subscription_expired(),
+        "limited" => messages.traffic_limit_reached(),
+        "disabled" => messages.subscription_disabled(),
+        "enabled" => messages.subscription_enabled(),
+        "expires_in_72_hours" => messages.subscription_expires_in(72),
+        "expires_in_48_hours" => messages.subscription_expires_in(48),
+        "expires_in_24_hours" => messages.subscription_expires_in(24),
+        _ => {
+            log::debug!("Ignoring panel webhook event user.{}", name);
+            return Ok(None);
+        }
+    };
+
+    let user: EventUser = serde_json::from_value(data.clone())?;
+    let Some(telegram_id) = user.telegram_id else {
+        log::info!(
+            "Panel user without Telegram ID, skipping user.{} notification",
+            name
+        );
+        return Ok(None);
+    };
+    Ok(Some((ChatId(telegram_id), text)))
+}
+
+fn node_notification(
+    name: &str,
+    data: &serde_json::Value,
+    admin_chat_id: Option<ChatId>,
+) -> Result<Option<(ChatId, String)>, MyError> {
+    let Some(admin_chat_id) = admin_chat_id else {
+        log::debug!(
+            "Admin chat is not configured, skipping node.{} notification",
+            name
+        );
+        return Ok(None);
+    };
+
+    let node: EventNode = serde_json::from_value(data.clone())?;
+    let messages = Messages::ru();
+    let text = match name {
+        "connection_lost" => messages.node_connection_lost(&node.name, &node.address),
+        "connection_restored" => messages.node_connection_restored(&node.name, &node.address),
+        "disabled" => messages.node_disabled(&node.name, &node.address),
+        "enabled" => messages.node_enabled(&node.name, &node.address),
+        _ => {
+            log::debug!("Ignoring panel webhook event node.{}", name);
+            return Ok(None);
+        }
+    };
+    Ok(Some((admin_chat_id, text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET: &str = "secret";
+
+    fn sign(body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let body = br#"{"event":"user.expired"}"#;
+        let signature = sign(body);
+        assert!(verify_signature(SECRET, body, &signature));
+        assert!(verify_signature(SECRET, body, &format!(" {} ", signature)));
+
+        assert!(!verify_signature(
+            SECRET,
+            br#"{"event":"user.enabled"}"#,
+            &signature
+        ));
+        assert!(!verify_signature("other", body, &signature));
+        let mut tampered = signature.into_bytes();
+        tampered[0] = if tampered[0] == b'0' { b'1' } else { b'0' };
+        assert!(!verify_signature(
+            SECRET,
+            body,
+            std::str::from_utf8(&tampered).unwrap()
+        ));
+        assert!(!verify_signature(SECRET, body, ""));
+        assert!(!verify_signature(SECRET, body, "not hex"));
+    }
+
+    #[tokio::test]
+    async fn rejects_unsigned_requests() {
+        let storage = crate::storage::Storage::open_in_memory().unwrap();
+        let outbox = Outbox::start(
+            Bot::new("token"),
+            storage,
+            &crate::config::OutboxConfig {
+                global_per_second: 30,
+                per_chat_interval: std::time::Duration::from_secs(1),
+                max_attempts: 1,
+            },
+        );
+        let state = WebhookState {
+            outbox,
+            secret: SECRET.to_string(),
+            admin_chat_id: None,
+        };
+        let body = br#"{"event":"node.enabled","data":{}}"#;
+
+        let missing = receive(
+            State(state.clone()),
+            HeaderMap::new(),
+            Bytes::from_static(body),
+        );
+        assert_eq!(missing.await, StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, sign(b"other").parse().unwrap());
+        let tampered = receive(State(state.clone()), headers, Bytes::from_static(body));
+        assert_eq!(tampered.await, StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, sign(body).parse().unwrap());
+        let signed = receive(State(state), headers, Bytes::from_static(body));
+        assert_eq!(signed.await, StatusCode::OK);
+    }
+
+    fn event(name: &str, data: serde_json::Value) -> WebhookEvent {
+        WebhookEvent {
+            event: name.to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn user_events_go_to_the_user() {
+        let messages = Messages::ru();
+        let user = json!({ "telegramId": 42, "username": "alice" });
+        for (name, text) in [
+            ("user.expired", messages.subscription_expired()),
+            ("user.limited", messages.traffic_limit_reached()),
+            ("user.disabled", messages.subscription_disabled()),
+            ("user.enabled", messages.subscription_enabled()),
+            (
+                "user.expires_in_72_hours",
+                messages.subscription_expires_in(72),
+            ),
+            (
+                "user.expires_in_48_hours",
+                messages.subscription_expires_in(48),
+            ),
+            (
+                "user.expires_in_24_hours",
+                messages.subscription_expires_in(24),
+            ),
+        ] {
+            assert_eq!(
+                notification(&event(name, user.clone()), Some(ChatId(-1))).unwrap(),
+                Some((ChatId(42), text)),
+                "{}",
+                name
+            );
+        }
+
+        let without_telegram = json!({ "telegramId": null });
+        assert_eq!(
+            notification(&event("user.expired", without_telegram), None).unwrap(),
+            None
+        );
+        assert_eq!(
+            notification(&event("user.created", user), None).unwrap(),
+            None
+        );
+        assert!(notification(&event("user.expired", json!("garbage")), None).is_err());
+    }
+
+    #[test]
+    fn node_events_go_to_the_admin_chat() {
+        let messages = Messages::ru();
+        let node = json!({ "name": "de-1", "address": "1.2.3.4" });
+        let admins = Some(ChatId(-100));
+        for (name, text) in [
+            (
+                "node.connection_lost",
+                messages.node_connection_lost("de-1", "1.2.3.4"),
+            ),
+            (
+                "node.connection_restored",
+                messages.node_connection_restored("de-1", "1.2.3.4"),
+            ),
+            ("node.disabled", messages.node_disabled("de-1", "1.2.3.4")),
+            ("node.enabled", messages.node_enabled("de-1", "1.2.3.4")),
+        ] {
+            assert_eq!(
+                notification(&event(name, node.clone()), admins).unwrap(),
+                Some((ChatId(-100), text)),
+                "{}",
+                name
+            );
+        }
+
+        assert_eq!(
+            notification(&event("node.enabled", node.clone()), None).unwrap(),
+            None
+        );
+        assert_eq!(
+            notification(&event("node.created", node.clone()), admins).unwrap(),
+            None
+        );
+        assert_eq!(
+            notification(&event("crm.billing", node), admins).unwrap(),
+            None
+        );
+    }
+}